@@ -48,7 +48,8 @@ pub struct AccountAuthProviderAccount {
     pub failed: bool,
     pub failed_reason: Option<String>,
     pub failure_retries: i32,
-    pub last_failure_retry: Option<DateTime<Utc>>
+    pub last_failure_retry: Option<DateTime<Utc>>,
+    pub stage: Option<String>, // Only used by runs in this stage. None means every stage
 }
 
 #[derive(Debug, Clone)]
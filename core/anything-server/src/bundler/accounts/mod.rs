@@ -6,11 +6,14 @@ use std::sync::Arc;
 use dotenv::dotenv;
 use std::env;
 use serde_json::json;
-use tracing::debug;
+use std::collections::HashSet;
+use std::future::Future;
+use tracing::{debug, warn};
 
 pub mod accounts_cache;
 
 use crate::auth::refresh::refresh_accounts;
+use crate::bundler::BundlerError;
 
 use std::error::Error;
 
@@ -19,6 +22,7 @@ pub async fn fetch_cached_auth_accounts(
     client: &Postgrest,
     account_id: &str,
     refresh_auth: bool,
+    referenced_slugs: &HashSet<String>,
 ) -> Result<Vec<AccountAuthProviderAccount>, Box<dyn Error + Send + Sync>> {
     println!("[FAST AUTH ACCOUNTS] Fetching cached auth accounts");
 
@@ -38,22 +42,10 @@ pub async fn fetch_cached_auth_accounts(
     //If caller needs up to date info
     //Check if cached accounts need to have access_token refreshed
     if refresh_auth {
-        let now = Utc::now();
-        let expiry_threshold = now + chrono::Duration::minutes(5);
-        let needs_refresh = accounts.iter().any(|account| {
-            !account.failed
-                && account
-                    .access_token_expires_at
-                    .map(|expires_at| expires_at <= expiry_threshold)
-                    .unwrap_or(false)
-        });
-
-        if !needs_refresh {
-            println!("[FAST AUTH ACCOUNTS] Cached accounts do not need refresh");
-        } else {
-            println!("[FAST AUTH ACCOUNTS] Cached accounts need to have access_token refreshed");
-            accounts = refresh_accounts(client, accounts).await?;
-        }
+        accounts = refresh_referenced_accounts(accounts, referenced_slugs, |account| {
+            refresh_accounts(client, vec![account])
+        })
+        .await?;
     }
 
     //Update the cache  
@@ -65,6 +57,51 @@ pub async fn fetch_cached_auth_accounts(
     Ok(accounts)
 }
 
+// Only accounts the templates reference are refreshed, one at a time. If one of them can't be
+// refreshed the bundle fails with AccountRefreshFailed. Accounts nothing references are left as
+// they are so an unrelated expired account doesn't block the run
+pub async fn refresh_referenced_accounts<F, Fut>(
+    accounts: Vec<AccountAuthProviderAccount>,
+    referenced_slugs: &HashSet<String>,
+    refresh: F,
+) -> Result<Vec<AccountAuthProviderAccount>, BundlerError>
+where
+    F: Fn(AccountAuthProviderAccount) -> Fut,
+    Fut: Future<Output = Result<Vec<AccountAuthProviderAccount>, Box<dyn Error + Send + Sync>>>,
+{
+    let expiry_threshold = Utc::now() + chrono::Duration::minutes(5);
+    let mut refreshed = Vec::with_capacity(accounts.len());
+
+    for account in accounts {
+        let slug = account.account_auth_provider_account_slug.clone();
+        let needs_refresh = referenced_slugs.contains(&slug)
+            && !account.failed
+            && account
+                .access_token_expires_at
+                .map(|expires_at| expires_at <= expiry_threshold)
+                .unwrap_or(false);
+        if !needs_refresh {
+            refreshed.push(account);
+            continue;
+        }
+
+        debug!(
+            "[FAST AUTH ACCOUNTS] Account {} needs to have access_token refreshed",
+            slug
+        );
+        match refresh(account).await.map(|mut accounts| accounts.pop()) {
+            // A failed refresh is recorded on the account rather than returned
+            Ok(Some(account)) if !account.failed => refreshed.push(account),
+            Ok(_) => return Err(BundlerError::AccountRefreshFailed(slug)),
+            Err(e) => {
+                warn!("[FAST AUTH ACCOUNTS] Failed to refresh account {}: {}", slug, e);
+                return Err(BundlerError::AccountRefreshFailed(slug));
+            }
+        }
+    }
+
+    Ok(refreshed)
+}
 
 async fn fetch_accounts_from_db(
     client: &Postgrest,
@@ -0,0 +1,70 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+use crate::bundler::bundler::AccessAudit;
+
+// Past this the cache is emptied rather than growing with every distinct bundle
+const MAX_CACHED_BUNDLES: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct CachedBundle {
+    pub rendered_inputs: Value,
+    pub exposed_secrets: Vec<String>,
+    pub accessed: AccessAudit, // Still audited when the bundle is reused
+}
+
+struct CachedEntry {
+    bundle: CachedBundle,
+    expires_at: SystemTime,
+}
+
+// Rendered task inputs, keyed by the action and a hash of everything its templates read (see
+// bundle_cache_key). A changed upstream result is a different key, so nothing has to be
+// invalidated, old entries just stop being hit and expire
+pub struct BundleCache {
+    cache: HashMap<String, CachedEntry>,
+    ttl: Duration,
+    hits: u64,
+}
+
+impl BundleCache {
+    pub fn new(ttl: Duration) -> Self {
+        debug!("[BUNDLER] Creating new BundleCache with TTL: {:?}", ttl);
+        Self {
+            cache: HashMap::new(),
+            ttl,
+            hits: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<CachedBundle> {
+        let entry = self.cache.get(key)?;
+        if entry.expires_at <= SystemTime::now() {
+            return None;
+        }
+        self.hits += 1;
+        Some(entry.bundle.clone())
+    }
+
+    pub fn set(&mut self, key: String, bundle: CachedBundle) {
+        if self.cache.len() >= MAX_CACHED_BUNDLES {
+            self.cleanup();
+            if self.cache.len() >= MAX_CACHED_BUNDLES {
+                self.cache.clear();
+            }
+        }
+        let expires_at = SystemTime::now() + self.ttl;
+        self.cache.insert(key, CachedEntry { bundle, expires_at });
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn cleanup(&mut self) {
+        let now = SystemTime::now();
+        self.cache.retain(|_, entry| entry.expires_at > now);
+    }
+}
@@ -1,33 +1,143 @@
 use crate::system_variables::get_system_variables;
+use crate::types::action_types::Action;
 use crate::types::json_schema::JsonSchema;
-use crate::types::task_types::Task;
+use crate::types::task_types::{Stage, Task};
 
 use crate::AppState;
 use postgrest::Postgrest;
-use serde_json::{json, Value};
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
+use tracing::{debug, info, warn};
 
+use crate::auth::init::AccountAuthProviderAccount;
 use crate::bundler::accounts::fetch_cached_auth_accounts;
+use crate::bundler::bundle_cache::CachedBundle;
 use crate::bundler::secrets::get_decrypted_secrets;
-use crate::templater::Templater;
+use crate::processor::large_results::resolve_results;
+use crate::templater::{LazyContext, Templater, JMESPATH_PREFIX};
+use crate::types::secret_types::Secret;
 use crate::types::task_types::TaskStatus;
 
 use uuid::Uuid;
 
 use crate::types::json_schema::ValidationFieldType;
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum BundlerError {
+    AccountRefreshFailed(String), // The account's slug
+}
+
+impl fmt::Display for BundlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundlerError::AccountRefreshFailed(slug) => {
+                write!(f, "Account '{}' could not be refreshed", slug)
+            }
+        }
+    }
+}
+
+impl Error for BundlerError {}
+
+// One iteration of a Loop action. Tasks run by the loop are bundled with it as `loop`, i.e.
+// `{{loop.item}}`, `{{loop.index}}`, `{{loop.first}}`, `{{loop.last}}` and `{{loop.prev_result}}`,
+// the result of the previous iteration (null on the first one). Only the innermost loop is
+// bundled so inside a nested loop `loop.*` is the inner loop's and the outer one is shadowed.
+// The processor doesn't run Loop actions yet, until it does only tests bundle iterations
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct LoopIteration {
+    pub item: Value,
+    pub index: usize,
+    pub count: usize,
+    pub prev_result: Option<Value>,
+}
+
+#[cfg(test)]
+impl LoopIteration {
+    pub fn to_context(&self) -> Value {
+        json!({
+            "item": self.item,
+            "index": self.index,
+            "first": self.index == 0,
+            "last": self.index + 1 == self.count,
+            "prev_result": self.prev_result,
+        })
+    }
+}
+
+// `{{actions.*}}`, keyed by action_id. Workflows with duplicate action ids don't get to run (see
+// validate_workflow_graph), should two tasks still share one the later task wins and it's logged
+// instead of whichever came last silently overwriting the other
+fn latest_tasks(tasks: Vec<Task>) -> HashMap<String, Task> {
+    let mut latest: HashMap<String, Task> = HashMap::with_capacity(tasks.len());
+    for task in tasks {
+        match latest.entry(task.action_id.clone()) {
+            Entry::Occupied(mut existing) => {
+                warn!(
+                    "[BUNDLER] Tasks {} and {} share action_id {}, using the later one",
+                    existing.get().task_id,
+                    task.task_id,
+                    task.action_id
+                );
+                if task.processing_order >= existing.get().processing_order {
+                    existing.insert(task);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(task);
+            }
+        }
+    }
+    latest
+}
+
 pub async fn bundle_tasks_cached_context(
     state: Arc<AppState>,
     client: &Postgrest,
     task: &Task,
     refresh_auth: bool,
 ) -> Result<(Value, Value), Box<dyn Error + Send + Sync>> {
-    println!("[BUNDLER] Starting to bundle context from parts");
+    bundle_task_context(state, client, task, refresh_auth, None).await
+}
+
+// For the tasks a Loop action runs, once per iteration
+#[cfg(test)]
+pub async fn bundle_looped_task_context(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    task: &Task,
+    refresh_auth: bool,
+    iteration: &LoopIteration,
+) -> Result<(Value, Value), Box<dyn Error + Send + Sync>> {
+    bundle_task_context(
+        state,
+        client,
+        task,
+        refresh_auth,
+        Some(iteration.to_context()),
+    )
+    .await
+}
+
+// `loop_context` is what `{{loop.*}}` reads, see LoopIteration
+async fn bundle_task_context(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    task: &Task,
+    refresh_auth: bool,
+    loop_context: Option<Value>,
+) -> Result<(Value, Value), Box<dyn Error + Send + Sync>> {
+    debug!("[BUNDLER] Starting to bundle context from parts");
 
-    let rendered_inputs_definition =
-        bundle_tasks_cached_inputs(state, client, task, refresh_auth).await?;
+    let (rendered_inputs_definition, exposed_secrets) =
+        bundle_task_inputs(state, client, task, refresh_auth, loop_context).await?;
 
     let plugin_config = task.config.plugin_config.as_ref();
     let plugin_config_schema = task.config.plugin_config_schema.as_ref();
@@ -36,6 +146,7 @@ pub async fn bundle_tasks_cached_context(
         rendered_inputs_definition.clone(),
         plugin_config,
         plugin_config_schema,
+        &exposed_secrets,
     )?;
 
     Ok((
@@ -49,26 +160,75 @@ pub async fn bundle_tasks_cached_inputs(
     client: &Postgrest,
     task: &Task,
     refresh_auth: bool,
-) -> Result<Value, Box<dyn Error + Send + Sync>> {
-    println!("[BUNDLER] Starting to bundle context from parts");
+) -> Result<(Value, Vec<String>), Box<dyn Error + Send + Sync>> {
+    bundle_task_inputs(state, client, task, refresh_auth, None).await
+}
+
+async fn bundle_task_inputs(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    task: &Task,
+    refresh_auth: bool,
+    loop_context: Option<Value>,
+) -> Result<(Value, Vec<String>), Box<dyn Error + Send + Sync>> {
+    debug!("[BUNDLER] Starting to bundle context from parts");
 
     let account_id = task.account_id.to_string();
     let flow_session_id = task.flow_session_id.to_string();
     let inputs = task.config.inputs.as_ref();
     let inputs_schema = task.config.inputs_schema.as_ref();
 
-    let rendered_inputs_definition = bundle_cached_inputs(
+    check_self_reference(&task.action_id, inputs)?;
+
+    bundle_cached_inputs_with_secrets(
         state,
         client,
         &account_id,
         &flow_session_id,
+        Some(task),
+        &task.stage,
         inputs,
         inputs_schema,
         refresh_auth,
+        loop_context,
     )
-    .await?;
+    .await
+}
+
+// The action's result doesn't exist while its inputs are rendered, so `actions.<own id>` would
+// only ever be missing or a stale cached value
+fn check_self_reference(
+    action_id: &str,
+    inputs: Option<&Value>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match inputs.and_then(|inputs| find_self_reference(action_id, inputs)) {
+        Some(path) => Err(format!(
+            "Action '{}' cannot reference its own result: {{{{{}}}}}",
+            action_id, path
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+fn find_self_reference(action_id: &str, inputs: &Value) -> Option<String> {
+    let mut templater = Templater::new();
+    templater.add_template("task_inputs_definition", inputs.clone());
+    // Broken templates are reported when they're rendered
+    let variables = templater
+        .get_template_variables("task_inputs_definition")
+        .ok()?;
 
-    Ok(rendered_inputs_definition)
+    let own_result = format!("actions.{}", action_id);
+    variables.iter().find_map(|variable| {
+        Templater::referenced_paths(variable)
+            .into_iter()
+            .find(|path| {
+                path.strip_prefix(&own_result)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+            })
+            .map(str::to_string)
+    })
 }
 
 pub async fn bundle_context_from_parts(
@@ -82,16 +242,20 @@ pub async fn bundle_context_from_parts(
     plugin_config_schema: Option<&JsonSchema>,
     refresh_auth: bool,
 ) -> Result<Value, Box<dyn Error + Send + Sync>> {
-    println!("[BUNDLER] Starting to bundle context from parts");
+    debug!("[BUNDLER] Starting to bundle context from parts");
 
-    let rendered_inputs_definition = bundle_cached_inputs(
+    // Trigger inputs are rendered before the session has a stage so they use production credentials
+    let (rendered_inputs_definition, exposed_secrets) = bundle_cached_inputs_with_secrets(
         state,
         client,
         account_id,
         flow_session_id,
+        None,
+        &Stage::Production,
         inputs,
         inputs_schema,
         refresh_auth,
+        None,
     )
     .await?;
 
@@ -99,6 +263,7 @@ pub async fn bundle_context_from_parts(
         rendered_inputs_definition,
         plugin_config,
         plugin_config_schema,
+        &exposed_secrets,
     )
 }
 
@@ -111,108 +276,573 @@ pub async fn bundle_cached_inputs(
     inputs_schema: Option<&JsonSchema>,
     refresh_auth: bool,
 ) -> Result<Value, Box<dyn Error + Send + Sync>> {
-    println!("[BUNDLER] Starting to bundle inputs");
+    let (rendered_inputs, _) = bundle_cached_inputs_with_secrets(
+        state,
+        client,
+        account_id,
+        flow_session_id,
+        None,
+        &Stage::Production,
+        inputs,
+        inputs_schema,
+        refresh_auth,
+        None,
+    )
+    .await?;
+    Ok(rendered_inputs)
+}
 
-    // Pre-allocate with known capacity
-    let mut render_inputs_context = HashMap::with_capacity(4);
+// Also returns the secret values that were substituted so later logging can mask them. With a
+// task the inputs can use {{system.run.*}}, and they're cached and reused while nothing they read
+// has changed
+async fn bundle_cached_inputs_with_secrets(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    account_id: &str,
+    flow_session_id: &str,
+    task: Option<&Task>,
+    stage: &Stage,
+    inputs: Option<&Value>,
+    inputs_schema: Option<&JsonSchema>,
+    refresh_auth: bool,
+    loop_context: Option<Value>,
+) -> Result<(Value, Vec<String>), Box<dyn Error + Send + Sync>> {
+    debug!("[BUNDLER] Starting to bundle inputs");
+
+    let referenced_slugs = referenced_accounts(inputs);
+    let referenced_action_ids = match inputs {
+        Some(inputs) => referenced_actions(&template_variables(inputs)),
+        None => Some(HashSet::new()),
+    };
 
     // Parallel fetch of secrets, accounts, and cached task results
-    let (secrets_result, accounts_result, tasks_result) = tokio::join!(
-        get_decrypted_secrets(state.clone(), client, account_id), //cached secrets
-        fetch_cached_auth_accounts(state.clone(), client, account_id, refresh_auth), //cached accounts
-        fetch_completed_cached_tasks(state.clone(), flow_session_id) //cached task results
+    let (credentials, tasks_result) = tokio::join!(
+        fetch_credentials(
+            state.clone(),
+            client,
+            account_id,
+            stage,
+            refresh_auth,
+            &referenced_slugs
+        ),
+        //cached task results
+        fetch_completed_cached_tasks(
+            state.clone(),
+            flow_session_id,
+            referenced_action_ids.as_ref()
+        )
+    );
+    let (secrets, accounts) = credentials?;
+
+    // Process tasks
+    let tasks = latest_tasks(tasks_result?);
+
+    let inputs = match inputs {
+        Some(inputs) => inputs,
+        None => {
+            debug!("[BUNDLER] No inputs found in task config");
+            return Ok((json!({}), Vec::new()));
+        }
+    };
+
+    let mut templater = Templater::with_cache(state.template_cache.clone());
+    // Saved workflows were built against paths that traverse into JSON strings
+    templater.set_parse_json_strings(true);
+    templater.add_template("task_inputs_definition", inputs.clone());
+
+    // Only what the inputs read is serialized, see LazyContext
+    let context_value = {
+        let mut context = inputs_context(&state, &accounts, task.map(run_context), loop_context);
+        context.insert_keyed("actions", tasks.keys().cloned().collect(), |action_id| {
+            let task = tasks.get(action_id)?;
+            serde_json::to_value(task)
+                .map_err(|e| warn!("[BUNDLER] Task {} not bundled: {}", task.task_id, e))
+                .ok()
+        });
+        templater.build_context("task_inputs_definition", &context)?
+    };
+
+    let cache_key = task.and_then(|task| {
+        bundle_cache_key(
+            account_id,
+            &task.action_id,
+            stage,
+            &templater,
+            inputs,
+            inputs_schema,
+            &context_value,
+            &secrets,
+        )
+    });
+    if let Some(cache_key) = &cache_key {
+        if let Some(bundle) = state.bundle_cache.write().await.get(cache_key) {
+            debug!("[BUNDLER] Reusing cached inputs for {}", cache_key);
+            emit_access_audit(account_id, flow_session_id, task, &bundle.accessed);
+            return Ok((bundle.rendered_inputs, bundle.exposed_secrets));
+        }
+    }
+
+    let secret_names: HashSet<String> = secrets.keys().cloned().collect();
+    templater.set_secrets(secrets);
+
+    // Extract and set validations from schemas
+    let input_validations = extract_template_key_validations_from_schema(inputs_schema);
+    let rendered = templater.render("task_inputs_definition", &context_value, input_validations)?;
+
+    debug!(
+        "[BUNDLER] Rendered inputs output: {}",
+        templater.redact_secrets(&rendered)
+    );
+    let exposed_secrets = templater.exposed_secrets();
+    let accessed = access_audit(&templater, &secret_names, context_value.get("accounts"));
+    emit_access_audit(account_id, flow_session_id, task, &accessed);
+
+    if let Some(cache_key) = cache_key {
+        state.bundle_cache.write().await.set(
+            cache_key,
+            CachedBundle {
+                rendered_inputs: rendered.clone(),
+                exposed_secrets: exposed_secrets.clone(),
+                accessed,
+            },
+        );
+    }
+    Ok((rendered, exposed_secrets))
+}
+
+// The secret names and account slugs a task's inputs read, for the audit log
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessAudit {
+    pub secrets: Vec<String>,
+    pub accounts: Vec<String>,
+}
+
+// Only names that exist are listed, `{{accounts.slack.access_token ?? ''}}` falling back to ''
+// didn't read an account
+fn access_audit(
+    templater: &Templater,
+    secret_names: &HashSet<String>,
+    accounts: Option<&Value>,
+) -> AccessAudit {
+    let mut audit = AccessAudit::default();
+    for path in templater.resolved_paths() {
+        let mut segments = path.split(['.', '[']);
+        let (namespace, name) = match (segments.next(), segments.next()) {
+            (Some(namespace), Some(name)) if !name.is_empty() => (namespace, name),
+            _ => continue,
+        };
+        let (names, exists) = match namespace {
+            "secrets" => (&mut audit.secrets, secret_names.contains(name)),
+            "accounts" => (
+                &mut audit.accounts,
+                accounts.and_then(|accounts| accounts.get(name)).is_some(),
+            ),
+            _ => continue,
+        };
+        if exists && !names.iter().any(|seen| seen == name) {
+            names.push(name.to_string());
+        }
+    }
+    audit
+}
+
+// Logged on every bundle, cached ones included, so each run that used a secret or account shows up
+fn emit_access_audit(
+    account_id: &str,
+    flow_session_id: &str,
+    task: Option<&Task>,
+    audit: &AccessAudit,
+) {
+    if audit.secrets.is_empty() && audit.accounts.is_empty() {
+        return;
+    }
+    info!(
+        account_id,
+        flow_session_id,
+        action_id = task.map(|task| task.action_id.as_str()),
+        secrets = ?audit.secrets,
+        accounts = ?audit.accounts,
+        "[AUDIT] Task inputs read secrets and accounts"
+    );
+}
+
+// `{{system.run.*}}`, the run the task is part of, e.g. for logging or correlating requests
+fn run_context(task: &Task) -> Value {
+    json!({
+        "flow_session_id": task.flow_session_id,
+        "trigger_session_id": task.trigger_session_id,
+        "workflow_id": task.flow_id,
+        "workflow_version_id": task.flow_version_id,
+        "action_id": task.action_id,
+        "stage": task.stage.as_str(),
+    })
+}
+
+// What a task's rendered inputs depend on: its templates and schema, the stage, and the value of
+// each `namespace.name` they read, e.g. all of actions.fetch for {{actions.fetch.result.id}}.
+// None when that can't be worked out since JMESPath can read anything in the context
+fn bundle_cache_key(
+    account_id: &str,
+    action_id: &str,
+    stage: &Stage,
+    templater: &Templater,
+    inputs: &Value,
+    inputs_schema: Option<&JsonSchema>,
+    context: &Value,
+    secrets: &HashMap<String, Secret<String>>,
+) -> Option<String> {
+    let variables = templater
+        .get_template_variables("task_inputs_definition")
+        .ok()?;
+
+    let mut read = BTreeSet::new();
+    for variable in &variables {
+        if variable.trim_start().starts_with(JMESPATH_PREFIX) {
+            return None;
+        }
+        for path in Templater::referenced_paths(variable) {
+            let mut segments = path.split(['.', '[']);
+            read.insert((
+                segments.next().unwrap_or_default(),
+                segments.next().unwrap_or_default(),
+            ));
+        }
+    }
+
+    let mut hasher = DefaultHasher::new();
+    inputs.to_string().hash(&mut hasher);
+    serde_json::to_string(&inputs_schema)
+        .ok()?
+        .hash(&mut hasher);
+    stage.as_str().hash(&mut hasher);
+    for (namespace, name) in read {
+        (namespace, name).hash(&mut hasher);
+        let value = match (namespace, context.get(namespace)) {
+            ("secrets", _) => secrets
+                .get(name)
+                .map(|secret| secret.expose_secret().clone()),
+            (_, Some(value)) if name.is_empty() => Some(value.to_string()),
+            (_, Some(value)) => value.get(name).map(Value::to_string),
+            (_, None) => None,
+        };
+        value.hash(&mut hasher);
+    }
+
+    Some(format!(
+        "{}:{}:{:x}",
+        account_id,
+        action_id,
+        hasher.finish()
+    ))
+}
+
+// Plan mode. Bundles an action's inputs and plugin config the way a run would, except
+// `{{actions.*}}` is `planned_actions`, what the actions before it are expected to be, instead of a
+// session's results. Nothing is run or written. Accounts the inputs use are refreshed like in a
+// run so one that can't be fails the same way
+pub async fn plan_bundle(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    account_id: &str,
+    stage: &Stage,
+    action: &Action,
+    planned_actions: &Map<String, Value>,
+) -> Result<(Value, Value), Box<dyn Error + Send + Sync>> {
+    let inputs = action.inputs.as_ref();
+    check_self_reference(&action.action_id, inputs)?;
+
+    let (secrets, accounts) = fetch_credentials(
+        state.clone(),
+        client,
+        account_id,
+        stage,
+        true,
+        &referenced_accounts(inputs),
+    )
+    .await?;
+
+    let rendered_inputs = match inputs {
+        Some(inputs) => {
+            let mut templater = Templater::with_cache(state.template_cache.clone());
+            templater.set_parse_json_strings(true);
+            templater.set_secrets(secrets);
+            templater.add_template("task_inputs_definition", inputs.clone());
+
+            // The run doesn't exist yet
+            let run = json!({
+                "flow_session_id": Uuid::new_v4(),
+                "trigger_session_id": Uuid::new_v4(),
+                "workflow_id": null,
+                "workflow_version_id": null,
+                "action_id": action.action_id,
+                "stage": stage.as_str(),
+            });
+            let mut context = inputs_context(&state, &accounts, Some(run), None);
+            context.insert_keyed(
+                "actions",
+                planned_actions.keys().cloned().collect(),
+                |action_id| planned_actions.get(action_id).cloned(),
+            );
+            let validations =
+                extract_template_key_validations_from_schema(action.inputs_schema.as_ref());
+            templater.render_lazy("task_inputs_definition", &context, &validations)?
+        }
+        None => json!({}),
+    };
+
+    let rendered_plugin_config = bundle_plugin_config(
+        rendered_inputs.clone(),
+        Some(&action.plugin_config),
+        Some(&action.plugin_config_schema),
+        &[],
+    )?;
+    Ok((rendered_inputs, rendered_plugin_config))
+}
+
+// The account's secrets and accounts for the stage, see select_for_stage. Only the accounts in
+// `referenced_slugs` are refreshed
+async fn fetch_credentials(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    account_id: &str,
+    stage: &Stage,
+    refresh_auth: bool,
+    referenced_slugs: &HashSet<String>,
+) -> Result<
+    (
+        HashMap<String, Secret<String>>,
+        HashMap<String, AccountAuthProviderAccount>,
+    ),
+    Box<dyn Error + Send + Sync>,
+> {
+    let (secrets_result, accounts_result) = tokio::join!(
+        get_decrypted_secrets(state.clone(), account_id), //cached secrets
+        //cached accounts, only the ones the inputs use are refreshed
+        fetch_cached_auth_accounts(
+            state.clone(),
+            client,
+            account_id,
+            refresh_auth,
+            referenced_slugs
+        )
     );
 
     // Process accounts
     let mut accounts = HashMap::new();
-    for account in accounts_result? {
+    for account in select_for_stage(
+        accounts_result?,
+        stage,
+        |account| account.account_auth_provider_account_slug.as_str(),
+        |account| account.stage.as_deref(),
+    ) {
         let slug = account.account_auth_provider_account_slug.clone();
-        println!("[BUNDLER] Inserting account with slug: {}", slug);
-        accounts.insert(slug, serde_json::to_value(account)?);
+        debug!("[BUNDLER] Inserting account with slug: {}", slug);
+        accounts.insert(slug, account);
     }
-    render_inputs_context.insert("accounts".to_string(), serde_json::to_value(accounts)?);
 
-    // Process secrets
+    // Process secrets. They stay wrapped and out of the context, the templater only
+    // exposes the plaintext where a secret is substituted
     let mut secrets = HashMap::new();
-    for secret in secrets_result? {
-        let secret_name = secret.secret_name.clone();
-        println!("[BUNDLER] Inserting secret with name: {}", secret_name);
-        secrets.insert(secret_name, serde_json::to_value(secret.secret_value)?);
+    for secret in select_for_stage(
+        secrets_result?,
+        stage,
+        |secret| secret.secret_name.as_str(),
+        |secret| secret.stage.as_deref(),
+    ) {
+        debug!(
+            "[BUNDLER] Inserting secret with name: {}",
+            secret.secret_name
+        );
+        secrets.insert(secret.secret_name, secret.secret_value);
     }
-    render_inputs_context.insert("secrets".to_string(), serde_json::to_value(secrets)?);
 
-    // Process tasks
-    let tasks_result = tasks_result?;
-    let mut tasks_map = HashMap::with_capacity(tasks_result.len());
-    for task in tasks_result {
-        tasks_map.insert(task.action_id.to_string(), serde_json::to_value(task)?);
-    }
-    render_inputs_context.insert("actions".to_string(), serde_json::to_value(tasks_map)?);
+    Ok((secrets, accounts))
+}
 
-    // Add system variables
-    render_inputs_context.insert(
-        "system".to_string(),
-        serde_json::to_value(get_system_variables())?,
+// Everything inputs are rendered with but `{{actions.*}}`, which the caller adds. Only what the
+// inputs read is serialized, see LazyContext
+fn inputs_context<'a>(
+    state: &'a AppState,
+    accounts: &'a HashMap<String, AccountAuthProviderAccount>,
+    run: Option<Value>,
+    loop_context: Option<Value>,
+) -> LazyContext<'a> {
+    let mut context = LazyContext::new();
+    context.insert_keyed(
+        "accounts",
+        accounts.keys().cloned().collect(),
+        move |slug| {
+            serde_json::to_value(accounts.get(slug)?)
+                .map_err(|e| warn!("[BUNDLER] Account {} not bundled: {}", slug, e))
+                .ok()
+        },
     );
+    context.insert_lazy("system", move || {
+        let mut system_variables = get_system_variables();
+        if let Some(run) = &run {
+            system_variables.insert("run".to_string(), run.clone());
+        }
+        Value::Object(system_variables.into_iter().collect())
+    });
+    context.insert_lazy("config", move || {
+        Value::Object(state.config_source.get_config())
+    });
+    if let Some(loop_context) = loop_context {
+        context.insert("loop", loop_context);
+    }
+    context
+}
 
-    // Extract and set validations from schemas
+// Slugs of the accounts the inputs use, e.g. "gmail" for {{accounts.gmail.access_token}}
+fn referenced_accounts(inputs: Option<&Value>) -> HashSet<String> {
     let mut templater = Templater::new();
+    let variables = match inputs {
+        Some(inputs) => {
+            templater.add_template("task_inputs_definition", inputs.clone());
+            // Broken templates are reported when they're rendered
+            templater
+                .get_template_variables("task_inputs_definition")
+                .unwrap_or_default()
+        }
+        None => return HashSet::new(),
+    };
 
-    if let Some(inputs) = inputs {
-        templater.add_template("task_inputs_definition", inputs.clone());
+    variables
+        .iter()
+        .flat_map(|variable| Templater::referenced_paths(variable))
+        .filter_map(|path| {
+            let slug = path
+                .strip_prefix("accounts.")?
+                .split(['.', '[', '?'])
+                .next()?;
+            (!slug.is_empty()).then(|| slug.to_string())
+        })
+        .collect()
+}
 
-        let input_validations = extract_template_key_validations_from_schema(inputs_schema);
-        let context_value = serde_json::to_value(&render_inputs_context)?;
-        let rendered = templater.render(
-            "task_inputs_definition",
-            &context_value,
-            input_validations,
-        )?;
+// Secrets and accounts can be scoped to a stage. For each name one scoped to the run's stage
+// wins over an unscoped one, and ones scoped to another stage are never used. So a staging run
+// falls back to an unscoped account, but an account scoped to production only is missing from
+// the staging context and templates that use it fail to render rather than run with production credentials
+fn select_for_stage<T>(
+    items: Vec<T>,
+    stage: &Stage,
+    name: impl Fn(&T) -> &str,
+    item_stage: impl Fn(&T) -> Option<&str>,
+) -> Vec<T> {
+    let mut selected: HashMap<String, (bool, T)> = HashMap::new();
+    for item in items {
+        let scoped = match item_stage(&item) {
+            Some(item_stage) if item_stage != stage.as_str() => continue,
+            Some(_) => true,
+            None => false,
+        };
+        let replace = match selected.get(name(&item)) {
+            Some((existing_scoped, _)) => scoped && !existing_scoped,
+            None => true,
+        };
+        if replace {
+            selected.insert(name(&item).to_string(), (scoped, item));
+        }
+    }
+    selected.into_values().map(|(_, item)| item).collect()
+}
 
-        println!("[BUNDLER] Rendered inputs output: {}", rendered);
-        Ok(rendered)
-    } else {
-        println!("[BUNDLER] No inputs found in task config");
-        Ok(json!({}))
+fn template_variables(inputs: &Value) -> Vec<String> {
+    let mut templater = Templater::new();
+    templater.add_template("task_inputs_definition", inputs.clone());
+    // Broken templates are reported when they're rendered
+    templater
+        .get_template_variables("task_inputs_definition")
+        .unwrap_or_default()
+}
+
+// Ids of the actions whose results the variables read, e.g. "fetch" for
+// {{actions.fetch.result.id}}. None when that can't be worked out since JMESPath or all of
+// `actions` can read any of them
+pub fn referenced_actions<'a>(
+    variables: impl IntoIterator<Item = &'a String>,
+) -> Option<HashSet<String>> {
+    let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut action_ids = HashSet::new();
+    for variable in variables {
+        let variable = variable.trim();
+        if variable.starts_with(JMESPATH_PREFIX) {
+            return None;
+        }
+        for (idx, _) in variable.match_indices("actions") {
+            // Part of another name, e.g. {{system.actions}} or {{my_actions}}
+            let rest = &variable[idx + "actions".len()..];
+            if variable[..idx].ends_with(|c: char| is_name_char(c) || c == '.')
+                || rest.starts_with(is_name_char)
+            {
+                continue;
+            }
+            let action_id: String = rest
+                .strip_prefix('.')?
+                .chars()
+                .take_while(|c| is_name_char(*c))
+                .collect();
+            if action_id.is_empty() {
+                return None;
+            }
+            action_ids.insert(action_id);
+        }
     }
+    Some(action_ids)
 }
 
+// Only the results of `referenced_action_ids` are resolved, None resolves them all
 async fn fetch_completed_cached_tasks(
     state: Arc<AppState>,
     flow_session_id: &str,
+    referenced_action_ids: Option<&HashSet<String>>,
 ) -> Result<Vec<Task>, Box<dyn Error + Send + Sync>> {
-    let cache = state.flow_session_cache.read().await;
     let session_id = Uuid::parse_str(flow_session_id).unwrap();
-    let tasks = if let Some(session_data) = cache.get(&session_id) {
-        session_data
-            .tasks
-            .values()
-            .filter(|task| task.task_status == TaskStatus::Completed)
-            .cloned()
-            .collect()
-    } else {
-        Vec::new()
+    let tasks: Vec<Task> = {
+        let cache = state.flow_session_cache.read().await;
+        if let Some(session_data) = cache.get(&session_id) {
+            session_data
+                .tasks()
+                .values()
+                .filter(|task| task.task_status == TaskStatus::Completed)
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        }
     };
-    Ok(tasks)
+
+    let (mut referenced, unreferenced): (Vec<Task>, Vec<Task>) =
+        tasks.into_iter().partition(|task| {
+            referenced_action_ids.map_or(true, |action_ids| action_ids.contains(&task.action_id))
+        });
+
+    // Results over the cache's limit are only previews in it and ones over the offload threshold
+    // are references
+    resolve_results(state, &mut referenced).await?;
+
+    referenced.extend(unreferenced);
+    Ok(referenced)
 }
 
 pub fn bundle_plugin_config(
     rendered_inputs: Value,
     plugin_config: Option<&Value>,
     plugin_config_schema: Option<&JsonSchema>,
+    secret_values: &[String],
 ) -> Result<Value, Box<dyn Error + Send + Sync>> {
-    let mut render_input_context: HashMap<String, Value> = HashMap::new();
-    render_input_context.insert("inputs".to_string(), rendered_inputs);
-
     // Create a new Templater instance for rendering inputs
     let mut templater = Templater::new();
+    templater.set_parse_json_strings(true);
 
-    // Convert context HashMap to Value
-    let inputs_context_value = serde_json::to_value(render_input_context.clone())?;
+    // json! would serialize a copy of the inputs
+    let inputs_context_value =
+        Value::Object(Map::from_iter([("inputs".to_string(), rendered_inputs)]));
 
     // Add the task definition as a template and render if it exists
     if let Some(plugin_config) = plugin_config {
-        println!(
+        debug!(
             "[BUNDLER] Task plugin config definition: {}",
             plugin_config.clone()
         );
@@ -226,13 +856,16 @@ pub fn bundle_plugin_config(
             &inputs_context_value,
             plugin_config_validations,
         )?;
-        println!(
+        debug!(
             "[BUNDLER] Rendered plugin config output: {}",
-            rendered_plugin_config_definition
+            Templater::redact(
+                &rendered_plugin_config_definition.to_string(),
+                secret_values
+            )
         );
         Ok(rendered_plugin_config_definition)
     } else {
-        println!("[BUNDLER] No plugin config found in task config, returning empty object");
+        debug!("[BUNDLER] No plugin config found in task config, returning empty object");
         Ok(json!({}))
     }
 }
@@ -240,18 +873,780 @@ pub fn bundle_plugin_config(
 fn extract_template_key_validations_from_schema(
     schema: Option<&JsonSchema>,
 ) -> HashMap<String, ValidationFieldType> {
-    let mut template_key_validations = HashMap::new();
-
-    if let Some(schema) = schema {
-        if let Some(properties) = &schema.properties {
-            for (property_name, property_schema) in properties {
-                if let Some(validation) = &property_schema.x_any_validation {
-                    template_key_validations
-                        .insert(property_name.clone(), validation.r#type.clone());
-                }
+    schema
+        .and_then(|schema| serde_json::to_value(schema).ok())
+        .map(|schema| ValidationFieldType::from_schema(&schema))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::accounts::refresh_referenced_accounts;
+    use crate::bundler::config::InMemoryConfigSource;
+    use crate::bundler::secrets::{DecryptedSecret, InMemorySecretProvider};
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{
+        action, start_test_processor, task, test_app_state, test_app_state_with_secret_provider,
+        InMemoryTaskStore,
+    };
+
+    fn secret(name: &str, value: &str, stage: Option<&str>) -> DecryptedSecret {
+        serde_json::from_value(json!({
+            "secret_id": Uuid::new_v4(),
+            "secret_name": name,
+            "secret_value": value,
+            "secret_description": null,
+            "stage": stage
+        }))
+        .unwrap()
+    }
+
+    fn account(slug: &str, access_token: &str, stage: Option<&str>) -> AccountAuthProviderAccount {
+        serde_json::from_value(json!({
+            "account_auth_provider_account_id": Uuid::new_v4(),
+            "account_id": Uuid::new_v4(),
+            "auth_provider_id": slug,
+            "account_auth_provider_account_label": slug,
+            "account_auth_provider_account_slug": slug,
+            "access_token": access_token,
+            "access_token_vault_id": "",
+            "refresh_token_vault_id": "",
+            "failed": false,
+            "failure_retries": 0,
+            "stage": stage
+        }))
+        .unwrap()
+    }
+
+    fn secret_values(secrets: Vec<DecryptedSecret>, stage: &Stage) -> HashMap<String, String> {
+        select_for_stage(
+            secrets,
+            stage,
+            |secret| secret.secret_name.as_str(),
+            |secret| secret.stage.as_deref(),
+        )
+        .into_iter()
+        .map(|secret| {
+            (
+                secret.secret_name,
+                secret.secret_value.expose_secret().clone(),
+            )
+        })
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_looped_task_sees_its_iteration() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+        let workflow = store
+            .get_workflow_definition(&workflow_id, Some(&flow_version_id))
+            .await
+            .unwrap();
+
+        let inputs = json!({
+            "line": "{{loop.index}}: {{loop.item.name}}",
+            "first": "{{loop.first}}",
+            "last": "{{loop.last}}",
+            "total": "{{loop.prev_result.total ?? 0}}"
+        });
+        let mut properties = serde_json::Map::new();
+        for (key, validation) in [
+            ("line", "string"),
+            ("first", "boolean"),
+            ("last", "boolean"),
+            ("total", "number"),
+        ] {
+            properties.insert(
+                key.to_string(),
+                json!({ "x-any-validation": { "type": validation } }),
+            );
+        }
+        let task: Task = task(
+            "sum",
+            "action",
+            json!({
+                "account_id": workflow.account_id,
+                "flow_id": workflow_id,
+                "flow_version_id": flow_version_id,
+                "config": {
+                    "inputs": inputs,
+                    "inputs_schema": { "type": "object", "properties": properties }
+                },
+                "processing_order": 1
+            }),
+        );
+
+        // A running total, each iteration adds its item's amount to the previous result
+        let items = vec![
+            json!({ "name": "a", "amount": 1 }),
+            json!({ "name": "b", "amount": 2 }),
+            json!({ "name": "c", "amount": 3 }),
+        ];
+        let mut prev_result = None;
+        let mut rendered = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            let iteration = LoopIteration {
+                item: item.clone(),
+                index,
+                count: items.len(),
+                prev_result: prev_result.clone(),
+            };
+            let (inputs, _) = bundle_looped_task_context(
+                state.clone(),
+                &state.anything_client,
+                &task,
+                false,
+                &iteration,
+            )
+            .await
+            .unwrap();
+            let total = inputs["total"].as_i64().unwrap() + item["amount"].as_i64().unwrap();
+            prev_result = Some(json!({ "total": total }));
+            rendered.push(inputs);
+        }
+
+        assert_eq!(
+            rendered,
+            vec![
+                json!({ "line": "0: a", "first": true, "last": false, "total": 0 }),
+                json!({ "line": "1: b", "first": false, "last": false, "total": 1 }),
+                json!({ "line": "2: c", "first": false, "last": true, "total": 3 }),
+            ]
+        );
+
+        // Outside a loop there is no `loop` to reference
+        assert!(
+            bundle_tasks_cached_context(state.clone(), &state.anything_client, &task, false)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_inputs_are_bundled_once() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+        let workflow = store
+            .get_workflow_definition(&workflow_id, Some(&flow_version_id))
+            .await
+            .unwrap();
+
+        let task_with_inputs = |inputs: Value| -> Task {
+            task(
+                "greet",
+                "action",
+                json!({
+                    "account_id": workflow.account_id,
+                    "flow_id": workflow_id,
+                    "flow_version_id": flow_version_id,
+                    "config": {
+                        "inputs": inputs,
+                        "inputs_schema": {
+                            "type": "object",
+                            "properties": { "name": { "x-any-validation": { "type": "string" } } }
+                        }
+                    },
+                    "processing_order": 1
+                }),
+            )
+        };
+        let iteration = |name: &str, index: usize| LoopIteration {
+            item: json!({ "name": name }),
+            index,
+            count: 3,
+            prev_result: None,
+        };
+        let bundle = |task: Task, iteration: LoopIteration| {
+            let state = state.clone();
+            async move {
+                let (inputs, _) = bundle_looped_task_context(
+                    state.clone(),
+                    &state.anything_client,
+                    &task,
+                    false,
+                    &iteration,
+                )
+                .await
+                .unwrap();
+                inputs
+            }
+        };
+        let hits = || async { state.bundle_cache.read().await.hits() };
+
+        // Only loop.item is read, a different index is the same bundle
+        let task = task_with_inputs(json!({ "name": "{{loop.item.name}}" }));
+        assert_eq!(
+            bundle(task.clone(), iteration("ada", 0)).await["name"],
+            "ada"
+        );
+        assert_eq!(hits().await, 0);
+        assert_eq!(
+            bundle(task.clone(), iteration("ada", 1)).await["name"],
+            "ada"
+        );
+        assert_eq!(hits().await, 1);
+
+        // A changed value it reads is a miss
+        assert_eq!(
+            bundle(task.clone(), iteration("grace", 2)).await["name"],
+            "grace"
+        );
+        assert_eq!(hits().await, 1);
+
+        // JMESPath can read anything so it's never cached
+        let task = task_with_inputs(json!({ "name": "{{ jmes: loop.item.name }}" }));
+        assert_eq!(
+            bundle(task.clone(), iteration("ada", 0)).await["name"],
+            "ada"
+        );
+        assert_eq!(bundle(task, iteration("ada", 0)).await["name"], "ada");
+        assert_eq!(hits().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_metadata_is_in_system_run() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+        let workflow = store
+            .get_workflow_definition(&workflow_id, Some(&flow_version_id))
+            .await
+            .unwrap();
+
+        let fields = [
+            "flow_session_id",
+            "trigger_session_id",
+            "workflow_id",
+            "workflow_version_id",
+            "action_id",
+            "stage",
+        ];
+        let mut inputs = serde_json::Map::new();
+        let mut properties = serde_json::Map::new();
+        for field in fields {
+            inputs.insert(
+                field.to_string(),
+                json!(format!("{{{{system.run.{}}}}}", field)),
+            );
+            properties.insert(
+                field.to_string(),
+                json!({ "x-any-validation": { "type": "string" } }),
+            );
+        }
+        let flow_session_id = Uuid::new_v4();
+        let trigger_session_id = Uuid::new_v4();
+        let task: Task = task(
+            "log",
+            "action",
+            json!({
+                "account_id": workflow.account_id,
+                "flow_id": workflow_id,
+                "flow_version_id": flow_version_id,
+                "trigger_session_id": trigger_session_id,
+                "flow_session_id": flow_session_id,
+                "config": {
+                    "inputs": inputs,
+                    "inputs_schema": { "type": "object", "properties": properties }
+                },
+                "processing_order": 1
+            }),
+        );
+
+        let (inputs, _) =
+            bundle_tasks_cached_context(state.clone(), &state.anything_client, &task, false)
+                .await
+                .unwrap();
+        assert_eq!(
+            inputs,
+            json!({
+                "flow_session_id": flow_session_id.to_string(),
+                "trigger_session_id": trigger_session_id.to_string(),
+                "workflow_id": workflow_id.to_string(),
+                "workflow_version_id": flow_version_id.to_string(),
+                "action_id": "log",
+                "stage": "testing"
+            })
+        );
+    }
+
+    fn completed_task(action_id: &str, r#type: &str, processing_order: i32) -> Task {
+        task(
+            action_id,
+            r#type,
+            json!({
+                "task_status": "completed",
+                "config": { "inputs": {}, "inputs_schema": null },
+                "processing_order": processing_order
+            }),
+        )
+    }
+
+    #[test]
+    fn test_tasks_sharing_an_action_id_dont_overwrite_each_other() {
+        let mut earlier = completed_task("http", "action", 1);
+        earlier.result = Some(json!({ "attempt": 1 }));
+        let mut later = completed_task("http", "action", 2);
+        later.result = Some(json!({ "attempt": 2 }));
+        let webhook = completed_task("webhook", "trigger", 0);
+
+        // The later task wins whichever order they come in
+        for tasks in [
+            vec![webhook.clone(), earlier.clone(), later.clone()],
+            vec![later, earlier, webhook],
+        ] {
+            let actions = latest_tasks(tasks);
+            assert_eq!(actions.len(), 2);
+            assert_eq!(actions["http"].result, Some(json!({ "attempt": 2 })));
+            assert_eq!(actions["webhook"].r#type, "trigger");
+        }
+    }
+
+    // cargo test --release bench_inputs_with_a_large_actions_map -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_inputs_with_a_large_actions_map() {
+        let tasks = latest_tasks(
+            (0..2000)
+                .map(|i| {
+                    let mut task = completed_task(&format!("action_{}", i), "action", i);
+                    task.result = Some(json!({
+                        "id": i,
+                        "items": vec![json!({ "name": "item", "value": i }); 20]
+                    }));
+                    task
+                })
+                .collect(),
+        );
+        let mut templater = Templater::new();
+        templater.add_template(
+            "task_inputs_definition",
+            json!({
+                "url": "https://example.com/{{actions.action_7.result.id}}",
+                "count": "{{actions.action_1999.result.items | length}}"
+            }),
+        );
+        let mut validations = HashMap::new();
+        validations.insert("url".to_string(), ValidationFieldType::String);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+        const RENDERS: u32 = 50;
+
+        // Every task serialized into one Value, how inputs were bundled before LazyContext
+        let started = std::time::Instant::now();
+        let mut whole = Value::Null;
+        for _ in 0..RENDERS {
+            let actions = tasks
+                .iter()
+                .map(|(action_id, task)| Ok((action_id.clone(), serde_json::to_value(task)?)))
+                .collect::<Result<Map<String, Value>, serde_json::Error>>()
+                .unwrap();
+            let context = Value::Object(Map::from_iter([(
+                "actions".to_string(),
+                Value::Object(actions),
+            )]));
+            whole = templater
+                .render_ref("task_inputs_definition", &context, &validations)
+                .unwrap();
+        }
+        let whole_elapsed = started.elapsed();
+
+        let started = std::time::Instant::now();
+        let mut lazy = Value::Null;
+        for _ in 0..RENDERS {
+            let mut context = LazyContext::new();
+            context.insert_keyed("actions", tasks.keys().cloned().collect(), |action_id| {
+                serde_json::to_value(tasks.get(action_id)?).ok()
+            });
+            lazy = templater
+                .render_lazy("task_inputs_definition", &context, &validations)
+                .unwrap();
+        }
+        let lazy_elapsed = started.elapsed();
+
+        assert_eq!(lazy, whole);
+        assert_eq!(lazy, json!({ "url": "https://example.com/7", "count": 20 }));
+        println!(
+            "[BENCH] {} renders reading 2 of {} actions: whole context {:?}, lazy context {:?}",
+            RENDERS,
+            tasks.len(),
+            whole_elapsed,
+            lazy_elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_action_referencing_its_own_result() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+        let workflow = store
+            .get_workflow_definition(&workflow_id, Some(&flow_version_id))
+            .await
+            .unwrap();
+
+        let task_with_inputs = |inputs: Value| -> Task {
+            task(
+                "total",
+                "action",
+                json!({
+                    "account_id": workflow.account_id,
+                    "flow_id": workflow_id,
+                    "flow_version_id": flow_version_id,
+                    "config": {
+                        "inputs": inputs,
+                        "inputs_schema": {
+                            "type": "object",
+                            "properties": { "total": { "x-any-validation": { "type": "string" } } }
+                        }
+                    },
+                    "processing_order": 1
+                }),
+            )
+        };
+
+        let task = task_with_inputs(json!({ "total": "{{actions.total.result.sum ?? 0}}" }));
+        let error =
+            bundle_tasks_cached_context(state.clone(), &state.anything_client, &task, false)
+                .await
+                .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Action 'total' cannot reference its own result: {{actions.total.result.sum}}"
+        );
+
+        // Another action whose id starts the same way is fine
+        let task =
+            task_with_inputs(json!({ "total": "{{actions.total_before.result ?? 'none'}}" }));
+        let (inputs, _) =
+            bundle_tasks_cached_context(state.clone(), &state.anything_client, &task, false)
+                .await
+                .unwrap();
+        assert_eq!(inputs, json!({ "total": "none" }));
+    }
+
+    #[tokio::test]
+    async fn test_secrets_come_from_the_secret_provider() {
+        let account_id = Uuid::new_v4().to_string();
+        let provider = InMemorySecretProvider::new(HashMap::from([(
+            account_id.clone(),
+            vec![secret("API_KEY", "sk-memory", None)],
+        )]));
+        let state = test_app_state_with_secret_provider(
+            Arc::new(InMemoryTaskStore::new()),
+            Arc::new(provider),
+        );
+        state
+            .bundler_accounts_cache
+            .write()
+            .await
+            .set(&account_id, vec![account("airtable", "token", None)]);
+
+        let inputs = json!({ "token": "Bearer {{secrets.API_KEY}}" });
+        let inputs_schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": { "token": { "x-any-validation": { "type": "string" } } }
+        }))
+        .unwrap();
+        let rendered = bundle_cached_inputs(
+            state.clone(),
+            &state.anything_client,
+            &account_id,
+            &Uuid::new_v4().to_string(),
+            Some(&inputs),
+            Some(&inputs_schema),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rendered, json!({ "token": "Bearer sk-memory" }));
+
+        // Cached like the vault's secrets so the provider isn't asked on every task
+        assert_eq!(
+            state
+                .bundler_secrets_cache
+                .read()
+                .await
+                .get(&account_id)
+                .map(|secrets| secrets.len()),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_comes_from_the_config_source() {
+        let account_id = Uuid::new_v4().to_string();
+        let mut state = test_app_state(Arc::new(InMemoryTaskStore::new()));
+        Arc::get_mut(&mut state).unwrap().config_source =
+            Arc::new(InMemoryConfigSource::new(Map::from_iter([
+                ("api_base_url".to_string(), json!("https://api.example.com")),
+                ("page_size".to_string(), json!(50)),
+            ])));
+        state
+            .bundler_accounts_cache
+            .write()
+            .await
+            .set(&account_id, vec![account("airtable", "token", None)]);
+
+        let inputs = json!({
+            "url": "{{config.api_base_url}}/orders",
+            "limit": "{{config.page_size}}"
+        });
+        let inputs_schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": {
+                "url": { "x-any-validation": { "type": "string" } },
+                "limit": { "x-any-validation": { "type": "number" } }
             }
+        }))
+        .unwrap();
+        let rendered = bundle_cached_inputs(
+            state.clone(),
+            &state.anything_client,
+            &account_id,
+            &Uuid::new_v4().to_string(),
+            Some(&inputs),
+            Some(&inputs_schema),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rendered,
+            json!({ "url": "https://api.example.com/orders", "limit": 50 })
+        );
+    }
+
+    #[test]
+    fn test_access_audit_lists_only_what_was_read() {
+        let mut templater = Templater::new();
+        templater.set_secrets(HashMap::from([
+            ("API_KEY".to_string(), Secret::new("sk-live".to_string())),
+            ("UNUSED".to_string(), Secret::new("sk-unused".to_string())),
+        ]));
+        templater.add_template(
+            "inputs",
+            json!({
+                "auth": "Bearer {{secrets.API_KEY}}",
+                "token": "{{accounts.slack.access_token}}",
+                "fallback": "{{accounts.missing.access_token ?? 'none'}}",
+                "again": "{{secrets.API_KEY}}"
+            }),
+        );
+        let validations = ["auth", "token", "fallback", "again"]
+            .into_iter()
+            .map(|key| (key.to_string(), ValidationFieldType::String))
+            .collect();
+        let context = json!({
+            "accounts": {
+                "slack": { "access_token": "xoxb" },
+                "github": { "access_token": "gho" }
+            }
+        });
+        templater.render("inputs", &context, validations).unwrap();
+
+        // Fetched isn't enough, UNUSED and github are in the context but never read
+        let secret_names = HashSet::from(["API_KEY".to_string(), "UNUSED".to_string()]);
+        assert_eq!(
+            access_audit(&templater, &secret_names, context.get("accounts")),
+            AccessAudit {
+                secrets: vec!["API_KEY".to_string()],
+                accounts: vec!["slack".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_staging_selects_staging_credentials() {
+        let secrets = vec![
+            secret("STRIPE_KEY", "sk_live", None),
+            secret("STRIPE_KEY", "sk_staging", Some("staging")),
+            secret("SLACK_TOKEN", "xoxb-shared", None),
+            secret("PROD_ONLY", "prod", Some("production")),
+        ];
+
+        let staging = secret_values(secrets.clone(), &Stage::Staging);
+        assert_eq!(staging["STRIPE_KEY"], "sk_staging");
+        assert_eq!(staging["SLACK_TOKEN"], "xoxb-shared");
+        assert!(!staging.contains_key("PROD_ONLY"));
+
+        let production = secret_values(secrets, &Stage::Production);
+        assert_eq!(production["STRIPE_KEY"], "sk_live");
+        assert_eq!(production["PROD_ONLY"], "prod");
+
+        // Same rules for accounts, whatever order they come back in
+        let accounts = vec![
+            account("airtable", "staging_token", Some("staging")),
+            account("airtable", "live_token", None),
+            account("gmail", "live_token", Some("production")),
+        ];
+        let selected = select_for_stage(
+            accounts,
+            &Stage::Staging,
+            |account| account.account_auth_provider_account_slug.as_str(),
+            |account| account.stage.as_deref(),
+        );
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].account_auth_provider_account_slug, "airtable");
+        assert_eq!(selected[0].access_token, "staging_token");
+    }
+
+    #[test]
+    fn test_only_referenced_action_results_are_resolved() {
+        let inputs = json!({
+            "id": "{{actions.fetch.result.id}}",
+            "label": "{{actions.count.result.total > 0 ? actions.list-items.result : 'none'}}",
+            "rows": "{{#each actions.rows.result}}{{this.name}}{{/each}}",
+            "other": "{{system.actions}} {{my_actions}}"
+        });
+        assert_eq!(
+            referenced_actions(&template_variables(&inputs)),
+            Some(HashSet::from(
+                ["fetch", "count", "list-items", "rows"].map(String::from)
+            ))
+        );
+
+        // Both can read any action's result
+        for inputs in [
+            json!({ "all": "{{actions}}" }),
+            json!({ "ids": "{{jmes: actions.*.result.id}}" }),
+        ] {
+            assert_eq!(referenced_actions(&template_variables(&inputs)), None);
         }
     }
 
-    template_key_validations
+    #[tokio::test]
+    async fn test_only_referenced_account_refresh_failures_fail_the_bundle() {
+        let inputs = json!({
+            "token": "{{accounts.gmail.access_token}}",
+            "label": "{{accounts.slack?.account_auth_provider_account_label ?? 'none'}}"
+        });
+        let referenced = referenced_accounts(Some(&inputs));
+        assert_eq!(
+            referenced,
+            HashSet::from(["gmail".to_string(), "slack".to_string()])
+        );
+
+        let expiring = |slug: &str| {
+            let mut account = account(slug, "old_token", None);
+            account.access_token_expires_at = Some(chrono::Utc::now());
+            account
+        };
+        // Refreshing airtable fails, the others get a new token
+        let refresh = |mut account: AccountAuthProviderAccount| async move {
+            if account.account_auth_provider_account_slug == "airtable" {
+                account.failed = true;
+            } else {
+                account.access_token = "new_token".to_string();
+            }
+            Ok::<_, Box<dyn Error + Send + Sync>>(vec![account])
+        };
+
+        // Nothing references airtable so its failure doesn't matter, and it isn't refreshed
+        let accounts = refresh_referenced_accounts(
+            vec![expiring("gmail"), expiring("airtable")],
+            &referenced,
+            refresh,
+        )
+        .await
+        .unwrap();
+        assert_eq!(accounts[0].access_token, "new_token");
+        assert_eq!(accounts[1].access_token, "old_token");
+        assert!(!accounts[1].failed);
+
+        let referenced = HashSet::from(["airtable".to_string()]);
+        let error = refresh_referenced_accounts(
+            vec![expiring("gmail"), expiring("airtable")],
+            &referenced,
+            refresh,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error,
+            BundlerError::AccountRefreshFailed("airtable".to_string())
+        );
+        assert_eq!(
+            error.to_string(),
+            "Account 'airtable' could not be refreshed"
+        );
+
+        // A refresh that errors is the same failure
+        let referenced = HashSet::from(["gmail".to_string()]);
+        let error = refresh_referenced_accounts(vec![expiring("gmail")], &referenced, |_| async {
+            Err::<Vec<AccountAuthProviderAccount>, Box<dyn Error + Send + Sync>>(
+                "vault unavailable".into(),
+            )
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error,
+            BundlerError::AccountRefreshFailed("gmail".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validations_from_a_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "url": {
+                    "title": "URL",
+                    "type": "string",
+                    "x-any-validation": { "type": "string" }
+                },
+                "method": {
+                    "type": "string",
+                    "x-any-validation": { "type": { "one_of": ["GET", "POST"] } }
+                },
+                "headers": { "type": "object", "x-any-validation": { "type": "object" } },
+                "retries": { "type": "integer" },
+                "mode": { "enum": ["fast", "safe"] },
+                "payload": { "x-any-validation": { "type": "any" } },
+                "note": { "type": ["string", "null"] },
+                "extra": { "x-any-validation": { "type": "something_new" } }
+            },
+            "required": ["url"]
+        });
+
+        let validations = ValidationFieldType::from_schema(&schema);
+        let expected: HashMap<String, ValidationFieldType> = [
+            ("url", ValidationFieldType::String),
+            (
+                "method",
+                ValidationFieldType::OneOf(vec!["GET".to_string(), "POST".to_string()]),
+            ),
+            ("headers", ValidationFieldType::Object),
+            ("retries", ValidationFieldType::Integer),
+            (
+                "mode",
+                ValidationFieldType::OneOf(vec!["fast".to_string(), "safe".to_string()]),
+            ),
+            ("payload", ValidationFieldType::Any),
+            ("note", ValidationFieldType::Unknown),
+            ("extra", ValidationFieldType::Unknown),
+        ]
+        .into_iter()
+        .map(|(name, validation_type)| (name.to_string(), validation_type))
+        .collect();
+        assert_eq!(validations, expected);
+        assert!(ValidationFieldType::from_schema(&json!({ "type": "object" })).is_empty());
+
+        // What the bundler reads off an action's schema
+        let action_schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": {
+                "url": { "type": "string", "x-any-validation": { "type": "string" } },
+                "retries": { "type": "number" }
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            extract_template_key_validations_from_schema(Some(&action_schema)),
+            HashMap::from([
+                ("url".to_string(), ValidationFieldType::String),
+                ("retries".to_string(), ValidationFieldType::Number),
+            ])
+        );
+        assert!(extract_template_key_validations_from_schema(None).is_empty());
+    }
 }
@@ -0,0 +1,95 @@
+use dotenv::dotenv;
+use serde_json::{Map, Value};
+use std::env;
+use std::sync::Arc;
+
+// Env vars with this prefix are config values, e.g. ANYTHING_CONFIG_API_BASE_URL is
+// {{config.api_base_url}}
+const CONFIG_ENV_PREFIX: &str = "ANYTHING_CONFIG_";
+
+// Where `{{config.*}}` comes from. Unlike secrets it's set by whoever runs the deployment, is the
+// same for every account and isn't encrypted, e.g. base URLs and feature toggles.
+// Picked at startup, see config_source_from_env
+pub trait ConfigSource: Send + Sync {
+    fn get_config(&self) -> Map<String, Value>;
+}
+
+// Config read once from the environment, the default source
+pub struct EnvConfigSource {
+    config: Map<String, Value>,
+}
+
+impl EnvConfigSource {
+    pub fn from_env() -> Self {
+        let config = env::vars()
+            .filter_map(|(name, value)| {
+                let key = name.strip_prefix(CONFIG_ENV_PREFIX)?.to_lowercase();
+                (!key.is_empty()).then_some((key, Value::String(value)))
+            })
+            .collect();
+        Self { config }
+    }
+}
+
+impl ConfigSource for EnvConfigSource {
+    fn get_config(&self) -> Map<String, Value> {
+        self.config.clone()
+    }
+}
+
+// Config from a JSON object in a file, so values can be numbers, booleans or nested
+pub struct FileConfigSource {
+    config: Map<String, Value>,
+}
+
+impl FileConfigSource {
+    pub fn new(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file '{}': {}", path, e))?;
+        match serde_json::from_str(&contents) {
+            Ok(Value::Object(config)) => Ok(Self { config }),
+            Ok(_) => Err(format!("Config file '{}' must be a JSON object", path)),
+            Err(e) => Err(format!("Invalid config file '{}': {}", path, e)),
+        }
+    }
+}
+
+impl ConfigSource for FileConfigSource {
+    fn get_config(&self) -> Map<String, Value> {
+        self.config.clone()
+    }
+}
+
+// CONFIG_SOURCE picks the source, "file" reads CONFIG_FILE
+pub fn config_source_from_env() -> Result<Arc<dyn ConfigSource>, String> {
+    dotenv().ok();
+    match env::var("CONFIG_SOURCE").as_deref() {
+        Err(_) | Ok("env") => Ok(Arc::new(EnvConfigSource::from_env())),
+        Ok("file") => {
+            let path = env::var("CONFIG_FILE")
+                .map_err(|_| "CONFIG_SOURCE is file but CONFIG_FILE isn't set".to_string())?;
+            Ok(Arc::new(FileConfigSource::new(&path)?))
+        }
+        Ok(other) => Err(format!("Unknown CONFIG_SOURCE '{}'", other)),
+    }
+}
+
+// Config handed out from memory
+#[cfg(test)]
+pub struct InMemoryConfigSource {
+    config: Map<String, Value>,
+}
+
+#[cfg(test)]
+impl InMemoryConfigSource {
+    pub fn new(config: Map<String, Value>) -> Self {
+        Self { config }
+    }
+}
+
+#[cfg(test)]
+impl ConfigSource for InMemoryConfigSource {
+    fn get_config(&self) -> Map<String, Value> {
+        self.config.clone()
+    }
+}
@@ -1,5 +1,7 @@
 pub mod accounts;
+pub mod bundle_cache;
 pub mod bundler;
+pub mod config;
 pub mod secrets;
 
 #[cfg(test)]
@@ -25,5 +27,9 @@ pub async fn cleanup_bundler_caches(state: Arc<AppState>) {
             let mut accounts_cache = state.bundler_accounts_cache.write().await;
             accounts_cache.cleanup();
         }
+        {
+            let mut bundle_cache = state.bundle_cache.write().await;
+            bundle_cache.cleanup();
+        }
     }
 }
@@ -1,3 +1,4 @@
+use axum::async_trait;
 use dotenv::dotenv;
 use postgrest::Postgrest;
 use secrets_cache::SecretsCache;
@@ -5,23 +6,88 @@ use std::{env, sync::Arc};
 use tracing::debug;
 use uuid::Uuid;
 
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
+use crate::types::secret_types::Secret;
 use crate::AppState;
 
 pub mod secrets_cache;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct DecryptedSecret {
     pub secret_id: Uuid,
     pub secret_name: String,
-    pub secret_value: String,
+    pub secret_value: Secret<String>,
     pub secret_description: Option<String>,
+    pub stage: Option<String>, // Only used by runs in this stage. None means every stage
+}
+
+// Where the bundler gets an account's secrets from when they aren't cached.
+// Picked at startup, see secret_provider_from_env
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    async fn get_secrets(
+        &self,
+        account_id: &str,
+    ) -> Result<Vec<DecryptedSecret>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+// Secrets stored in the Supabase vault. The default
+pub struct SupabaseVaultSecretProvider {
+    client: Arc<Postgrest>,
+}
+
+impl SupabaseVaultSecretProvider {
+    pub fn new(client: Arc<Postgrest>) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl SecretProvider for SupabaseVaultSecretProvider {
+    async fn get_secrets(
+        &self,
+        account_id: &str,
+    ) -> Result<Vec<DecryptedSecret>, Box<dyn std::error::Error + Send + Sync>> {
+        fetch_secrets_from_vault(&self.client, account_id).await
+    }
+}
+
+// SECRET_PROVIDER picks the provider, other backends are added as another name here
+pub fn secret_provider_from_env(client: Arc<Postgrest>) -> Result<Arc<dyn SecretProvider>, String> {
+    dotenv().ok();
+    match env::var("SECRET_PROVIDER").as_deref() {
+        Err(_) | Ok("supabase_vault") => Ok(Arc::new(SupabaseVaultSecretProvider::new(client))),
+        Ok(other) => Err(format!("Unknown SECRET_PROVIDER '{}'", other)),
+    }
+}
+
+// Secrets handed out from memory, per account_id
+#[cfg(test)]
+pub struct InMemorySecretProvider {
+    secrets: std::collections::HashMap<String, Vec<DecryptedSecret>>,
+}
+
+#[cfg(test)]
+impl InMemorySecretProvider {
+    pub fn new(secrets: std::collections::HashMap<String, Vec<DecryptedSecret>>) -> Self {
+        Self { secrets }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl SecretProvider for InMemorySecretProvider {
+    async fn get_secrets(
+        &self,
+        account_id: &str,
+    ) -> Result<Vec<DecryptedSecret>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.secrets.get(account_id).cloned().unwrap_or_default())
+    }
 }
 
 pub async fn get_decrypted_secrets(
     state: Arc<AppState>,
-    client: &Postgrest,
     account_id: &str,
 ) -> Result<Vec<DecryptedSecret>, Box<dyn std::error::Error + Send + Sync>> {
     // Try to get from cache first using a read lock
@@ -37,12 +103,12 @@ pub async fn get_decrypted_secrets(
     }
 
     println!(
-        "[BUNDLER] Cache miss for secrets, fetching from DB for account_id: {}",
+        "[BUNDLER] Cache miss for secrets, fetching from the secret provider for account_id: {}",
         account_id
     );
 
-    // If not in cache, fetch from DB
-    let secrets = fetch_secrets_from_vault(client, account_id).await?;
+    // If not in cache, fetch from the provider
+    let secrets = state.secret_provider.get_secrets(account_id).await?;
 
     // Update cache with a write lock
     {
@@ -91,7 +157,8 @@ pub async fn fetch_secrets_from_vault(
         Ok(parsed) => parsed,
         Err(e) => {
             println!("[BUNDLER] Error parsing decrypted secrets: {}", e);
-            println!("[BUNDLER] Response body: {}", body);
+            // The body holds decrypted secrets so only its size is logged
+            println!("[BUNDLER] Response body was {} bytes", body.len());
             return Err(Box::new(e));
         }
     };
@@ -1,11 +1,11 @@
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::time::{Duration, SystemTime};
 use tracing::debug;
 
 use crate::bundler::secrets::DecryptedSecret;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 struct CachedSecret {
     secret: DecryptedSecret,
     expires_at: SystemTime,
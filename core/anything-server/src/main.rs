@@ -12,11 +12,11 @@ use processor::processor::ProcessorMessage;
 use postgrest::Postgrest;
 use reqwest::Client;
 use serde_json::Value;
-use std::{collections::HashMap, time::Duration};
+use std::{collections::{HashMap, HashSet}, time::Duration};
 use std::env;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tokio::sync::{watch, Semaphore};
+use tokio::sync::{watch, Notify, Semaphore};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::set_header::SetResponseHeaderLayer;
 use tokio::sync::mpsc; 
@@ -58,7 +58,7 @@ mod agents;
 
 use tokio::sync::oneshot;
 use tokio::sync::Mutex;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 
 // Add this struct to store completion channels
 pub struct FlowCompletion {
@@ -83,17 +83,44 @@ pub struct AppState {
     processor_sender: mpsc::Sender<ProcessorMessage>,
     processor_receiver: Mutex<mpsc::Receiver<ProcessorMessage>>, 
     flow_completions: Arc<Mutex<HashMap<String, FlowCompletion>>>,
+    flow_session_waiters: Arc<Mutex<HashMap<uuid::Uuid, oneshot::Sender<processor::run_workflow::FlowSessionOutcome>>>>, // Resolved by the processor when a session ends
     api_key_cache: Arc<RwLock<HashMap<String, CachedApiKey>>>,
     account_access_cache: Arc<RwLock<account_auth_middleware::AccountAccessCache>>,
     bundler_secrets_cache: RwLock<SecretsCache>,
     bundler_accounts_cache: RwLock<AccountsCache>,
+    bundle_cache: RwLock<bundler::bundle_cache::BundleCache>, // Rendered task inputs, see bundle_cache_key
     flow_session_cache: Arc<RwLock<processor::flow_session_cache::FlowSessionCache>>,
+    active_flow_sessions: Arc<Mutex<HashSet<uuid::Uuid>>>, // Sessions being processed, shared by every processor loop
+    canceled_flow_sessions: Arc<RwLock<HashSet<uuid::Uuid>>>, // Checked by the processor before each task
+    flow_session_canceled: Arc<Notify>, // Wakes the tasks in flight when a session is canceled
+    stalled_flow_sessions: Arc<RwLock<HashSet<uuid::Uuid>>>, // Canceled by fail_stalled_session, the processor fails them instead
+    stalled_flow_session_count: AtomicUsize, // How many stalled sessions the last check found, see find_stalled_sessions
+    webhook_deliveries: Arc<RwLock<HashMap<String, (String, std::time::SystemTime)>>>, // workflow_id:delivery_id -> (flow_session_id, expires_at)
+    offload_threshold_bytes: AtomicUsize, // Results bigger than this are stored in task_large_results, 0 never offloads
     shutdown_signal: Arc<AtomicBool>,
+    task_store: Arc<dyn processor::db_calls::TaskStore>, // Where the processor reads and writes workflows and tasks
+    task_middleware: RwLock<Vec<Arc<dyn processor::task_middleware::TaskMiddleware>>>, // Run around every task, see execute_task
+    secret_provider: Arc<dyn bundler::secrets::SecretProvider>, // Where the bundler fetches secrets on a cache miss
+    config_source: Arc<dyn bundler::config::ConfigSource>, // What templates read as {{config.*}}
+    batch_task_creation: Arc<AtomicBool>, // Create every task of a linear workflow in one insert, see plan_linear_tasks
+    plugin_rate_limiter: processor::rate_limiter::PluginRateLimiter, // Acquired by execute_task before a plugin runs
+    concurrency_keys: processor::concurrency_keys::ConcurrencyKeys, // Locked by execute_task for actions with a concurrency_key
+    max_parallel_branches: AtomicUsize, // How many of a Parallel task's children run at once, see run_parallel_task
+    write_retries: processor::write_retries::WriteRetryQueue, // Status writes that failed, drained by write_retry_loop
+    template_cache: Arc<templater::TemplateCache>, // Compiled task input templates every bundle shares, see Templater::with_cache
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+
+    // Processor and bundler logs go through tracing. Use RUST_LOG to filter, e.g. RUST_LOG=anything_server=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
     let supabase_url = env::var("SUPABASE_URL").expect("SUPABASE_URL must be set");
     let supabase_api_key = env::var("SUPABASE_API_KEY").expect("SUPABASE_API_KEY must be set");
     let cors_origin = env::var("ANYTHING_BASE_URL").expect("ANYTHING_BASE_URL must be set");
@@ -178,6 +205,10 @@ async fn main() {
     let (processor_tx, processor_rx) = mpsc::channel::<ProcessorMessage>(1000); // Create both sender and receiver
 
 
+    let mut flow_session_cache =
+        processor::flow_session_cache::FlowSessionCache::new(Duration::from_secs(3600));
+    flow_session_cache.set_max_result_bytes(processor::large_results::get_cache_result_limit());
+
     let state = Arc::new(AppState {
         anything_client: anything_client.clone(),
         marketplace_client: marketplace_client.clone(),
@@ -191,14 +222,41 @@ async fn main() {
         processor_sender: processor_tx,
         processor_receiver: Mutex::new(processor_rx),
         flow_completions: Arc::new(Mutex::new(HashMap::new())),
+        flow_session_waiters: Arc::new(Mutex::new(HashMap::new())),
         api_key_cache: Arc::new(RwLock::new(HashMap::new())),
         account_access_cache: Arc::new(RwLock::new(
             account_auth_middleware::AccountAccessCache::new(Duration::from_secs(86400))
         )),
         bundler_secrets_cache: RwLock::new(SecretsCache::new(Duration::from_secs(86400))), // 1 day TTL
         bundler_accounts_cache: RwLock::new(AccountsCache::new(Duration::from_secs(86400))), // 1 day TTL
-        flow_session_cache: Arc::new(RwLock::new(processor::flow_session_cache::FlowSessionCache::new(Duration::from_secs(3600)))),
+        bundle_cache: RwLock::new(bundler::bundle_cache::BundleCache::new(Duration::from_secs(3600))), // 1 hour TTL
+        flow_session_cache: Arc::new(RwLock::new(flow_session_cache)),
+        active_flow_sessions: Arc::new(Mutex::new(HashSet::new())),
+        canceled_flow_sessions: Arc::new(RwLock::new(HashSet::new())),
+        flow_session_canceled: Arc::new(Notify::new()),
+        stalled_flow_sessions: Arc::new(RwLock::new(HashSet::new())),
+        stalled_flow_session_count: AtomicUsize::new(0),
+        webhook_deliveries: Arc::new(RwLock::new(HashMap::new())),
+        offload_threshold_bytes: AtomicUsize::new(processor::large_results::get_offload_threshold()),
         shutdown_signal: Arc::new(AtomicBool::new(false)),
+        task_store: Arc::new(processor::db_calls::PostgrestTaskStore::new(anything_client.clone())),
+        task_middleware: RwLock::new(Vec::new()),
+        secret_provider: bundler::secrets::secret_provider_from_env(anything_client.clone())
+            .unwrap_or_else(|e| panic!("{}", e)),
+        config_source: bundler::config::config_source_from_env()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        batch_task_creation: Arc::new(AtomicBool::new(
+            env::var("BATCH_TASK_CREATION").is_ok_and(|value| value == "true"),
+        )),
+        plugin_rate_limiter: processor::rate_limiter::PluginRateLimiter::from_env()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        max_parallel_branches: AtomicUsize::new(
+            processor::parallel::max_parallel_branches_from_env()
+                .unwrap_or_else(|e| panic!("{}", e)),
+        ),
+        write_retries: processor::write_retries::WriteRetryQueue::from_env(),
+        concurrency_keys: processor::concurrency_keys::ConcurrencyKeys::default(),
+        template_cache: Arc::new(templater::TemplateCache::new()),
     });
 
 pub async fn root() -> impl IntoResponse {
@@ -259,10 +317,20 @@ pub async fn root() -> impl IntoResponse {
             "/account/:account_id/workflow/:workflow_id/version/:workflow_version_id/publish",
             put(workflows::publish_workflow_version),
         )
+        .route(
+            "/account/:account_id/workflow/:workflow_id/diff/:from_version_id/:to_version_id",
+            get(workflows::diff_workflow_versions),
+        )
         .route("/account/:account_id/workflow", post(workflows::create_workflow))
         .route("/account/:account_id/workflow/json", post(workflows::create_workflow_from_json))
+        .route("/account/:account_id/workflow/lint", post(workflows::lint_workflow_definition))
+        .route("/account/:account_id/workflow/preflight", post(workflows::preflight_workflow_definition))
         .route("/account/:account_id/workflow/:id", delete(workflows::delete_workflow))
         .route("/account/:account_id/workflow/:id", put(workflows::update_workflow))
+        .route(
+            "/account/:account_id/workflow/:workflow_id/session/:flow_session_id/cancel",
+            post(workflows::cancel_workflow_session),
+        )
         .route("/account/:account_id/actions", get(actions::get_actions))
         .route("/account/:account_id/triggers", get(actions::get_triggers))
         .route("/account/:account_id/other", get(actions::get_other_actions))
@@ -401,6 +469,12 @@ pub async fn root() -> impl IntoResponse {
     tokio::spawn(account_auth_middleware::cleanup_account_access_cache(state.clone()));
     tokio::spawn(bundler::cleanup_bundler_caches(state.clone()));
 
+    // Logs, and with FAIL_STALLED_SESSIONS fails, sessions that stopped making progress
+    tokio::spawn(processor::stalled_sessions::stalled_session_loop(state.clone()));
+
+    // Retries status writes that failed while the DB was unreachable
+    tokio::spawn(processor::write_retries::write_retry_loop(state.clone()));
+
     // Spawn the hydrate processor
     tokio::spawn(processor::hydrate_processor::hydrate_processor(state.clone()));
 
@@ -0,0 +1,316 @@
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::processor::processor::{CancelOutcome, ProcessorMessage};
+use crate::types::task_types::{FlowSessionStatus, Task, TaskStatus, TriggerSessionStatus};
+use crate::AppState;
+
+pub const APPROVAL_PLUGIN: &str = "@anything/approval";
+
+// Approvals don't run, the processor pauses the session at them until resume_flow_session
+// completes them with the approval payload
+pub fn requests_pause(task: &Task) -> bool {
+    task.plugin_name
+        .as_ref()
+        .is_some_and(|plugin_name| plugin_name.as_str() == APPROVAL_PLUGIN)
+        && task.task_status != TaskStatus::Completed
+}
+
+// The waiting task is what the session picks up from, so it's written before the processor lets
+// go of the session
+pub async fn pause_flow_session(state: &AppState, flow_session_id: &Uuid, task: &Task) {
+    info!(
+        "[PROCESSOR] Flow session {} is waiting on approval {}",
+        flow_session_id, task.action_id
+    );
+
+    if let Err(e) = state
+        .task_store
+        .update_task_status(&task.task_id, &TaskStatus::Waiting, None, None, None, None)
+        .await
+    {
+        error!("[PROCESSOR] Failed to update task status: {}", e);
+    }
+
+    if let Err(e) = state
+        .task_store
+        .update_flow_session_status(
+            flow_session_id,
+            &FlowSessionStatus::Paused,
+            &TriggerSessionStatus::Waiting,
+        )
+        .await
+    {
+        error!("[PROCESSOR] Failed to update flow session status: {}", e);
+    }
+
+    {
+        let mut cache = state.flow_session_cache.write().await;
+        let mut task_copy = task.clone();
+        task_copy.task_status = TaskStatus::Waiting;
+        if let Err(e) = cache.update_task(flow_session_id, task_copy) {
+            warn!("[PROCESSOR] Failed to update task in cache: {}", e);
+        }
+    }
+
+    // A webhook waiting on a response won't get one until someone approves
+    let mut completions = state.flow_completions.lock().await;
+    if let Some(completion) = completions.remove(&flow_session_id.to_string()) {
+        if completion.needs_response {
+            let _ = completion.sender.send(json!({
+                "flow_session_id": flow_session_id,
+                "status": FlowSessionStatus::Paused.as_str()
+            }));
+        }
+    }
+}
+
+// No processor holds a paused session, so its approval and the session are marked canceled here
+// and resume_flow_session won't find anything waiting anymore
+pub async fn cancel_paused_flow_session(state: &AppState, flow_session_id: &Uuid) -> CancelOutcome {
+    let tasks = match state
+        .task_store
+        .get_tasks_for_session(flow_session_id)
+        .await
+    {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            error!("[PROCESSOR] Failed to get tasks for session: {}", e);
+            return CancelOutcome::NothingToCancel;
+        }
+    };
+    let Some(waiting) = tasks
+        .iter()
+        .find(|task| task.task_status == TaskStatus::Waiting)
+    else {
+        return CancelOutcome::NothingToCancel;
+    };
+
+    info!(
+        "[PROCESSOR] Canceling flow session {} paused at approval {}",
+        flow_session_id, waiting.action_id
+    );
+    if let Err(e) = state
+        .task_store
+        .update_task_status(
+            &waiting.task_id,
+            &TaskStatus::Canceled,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+    {
+        error!("[PROCESSOR] Failed to update task status: {}", e);
+    }
+    if let Err(e) = state
+        .task_store
+        .update_flow_session_status(
+            flow_session_id,
+            &FlowSessionStatus::Canceled,
+            &TriggerSessionStatus::Canceled,
+        )
+        .await
+    {
+        error!("[PROCESSOR] Failed to update flow session status: {}", e);
+    }
+    CancelOutcome::Canceled
+}
+
+// Completes the approval the session is waiting on with `approval_payload` as its result, so
+// later actions can use it as {{actions.<approval action_id>.result}}, and sends the session to
+// the processor again to carry on after it
+pub async fn resume_flow_session(
+    state: Arc<AppState>,
+    flow_session_id: &Uuid,
+    approval_payload: Value,
+) -> Result<ProcessorMessage, String> {
+    let tasks = state
+        .task_store
+        .get_tasks_for_session(flow_session_id)
+        .await?;
+    let waiting = tasks
+        .iter()
+        .find(|task| task.task_status == TaskStatus::Waiting)
+        .ok_or_else(|| format!("Flow session {} isn't paused", flow_session_id))?;
+    let trigger_session_id = Uuid::parse_str(&waiting.trigger_session_id)
+        .map_err(|e| format!("Invalid trigger_session_id: {}", e))?;
+
+    info!(
+        "[PROCESSOR] Resuming flow session {} after approval {}",
+        flow_session_id, waiting.action_id
+    );
+
+    state
+        .task_store
+        .update_task_status(
+            &waiting.task_id,
+            &TaskStatus::Completed,
+            None,
+            None,
+            Some(approval_payload),
+            None,
+        )
+        .await?;
+    state
+        .task_store
+        .update_flow_session_status(
+            flow_session_id,
+            &FlowSessionStatus::Running,
+            &TriggerSessionStatus::Running,
+        )
+        .await?;
+
+    let message = ProcessorMessage {
+        workflow_id: waiting.flow_id,
+        version_id: Some(waiting.flow_version_id),
+        flow_session_id: *flow_session_id,
+        trigger_session_id,
+        trigger_task: None,
+        response: None,
+        deadline: None,
+    };
+    state
+        .processor_sender
+        .send(message.clone())
+        .await
+        .map_err(|e| format!("Failed to send message to processor: {}", e))?;
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{action, edge, start_test_processor};
+    use crate::processor::processor::cancel_flow_session;
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_pause_at_approval_and_resume() {
+        let mut approval = action("approval", "action", None);
+        approval["plugin_name"] = json!(APPROVAL_PLUGIN);
+        let mut notify = action(
+            "notify",
+            "action",
+            Some(json!({ "mock_result": { "sent": true } })),
+        );
+        notify["inputs"] = json!({ "approver": "{{actions.approval.result.approver}}" });
+        notify["inputs_schema"] = json!({
+            "type": "object",
+            "properties": { "approver": { "x-any-validation": { "type": "string" } } }
+        });
+
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![action("webhook", "trigger", None), approval, notify],
+            vec![edge("webhook", "approval"), edge("approval", "notify")],
+        )
+        .await;
+
+        let paused = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(paused.status, FlowSessionStatus::Paused));
+        let flow_session_id = paused.flow_session_id;
+
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[1].action_id, "approval");
+        assert_eq!(tasks[1].task_status, TaskStatus::Waiting);
+        assert!(matches!(
+            tasks[1].flow_session_status,
+            FlowSessionStatus::Paused
+        ));
+
+        // Resumed as soon as we hear back, the processor has already let go of the session
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(flow_session_id, sender);
+        resume_flow_session(
+            state.clone(),
+            &flow_session_id,
+            json!({ "approver": "ada" }),
+        )
+        .await
+        .unwrap();
+
+        let resumed = receiver.await.unwrap();
+        assert!(matches!(resumed.status, FlowSessionStatus::Completed));
+        assert_eq!(resumed.output, Some(json!({ "sent": true })));
+
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(tasks[1].task_status, TaskStatus::Completed);
+        assert_eq!(tasks[1].result, Some(json!({ "approver": "ada" })));
+        assert_eq!(tasks[2].bundled_inputs, Some(json!({ "approver": "ada" })));
+
+        // Nothing is waiting anymore
+        assert!(resume_flow_session(state, &flow_session_id, json!({}))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_paused_flow_session() {
+        let mut approval = action("approval", "action", None);
+        approval["plugin_name"] = json!(APPROVAL_PLUGIN);
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                approval,
+                action("notify", "action", Some(json!({ "mock_result": {} }))),
+            ],
+            vec![edge("webhook", "approval"), edge("approval", "notify")],
+        )
+        .await;
+
+        let paused = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(paused.status, FlowSessionStatus::Paused));
+        let flow_session_id = paused.flow_session_id;
+
+        // The processor has let go of the session, there's nothing in the cache to signal
+        assert_eq!(
+            cancel_flow_session(&state, &flow_session_id).await,
+            CancelOutcome::Canceled
+        );
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[1].task_status, TaskStatus::Canceled);
+        assert!(tasks
+            .iter()
+            .all(|task| matches!(task.flow_session_status, FlowSessionStatus::Canceled)));
+
+        // It can't be resumed or canceled again
+        assert!(
+            resume_flow_session(state.clone(), &flow_session_id, json!({}))
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            cancel_flow_session(&state, &flow_session_id).await,
+            CancelOutcome::NothingToCancel
+        );
+    }
+}
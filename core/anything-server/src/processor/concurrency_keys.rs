@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+// A lock per Action::concurrency_key, shared by every flow session, so tasks of actions with the
+// same key run one at a time, e.g. writes to one spreadsheet. Keys come from workflow
+// definitions so the map only grows with the actions that set one
+#[derive(Default)]
+pub struct ConcurrencyKeys {
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ConcurrencyKeys {
+    // Waits until no other task holds the key. It's released when the guard is dropped
+    pub async fn lock(&self, concurrency_key: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .await
+            .entry(concurrency_key.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::processor::in_memory_task_store::{action, edge, start_test_processor};
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use crate::types::task_types::FlowSessionStatus;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    // On paused time the writes' delays take exactly 300ms each, without waiting for them
+    #[tokio::test(start_paused = true)]
+    async fn test_tasks_sharing_a_key_do_not_overlap() {
+        let mut write = action(
+            "write_row",
+            "action",
+            Some(json!({ "mock_result": {}, "mock_delay_ms": 300 })),
+        );
+        write["concurrency_key"] = json!("orders_sheet");
+        let (_, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![action("webhook", "trigger", None), write],
+            vec![edge("webhook", "write_row")],
+        )
+        .await;
+
+        let run = || {
+            run_workflow_and_wait(
+                state.clone(),
+                workflow_id,
+                Some(flow_version_id),
+                None,
+                json!({}),
+            )
+        };
+        let started = Instant::now();
+        let (first, second) = tokio::join!(run(), run());
+        // Each session's write waits 300ms, together they would take about 300ms
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(first.flow_session_id, second.flow_session_id);
+        assert!(matches!(first.status, FlowSessionStatus::Completed));
+        assert!(matches!(second.status, FlowSessionStatus::Completed));
+    }
+}
@@ -3,16 +3,19 @@ use dotenv::dotenv;
 use serde_json::Value;
 use std::collections::HashSet;
 use std::{env, sync::Arc};
-use tracing::debug;
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
+use crate::processor::dead_letters::{CreateDeadLetterInput, DeadLetter};
+use crate::processor::large_results::{CreateLargeResultInput, LargeResult};
 use crate::system_plugins::http::http_plugin::parse_headers;
 use crate::types::{
     task_types::{CreateTaskInput, FlowSessionStatus, Task, TaskStatus, TriggerSessionStatus},
     workflow_types::DatabaseFlowVersion,
 };
-use crate::AppState;
+use axum::async_trait;
 use chrono::DateTime;
+use postgrest::Postgrest;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -33,293 +36,635 @@ pub struct UpdateTaskInput {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub context: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundled_inputs: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<Value>,
 }
 
-pub async fn get_workflow_definition(
-    state: Arc<AppState>,
-    workflow_id: &Uuid,
-    version_id: Option<&Uuid>, // Make version_id optional since webhooks don't have it
-) -> Result<DatabaseFlowVersion, String> {
-    println!(
-        "[PROCESSOR DB CALLS] Getting workflow definition for workflow_id: {}, version_id: {:?}",
-        workflow_id, version_id
-    );
-    //Super User Access
-    dotenv().ok();
-    let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
-        .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
-
-    // Get flow version from database
-    let mut query = state
-        .anything_client
-        .from("flow_versions")
-        .eq("flow_id", workflow_id.to_string());
-
-    // If version_id is provided, use it. Otherwise get published version
-    if let Some(version) = version_id {
-        query = query.eq("flow_version_id", version.to_string());
-    } else {
-        query = query.eq("published", "true");
+// Everything the processor reads and writes about workflows and tasks. Production uses
+// PostgrestTaskStore. Tests can use InMemoryTaskStore so they don't need a database
+#[async_trait]
+pub trait TaskStore: Send + Sync {
+    async fn get_workflow_definition(
+        &self,
+        workflow_id: &Uuid,
+        version_id: Option<&Uuid>,
+    ) -> Result<DatabaseFlowVersion, String>;
+
+    // In processing order. A session with no tasks yet is an empty list, not an error
+    async fn get_tasks_for_session(&self, flow_session_id: &Uuid) -> Result<Vec<Task>, String>;
+
+    // Ids that aren't in the store are left out
+    async fn get_tasks(&self, task_ids: &[Uuid]) -> Result<Vec<Task>, String>;
+
+    async fn create_task(&self, task: &CreateTaskInput) -> Result<Task, String>;
+
+    // One insert for all of them, the created tasks come back in the same order
+    async fn create_tasks_batch(&self, tasks: &[CreateTaskInput]) -> Result<Vec<Task>, String>;
+
+    async fn update_task_status(
+        &self,
+        task_id: &Uuid,
+        status: &TaskStatus,
+        context: Option<Value>,
+        bundled_inputs: Option<Value>,
+        result: Option<Value>,
+        error: Option<Value>,
+    ) -> Result<(), String>;
+
+    async fn update_flow_session_status(
+        &self,
+        flow_session_id: &Uuid,
+        flow_session_status: &FlowSessionStatus,
+        trigger_session_status: &TriggerSessionStatus,
+    ) -> Result<(), String>;
+
+    async fn create_dead_letter(&self, dead_letter: &CreateDeadLetterInput) -> Result<(), String>;
+
+    async fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, String>;
+
+    async fn get_dead_letter(&self, dead_letter_id: &Uuid) -> Result<DeadLetter, String>;
+
+    async fn delete_dead_letter(&self, dead_letter_id: &Uuid) -> Result<(), String>;
+
+    // Returns the stored result's id, see offload_large_result
+    async fn create_large_result(
+        &self,
+        large_result: &CreateLargeResultInput,
+    ) -> Result<Uuid, String>;
+
+    async fn get_large_result(&self, result_id: &Uuid) -> Result<Value, String>;
+}
+
+pub struct PostgrestTaskStore {
+    client: Arc<Postgrest>,
+}
+
+impl PostgrestTaskStore {
+    pub fn new(client: Arc<Postgrest>) -> Self {
+        Self { client }
     }
+}
 
-    let response = query
-        .auth(&supabase_service_role_api_key)
-        .select("*")
-        .single()
-        .execute()
-        .await
-        .map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to execute workflow definition request: {}",
+#[async_trait]
+impl TaskStore for PostgrestTaskStore {
+    async fn get_workflow_definition(
+        &self,
+        workflow_id: &Uuid,
+        version_id: Option<&Uuid>, // Make version_id optional since webhooks don't have it
+    ) -> Result<DatabaseFlowVersion, String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Getting workflow definition for workflow_id: {}, version_id: {:?}",
+            workflow_id, version_id
+        );
+        //Super User Access
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        // Get flow version from database
+        let mut query = self
+            .client
+            .from("flow_versions")
+            .eq("flow_id", workflow_id.to_string());
+
+        // If version_id is provided, use it. Otherwise get published version
+        if let Some(version) = version_id {
+            query = query.eq("flow_version_id", version.to_string());
+        } else {
+            query = query.eq("published", "true");
+        }
+
+        let response = query
+            .auth(&supabase_service_role_api_key)
+            .select("*")
+            .single()
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute workflow definition request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        let response_body = response.text().await.map_err(|e| {
+            error!(
+                "[PROCESSOR DB CALLS] Failed to read workflow definition response: {}",
                 e
             );
-            format!("Failed to execute request: {}", e)
+            format!("Failed to read response body: {}", e)
         })?;
 
-    let response_body = response.text().await.map_err(|e| {
-        println!(
-            "[PROCESSOR DB CALLS] Failed to read workflow definition response: {}",
-            e
+        let workflow_version: DatabaseFlowVersion =
+            serde_json::from_str(&response_body).map_err(|e| {
+                error!("[PROCESSOR DB CALLS] No workflow version found: {}", e);
+                String::from("No workflow version found")
+            })?;
+
+        debug!("[PROCESSOR DB CALLS] Successfully retrieved workflow definition");
+        Ok(workflow_version)
+    }
+
+    async fn get_tasks_for_session(
+        &self,
+        flow_session_id: &Uuid, //UUID
+    ) -> Result<Vec<Task>, String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Fetching tasks for flow_session_id {}",
+            flow_session_id
         );
-        format!("Failed to read response body: {}", e)
-    })?;
 
-    let workflow_version: DatabaseFlowVersion =
-        serde_json::from_str(&response_body).map_err(|e| {
-            println!("[PROCESSOR DB CALLS] No workflow version found: {}", e);
-            String::from("No workflow version found")
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let response = self
+            .client
+            .from("tasks")
+            .auth(supabase_service_role_api_key)
+            .select("*")
+            .eq("flow_session_id", flow_session_id.to_string())
+            .order("processing_order.asc")
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute session tasks request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        let response_body = response.text().await.map_err(|e| {
+            error!(
+                "[PROCESSOR DB CALLS] Failed to read session tasks response: {}",
+                e
+            );
+            format!("Failed to read response body: {}", e)
         })?;
 
-    println!("[PROCESSOR DB CALLS] Successfully retrieved workflow definition");
-    Ok(workflow_version)
-}
+        let tasks: Vec<Task> = serde_json::from_str(&response_body).map_err(|e| {
+            error!("[PROCESSOR DB CALLS] Failed to parse tasks: {}", e);
+            format!("Failed to parse tasks: {}", e)
+        })?;
+
+        debug!(
+            "[PROCESSOR DB CALLS] Successfully retrieved {} tasks",
+            tasks.len()
+        );
+        Ok(tasks)
+    }
 
-pub async fn get_session_tasks(
-    state: Arc<AppState>,
-    flow_session_id: &Uuid, //UUID
-) -> Result<Vec<Task>, String> {
-    println!(
-        "[PROCESSOR DB CALLS] Fetching tasks for flow_session_id {}",
-        flow_session_id
-    );
+    async fn get_tasks(&self, task_ids: &[Uuid]) -> Result<Vec<Task>, String> {
+        debug!("[PROCESSOR DB CALLS] Fetching {} tasks", task_ids.len());
+        let supabase_service_role_api_key = service_role_api_key()?;
+
+        let response = self
+            .client
+            .from("tasks")
+            .auth(supabase_service_role_api_key)
+            .select("*")
+            .in_("task_id", task_ids.iter().map(Uuid::to_string))
+            .execute()
+            .await
+            .map_err(|e| format!("Failed to execute request: {}", e))?;
+        let response_body = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response body: {}", e))?;
+
+        serde_json::from_str(&response_body).map_err(|e| format!("Failed to parse tasks: {}", e))
+    }
 
-    dotenv().ok();
-    let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
-        .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
-
-    let response = state
-        .anything_client
-        .from("tasks")
-        .auth(supabase_service_role_api_key)
-        .select("*")
-        .eq("flow_session_id", flow_session_id.to_string())
-        .order("processing_order.asc")
-        .execute()
-        .await
-        .map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to execute session tasks request: {}",
+    async fn create_task(&self, task: &CreateTaskInput) -> Result<Task, String> {
+        debug!("[PROCESSOR DB CALLS] Creating new task");
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let response = self
+            .client
+            .from("tasks")
+            .auth(supabase_service_role_api_key)
+            .insert(
+                serde_json::to_value(task)
+                    .map_err(|e| {
+                        error!("[PROCESSOR DB CALLS] Failed to serialize task: {}", e);
+                        format!("Failed to serialize task: {}", e)
+                    })?
+                    .to_string(),
+            )
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute create task request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        let response_body = response.text().await.map_err(|e| {
+            error!(
+                "[PROCESSOR DB CALLS] Failed to read create task response: {}",
                 e
             );
-            format!("Failed to execute request: {}", e)
+            format!("Failed to read response body: {}", e)
         })?;
 
-    let response_body = response.text().await.map_err(|e| {
-        println!(
-            "[PROCESSOR DB CALLS] Failed to read session tasks response: {}",
-            e
-        );
-        format!("Failed to read response body: {}", e)
-    })?;
+        let tasks: Vec<Task> = serde_json::from_str(&response_body).map_err(|e| {
+            error!("[PROCESSOR DB CALLS] Failed to parse created task: {}", e);
+            format!("Failed to parse created task: {}", e)
+        })?;
 
-    let tasks: Vec<Task> = serde_json::from_str(&response_body).map_err(|e| {
-        println!("[PROCESSOR DB CALLS] Failed to parse tasks: {}", e);
-        format!("Failed to parse tasks: {}", e)
-    })?;
+        let task = tasks.into_iter().next().ok_or_else(|| {
+            error!("[PROCESSOR DB CALLS] No task was created");
+            "No task was created".to_string()
+        })?;
 
-    if tasks.is_empty() {
-        println!(
-            "[PROCESSOR DB CALLS] No tasks found for session {}",
-            flow_session_id
-        );
-        return Err("No tasks found for session".to_string());
+        debug!("[PROCESSOR DB CALLS] Successfully created task");
+        Ok(task)
     }
 
-    println!(
-        "[PROCESSOR DB CALLS] Successfully retrieved {} tasks",
-        tasks.len()
-    );
-    Ok(tasks)
-}
-
-pub async fn create_task(state: Arc<AppState>, task: &CreateTaskInput) -> Result<Task, String> {
-    println!("[PROCESSOR DB CALLS] Creating new task");
-    dotenv().ok();
-    let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
-        .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
-
-    let response = state
-        .anything_client
-        .from("tasks")
-        .auth(supabase_service_role_api_key)
-        .insert(
-            serde_json::to_value(task)
-                .map_err(|e| {
-                    println!("[PROCESSOR DB CALLS] Failed to serialize task: {}", e);
-                    format!("Failed to serialize task: {}", e)
-                })?
-                .to_string(),
-        )
-        .execute()
-        .await
-        .map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to execute create task request: {}",
+    async fn create_tasks_batch(&self, tasks: &[CreateTaskInput]) -> Result<Vec<Task>, String> {
+        debug!("[PROCESSOR DB CALLS] Creating {} tasks", tasks.len());
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let response = self
+            .client
+            .from("tasks")
+            .auth(supabase_service_role_api_key)
+            .insert(
+                serde_json::to_value(tasks)
+                    .map_err(|e| {
+                        error!("[PROCESSOR DB CALLS] Failed to serialize tasks: {}", e);
+                        format!("Failed to serialize tasks: {}", e)
+                    })?
+                    .to_string(),
+            )
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute create tasks request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        let response_body = response.text().await.map_err(|e| {
+            error!(
+                "[PROCESSOR DB CALLS] Failed to read create tasks response: {}",
                 e
             );
-            format!("Failed to execute request: {}", e)
+            format!("Failed to read response body: {}", e)
+        })?;
+
+        let created: Vec<Task> = serde_json::from_str(&response_body).map_err(|e| {
+            error!("[PROCESSOR DB CALLS] Failed to parse created tasks: {}", e);
+            format!("Failed to parse created tasks: {}", e)
         })?;
 
-    let response_body = response.text().await.map_err(|e| {
-        println!(
-            "[PROCESSOR DB CALLS] Failed to read create task response: {}",
-            e
+        if created.len() != tasks.len() {
+            return Err(format!(
+                "Expected {} tasks to be created but got {}",
+                tasks.len(),
+                created.len()
+            ));
+        }
+
+        debug!(
+            "[PROCESSOR DB CALLS] Successfully created {} tasks",
+            created.len()
         );
-        format!("Failed to read response body: {}", e)
-    })?;
+        Ok(created)
+    }
 
-    let tasks: Vec<Task> = serde_json::from_str(&response_body).map_err(|e| {
-        println!("[PROCESSOR DB CALLS] Failed to parse created task: {}", e);
-        format!("Failed to parse created task: {}", e)
-    })?;
+    //Send just the data we need. Safer to not update every key.
+    async fn update_task_status(
+        &self,
+        task_id: &Uuid,
+        status: &TaskStatus,
+        context: Option<Value>,
+        bundled_inputs: Option<Value>,
+        result: Option<Value>,
+        error: Option<Value>,
+    ) -> Result<(), String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Updating task {} status to {}",
+            task_id,
+            status.as_str()
+        );
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let started_at = if status.as_str() == TaskStatus::Running.as_str() {
+            Some(Utc::now())
+        } else {
+            None
+        };
+
+        let ended_at = if status.as_str() != TaskStatus::Running.as_str() {
+            Some(Utc::now())
+        } else {
+            None
+        };
+
+        //Remove sensitive headers from context
+        let cleaned_context = if let Some(context) = context {
+            Some(redact_headers_from_context(&context))
+        } else {
+            None
+        };
+
+        let input = UpdateTaskInput {
+            task_status: status.as_str().to_string(),
+            started_at,
+            ended_at,
+            result,
+            context: cleaned_context,
+            bundled_inputs,
+            error,
+        };
+
+        self.client
+            .from("tasks")
+            .auth(supabase_service_role_api_key)
+            .eq("task_id", &task_id.to_string())
+            .update(serde_json::to_string(&input).map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to serialize update input: {}",
+                    e
+                );
+                format!("Failed to serialize input: {}", e)
+            })?)
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute update task request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        debug!("[PROCESSOR DB CALLS] Successfully updated task status");
+        Ok(())
+    }
 
-    let task = tasks.into_iter().next().ok_or_else(|| {
-        println!("[PROCESSOR DB CALLS] No task was created");
-        "No task was created".to_string()
-    })?;
+    async fn update_flow_session_status(
+        &self,
+        flow_session_id: &Uuid,
+        flow_session_status: &FlowSessionStatus,
+        trigger_session_status: &TriggerSessionStatus,
+    ) -> Result<(), String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Updating flow session {} status to {} and trigger status to {}",
+            flow_session_id,
+            flow_session_status.as_str(),
+            trigger_session_status.as_str()
+        );
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let input = UpdateFlowSesssionInput {
+            flow_session_status: flow_session_status.as_str().to_string(),
+            trigger_session_status: trigger_session_status.as_str().to_string(),
+        };
+
+        self.client
+            .from("tasks")
+            .auth(supabase_service_role_api_key)
+            .eq("flow_session_id", &flow_session_id.to_string())
+            .update(serde_json::to_string(&input).map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to serialize update input: {}",
+                    e
+                );
+                format!("Failed to serialize input: {}", e)
+            })?)
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute update flow session request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        debug!("[PROCESSOR DB CALLS] Successfully updated flow session status");
+        Ok(())
+    }
 
-    println!("[PROCESSOR DB CALLS] Successfully created task");
-    Ok(task)
-}
+    async fn create_dead_letter(&self, dead_letter: &CreateDeadLetterInput) -> Result<(), String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Creating dead letter for flow session {}",
+            dead_letter.flow_session_id
+        );
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        self.client
+            .from("processor_dead_letters")
+            .auth(supabase_service_role_api_key)
+            .insert(serde_json::to_string(dead_letter).map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to serialize dead letter: {}",
+                    e
+                );
+                format!("Failed to serialize dead letter: {}", e)
+            })?)
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute create dead letter request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        debug!("[PROCESSOR DB CALLS] Successfully created dead letter");
+        Ok(())
+    }
 
-//Send just the data we need. Safer to not update every key.
-pub async fn update_task_status(
-    state: Arc<AppState>,
-    task_id: &Uuid,
-    status: &TaskStatus,
-    context: Option<Value>,
-    result: Option<Value>,
-    error: Option<Value>,
-) -> Result<(), String> {
-    println!(
-        "[PROCESSOR DB CALLS] Updating task {} status to {}",
-        task_id,
-        status.as_str()
-    );
-    dotenv().ok();
-    let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
-        .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
-
-    let started_at = if status.as_str() == TaskStatus::Running.as_str() {
-        Some(Utc::now())
-    } else {
-        None
-    };
-
-    let ended_at = if status.as_str() != TaskStatus::Running.as_str() {
-        Some(Utc::now())
-    } else {
-        None
-    };
-
-    //Remove sensitive headers from context
-    let cleaned_context = if let Some(context) = context {
-        Some(redact_headers_from_context(&context))
-    } else {
-        None
-    };
-
-    let input = UpdateTaskInput {
-        task_status: status.as_str().to_string(),
-        started_at,
-        ended_at,
-        result,
-        context: cleaned_context,
-        error,
-    };
-
-    state
-        .anything_client
-        .from("tasks")
-        .auth(supabase_service_role_api_key)
-        .eq("task_id", &task_id.to_string())
-        .update(serde_json::to_string(&input).map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to serialize update input: {}",
+    async fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, String> {
+        debug!("[PROCESSOR DB CALLS] Fetching dead letters");
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let response = self
+            .client
+            .from("processor_dead_letters")
+            .auth(supabase_service_role_api_key)
+            .select("*")
+            .order("created_at.desc")
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute dead letters request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        let response_body = response.text().await.map_err(|e| {
+            error!(
+                "[PROCESSOR DB CALLS] Failed to read dead letters response: {}",
                 e
             );
-            format!("Failed to serialize input: {}", e)
-        })?)
-        .execute()
-        .await
-        .map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to execute update task request: {}",
-                e
-            );
-            format!("Failed to execute request: {}", e)
+            format!("Failed to read response body: {}", e)
         })?;
 
-    println!("[PROCESSOR DB CALLS] Successfully updated task status");
-    Ok(())
-}
+        serde_json::from_str(&response_body).map_err(|e| {
+            error!("[PROCESSOR DB CALLS] Failed to parse dead letters: {}", e);
+            format!("Failed to parse dead letters: {}", e)
+        })
+    }
 
-pub async fn update_flow_session_status(
-    state: &AppState,
-    flow_session_id: &Uuid,
-    flow_session_status: &FlowSessionStatus,
-    trigger_session_status: &TriggerSessionStatus,
-) -> Result<(), String> {
-    println!(
-        "[PROCESSOR DB CALLS] Updating flow session {} status to {} and trigger status to {}",
-        flow_session_id,
-        flow_session_status.as_str(),
-        trigger_session_status.as_str()
-    );
-    dotenv().ok();
-    let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
-        .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
-
-    let input = UpdateFlowSesssionInput {
-        flow_session_status: flow_session_status.as_str().to_string(),
-        trigger_session_status: trigger_session_status.as_str().to_string(),
-    };
-
-    state
-        .anything_client
-        .from("tasks")
-        .auth(supabase_service_role_api_key)
-        .eq("flow_session_id", &flow_session_id.to_string())
-        .update(serde_json::to_string(&input).map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to serialize update input: {}",
-                e
-            );
-            format!("Failed to serialize input: {}", e)
-        })?)
-        .execute()
-        .await
-        .map_err(|e| {
-            println!(
-                "[PROCESSOR DB CALLS] Failed to execute update flow session request: {}",
+    async fn get_dead_letter(&self, dead_letter_id: &Uuid) -> Result<DeadLetter, String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Fetching dead letter {}",
+            dead_letter_id
+        );
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        let response = self
+            .client
+            .from("processor_dead_letters")
+            .auth(supabase_service_role_api_key)
+            .select("*")
+            .eq("dead_letter_id", dead_letter_id.to_string())
+            .single()
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute dead letter request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        let response_body = response.text().await.map_err(|e| {
+            error!(
+                "[PROCESSOR DB CALLS] Failed to read dead letter response: {}",
                 e
             );
-            format!("Failed to execute request: {}", e)
+            format!("Failed to read response body: {}", e)
         })?;
 
-    println!("[PROCESSOR DB CALLS] Successfully updated flow session status");
-    Ok(())
+        serde_json::from_str(&response_body).map_err(|e| {
+            error!("[PROCESSOR DB CALLS] No dead letter found: {}", e);
+            String::from("No dead letter found")
+        })
+    }
+
+    async fn delete_dead_letter(&self, dead_letter_id: &Uuid) -> Result<(), String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Deleting dead letter {}",
+            dead_letter_id
+        );
+        dotenv().ok();
+        let supabase_service_role_api_key = env::var("SUPABASE_SERVICE_ROLE_API_KEY")
+            .expect("SUPABASE_SERVICE_ROLE_API_KEY must be set");
+
+        self.client
+            .from("processor_dead_letters")
+            .auth(supabase_service_role_api_key)
+            .eq("dead_letter_id", dead_letter_id.to_string())
+            .delete()
+            .execute()
+            .await
+            .map_err(|e| {
+                error!(
+                    "[PROCESSOR DB CALLS] Failed to execute delete dead letter request: {}",
+                    e
+                );
+                format!("Failed to execute request: {}", e)
+            })?;
+
+        debug!("[PROCESSOR DB CALLS] Successfully deleted dead letter");
+        Ok(())
+    }
+
+    async fn create_large_result(
+        &self,
+        large_result: &CreateLargeResultInput,
+    ) -> Result<Uuid, String> {
+        debug!(
+            "[PROCESSOR DB CALLS] Storing {} byte result for task {}",
+            large_result.size_bytes, large_result.task_id
+        );
+        let supabase_service_role_api_key = service_role_api_key()?;
+        let body = serde_json::to_string(large_result)
+            .map_err(|e| format!("Failed to serialize large result: {}", e))?;
+
+        let response = self
+            .client
+            .from("task_large_results")
+            .auth(supabase_service_role_api_key)
+            .insert(body)
+            .execute()
+            .await
+            .map_err(|e| format!("Failed to execute request: {}", e))?;
+        let response_body = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response body: {}", e))?;
+
+        let created: Vec<LargeResult> = serde_json::from_str(&response_body)
+            .map_err(|e| format!("Failed to parse stored large result: {}", e))?;
+        created
+            .first()
+            .map(|large_result| large_result.result_id)
+            .ok_or_else(|| String::from("No large result was created"))
+    }
+
+    async fn get_large_result(&self, result_id: &Uuid) -> Result<Value, String> {
+        debug!("[PROCESSOR DB CALLS] Fetching large result {}", result_id);
+        let supabase_service_role_api_key = service_role_api_key()?;
+
+        let response = self
+            .client
+            .from("task_large_results")
+            .auth(supabase_service_role_api_key)
+            .eq("result_id", result_id.to_string())
+            .select("result_id,value")
+            .single()
+            .execute()
+            .await
+            .map_err(|e| format!("Failed to execute request: {}", e))?;
+        let response_body = response
+            .text()
+            .await
+            .map_err(|e| format!("Failed to read response body: {}", e))?;
+
+        let large_result: LargeResult = serde_json::from_str(&response_body)
+            .map_err(|e| format!("Failed to parse large result: {}", e))?;
+        Ok(large_result.value)
+    }
+}
+
+// An error instead of a panic, these run inside the spawned processor task
+fn service_role_api_key() -> Result<String, String> {
+    dotenv().ok();
+    env::var("SUPABASE_SERVICE_ROLE_API_KEY").map_err(|_| {
+        warn!("[PROCESSOR DB CALLS] SUPABASE_SERVICE_ROLE_API_KEY is not set");
+        String::from("SUPABASE_SERVICE_ROLE_API_KEY must be set")
+    })
 }
 
 pub fn redact_headers_from_context(context: &Value) -> Value {
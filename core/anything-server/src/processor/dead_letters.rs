@@ -0,0 +1,221 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::processor::processor::ProcessorMessage;
+use crate::processor::run_workflow::resolve_flow_session_waiter;
+use crate::types::task_types::FlowSessionStatus;
+use crate::AppState;
+
+// Messages the processor gave up on before it started running tasks, e.g. the workflow
+// couldn't be loaded or the trigger task couldn't be written. Nothing else records these
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeadLetter {
+    pub dead_letter_id: Uuid,
+    pub flow_session_id: Uuid,
+    pub workflow_id: Uuid,
+    pub message: ProcessorMessage,
+    pub error: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreateDeadLetterInput {
+    pub flow_session_id: Uuid,
+    pub workflow_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    pub message: ProcessorMessage,
+    pub error: String,
+}
+
+// Stores the message and tells anyone waiting on the session that it failed
+pub async fn dead_letter_message(state: &AppState, message: &ProcessorMessage, error: &str) {
+    warn!(
+        "[PROCESSOR] Dead lettering flow session {}: {}",
+        message.flow_session_id, error
+    );
+
+    let input = CreateDeadLetterInput {
+        flow_session_id: message.flow_session_id,
+        workflow_id: message.workflow_id,
+        account_id: message
+            .trigger_task
+            .as_ref()
+            .map(|task| task.account_id.clone()),
+        message: message.clone(),
+        error: error.to_string(),
+    };
+
+    if let Err(e) = state.task_store.create_dead_letter(&input).await {
+        error!(
+            "[PROCESSOR] Failed to dead letter flow session {}: {}",
+            message.flow_session_id, e
+        );
+    }
+
+    let mut completions = state.flow_completions.lock().await;
+    if let Some(completion) = completions.remove(&message.flow_session_id.to_string()) {
+        if completion.needs_response {
+            let _ = completion.sender.send(json!({ "error": error }));
+        }
+    }
+    drop(completions);
+
+    resolve_flow_session_waiter(
+        state,
+        &message.flow_session_id,
+        FlowSessionStatus::Failed,
+        Some(json!({ "error": error })),
+    )
+    .await;
+}
+
+// Newest first
+pub async fn list_dead_letters(state: Arc<AppState>) -> Result<Vec<DeadLetter>, String> {
+    state.task_store.get_dead_letters().await
+}
+
+// Sends the message to the processor again. The record is removed once it has been sent,
+// if the session fails before its first task again it is dead lettered again
+pub async fn replay_dead_letter(
+    state: Arc<AppState>,
+    dead_letter_id: &Uuid,
+) -> Result<ProcessorMessage, String> {
+    let dead_letter = state.task_store.get_dead_letter(dead_letter_id).await?;
+
+    info!(
+        "[PROCESSOR] Replaying dead lettered flow session {}",
+        dead_letter.flow_session_id
+    );
+
+    state
+        .processor_sender
+        .send(dead_letter.message.clone())
+        .await
+        .map_err(|e| format!("Failed to send message to processor: {}", e))?;
+
+    if let Err(e) = state.task_store.delete_dead_letter(dead_letter_id).await {
+        // Replaying it twice is better than losing it, so only warn
+        warn!(
+            "[PROCESSOR] Replayed dead letter {} but failed to remove it: {}",
+            dead_letter_id, e
+        );
+    }
+
+    Ok(dead_letter.message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store::{action, start_test_processor, task};
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_initial_create_task_failure_is_dead_lettered() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+
+        store.fail_create_task(true);
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Failed));
+
+        let dead_letters = list_dead_letters(state.clone()).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter = &dead_letters[0];
+        assert_eq!(dead_letter.flow_session_id, outcome.flow_session_id);
+        assert_eq!(dead_letter.workflow_id, workflow_id);
+        assert_eq!(dead_letter.message.version_id, Some(flow_version_id));
+        assert!(dead_letter.error.contains("create_task failed"));
+        assert_eq!(
+            outcome.output,
+            Some(json!({ "error": dead_letter.error.clone() }))
+        );
+
+        // Once the store is back the replayed session runs through and the record goes away
+        store.fail_create_task(false);
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(outcome.flow_session_id, sender);
+
+        let message = replay_dead_letter(state.clone(), &dead_letter.dead_letter_id)
+            .await
+            .unwrap();
+        assert_eq!(message.flow_session_id, outcome.flow_session_id);
+
+        let replayed = receiver.await.unwrap();
+        assert!(matches!(replayed.status, FlowSessionStatus::Completed));
+        assert!(list_dead_letters(state.clone()).await.unwrap().is_empty());
+        assert!(replay_dead_letter(state, &dead_letter.dead_letter_id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_workflow_is_dead_lettered() {
+        let (_, state, _, _) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+
+        let workflow_id = Uuid::new_v4();
+        let flow_session_id = Uuid::new_v4();
+        let trigger_session_id = Uuid::new_v4();
+        let trigger_task = task(
+            "webhook",
+            "trigger",
+            json!({
+                "flow_id": workflow_id,
+                "trigger_session_id": trigger_session_id,
+                "flow_session_id": flow_session_id,
+                "plugin_name": "@anything/webhook",
+                "plugin_version": "0.1.0",
+                "stage": "production"
+            }),
+        );
+        let message = ProcessorMessage {
+            workflow_id,
+            version_id: None,
+            flow_session_id,
+            trigger_session_id,
+            trigger_task: Some(trigger_task),
+            response: None,
+            deadline: None,
+        };
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(message.flow_session_id, sender);
+        state.processor_sender.send(message).await.unwrap();
+
+        let outcome = receiver.await.unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Failed));
+
+        let dead_letters = list_dead_letters(state).await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].workflow_id, workflow_id);
+        assert_eq!(
+            dead_letters[0].error,
+            format!(
+                "Failed to find a published version of workflow {}: No workflow version found",
+                workflow_id
+            )
+        );
+    }
+}
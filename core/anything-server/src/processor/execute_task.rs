@@ -1,92 +1,125 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use postgrest::Postgrest;
 
 use crate::bundler::bundle_tasks_cached_context;
+use crate::processor::parsing_utils::validate_task_result;
 use crate::processor::process_trigger_utils::process_trigger_task;
+use crate::processor::processor::flow_session_canceled;
+use crate::processor::task_middleware::{BundledInput, TaskMiddleware};
 use crate::system_plugins::formatter_actions::{
     date_formatter::process_date_task, text_formatter::process_text_task,
 };
+use crate::system_plugins::transform::process_transform_task;
 use crate::system_plugins::webhook_response::process_webhook_response_task;
 
 use crate::system_plugins::http::http_plugin::process_http_task;
 use crate::system_plugins::javascript::process_js_task;
-use crate::types::task_types::Task;
+use crate::types::json_schema::JsonSchema;
+use crate::types::task_types::{Stage, Task, TaskTestConfig};
 use crate::AppState;
 use crate::system_plugins::agent_tool_trigger_response::process_tool_call_result_task;
 use serde_json::{json, Value};
+use tracing::{debug, info};
+use uuid::Uuid;
 
-use crate::types::action_types::ActionType;
+use crate::types::action_types::{Action, ActionType};
 
 #[derive(Debug, Clone)]
 pub struct TaskError {
     pub error: Value,
     pub context: Value,
+    pub bundled_inputs: Option<Value>, // None when bundling itself failed
+    pub canceled: bool,                // The session was canceled while the task ran
 }
 
-pub type TaskResult = Result<(Option<Value>, Value), TaskError>;
+// (result, bundled inputs, bundled plugin config, skipped)
+pub type TaskResult = Result<(Option<Value>, Value, Value, bool), TaskError>;
 
-pub async fn execute_task(state: Arc<AppState>, client: &Postgrest, task: &Task) -> TaskResult {
-    println!("[PROCESS TASK] Processing task {}", task.task_id);
+// `action` is the task's action in the workflow, for Action::skip_on_empty and
+// Action::output_schema
+pub async fn execute_task(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    task: &Task,
+    action: Option<&Action>,
+) -> TaskResult {
+    info!("[PROCESS TASK] Processing task {}", task.task_id);
 
-    // Clone state before using it in join
-    let state_clone = Arc::clone(&state);
+    let middleware = state.task_middleware.read().await.clone();
+    let concurrency_key = action.and_then(|action| action.concurrency_key.as_deref());
+    let run = async {
+        // Held while the task bundles and runs so no other task with the key starts meanwhile
+        let _concurrency_guard = match concurrency_key {
+            Some(concurrency_key) => Some(state.concurrency_keys.lock(concurrency_key).await),
+            None => None,
+        };
+        bundle_and_execute_task(state.clone(), client, task, action, &middleware).await
+    };
+    // Canceling the session drops the task where it is, e.g. mid request or waiting on its
+    // concurrency key, instead of letting it finish first
+    let result = match Uuid::parse_str(&task.flow_session_id) {
+        Ok(flow_session_id) => tokio::select! {
+            result = run => result,
+            _ = flow_session_canceled(&state, &flow_session_id) => {
+                info!("[PROCESS TASK] Task {} canceled", task.task_id);
+                Err(TaskError {
+                    error: json!({ "message": "Flow session was canceled" }),
+                    context: json!({}),
+                    bundled_inputs: None,
+                    canceled: true,
+                })
+            }
+        },
+        Err(_) => run.await,
+    };
+    for middleware in &middleware {
+        middleware.after_execute(task, &result);
+    }
+    result
+}
 
+async fn bundle_and_execute_task(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    task: &Task,
+    action: Option<&Action>,
+    middleware: &[Arc<dyn TaskMiddleware>],
+) -> TaskResult {
     // Bundle context with results from cache
     let bundled_context_result: Result<(Value, Value), Box<dyn std::error::Error + Send + Sync>> =
-        bundle_tasks_cached_context(state, client, task, true).await;
-
-    let http_client = state_clone.http_client.clone();
+        bundle_tasks_cached_context(state.clone(), client, task, true).await;
 
     match bundled_context_result {
-        Ok((bundled_inputs, bundled_plugin_cofig)) => {
-            let task_result = if task.r#type == ActionType::Trigger.as_str().to_string() {
-                println!("[PROCESS TASK] Processing trigger task {}", task.task_id);
-                process_trigger_task(task)
-            } else {
-                println!("[PROCESS TASK] Processing regular task {}", task.task_id);
-                match &task.plugin_name {
-                    Some(plugin_name) => match plugin_name.as_str() {
-                        "@anything/http" => {
-                            process_http_task(&http_client, &bundled_plugin_cofig).await
-                        }
-                        //JS need bundled variables because variables are injected into the JS runtime vs tempalted into the string like we do other places.
-                        //Honestly not sure this is required vs templating the text but it feels safer even if this adds a anit pattern to task processing for JS.
-                        "@anything/javascript" => {
-                            process_js_task(&bundled_inputs, &bundled_plugin_cofig).await
-                        }
-                        "@anything/webhook_response" => {
-                            process_webhook_response_task(
-                                state_clone,
-                                task.flow_session_id.clone(),
-                                &bundled_plugin_cofig,
-                            )
-                            .await
-                        }
-                        "@anything/agent_tool_call_response" => {
-                            process_tool_call_result_task(
-                                state_clone,
-                                task.flow_session_id.clone(),
-                                &bundled_plugin_cofig,
-                            )
-                            .await
-                        }
-                        "@anything/format_text" => process_text_task(&bundled_plugin_cofig),
-                        "@anything/format_date" => process_date_task(&bundled_plugin_cofig),
-                        _ => {
-                            process_missing_plugin(plugin_name.as_str(), &task.task_id.to_string())
-                        }
-                    },
-                    None => process_no_plugin_name(&task.task_id.to_string()),
-                }
+        Ok((inputs, plugin_config)) => {
+            let mut input = BundledInput {
+                inputs,
+                plugin_config,
             };
+            for middleware in middleware {
+                middleware.before_execute(task, &mut input);
+            }
+            let BundledInput {
+                inputs: bundled_inputs,
+                plugin_config: bundled_plugin_cofig,
+            } = input;
 
-            match task_result {
-                Ok(result) => Ok((result, bundled_plugin_cofig)),
-                Err(e) => Err(TaskError {
-                    error: json!({ "message": e.to_string() }),
-                    context: bundled_plugin_cofig,
-                }),
+            // Checked after bundling so we never send an empty request to an external system
+            if let Some(path) = action.and_then(|action| action.skip_on_empty.as_deref()) {
+                if input_is_empty(&bundled_inputs, path) {
+                    info!(
+                        "[PROCESS TASK] Skipping task {}, input {} is empty",
+                        task.task_id, path
+                    );
+                    return Ok((None, bundled_inputs, bundled_plugin_cofig, true));
+                }
+            }
+            let result =
+                execute_task_with_bundle(state, task, bundled_inputs, bundled_plugin_cofig).await?;
+            match action.and_then(|action| action.output_schema.as_ref()) {
+                Some(output_schema) => check_output(result, output_schema),
+                None => Ok(result),
             }
         }
         Err(e) => {
@@ -95,11 +128,157 @@ pub async fn execute_task(state: Arc<AppState>, client: &Postgrest, task: &Task)
             Err(TaskError {
                 error: json!({ "message": format!("Failed to bundle task context: {}", e) }),
                 context: empty_context,
+                bundled_inputs: None,
+                canceled: false,
             })
         }
     }
 }
 
+// Re-runs a single task with the exact inputs and plugin config it was bundled with.
+// Upstream tasks are not re-run and nothing is re-bundled.
+// NOTE: tasks read back from the DB have their headers redacted (see redact_headers_from_context)
+// so replaying http tasks should use the copy from the flow session cache while it's still there.
+pub async fn replay_task(state: Arc<AppState>, task: &Task) -> TaskResult {
+    info!("[PROCESS TASK] Replaying task {}", task.task_id);
+
+    let bundled_plugin_cofig = task.context.clone().ok_or_else(|| TaskError {
+        error: json!({
+            "message": format!("Task {} has no stored context to replay", task.task_id)
+        }),
+        context: json!({}),
+        bundled_inputs: task.bundled_inputs.clone(),
+        canceled: false,
+    })?;
+    let bundled_inputs = task.bundled_inputs.clone().unwrap_or_else(|| json!({}));
+
+    execute_task_with_bundle(state, task, bundled_inputs, bundled_plugin_cofig).await
+}
+
+async fn execute_task_with_bundle(
+    state: Arc<AppState>,
+    task: &Task,
+    bundled_inputs: Value,
+    bundled_plugin_cofig: Value,
+) -> TaskResult {
+    let http_client = state.http_client.clone();
+
+    let task_result = if let Some(test_config) = get_mocked_test_config(task) {
+        info!(
+            "[PROCESS TASK] Using mocked result for task {}",
+            task.task_id
+        );
+        if let Some(delay) = test_config.mock_delay_ms {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        Ok(test_config.mock_result)
+    } else if task.r#type == ActionType::Trigger.as_str().to_string() {
+        debug!("[PROCESS TASK] Processing trigger task {}", task.task_id);
+        process_trigger_task(task)
+    } else {
+        debug!("[PROCESS TASK] Processing regular task {}", task.task_id);
+        // Only here, mocked and trigger tasks don't call anything external
+        if let Some(plugin_name) = &task.plugin_name {
+            state.plugin_rate_limiter.acquire(plugin_name).await;
+        }
+        match &task.plugin_name {
+            Some(plugin_name) => match plugin_name.as_str() {
+                "@anything/http" => process_http_task(&http_client, &bundled_plugin_cofig).await,
+                //JS need bundled variables because variables are injected into the JS runtime vs tempalted into the string like we do other places.
+                //Honestly not sure this is required vs templating the text but it feels safer even if this adds a anit pattern to task processing for JS.
+                "@anything/javascript" => {
+                    process_js_task(&bundled_inputs, &bundled_plugin_cofig).await
+                }
+                "@anything/webhook_response" => {
+                    process_webhook_response_task(
+                        state.clone(),
+                        task.flow_session_id.clone(),
+                        &bundled_plugin_cofig,
+                    )
+                    .await
+                }
+                "@anything/agent_tool_call_response" => {
+                    process_tool_call_result_task(
+                        state.clone(),
+                        task.flow_session_id.clone(),
+                        &bundled_plugin_cofig,
+                    )
+                    .await
+                }
+                "@anything/format_text" => process_text_task(&bundled_plugin_cofig),
+                "@anything/format_date" => process_date_task(&bundled_plugin_cofig),
+                "@anything/transform" => process_transform_task(&bundled_plugin_cofig),
+                _ => process_missing_plugin(plugin_name.as_str(), &task.task_id.to_string()),
+            },
+            None => process_no_plugin_name(&task.task_id.to_string()),
+        }
+    };
+
+    match task_result {
+        Ok(result) => Ok((result, bundled_inputs, bundled_plugin_cofig, false)),
+        Err(e) => Err(TaskError {
+            error: json!({ "message": e.to_string() }),
+            context: bundled_plugin_cofig,
+            bundled_inputs: Some(bundled_inputs),
+            canceled: false,
+        }),
+    }
+}
+
+// A result that doesn't match the action's output_schema fails the task, so the plugin breaking
+// its contract shows up here instead of in whatever reads the result later
+fn check_output(
+    result: (Option<Value>, Value, Value, bool),
+    output_schema: &JsonSchema,
+) -> TaskResult {
+    let (task_result, bundled_inputs, bundled_plugin_cofig, skipped) = result;
+    let problems =
+        validate_task_result(task_result.as_ref().unwrap_or(&Value::Null), output_schema);
+    if problems.is_empty() {
+        return Ok((task_result, bundled_inputs, bundled_plugin_cofig, skipped));
+    }
+
+    Err(TaskError {
+        error: json!({
+            "message": "Result does not match the action's output_schema",
+            "problems": problems,
+            "result": task_result
+        }),
+        context: bundled_plugin_cofig,
+        bundled_inputs: Some(bundled_inputs),
+        canceled: false,
+    })
+}
+
+// Missing paths and null count as empty along with empty arrays and objects
+fn input_is_empty(inputs: &Value, path: &str) -> bool {
+    let value = path.split('.').try_fold(inputs, |value, key| match value {
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|index| items.get(index)),
+        _ => value.get(key),
+    });
+
+    match value {
+        None | Some(Value::Null) => true,
+        Some(Value::Array(items)) => items.is_empty(),
+        Some(Value::Object(fields)) => fields.is_empty(),
+        Some(_) => false,
+    }
+}
+
+// Test runs can mock a task's output so authors can check wiring without calling external systems.
+// The mock is stored like any other result so downstream actions can reference it
+fn get_mocked_test_config(task: &Task) -> Option<TaskTestConfig> {
+    if !matches!(task.stage, Stage::Testing) {
+        return None;
+    }
+
+    let test_config: TaskTestConfig = serde_json::from_value(task.test_config.clone()?).ok()?;
+    if test_config.mock_result.is_none() {
+        return None;
+    }
+    Some(test_config)
+}
+
 pub fn process_missing_plugin(
     plugin_id: &str,
     task_id: &str,
@@ -116,3 +295,28 @@ pub fn process_no_plugin_name(
         "message": format!("Processed task {} :: no plugin_id found.", task_id)
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_is_empty() {
+        let inputs = json!({
+            "items": [],
+            "body": { "records": [{ "id": 1 }] },
+            "filters": {},
+            "cursor": null
+        });
+
+        assert!(input_is_empty(&inputs, "items"));
+        assert!(input_is_empty(&inputs, "filters"));
+        assert!(input_is_empty(&inputs, "cursor"));
+        assert!(input_is_empty(&inputs, "missing"));
+
+        assert!(!input_is_empty(&inputs, "body"));
+        assert!(!input_is_empty(&inputs, "body.records"));
+        assert!(!input_is_empty(&inputs, "body.records.0"));
+        assert!(input_is_empty(&inputs, "body.records.1"));
+    }
+}
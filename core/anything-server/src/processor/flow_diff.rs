@@ -0,0 +1,218 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap};
+
+use crate::types::{
+    action_types::Action, react_flow_types::Edge, workflow_types::WorkflowVersionDefinition,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String, // Top level Action field, e.g. "label" or "inputs"
+    pub from: Option<Value>,
+    pub to: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModifiedAction {
+    pub action_id: String,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowDiff {
+    pub added_actions: Vec<Action>,
+    pub removed_actions: Vec<Action>,
+    pub modified_actions: Vec<ModifiedAction>,
+    pub added_edges: Vec<Edge>,
+    pub removed_edges: Vec<Edge>,
+}
+
+impl FlowDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_actions.is_empty()
+            && self.removed_actions.is_empty()
+            && self.modified_actions.is_empty()
+            && self.added_edges.is_empty()
+            && self.removed_edges.is_empty()
+    }
+}
+
+// What changed going from one version to the other. Actions are matched by action_id, edges
+// only match when everything about them is the same, so a changed edge is removed and added
+pub fn diff_flow_versions(
+    from: &WorkflowVersionDefinition,
+    to: &WorkflowVersionDefinition,
+) -> FlowDiff {
+    let from_actions: HashMap<&str, &Action> = from
+        .actions
+        .iter()
+        .map(|action| (action.action_id.as_str(), action))
+        .collect();
+    let to_actions: HashMap<&str, &Action> = to
+        .actions
+        .iter()
+        .map(|action| (action.action_id.as_str(), action))
+        .collect();
+
+    let mut modified_actions = Vec::new();
+    for action in &to.actions {
+        if let Some(previous) = from_actions.get(action.action_id.as_str()) {
+            let changes = diff_fields(&as_object(previous), &as_object(action));
+            if !changes.is_empty() {
+                modified_actions.push(ModifiedAction {
+                    action_id: action.action_id.clone(),
+                    changes,
+                });
+            }
+        }
+    }
+
+    FlowDiff {
+        added_actions: to
+            .actions
+            .iter()
+            .filter(|action| !from_actions.contains_key(action.action_id.as_str()))
+            .cloned()
+            .collect(),
+        removed_actions: from
+            .actions
+            .iter()
+            .filter(|action| !to_actions.contains_key(action.action_id.as_str()))
+            .cloned()
+            .collect(),
+        modified_actions,
+        added_edges: missing_edges(&to.edges, &from.edges),
+        removed_edges: missing_edges(&from.edges, &to.edges),
+    }
+}
+
+fn as_object(action: &Action) -> Map<String, Value> {
+    match serde_json::to_value(action) {
+        Ok(Value::Object(fields)) => fields,
+        _ => Map::new(),
+    }
+}
+
+fn diff_fields(from: &Map<String, Value>, to: &Map<String, Value>) -> Vec<FieldChange> {
+    // Sorted so the diff reads the same every time
+    let fields: BTreeSet<&String> = from.keys().chain(to.keys()).collect();
+    fields
+        .into_iter()
+        .filter(|field| from.get(*field) != to.get(*field))
+        .map(|field| FieldChange {
+            field: field.clone(),
+            from: from.get(field).cloned(),
+            to: to.get(field).cloned(),
+        })
+        .collect()
+}
+
+// Edges in `edges` that `other` doesn't have
+fn missing_edges(edges: &[Edge], other: &[Edge]) -> Vec<Edge> {
+    let other: Vec<Value> = other
+        .iter()
+        .filter_map(|edge| serde_json::to_value(edge).ok())
+        .collect();
+    edges
+        .iter()
+        .filter(|edge| {
+            serde_json::to_value(edge)
+                .map(|edge| !other.contains(&edge))
+                .unwrap_or(true)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store::{action, edge};
+    use serde_json::json;
+
+    fn workflow(actions: Vec<Value>, edges: Vec<Value>) -> WorkflowVersionDefinition {
+        serde_json::from_value(json!({ "actions": actions, "edges": edges })).unwrap()
+    }
+
+    #[test]
+    fn test_unchanged_versions_have_an_empty_diff() {
+        let version = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", None),
+            ],
+            vec![edge("webhook", "http")],
+        );
+        assert!(diff_flow_versions(&version, &version.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_added_action() {
+        let from = workflow(vec![action("webhook", "trigger", None)], vec![]);
+        let to = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", None),
+            ],
+            vec![edge("webhook", "http")],
+        );
+
+        let diff = diff_flow_versions(&from, &to);
+        assert_eq!(diff.added_actions.len(), 1);
+        assert_eq!(diff.added_actions[0].action_id, "http");
+        assert!(diff.removed_actions.is_empty());
+        assert!(diff.modified_actions.is_empty());
+        assert_eq!(diff.added_edges.len(), 1);
+        assert_eq!(diff.added_edges[0].target, "http");
+        assert!(diff.removed_edges.is_empty());
+    }
+
+    #[test]
+    fn test_removed_edge() {
+        let actions = || {
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", None),
+                action("slack", "action", None),
+            ]
+        };
+        let from = workflow(
+            actions(),
+            vec![edge("webhook", "http"), edge("http", "slack")],
+        );
+        let to = workflow(actions(), vec![edge("webhook", "http")]);
+
+        let diff = diff_flow_versions(&from, &to);
+        assert!(diff.added_edges.is_empty());
+        assert_eq!(diff.removed_edges.len(), 1);
+        assert_eq!(diff.removed_edges[0].source, "http");
+        assert_eq!(diff.removed_edges[0].target, "slack");
+        assert!(diff.added_actions.is_empty());
+        assert!(diff.removed_actions.is_empty());
+        assert!(diff.modified_actions.is_empty());
+    }
+
+    #[test]
+    fn test_changed_action_label() {
+        let from = workflow(vec![action("webhook", "trigger", None)], vec![]);
+        let mut renamed = action("webhook", "trigger", None);
+        renamed["label"] = json!("Incoming order");
+        let to = workflow(vec![renamed], vec![]);
+
+        let diff = diff_flow_versions(&from, &to);
+        assert_eq!(
+            diff.modified_actions,
+            vec![ModifiedAction {
+                action_id: "webhook".to_string(),
+                changes: vec![FieldChange {
+                    field: "label".to_string(),
+                    from: Some(json!(from.actions[0].label)),
+                    to: Some(json!("Incoming order")),
+                }],
+            }]
+        );
+        assert!(diff.added_actions.is_empty());
+        assert!(diff.removed_actions.is_empty());
+    }
+}
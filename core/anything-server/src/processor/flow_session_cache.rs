@@ -1,30 +1,155 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 use std::time::{Duration, SystemTime};
 use tracing::debug;
 use uuid::Uuid;
 
-use crate::types::task_types::Task;
+use crate::types::task_types::{Task, TaskStatus};
 use crate::types::workflow_types::DatabaseFlowVersion;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// Tasks are only changed through insert_task and remove_task so the action index stays in sync
+#[derive(Clone, Debug)]
 pub struct FlowSessionData {
     pub workflow: Option<DatabaseFlowVersion>,
-    pub tasks: HashMap<Uuid, Task>, // task_id -> task
+    tasks: HashMap<Uuid, Task>,          // task_id -> task
+    action_index: HashMap<String, Uuid>, // action_id -> task_id of its latest task
     pub flow_session_id: Uuid,
     pub workflow_id: Uuid,
     pub workflow_version_id: Option<Uuid>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl FlowSessionData {
+    pub fn new(
+        workflow: Option<DatabaseFlowVersion>,
+        flow_session_id: Uuid,
+        workflow_id: Uuid,
+        workflow_version_id: Option<Uuid>,
+    ) -> Self {
+        Self {
+            workflow,
+            tasks: HashMap::new(),
+            action_index: HashMap::new(),
+            flow_session_id,
+            workflow_id,
+            workflow_version_id,
+        }
+    }
+
+    pub fn tasks(&self) -> &HashMap<Uuid, Task> {
+        &self.tasks
+    }
+
+    pub fn into_tasks(self) -> HashMap<Uuid, Task> {
+        self.tasks
+    }
+
+    pub fn insert_task(&mut self, task: Task) {
+        // If an action ran more than once the index points at the run with the highest processing order
+        let is_latest = match self.get_task_by_action_id(&task.action_id) {
+            Some(existing) => {
+                existing.task_id == task.task_id
+                    || existing.processing_order <= task.processing_order
+            }
+            None => true,
+        };
+        if is_latest {
+            self.action_index
+                .insert(task.action_id.clone(), task.task_id);
+        }
+        self.tasks.insert(task.task_id, task);
+    }
+
+    pub fn remove_task(&mut self, task_id: &Uuid) -> Option<Task> {
+        let task = self.tasks.remove(task_id)?;
+        if self.action_index.get(&task.action_id) == Some(task_id) {
+            self.action_index.remove(&task.action_id);
+            // Fall back to an earlier run of the same action if there is one
+            if let Some(previous) = self
+                .tasks
+                .values()
+                .filter(|other| other.action_id == task.action_id)
+                .max_by_key(|other| other.processing_order)
+            {
+                self.action_index
+                    .insert(task.action_id.clone(), previous.task_id);
+            }
+        }
+        Some(task)
+    }
+
+    pub fn get_task_by_action_id(&self, action_id: &str) -> Option<&Task> {
+        self.action_index
+            .get(action_id)
+            .and_then(|task_id| self.tasks.get(task_id))
+    }
+
+    pub fn is_action_completed(&self, action_id: &str) -> bool {
+        self.get_task_by_action_id(action_id)
+            .map_or(false, |task| task.task_status == TaskStatus::Completed)
+    }
+
+    pub fn get_result(&self, action_id: &str) -> Option<&Value> {
+        self.get_task_by_action_id(action_id)?.result.as_ref()
+    }
+
+    // Time spent running tasks, summed over every task that has ended
+    pub fn total_duration_ms(&self) -> i64 {
+        self.tasks.values().filter_map(Task::duration_ms).sum()
+    }
+
+    // The whole session as JSON, e.g. to store it or look at it offline. from_snapshot reads it
+    // back as it was
+    pub fn to_snapshot(&self) -> Value {
+        let mut tasks: Vec<Task> = self.tasks.values().cloned().collect();
+        tasks.sort_by_key(|task| task.processing_order);
+        let snapshot = FlowSessionSnapshot {
+            flow_session_id: self.flow_session_id,
+            workflow_id: self.workflow_id,
+            workflow_version_id: self.workflow_version_id,
+            workflow: self.workflow.clone(),
+            tasks,
+        };
+        serde_json::to_value(snapshot).unwrap_or(Value::Null)
+    }
+
+    pub fn from_snapshot(snapshot: Value) -> Result<Self, String> {
+        let snapshot: FlowSessionSnapshot = serde_json::from_value(snapshot)
+            .map_err(|e| format!("Invalid flow session snapshot: {}", e))?;
+        let mut session = Self::new(
+            snapshot.workflow,
+            snapshot.flow_session_id,
+            snapshot.workflow_id,
+            snapshot.workflow_version_id,
+        );
+        for task in snapshot.tasks {
+            session.insert_task(task);
+        }
+        Ok(session)
+    }
+}
+
+// What to_snapshot writes. The action index isn't part of it, it's rebuilt from the tasks
+#[derive(Deserialize, Serialize)]
+struct FlowSessionSnapshot {
+    flow_session_id: Uuid,
+    workflow_id: Uuid,
+    workflow_version_id: Option<Uuid>,
+    workflow: Option<DatabaseFlowVersion>,
+    tasks: Vec<Task>, // By processing order
+}
+
+#[derive(Clone, Debug)]
 struct CachedSession {
     data: FlowSessionData,
     expires_at: SystemTime,
+    version: u64, // Bumped on every write so a stale copy can't be written back over newer tasks
 }
 
 pub struct FlowSessionCache {
     cache: HashMap<Uuid, CachedSession>, // flow_session_id -> session data
     ttl: Duration,
+    max_result_bytes: usize, // Bigger results are truncated by the processor, 0 keeps them whole
 }
 
 impl FlowSessionCache {
@@ -36,65 +161,110 @@ impl FlowSessionCache {
         Self {
             cache: HashMap::new(),
             ttl,
+            max_result_bytes: 0,
         }
     }
 
+    pub fn set_max_result_bytes(&mut self, max_result_bytes: usize) {
+        self.max_result_bytes = max_result_bytes;
+    }
+
+    pub fn max_result_bytes(&self) -> usize {
+        self.max_result_bytes
+    }
+
     pub fn get(&self, flow_session_id: &Uuid) -> Option<FlowSessionData> {
-        self.cache.get(flow_session_id).and_then(|entry| {
-            let now = SystemTime::now();
-            if entry.expires_at > now {
-                Some(entry.data.clone())
-            } else {
-                None
-            }
-        })
+        self.live_session(flow_session_id)
+            .map(|entry| entry.data.clone())
     }
 
+    pub fn get_with_version(&self, flow_session_id: &Uuid) -> Option<(FlowSessionData, u64)> {
+        self.live_session(flow_session_id)
+            .map(|entry| (entry.data.clone(), entry.version))
+    }
+
+    // Replaces the whole session, whatever was there. Only for starting a session, anything
+    // built from a copy of an existing session should go through compare_and_set
     pub fn set(&mut self, flow_session_id: &Uuid, data: FlowSessionData) {
         println!(
             "[PROCESSOR] Setting flow session cache for session_id: {}",
             flow_session_id
         );
         let expires_at = SystemTime::now() + self.ttl;
-        let cached_session = CachedSession { data, expires_at };
+        let version = self
+            .cache
+            .get(flow_session_id)
+            .map_or(0, |entry| entry.version + 1);
+        let cached_session = CachedSession {
+            data,
+            expires_at,
+            version,
+        };
         self.cache.insert(*flow_session_id, cached_session);
     }
 
-    pub fn add_task(&mut self, flow_session_id: &Uuid, task: Task) -> bool {
-        if let Some(cached_session) = self.cache.get_mut(flow_session_id) {
-            if SystemTime::now() > cached_session.expires_at {
-                return false;
+    // Writes back a copy from get_with_version only if nothing else wrote to the session since
+    pub fn compare_and_set(
+        &mut self,
+        flow_session_id: &Uuid,
+        expected_version: u64,
+        data: FlowSessionData,
+    ) -> bool {
+        match self.live_session_mut(flow_session_id) {
+            Some(cached_session) if cached_session.version == expected_version => {
+                cached_session.data = data;
+                cached_session.version += 1;
+                true
             }
-            cached_session.data.tasks.insert(task.task_id, task);
-            true
-        } else {
-            false
+            _ => false,
         }
     }
 
-    pub fn update_task(&mut self, flow_session_id: &Uuid, task: Task) -> bool {
-        if let Some(cached_session) = self.cache.get_mut(flow_session_id) {
-            if SystemTime::now() > cached_session.expires_at {
-                return false;
+    // Tasks are written into the cached session in place so concurrent writers to
+    // different tasks of one session never overwrite each other. Fails if the session
+    // isn't cached anymore, e.g. it expired, since the task would be silently dropped
+    pub fn add_task(&mut self, flow_session_id: &Uuid, task: Task) -> Result<(), String> {
+        self.update_task(flow_session_id, task)
+    }
+
+    pub fn update_task(&mut self, flow_session_id: &Uuid, task: Task) -> Result<(), String> {
+        match self.live_session_mut(flow_session_id) {
+            Some(cached_session) => {
+                cached_session.data.insert_task(task);
+                cached_session.version += 1;
+                Ok(())
             }
-            cached_session.data.tasks.insert(task.task_id, task);
-            true
-        } else {
-            false
+            None => Err(format!(
+                "Flow session {} is not in the cache",
+                flow_session_id
+            )),
         }
     }
 
     pub fn remove_task(&mut self, flow_session_id: &Uuid, task_id: &Uuid) -> bool {
-        if let Some(cached_session) = self.cache.get_mut(flow_session_id) {
-            if SystemTime::now() > cached_session.expires_at {
-                return false;
+        if let Some(cached_session) = self.live_session_mut(flow_session_id) {
+            let removed = cached_session.data.remove_task(task_id).is_some();
+            if removed {
+                cached_session.version += 1;
             }
-            cached_session.data.tasks.remove(task_id).is_some()
+            removed
         } else {
             false
         }
     }
 
+    fn live_session(&self, flow_session_id: &Uuid) -> Option<&CachedSession> {
+        self.cache
+            .get(flow_session_id)
+            .filter(|entry| entry.expires_at > SystemTime::now())
+    }
+
+    fn live_session_mut(&mut self, flow_session_id: &Uuid) -> Option<&mut CachedSession> {
+        self.cache
+            .get_mut(flow_session_id)
+            .filter(|entry| entry.expires_at > SystemTime::now())
+    }
+
     pub fn invalidate(&mut self, flow_session_id: &Uuid) {
         println!(
             "[PROCESSOR] Invalidating flow session cache for session_id: {}",
@@ -109,3 +279,184 @@ impl FlowSessionCache {
         self.cache.retain(|_, session| session.expires_at > now);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn task(action_id: &str, task_status: &str, processing_order: i32, result: Value) -> Task {
+        in_memory_task_store::task(
+            action_id,
+            "action",
+            json!({
+                "task_status": task_status,
+                "processing_order": processing_order,
+                "result": result
+            }),
+        )
+    }
+
+    #[test]
+    fn test_task_accessors() {
+        let mut session = FlowSessionData::new(None, Uuid::new_v4(), Uuid::new_v4(), None);
+        session.insert_task(task("webhook", "completed", 0, json!({ "body": {} })));
+        session.insert_task(task("http", "running", 1, Value::Null));
+
+        assert_eq!(
+            session.get_task_by_action_id("webhook").unwrap().action_id,
+            "webhook"
+        );
+        assert!(session.is_action_completed("webhook"));
+        assert!(!session.is_action_completed("http"));
+        assert_eq!(session.get_result("webhook"), Some(&json!({ "body": {} })));
+        assert_eq!(session.get_result("http"), None);
+
+        assert!(session.get_task_by_action_id("missing").is_none());
+        assert!(!session.is_action_completed("missing"));
+        assert_eq!(session.get_result("missing"), None);
+    }
+
+    #[test]
+    fn test_index_follows_latest_run_of_an_action() {
+        let mut session = FlowSessionData::new(None, Uuid::new_v4(), Uuid::new_v4(), None);
+        let first = task("http", "completed", 1, json!({ "run": 1 }));
+        let second = task("http", "completed", 2, json!({ "run": 2 }));
+        let second_id = second.task_id;
+
+        session.insert_task(second);
+        session.insert_task(first.clone());
+        assert_eq!(session.get_result("http"), Some(&json!({ "run": 2 })));
+
+        // Updating a task in place keeps it indexed
+        let mut updated = first.clone();
+        updated.task_status = TaskStatus::Failed;
+        session.insert_task(updated);
+        assert_eq!(session.get_result("http"), Some(&json!({ "run": 2 })));
+
+        session.remove_task(&second_id);
+        assert_eq!(session.get_result("http"), Some(&json!({ "run": 1 })));
+        session.remove_task(&first.task_id);
+        assert!(session.get_task_by_action_id("http").is_none());
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut session =
+            FlowSessionData::new(None, Uuid::new_v4(), Uuid::new_v4(), Some(Uuid::new_v4()));
+        let mut webhook = task("webhook", "completed", 0, json!({ "body": { "id": 7 } }));
+        let mut http = task("http", "completed", 1, json!({ "status": 200 }));
+        webhook.started_at = Some("2024-05-01T10:00:00.123456Z".parse().unwrap());
+        webhook.ended_at = Some("2024-05-01T10:00:00.250Z".parse().unwrap());
+        http.started_at = webhook.ended_at;
+        http.ended_at = Some("2024-05-01T10:00:01.5Z".parse().unwrap());
+        session.insert_task(http.clone());
+        session.insert_task(webhook.clone());
+
+        let snapshot = session.to_snapshot();
+        assert_eq!(snapshot["tasks"][0]["action_id"], "webhook");
+        assert_eq!(snapshot["tasks"][1]["action_id"], "http");
+
+        let restored = FlowSessionData::from_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(restored.flow_session_id, session.flow_session_id);
+        assert_eq!(restored.workflow_id, session.workflow_id);
+        assert_eq!(restored.workflow_version_id, session.workflow_version_id);
+        assert_eq!(restored.tasks().len(), 2);
+        assert_eq!(
+            restored.get_result("webhook"),
+            Some(&json!({ "body": { "id": 7 } }))
+        );
+        assert!(restored.is_action_completed("http"));
+        let restored_http = restored.get_task_by_action_id("http").unwrap();
+        assert_eq!(restored_http.task_id, http.task_id);
+        assert_eq!(restored_http.started_at, http.started_at);
+        assert_eq!(restored_http.ended_at, http.ended_at);
+        assert_eq!(
+            restored
+                .get_task_by_action_id("webhook")
+                .unwrap()
+                .started_at,
+            webhook.started_at
+        );
+        assert_eq!(restored.total_duration_ms(), session.total_duration_ms());
+        assert_eq!(restored.to_snapshot(), snapshot);
+
+        assert!(FlowSessionData::from_snapshot(json!({ "tasks": [] }))
+            .unwrap_err()
+            .starts_with("Invalid flow session snapshot"));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_task_completions_both_survive() {
+        let flow_session_id = Uuid::new_v4();
+        let cache = Arc::new(RwLock::new(FlowSessionCache::new(Duration::from_secs(60))));
+
+        let left = task("left", "running", 1, Value::Null);
+        let right = task("right", "running", 1, Value::Null);
+        let mut session = FlowSessionData::new(None, flow_session_id, Uuid::new_v4(), None);
+        session.insert_task(left.clone());
+        session.insert_task(right.clone());
+        cache.write().await.set(&flow_session_id, session);
+
+        // Both branches took their copy of the task before either finished
+        let complete = |mut task: Task, result: Value| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                tokio::task::yield_now().await;
+                task.task_status = TaskStatus::Completed;
+                task.result = Some(result);
+                cache.write().await.update_task(&flow_session_id, task)
+            })
+        };
+        let (left_done, right_done) = tokio::join!(
+            complete(left, json!({ "branch": "left" })),
+            complete(right, json!({ "branch": "right" }))
+        );
+        assert!(left_done.unwrap().is_ok() && right_done.unwrap().is_ok());
+
+        let session = cache.read().await.get(&flow_session_id).unwrap();
+        assert_eq!(
+            session.get_result("left"),
+            Some(&json!({ "branch": "left" }))
+        );
+        assert_eq!(
+            session.get_result("right"),
+            Some(&json!({ "branch": "right" }))
+        );
+        assert!(session.is_action_completed("left") && session.is_action_completed("right"));
+    }
+
+    #[test]
+    fn test_compare_and_set_rejects_stale_copy() {
+        let flow_session_id = Uuid::new_v4();
+        let mut cache = FlowSessionCache::new(Duration::from_secs(60));
+        cache.set(
+            &flow_session_id,
+            FlowSessionData::new(None, flow_session_id, Uuid::new_v4(), None),
+        );
+
+        let (mut stale, version) = cache.get_with_version(&flow_session_id).unwrap();
+        assert!(cache
+            .add_task(&flow_session_id, task("left", "completed", 1, json!(1)))
+            .is_ok());
+        assert!(cache
+            .add_task(&Uuid::new_v4(), task("left", "completed", 1, json!(1)))
+            .is_err());
+
+        // Writing the old copy back would drop the task added in between
+        stale.insert_task(task("right", "completed", 1, json!(2)));
+        assert!(!cache.compare_and_set(&flow_session_id, version, stale));
+
+        let (mut fresh, version) = cache.get_with_version(&flow_session_id).unwrap();
+        fresh.insert_task(task("right", "completed", 1, json!(2)));
+        assert!(cache.compare_and_set(&flow_session_id, version, fresh));
+
+        let session = cache.get(&flow_session_id).unwrap();
+        assert_eq!(session.get_result("left"), Some(&json!(1)));
+        assert_eq!(session.get_result("right"), Some(&json!(2)));
+        assert!(!cache.compare_and_set(&Uuid::new_v4(), 0, session));
+    }
+}
@@ -1,7 +1,6 @@
 use crate::{
     processor::{
-        create_workflow_graph, db_calls::update_flow_session_status,
-        flow_session_cache::FlowSessionData, processor::ProcessorMessage,
+        create_workflow_graph, flow_session_cache::FlowSessionData, processor::ProcessorMessage,
     },
     types::{
         task_types::{FlowSessionStatus, Task, TaskStatus, TriggerSessionStatus},
@@ -124,21 +123,22 @@ pub async fn hydrate_processor(state: Arc<AppState>) {
                                 }
                             );
                             //THis is basically cleanup. this should not happen often but if it does this will "cure" it
-                            if let Err(e) = update_flow_session_status(
-                                &state,
-                                &Uuid::parse_str(&session_id).unwrap(),
-                                if workflow_failed {
-                                    &FlowSessionStatus::Failed
-                                } else {
-                                    &FlowSessionStatus::Completed
-                                },
-                                if workflow_failed {
-                                    &TriggerSessionStatus::Failed
-                                } else {
-                                    &TriggerSessionStatus::Completed
-                                },
-                            )
-                            .await
+                            if let Err(e) = state
+                                .task_store
+                                .update_flow_session_status(
+                                    &Uuid::parse_str(&session_id).unwrap(),
+                                    if workflow_failed {
+                                        &FlowSessionStatus::Failed
+                                    } else {
+                                        &FlowSessionStatus::Completed
+                                    },
+                                    if workflow_failed {
+                                        &TriggerSessionStatus::Failed
+                                    } else {
+                                        &TriggerSessionStatus::Completed
+                                    },
+                                )
+                                .await
                             {
                                 println!(
                                     "[HYDRATE PROCESSOR] Failed to update flow session status: {}",
@@ -156,13 +156,15 @@ pub async fn hydrate_processor(state: Arc<AppState>) {
                     }
 
                     //Put workflow in the cache
-                    let flow_session_data = FlowSessionData {
-                        workflow: workflow_def.clone(),
-                        tasks: session_tasks.into_iter().map(|t| (t.task_id, t)).collect(),
-                        flow_session_id: Uuid::parse_str(&session_id).unwrap(),
-                        workflow_id: workflow_def.clone().unwrap().flow_id,
-                        workflow_version_id: Some(flow_version_id),
-                    };
+                    let mut flow_session_data = FlowSessionData::new(
+                        workflow_def.clone(),
+                        Uuid::parse_str(&session_id).unwrap(),
+                        workflow_def.clone().unwrap().flow_id,
+                        Some(flow_version_id),
+                    );
+                    for task in session_tasks {
+                        flow_session_data.insert_task(task);
+                    }
 
                     println!("[HYDRATE PROCESSOR] Setting flow session data in cache");
                     // Set the flow session data in cache
@@ -178,6 +180,8 @@ pub async fn hydrate_processor(state: Arc<AppState>) {
                         flow_session_id: Uuid::parse_str(&session_id).unwrap(),
                         trigger_session_id: Uuid::parse_str(&trigger_session_id).unwrap(),
                         trigger_task: None,
+                        response: None,
+                        deadline: None,
                     };
 
                     if let Err(e) = state.processor_sender.send(processor_message).await {
@@ -0,0 +1,483 @@
+use axum::async_trait;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch, Mutex, Notify, RwLock, Semaphore};
+use uuid::Uuid;
+
+use crate::auth::init::AccountAuthProviderAccount;
+use crate::bundler::{
+    accounts::accounts_cache::AccountsCache,
+    bundle_cache::BundleCache,
+    config::InMemoryConfigSource,
+    secrets::{secrets_cache::SecretsCache, InMemorySecretProvider, SecretProvider},
+};
+use crate::processor::concurrency_keys::ConcurrencyKeys;
+use crate::processor::db_calls::{redact_headers_from_context, TaskStore};
+use crate::processor::dead_letters::{CreateDeadLetterInput, DeadLetter};
+use crate::processor::flow_session_cache::FlowSessionCache;
+use crate::processor::large_results::CreateLargeResultInput;
+use crate::processor::processor::processor;
+use crate::processor::rate_limiter::PluginRateLimiter;
+use crate::processor::write_retries::WriteRetryQueue;
+use crate::templater::TemplateCache;
+use crate::types::{
+    task_types::{CreateTaskInput, FlowSessionStatus, Task, TaskStatus, TriggerSessionStatus},
+    workflow_types::DatabaseFlowVersion,
+};
+use crate::{account_auth_middleware::AccountAccessCache, AppState};
+
+// Keeps workflows and tasks in memory so the processor can be tested without a database.
+// Behaves like PostgrestTaskStore, e.g. only the keys passed to an update are changed
+#[derive(Default)]
+pub struct InMemoryTaskStore {
+    workflows: RwLock<Vec<DatabaseFlowVersion>>,
+    tasks: RwLock<HashMap<Uuid, Task>>,
+    dead_letters: RwLock<Vec<DeadLetter>>,
+    large_results: RwLock<HashMap<Uuid, Value>>,
+    fail_create_task: AtomicBool,
+    failing_status_writes: AtomicUsize,
+    create_requests: AtomicUsize,
+    create_latency_micros: AtomicU64,
+}
+
+impl InMemoryTaskStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn add_workflow(&self, workflow: DatabaseFlowVersion) {
+        self.workflows.write().await.push(workflow);
+    }
+
+    // Makes this version the workflow's only published one
+    pub async fn publish_workflow(&self, flow_version_id: &Uuid) {
+        let mut workflows = self.workflows.write().await;
+        let Some(flow_id) = workflows
+            .iter()
+            .find(|workflow| workflow.flow_version_id == *flow_version_id)
+            .map(|workflow| workflow.flow_id)
+        else {
+            return;
+        };
+        for workflow in workflows.iter_mut() {
+            if workflow.flow_id == flow_id {
+                workflow.published = workflow.flow_version_id == *flow_version_id;
+            }
+        }
+    }
+
+    // Makes create_task return an error, like the DB being unreachable
+    pub fn fail_create_task(&self, fail: bool) {
+        self.fail_create_task.store(fail, Ordering::SeqCst);
+    }
+
+    // Makes the next `count` update_task_status and update_flow_session_status calls fail
+    pub fn fail_status_writes(&self, count: usize) {
+        self.failing_status_writes.store(count, Ordering::SeqCst);
+    }
+
+    fn status_write_fails(&self) -> bool {
+        self.failing_status_writes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    // How many create_task and create_tasks_batch calls were made, each one is a DB round trip
+    pub fn create_requests(&self) -> usize {
+        self.create_requests.load(Ordering::SeqCst)
+    }
+
+    // Makes every create_task and create_tasks_batch call take this long, like the round trip to the DB
+    pub fn set_create_latency(&self, latency: Duration) {
+        self.create_latency_micros
+            .store(latency.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    async fn create_round_trip(&self) {
+        self.create_requests.fetch_add(1, Ordering::SeqCst);
+        let latency = self.create_latency_micros.load(Ordering::SeqCst);
+        if latency > 0 {
+            tokio::time::sleep(Duration::from_micros(latency)).await;
+        }
+    }
+
+    fn new_task(task: &CreateTaskInput) -> Result<Task, String> {
+        // Round trip through json so the statuses parse the same way they do from the DB
+        let mut value =
+            serde_json::to_value(task).map_err(|e| format!("Failed to serialize task: {}", e))?;
+        value["task_id"] = json!(Uuid::new_v4());
+        value["archived"] = json!(false);
+        value["created_at"] = json!(Utc::now());
+
+        serde_json::from_value(value).map_err(|e| format!("Failed to parse created task: {}", e))
+    }
+}
+
+#[async_trait]
+impl TaskStore for InMemoryTaskStore {
+    async fn get_workflow_definition(
+        &self,
+        workflow_id: &Uuid,
+        version_id: Option<&Uuid>,
+    ) -> Result<DatabaseFlowVersion, String> {
+        self.workflows
+            .read()
+            .await
+            .iter()
+            .find(|workflow| {
+                workflow.flow_id == *workflow_id
+                    && match version_id {
+                        Some(version_id) => workflow.flow_version_id == *version_id,
+                        None => workflow.published,
+                    }
+            })
+            .cloned()
+            .ok_or_else(|| String::from("No workflow version found"))
+    }
+
+    async fn get_tasks_for_session(&self, flow_session_id: &Uuid) -> Result<Vec<Task>, String> {
+        let mut tasks: Vec<Task> = self
+            .tasks
+            .read()
+            .await
+            .values()
+            .filter(|task| task.flow_session_id == flow_session_id.to_string())
+            .cloned()
+            .collect();
+
+        tasks.sort_by_key(|task| task.processing_order);
+        Ok(tasks)
+    }
+
+    async fn get_tasks(&self, task_ids: &[Uuid]) -> Result<Vec<Task>, String> {
+        let tasks = self.tasks.read().await;
+        Ok(task_ids
+            .iter()
+            .filter_map(|task_id| tasks.get(task_id).cloned())
+            .collect())
+    }
+
+    async fn create_task(&self, task: &CreateTaskInput) -> Result<Task, String> {
+        self.create_round_trip().await;
+        if self.fail_create_task.load(Ordering::SeqCst) {
+            return Err("create_task failed: store is unavailable".to_string());
+        }
+
+        let task = Self::new_task(task)?;
+        self.tasks.write().await.insert(task.task_id, task.clone());
+        Ok(task)
+    }
+
+    async fn create_tasks_batch(&self, tasks: &[CreateTaskInput]) -> Result<Vec<Task>, String> {
+        self.create_round_trip().await;
+        if self.fail_create_task.load(Ordering::SeqCst) {
+            return Err("create_tasks_batch failed: store is unavailable".to_string());
+        }
+
+        // All or nothing like a single insert
+        let created = tasks
+            .iter()
+            .map(Self::new_task)
+            .collect::<Result<Vec<Task>, String>>()?;
+        let mut stored = self.tasks.write().await;
+        for task in &created {
+            stored.insert(task.task_id, task.clone());
+        }
+        Ok(created)
+    }
+
+    async fn update_task_status(
+        &self,
+        task_id: &Uuid,
+        status: &TaskStatus,
+        context: Option<Value>,
+        bundled_inputs: Option<Value>,
+        result: Option<Value>,
+        error: Option<Value>,
+    ) -> Result<(), String> {
+        if self.status_write_fails() {
+            return Err("update_task_status failed: store is unavailable".to_string());
+        }
+        let mut tasks = self.tasks.write().await;
+        let task = tasks
+            .get_mut(task_id)
+            .ok_or_else(|| format!("Task {} not found", task_id))?;
+
+        if *status == TaskStatus::Running {
+            task.started_at = Some(Utc::now());
+        } else {
+            task.ended_at = Some(Utc::now());
+        }
+        task.task_status = status.clone();
+        if let Some(context) = context {
+            task.context = Some(redact_headers_from_context(&context));
+        }
+        if bundled_inputs.is_some() {
+            task.bundled_inputs = bundled_inputs;
+        }
+        if result.is_some() {
+            task.result = result;
+        }
+        if error.is_some() {
+            task.error = error;
+        }
+        Ok(())
+    }
+
+    async fn update_flow_session_status(
+        &self,
+        flow_session_id: &Uuid,
+        flow_session_status: &FlowSessionStatus,
+        trigger_session_status: &TriggerSessionStatus,
+    ) -> Result<(), String> {
+        if self.status_write_fails() {
+            return Err("update_flow_session_status failed: store is unavailable".to_string());
+        }
+        for task in self.tasks.write().await.values_mut() {
+            if task.flow_session_id == flow_session_id.to_string() {
+                task.flow_session_status = flow_session_status.clone();
+                task.trigger_session_status = trigger_session_status.clone();
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_dead_letter(&self, dead_letter: &CreateDeadLetterInput) -> Result<(), String> {
+        self.dead_letters.write().await.push(DeadLetter {
+            dead_letter_id: Uuid::new_v4(),
+            flow_session_id: dead_letter.flow_session_id,
+            workflow_id: dead_letter.workflow_id,
+            message: dead_letter.message.clone(),
+            error: dead_letter.error.clone(),
+            created_at: Some(Utc::now()),
+        });
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, String> {
+        Ok(self
+            .dead_letters
+            .read()
+            .await
+            .iter()
+            .rev()
+            .cloned()
+            .collect())
+    }
+
+    async fn get_dead_letter(&self, dead_letter_id: &Uuid) -> Result<DeadLetter, String> {
+        self.dead_letters
+            .read()
+            .await
+            .iter()
+            .find(|dead_letter| dead_letter.dead_letter_id == *dead_letter_id)
+            .cloned()
+            .ok_or_else(|| String::from("No dead letter found"))
+    }
+
+    async fn delete_dead_letter(&self, dead_letter_id: &Uuid) -> Result<(), String> {
+        self.dead_letters
+            .write()
+            .await
+            .retain(|dead_letter| dead_letter.dead_letter_id != *dead_letter_id);
+        Ok(())
+    }
+
+    async fn create_large_result(
+        &self,
+        large_result: &CreateLargeResultInput,
+    ) -> Result<Uuid, String> {
+        let result_id = Uuid::new_v4();
+        self.large_results
+            .write()
+            .await
+            .insert(result_id, large_result.value.clone());
+        Ok(result_id)
+    }
+
+    async fn get_large_result(&self, result_id: &Uuid) -> Result<Value, String> {
+        self.large_results
+            .read()
+            .await
+            .get(result_id)
+            .cloned()
+            .ok_or_else(|| format!("No large result {}", result_id))
+    }
+}
+
+// AppState backed by the given store. The Postgrest clients point nowhere so anything
+// that still reaches for the database directly fails instead of touching real data
+pub fn test_app_state(task_store: Arc<dyn TaskStore>) -> Arc<AppState> {
+    test_app_state_with_secret_provider(
+        task_store,
+        Arc::new(InMemorySecretProvider::new(HashMap::new())),
+    )
+}
+
+pub fn test_app_state_with_secret_provider(
+    task_store: Arc<dyn TaskStore>,
+    secret_provider: Arc<dyn SecretProvider>,
+) -> Arc<AppState> {
+    let client = || Arc::new(postgrest::Postgrest::new("http://localhost:0"));
+    let (trigger_engine_signal, _) = watch::channel("".to_string());
+    let (processor_sender, processor_receiver) = mpsc::channel(100);
+    let ttl = std::time::Duration::from_secs(3600);
+
+    Arc::new(AppState {
+        anything_client: client(),
+        marketplace_client: client(),
+        public_client: client(),
+        http_client: Arc::new(reqwest::Client::new()),
+        workflow_processor_semaphore: Arc::new(Semaphore::new(10)),
+        auth_states: RwLock::new(HashMap::new()),
+        trigger_engine_signal,
+        processor_sender,
+        processor_receiver: Mutex::new(processor_receiver),
+        flow_completions: Arc::new(Mutex::new(HashMap::new())),
+        flow_session_waiters: Arc::new(Mutex::new(HashMap::new())),
+        api_key_cache: Arc::new(RwLock::new(HashMap::new())),
+        account_access_cache: Arc::new(RwLock::new(AccountAccessCache::new(ttl))),
+        bundler_secrets_cache: RwLock::new(SecretsCache::new(ttl)),
+        bundler_accounts_cache: RwLock::new(AccountsCache::new(ttl)),
+        bundle_cache: RwLock::new(BundleCache::new(ttl)),
+        flow_session_cache: Arc::new(RwLock::new(FlowSessionCache::new(ttl))),
+        active_flow_sessions: Arc::new(Mutex::new(HashSet::new())),
+        canceled_flow_sessions: Arc::new(RwLock::new(HashSet::new())),
+        flow_session_canceled: Arc::new(Notify::new()),
+        stalled_flow_sessions: Arc::new(RwLock::new(HashSet::new())),
+        stalled_flow_session_count: AtomicUsize::new(0),
+        webhook_deliveries: Arc::new(RwLock::new(HashMap::new())),
+        offload_threshold_bytes: AtomicUsize::new(0),
+        shutdown_signal: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        task_store,
+        task_middleware: RwLock::new(Vec::new()),
+        secret_provider,
+        config_source: Arc::new(InMemoryConfigSource::new(serde_json::Map::new())),
+        batch_task_creation: Arc::new(AtomicBool::new(false)),
+        plugin_rate_limiter: PluginRateLimiter::new(HashMap::new()),
+        max_parallel_branches: AtomicUsize::new(20),
+        write_retries: WriteRetryQueue::new(100),
+        concurrency_keys: ConcurrencyKeys::default(),
+        template_cache: Arc::new(TemplateCache::new()),
+    })
+}
+
+pub fn action(action_id: &str, action_type: &str, test_config: Option<Value>) -> Value {
+    json!({
+        "anything_action_version": "0.1.0",
+        "type": action_type,
+        "plugin_name": "@anything/http",
+        "plugin_version": "0.1.0",
+        "action_id": action_id,
+        "label": action_id,
+        "icon": "",
+        "inputs": {},
+        "inputs_schema": {},
+        "plugin_config": {},
+        "plugin_config_schema": {},
+        "test_config": test_config
+    })
+}
+
+pub fn edge(source: &str, target: &str) -> Value {
+    json!({
+        "id": format!("{}->{}", source, target),
+        "source": source,
+        "target": target,
+        "type": "anything"
+    })
+}
+
+// A connected account the bundler can use without refreshing it
+pub fn account(account_id: &str, slug: &str) -> AccountAuthProviderAccount {
+    serde_json::from_value(json!({
+        "account_auth_provider_account_id": Uuid::new_v4(),
+        "account_id": account_id,
+        "auth_provider_id": slug,
+        "account_auth_provider_account_label": slug,
+        "account_auth_provider_account_slug": slug,
+        "access_token": "",
+        "access_token_vault_id": "",
+        "refresh_token_vault_id": "",
+        "failed": false,
+        "failure_retries": 0
+    }))
+    .unwrap()
+}
+
+// A running task of the action, alone in a new session. `fields` replace the defaults, e.g.
+// json!({ "task_status": "completed", "result": {} }). Deserializes to a Task, or to the
+// CreateTaskInput it would be created from given a plugin_name and plugin_version
+pub fn task<T: DeserializeOwned>(action_id: &str, action_type: &str, fields: Value) -> T {
+    let mut task = json!({
+        "task_id": Uuid::new_v4(),
+        "account_id": Uuid::new_v4(),
+        "task_status": "running",
+        "flow_id": Uuid::new_v4(),
+        "flow_version_id": Uuid::new_v4(),
+        "action_label": action_id,
+        "trigger_id": "webhook",
+        "trigger_session_id": Uuid::new_v4(),
+        "trigger_session_status": "running",
+        "flow_session_id": Uuid::new_v4(),
+        "flow_session_status": "running",
+        "action_id": action_id,
+        "type": action_type,
+        "stage": "testing",
+        "config": {},
+        "archived": false,
+        "processing_order": 0
+    });
+    if let (Some(task), Value::Object(fields)) = (task.as_object_mut(), fields) {
+        task.extend(fields);
+    }
+    serde_json::from_value(task).unwrap()
+}
+
+// Starts a processor over an in-memory store holding one unpublished workflow.
+// Returns the store and state with the workflow and version ids to run
+pub async fn start_test_processor(
+    actions: Vec<Value>,
+    edges: Vec<Value>,
+) -> (Arc<InMemoryTaskStore>, Arc<AppState>, Uuid, Uuid) {
+    let store = Arc::new(InMemoryTaskStore::new());
+    let state = test_app_state(store.clone());
+
+    let workflow_id = Uuid::new_v4();
+    let flow_version_id = Uuid::new_v4();
+    let account_id = Uuid::new_v4();
+    store
+        .add_workflow(DatabaseFlowVersion {
+            flow_version_id,
+            flow_id: workflow_id,
+            flow: None,
+            published: false,
+            account_id,
+            flow_definition: serde_json::from_value(json!({
+                "actions": actions,
+                "edges": edges
+            }))
+            .unwrap(),
+        })
+        .await;
+
+    // The bundler goes to the DB for accounts on a cache miss so seed them. Secrets come
+    // from the in-memory provider test_app_state sets up
+    let account = account(&account_id.to_string(), "test");
+    state
+        .bundler_accounts_cache
+        .write()
+        .await
+        .set(&account_id.to_string(), vec![account]);
+
+    tokio::spawn(processor(state.clone()));
+
+    (store, state, workflow_id, flow_version_id)
+}
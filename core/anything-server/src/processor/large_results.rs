@@ -0,0 +1,315 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use std::{env, sync::Arc};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::types::task_types::{Task, TaskStatus};
+use crate::AppState;
+
+// Results bigger than the threshold live in anything.task_large_results and the task
+// result in the cache and DB becomes {"$large_result_ref": "<result_id>", "size_bytes": 123}
+pub const LARGE_RESULT_REF_KEY: &str = "$large_result_ref";
+
+//256KB
+const DEFAULT_OFFLOAD_THRESHOLD_BYTES: usize = 262144;
+
+// Results bigger than the flow session cache's max_result_bytes are only a preview in the cache,
+// {"result_truncated": true, "preview": "{\"rows\":[...", "size_bytes": 123}. The store keeps them whole
+pub const RESULT_TRUNCATED_KEY: &str = "result_truncated";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateLargeResultInput {
+    pub account_id: String,
+    pub task_id: String,
+    pub flow_session_id: String,
+    pub value: Value,
+    pub size_bytes: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LargeResult {
+    pub result_id: Uuid,
+    pub value: Value,
+}
+
+// Read once at startup into AppState's offload_threshold_bytes. Set
+// TASK_RESULT_OFFLOAD_THRESHOLD_BYTES=0 to never offload
+pub fn get_offload_threshold() -> usize {
+    env::var("TASK_RESULT_OFFLOAD_THRESHOLD_BYTES")
+        .ok()
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(DEFAULT_OFFLOAD_THRESHOLD_BYTES)
+}
+
+// Unset or 0 keeps every result whole in the cache
+pub fn get_cache_result_limit() -> usize {
+    env::var("FLOW_SESSION_CACHE_MAX_RESULT_BYTES")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(0)
+}
+
+// The cache's copy of a result over the limit, None if it fits
+pub fn truncate_result(result: &Value, max_result_bytes: usize) -> Option<Value> {
+    if max_result_bytes == 0 {
+        return None;
+    }
+
+    let serialized = result.to_string();
+    if serialized.len() <= max_result_bytes {
+        return None;
+    }
+
+    let mut preview_end = max_result_bytes;
+    while !serialized.is_char_boundary(preview_end) {
+        preview_end -= 1;
+    }
+
+    Some(json!({
+        RESULT_TRUNCATED_KEY: true,
+        "preview": &serialized[..preview_end],
+        "size_bytes": serialized.len()
+    }))
+}
+
+pub fn is_truncated_result(result: &Value) -> bool {
+    result.get(RESULT_TRUNCATED_KEY) == Some(&Value::Bool(true))
+}
+
+pub fn get_large_result_reference(result: &Value) -> Option<Uuid> {
+    result
+        .get(LARGE_RESULT_REF_KEY)
+        .and_then(|v| v.as_str())
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
+// Swaps a large result for a reference. If storing it fails we keep the full result so nothing is lost
+pub async fn offload_large_result(
+    state: Arc<AppState>,
+    task: &Task,
+    result: Option<Value>,
+) -> Option<Value> {
+    let threshold = state.offload_threshold_bytes.load(Ordering::Relaxed);
+    offload_result_over(&state, task, result?, threshold).await
+}
+
+async fn offload_result_over(
+    state: &AppState,
+    task: &Task,
+    result: Value,
+    threshold: usize,
+) -> Option<Value> {
+    if threshold == 0 {
+        return Some(result);
+    }
+
+    let size_bytes = result.to_string().len();
+    if size_bytes <= threshold {
+        return Some(result);
+    }
+
+    debug!(
+        "[LARGE RESULTS] Offloading {} byte result for task {}",
+        size_bytes, task.task_id
+    );
+    let input = CreateLargeResultInput {
+        account_id: task.account_id.to_string(),
+        task_id: task.task_id.to_string(),
+        flow_session_id: task.flow_session_id.to_string(),
+        value: result,
+        size_bytes,
+    };
+
+    match state.task_store.create_large_result(&input).await {
+        Ok(result_id) => Some(json!({
+            LARGE_RESULT_REF_KEY: result_id.to_string(),
+            "size_bytes": size_bytes
+        })),
+        Err(e) => {
+            warn!(
+                "[LARGE RESULTS] Failed to store large result for task {}: {}",
+                task.task_id, e
+            );
+            Some(input.value)
+        }
+    }
+}
+
+pub async fn fetch_large_result(state: Arc<AppState>, result_id: &Uuid) -> Result<Value, String> {
+    debug!("[LARGE RESULTS] Fetching large result {}", result_id);
+    state.task_store.get_large_result(result_id).await
+}
+
+// Replaces references with the real values so templates can read them like any other result
+pub async fn resolve_large_results(state: Arc<AppState>, tasks: &mut [Task]) -> Result<(), String> {
+    for task in tasks.iter_mut() {
+        let result_id = match task.result.as_ref().and_then(get_large_result_reference) {
+            Some(result_id) => result_id,
+            None => continue,
+        };
+        task.result = Some(fetch_large_result(state.clone(), &result_id).await?);
+    }
+    Ok(())
+}
+
+// Swaps truncated cache copies for the whole results in the store, or in the write retry queue
+// while the write with the result hasn't landed. Not having the task's result in either is an
+// error rather than a preview or no result standing in for it
+pub async fn resolve_truncated_results(
+    state: Arc<AppState>,
+    tasks: &mut [Task],
+) -> Result<(), String> {
+    let truncated: Vec<Uuid> = tasks
+        .iter()
+        .filter(|task| task.result.as_ref().is_some_and(is_truncated_result))
+        .map(|task| task.task_id)
+        .collect();
+    if truncated.is_empty() {
+        return Ok(());
+    }
+
+    debug!(
+        "[LARGE RESULTS] Fetching {} truncated results",
+        truncated.len()
+    );
+    let stored_tasks = state.task_store.get_tasks(&truncated).await?;
+
+    for task in tasks.iter_mut() {
+        if !task.result.as_ref().is_some_and(is_truncated_result) {
+            continue;
+        }
+        if let Some(result) = state.write_retries.pending_result(&task.task_id).await {
+            task.result = Some(result);
+            continue;
+        }
+        task.result = stored_tasks
+            .iter()
+            .find(|stored_task| {
+                stored_task.task_id == task.task_id
+                    && stored_task.task_status == TaskStatus::Completed
+            })
+            .and_then(|stored_task| stored_task.result.clone());
+        if task.result.is_none() {
+            return Err(format!(
+                "Task {}'s whole result isn't in the store",
+                task.task_id
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Truncated previews and then references, a truncated result can be a reference once it's whole
+pub async fn resolve_results(state: Arc<AppState>, tasks: &mut [Task]) -> Result<(), String> {
+    resolve_truncated_results(state.clone(), tasks).await?;
+    resolve_large_results(state, tasks).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{
+        action, start_test_processor, task, test_app_state, InMemoryTaskStore,
+    };
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use crate::processor::write_retries::write_task_status;
+    use crate::types::task_types::CreateTaskInput;
+
+    #[tokio::test]
+    async fn test_offloaded_result_is_fetched_back() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({}),
+        )
+        .await
+        .unwrap();
+        let mut task = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap()
+            .remove(0);
+
+        let result = json!({ "rows": ["a", "b", "c"] });
+        let size_bytes = result.to_string().len();
+        // Small enough results stay as they are
+        assert_eq!(
+            offload_result_over(&state, &task, result.clone(), size_bytes).await,
+            Some(result.clone())
+        );
+
+        let reference = offload_result_over(&state, &task, result.clone(), 10)
+            .await
+            .unwrap();
+        assert!(get_large_result_reference(&reference).is_some());
+        assert_eq!(reference["size_bytes"], json!(size_bytes));
+
+        task.result = Some(reference);
+        let mut tasks = vec![task];
+        resolve_large_results(state, &mut tasks).await.unwrap();
+        assert_eq!(tasks[0].result, Some(result));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_result_the_store_doesnt_have_is_an_error() {
+        let store = Arc::new(InMemoryTaskStore::new());
+        let state = test_app_state(store.clone());
+        let input: CreateTaskInput = task(
+            "fetch",
+            "action",
+            json!({ "plugin_name": "@anything/http", "plugin_version": "0.1.0" }),
+        );
+        let stored = store.create_task(&input).await.unwrap();
+
+        // The completed write hasn't landed yet, e.g. it's being retried
+        let rows = json!({ "rows": ["a", "b", "c"] });
+        let mut cached = stored.clone();
+        cached.task_status = TaskStatus::Completed;
+        cached.result = truncate_result(&rows, 8);
+        let mut tasks = vec![cached];
+        let error = resolve_truncated_results(state.clone(), &mut tasks)
+            .await
+            .unwrap_err();
+        assert!(error.contains(&stored.task_id.to_string()), "{}", error);
+
+        // Until the write that failed lands the result comes from the write retry queue
+        store.fail_status_writes(1);
+        write_task_status(
+            &state,
+            &stored.task_id,
+            &TaskStatus::Completed,
+            None,
+            None,
+            Some(rows.clone()),
+            None,
+        )
+        .await;
+        tasks[0].result = truncate_result(&rows, 8);
+        resolve_truncated_results(state.clone(), &mut tasks)
+            .await
+            .unwrap();
+        assert_eq!(tasks[0].result, Some(rows.clone()));
+
+        store
+            .update_task_status(
+                &stored.task_id,
+                &TaskStatus::Completed,
+                None,
+                None,
+                Some(rows.clone()),
+                None,
+            )
+            .await
+            .unwrap();
+        tasks[0].result = truncate_result(&rows, 8);
+        resolve_truncated_results(state, &mut tasks).await.unwrap();
+        assert_eq!(tasks[0].result, Some(rows));
+    }
+}
@@ -1,9 +1,24 @@
+pub mod approvals;
+pub mod concurrency_keys;
 pub mod db_calls;
+pub mod dead_letters;
 pub mod execute_task;
+pub mod flow_diff;
 pub mod flow_session_cache;
 pub mod hydrate_processor;
+#[cfg(test)]
+pub mod in_memory_task_store;
+pub mod large_results;
+pub mod parallel;
 pub mod parsing_utils;
+pub mod plugin_versions;
 pub mod process_trigger_utils;
 pub mod processor;
+pub mod rate_limiter;
+pub mod run_workflow;
+pub mod stalled_sessions;
+pub mod task_middleware;
+pub mod workflow_lint;
+pub mod write_retries;
 
 pub use processor::*;
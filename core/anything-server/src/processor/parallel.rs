@@ -0,0 +1,417 @@
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use postgrest::Postgrest;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::processor::execute_task::{execute_task, TaskError, TaskResult};
+use crate::processor::large_results::{offload_large_result, truncate_result};
+use crate::processor::write_retries::write_task_status;
+use crate::types::action_types::Action;
+use crate::types::react_flow_types::Edge;
+use crate::types::task_types::{
+    CreateTaskInput, FlowSessionStatus, Task, TaskStatus, TriggerSessionStatus,
+};
+use crate::AppState;
+
+const DEFAULT_MAX_PARALLEL_BRANCHES: usize = 20;
+
+// MAX_PARALLEL_BRANCHES caps how many of a Parallel task's children run at once, 20 by default.
+// Separate from the plugin rate limits, it keeps one wide fan-out from starting hundreds of
+// tasks together
+pub fn max_parallel_branches_from_env() -> Result<usize, String> {
+    match env::var("MAX_PARALLEL_BRANCHES") {
+        Ok(max) => match max.parse() {
+            Ok(max) if max > 0 => Ok(max),
+            _ => Err(format!(
+                "MAX_PARALLEL_BRANCHES must be a positive number, got '{}'",
+                max
+            )),
+        },
+        Err(_) => Ok(DEFAULT_MAX_PARALLEL_BRANCHES),
+    }
+}
+
+// The actions a Parallel action's edges point at. They all run at once, conditions on those
+// edges aren't evaluated. Each branch is that one action, validate_workflow_graph rejects
+// branches that don't lead straight to the join
+pub fn parallel_children(
+    actions: &[Action],
+    graph: &HashMap<String, Vec<Edge>>,
+    parallel_action_id: &str,
+) -> Vec<Action> {
+    graph
+        .get(parallel_action_id)
+        .into_iter()
+        .flatten()
+        .filter_map(|edge| {
+            actions
+                .iter()
+                .find(|action| action.action_id == edge.target)
+                .cloned()
+        })
+        .collect()
+}
+
+// Runs a Parallel task's children concurrently, at most AppState::max_parallel_branches at a
+// time. A session runs one task at a time so this is every branch the session has in flight.
+// Its result is theirs by action_id, e.g. `{{actions.fan_out.result.fetch_orders}}`, and
+// whatever comes after the children only runs once all of them are done. Any child failing
+// fails the Parallel task after the rest finish
+pub async fn run_parallel_task(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    flow_session_id: &Uuid,
+    task: &Task,
+    children: &[Action],
+) -> TaskResult {
+    info!(
+        "[PROCESSOR] Running {} parallel branches of {}",
+        children.len(),
+        task.action_id
+    );
+
+    let mut child_tasks = Vec::with_capacity(children.len());
+    for (i, child) in children.iter().enumerate() {
+        let input = child_task_input(task, child, task.processing_order + 1 + i as i32);
+        let child_task = state.task_store.create_task(&input).await.map_err(|e| {
+            parallel_error(format!(
+                "Failed to create task for {}: {}",
+                child.action_id, e
+            ))
+        })?;
+        state
+            .flow_session_cache
+            .write()
+            .await
+            .add_task(flow_session_id, child_task.clone())
+            .map_err(parallel_error)?;
+        child_tasks.push(child_task);
+    }
+
+    let max_branches = state.max_parallel_branches.load(Ordering::Relaxed).max(1);
+    let results: Vec<TaskResult> = stream::iter(child_tasks.iter().zip(children))
+        .map(|(child_task, child)| execute_task(state.clone(), client, child_task, Some(child)))
+        .buffered(max_branches)
+        .collect()
+        .await;
+
+    let mut aggregated = serde_json::Map::new();
+    let mut failure: Option<(String, TaskError)> = None;
+    for ((mut child_task, child), result) in child_tasks.into_iter().zip(children).zip(results) {
+        match result {
+            Ok((result, bundled_inputs, bundled_context, skipped)) => {
+                let result = offload_large_result(state.clone(), &child_task, result).await;
+                aggregated.insert(
+                    child.action_id.clone(),
+                    result.clone().unwrap_or(Value::Null),
+                );
+                child_task.task_status = if skipped {
+                    TaskStatus::Skipped
+                } else {
+                    TaskStatus::Completed
+                };
+                child_task.result = result;
+                child_task.context = Some(bundled_context);
+                child_task.bundled_inputs = Some(bundled_inputs);
+            }
+            Err(error) => {
+                child_task.task_status = if error.canceled {
+                    TaskStatus::Canceled
+                } else {
+                    TaskStatus::Failed
+                };
+                child_task.context = Some(error.context.clone());
+                child_task.bundled_inputs = error.bundled_inputs.clone();
+                child_task.error = Some(error.error.clone());
+                failure.get_or_insert((child.action_id.clone(), error));
+            }
+        }
+        record_child_task(&state, flow_session_id, child_task).await;
+    }
+
+    if let Some((action_id, error)) = failure {
+        return Err(TaskError {
+            error: json!({
+                "message": format!("Parallel branch {} failed", action_id),
+                "error": error.error
+            }),
+            context: json!({}),
+            bundled_inputs: None,
+            canceled: error.canceled,
+        });
+    }
+    Ok((Some(Value::Object(aggregated)), json!({}), json!({}), false))
+}
+
+fn parallel_error(message: String) -> TaskError {
+    TaskError {
+        error: json!({ "message": message }),
+        context: json!({}),
+        bundled_inputs: None,
+        canceled: false,
+    }
+}
+
+// Created Running like any task the processor moves on to
+fn child_task_input(
+    parallel_task: &Task,
+    action: &Action,
+    processing_order: i32,
+) -> CreateTaskInput {
+    let action = action.executable();
+    CreateTaskInput {
+        account_id: parallel_task.account_id.to_string(),
+        processing_order,
+        task_status: TaskStatus::Running.as_str().to_string(),
+        flow_id: parallel_task.flow_id.to_string(),
+        flow_version_id: parallel_task.flow_version_id.to_string(),
+        action_label: action.label.to_string(),
+        trigger_id: parallel_task.trigger_id.clone(),
+        trigger_session_id: parallel_task.trigger_session_id.clone(),
+        trigger_session_status: TriggerSessionStatus::Pending.as_str().to_string(),
+        flow_session_id: parallel_task.flow_session_id.clone(),
+        flow_session_status: FlowSessionStatus::Pending.as_str().to_string(),
+        action_id: action.action_id.to_string(),
+        r#type: action.r#type.clone(),
+        plugin_name: action.plugin_name.clone(),
+        plugin_version: action.plugin_version.clone(),
+        stage: parallel_task.stage.as_str().to_string(),
+        config: action.task_config(),
+        result: None,
+        error: None,
+        started_at: Some(Utc::now()),
+        test_config: action.test_config.cloned(),
+    }
+}
+
+// The store is written first and awaited, the cache may only keep a truncated result and the
+// tasks after the Parallel read the rest from the store
+async fn record_child_task(state: &AppState, flow_session_id: &Uuid, mut child_task: Task) {
+    write_task_status(
+        state,
+        &child_task.task_id,
+        &child_task.task_status,
+        child_task.context.clone(),
+        child_task.bundled_inputs.clone(),
+        child_task.result.clone(),
+        child_task.error.clone(),
+    )
+    .await;
+
+    let max_result_bytes = state.flow_session_cache.read().await.max_result_bytes();
+    if let Some(truncated) = child_task
+        .result
+        .as_ref()
+        .and_then(|result| truncate_result(result, max_result_bytes))
+    {
+        child_task.result = Some(truncated);
+    }
+    child_task.ended_at = Some(Utc::now());
+    if let Err(e) = state
+        .flow_session_cache
+        .write()
+        .await
+        .update_task(flow_session_id, child_task)
+    {
+        warn!("[PROCESSOR] Failed to update task in cache: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{action, edge, start_test_processor};
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use crate::processor::task_middleware::{BundledInput, TaskMiddleware};
+    use crate::types::task_types::{FlowSessionStatus, TaskStatus};
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    // Records when each task starts and finishes its plugin, and the most ever running at once
+    #[derive(Default)]
+    struct Branches {
+        calls: Mutex<Vec<String>>,
+        running: AtomicUsize,
+        most_running: AtomicUsize,
+    }
+
+    impl TaskMiddleware for Branches {
+        fn before_execute(&self, task: &Task, _input: &mut BundledInput) {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("start {}", task.action_id));
+        }
+
+        fn after_execute(&self, task: &Task, _result: &TaskResult) {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("end {}", task.action_id));
+        }
+    }
+
+    // Mock delays run on paused time, they only order the branches
+    #[tokio::test(start_paused = true)]
+    async fn test_parallel_children_run_together_before_the_join() {
+        let branch = |action_id: &str, value: i64| {
+            action(
+                action_id,
+                "action",
+                Some(json!({ "mock_result": { "value": value }, "mock_delay_ms": 300 })),
+            )
+        };
+        let mut join = action("join", "action", None);
+        join["plugin_name"] = json!("@anything/transform");
+        join["inputs"] = json!({
+            "a": "{{actions.fan_out.result.a}}",
+            "b": "{{actions.b.result}}"
+        });
+        join["inputs_schema"] = json!({
+            "type": "object",
+            "properties": {
+                "a": { "x-any-validation": { "type": "object" } },
+                "b": { "x-any-validation": { "type": "object" } }
+            }
+        });
+        join["plugin_config"] = json!({
+            "output": { "a": "{{inputs.a.value}}", "b": "{{inputs.b.value}}" }
+        });
+        join["plugin_config_schema"] = json!({
+            "type": "object",
+            "properties": { "output": { "x-any-validation": { "type": "any" } } }
+        });
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action("fan_out", "parallel", None),
+                branch("a", 1),
+                branch("b", 2),
+                join,
+            ],
+            vec![
+                edge("webhook", "fan_out"),
+                edge("fan_out", "a"),
+                edge("fan_out", "b"),
+                edge("a", "join"),
+                edge("b", "join"),
+            ],
+        )
+        .await;
+        let branches = Arc::new(Branches::default());
+        state.task_middleware.write().await.push(branches.clone());
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({}),
+        )
+        .await
+        .unwrap();
+        // Both branches start before either ends, the join only after both
+        let mut calls = branches.calls.lock().unwrap().clone();
+        calls[2..4].sort();
+        calls[4..6].sort();
+        assert_eq!(
+            calls,
+            vec![
+                "start webhook",
+                "end webhook",
+                "start a",
+                "start b",
+                "end a",
+                "end b",
+                "start join",
+                "end join"
+            ]
+        );
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(outcome.output, Some(json!({ "a": 1, "b": 2 })));
+
+        let tasks = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap();
+        let task = |action_id: &str| {
+            tasks
+                .iter()
+                .find(|task| task.action_id == action_id)
+                .unwrap()
+        };
+        assert_eq!(
+            task("fan_out").result,
+            Some(json!({ "a": { "value": 1 }, "b": { "value": 2 } }))
+        );
+        assert_eq!(task("a").task_status, TaskStatus::Completed);
+        assert_eq!(task("b").task_status, TaskStatus::Completed);
+        // The join runs once, after both branches
+        assert_eq!(
+            tasks.iter().filter(|task| task.action_id == "join").count(),
+            1
+        );
+        let processing_orders: Vec<i32> = ["webhook", "fan_out", "a", "b", "join"]
+            .iter()
+            .map(|action_id| task(action_id).processing_order)
+            .collect();
+        assert_eq!(processing_orders, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_parallel_children_are_limited_to_max_parallel_branches() {
+        let branch_ids: Vec<String> = (0..8).map(|i| format!("branch_{}", i)).collect();
+        let mut actions = vec![
+            action("webhook", "trigger", None),
+            action("fan_out", "parallel", None),
+        ];
+        let mut edges = vec![edge("webhook", "fan_out")];
+        for (i, branch_id) in branch_ids.iter().enumerate() {
+            actions.push(action(
+                branch_id,
+                "action",
+                Some(json!({ "mock_result": { "value": i }, "mock_delay_ms": 100 })),
+            ));
+            edges.push(edge("fan_out", branch_id));
+        }
+        let (_, state, workflow_id, flow_version_id) = start_test_processor(actions, edges).await;
+        state.max_parallel_branches.store(3, Ordering::SeqCst);
+        let branches = Arc::new(Branches::default());
+        state.task_middleware.write().await.push(branches.clone());
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({}),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(branches.most_running.load(Ordering::SeqCst), 3);
+        let calls = branches.calls.lock().unwrap();
+        for branch_id in &branch_ids {
+            assert!(calls.contains(&format!("end {}", branch_id)));
+        }
+        // The first three start together, the rest as they finish
+        let started_together = calls
+            .iter()
+            .skip_while(|call| !call.starts_with("start branch_"))
+            .take_while(|call| call.starts_with("start "))
+            .count();
+        assert_eq!(started_together, 3);
+    }
+}
@@ -1,14 +1,607 @@
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 
+use crate::processor::processor::create_workflow_graph;
+use crate::templater::{TemplateError, Templater};
 use crate::types::{
     action_types::{Action, ActionType},
+    json_schema::{JsonSchema, ValidationFieldType},
     task_types::Task,
     workflow_types::WorkflowVersionDefinition,
 };
 
-pub fn get_trigger_node(workflow: &WorkflowVersionDefinition) -> Option<&Action> {
-    workflow
+// A workflow needs exactly one trigger, and unless the trigger is the only action it needs
+// an edge out of it. plugin_name is checked when the definition is parsed (see PluginName)
+pub fn get_trigger_node(
+    workflow: &WorkflowVersionDefinition,
+) -> Result<&Action, WorkflowGraphProblem> {
+    let triggers: Vec<&Action> = workflow
         .actions
         .iter()
-        .find(|action| action.r#type == ActionType::Trigger)
+        .filter(|action| action.r#type == ActionType::Trigger)
+        .collect();
+
+    let trigger = match triggers.as_slice() {
+        [] => return Err(WorkflowGraphProblem::MissingTrigger),
+        [trigger] => *trigger,
+        _ => {
+            return Err(WorkflowGraphProblem::MultipleTriggers {
+                action_ids: triggers
+                    .iter()
+                    .map(|trigger| trigger.action_id.clone())
+                    .collect(),
+            })
+        }
+    };
+
+    if workflow.actions.len() > 1
+        && !workflow
+            .edges
+            .iter()
+            .any(|edge| edge.source == trigger.action_id)
+    {
+        return Err(WorkflowGraphProblem::TriggerHasNoEdges {
+            action_id: trigger.action_id.clone(),
+        });
+    }
+
+    Ok(trigger)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkflowGraphProblem {
+    MissingTrigger,
+    MultipleTriggers {
+        action_ids: Vec<String>,
+    },
+    TriggerHasNoEdges {
+        action_id: String,
+    },
+    DanglingEdge {
+        edge_id: String,
+        missing_action_id: String,
+    },
+    UnreachableAction {
+        action_id: String,
+    },
+    NoPathToTerminal {
+        action_id: String,
+    },
+    DuplicateActionId {
+        action_id: String,
+    },
+    ParallelBranchTooLong {
+        action_id: String,
+        branch_action_id: String,
+    },
+}
+
+impl WorkflowGraphProblem {
+    // Dangling edges, duplicate action ids and trigger problems mean the processor can't walk the
+    // graph at all, and it would skip whatever comes after the first action of a parallel branch
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            WorkflowGraphProblem::MissingTrigger
+                | WorkflowGraphProblem::MultipleTriggers { .. }
+                | WorkflowGraphProblem::TriggerHasNoEdges { .. }
+                | WorkflowGraphProblem::DanglingEdge { .. }
+                | WorkflowGraphProblem::DuplicateActionId { .. }
+                | WorkflowGraphProblem::ParallelBranchTooLong { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for WorkflowGraphProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkflowGraphProblem::MissingTrigger => write!(f, "Workflow has no trigger"),
+            WorkflowGraphProblem::MultipleTriggers { action_ids } => write!(
+                f,
+                "Workflow has more than one trigger: {}",
+                action_ids.join(", ")
+            ),
+            WorkflowGraphProblem::TriggerHasNoEdges { action_id } => write!(
+                f,
+                "Trigger {} is not connected to any other action",
+                action_id
+            ),
+            WorkflowGraphProblem::DanglingEdge {
+                edge_id,
+                missing_action_id,
+            } => write!(
+                f,
+                "Edge {} points to action {} which does not exist",
+                edge_id, missing_action_id
+            ),
+            WorkflowGraphProblem::UnreachableAction { action_id } => write!(
+                f,
+                "Action {} can not be reached from the trigger",
+                action_id
+            ),
+            WorkflowGraphProblem::NoPathToTerminal { action_id } => write!(
+                f,
+                "Action {} has no path to an action that ends the workflow",
+                action_id
+            ),
+            WorkflowGraphProblem::DuplicateActionId { action_id } => {
+                write!(f, "More than one action has the action_id {}", action_id)
+            }
+            WorkflowGraphProblem::ParallelBranchTooLong {
+                action_id,
+                branch_action_id,
+            } => write!(
+                f,
+                "Parallel action {} runs single actions, {} has to lead straight to the join",
+                action_id, branch_action_id
+            ),
+        }
+    }
+}
+
+// Reshapes a trigger payload with the trigger's payload_mapping so the workflow sees the same
+// shape whichever provider sent it. The mapping is rendered against the payload as `body`, e.g.
+// { "email": "{{body.data.customer.email}}" } for one provider and "{{body.email}}" for another
+pub fn normalize_trigger_payload(mapping: &Value, payload: &Value) -> Result<Value, TemplateError> {
+    let mut templater = Templater::new();
+    templater.set_parse_json_strings(true);
+    // Wrapped so a mapping that is a single `{{ }}` or an array renders like an object does
+    templater.add_template("payload_mapping", json!({ "payload": mapping }));
+
+    let validations = HashMap::from([("payload".to_string(), ValidationFieldType::Unknown)]);
+    let mut rendered =
+        templater.render("payload_mapping", &json!({ "body": payload }), validations)?;
+    Ok(rendered["payload"].take())
+}
+
+// Checks a trigger payload against the trigger's payload_schema with the same validation types
+// the templater uses for inputs. Required fields and Any fields must be present and not null.
+// Values are only checked, the payload the workflow runs with is not converted
+pub fn validate_trigger_payload(payload: &Value, schema: &JsonSchema) -> Vec<String> {
+    validate_against_schema(payload, schema, "Payload")
+}
+
+// Same checks for a task's result against its action's output_schema
+pub fn validate_task_result(result: &Value, schema: &JsonSchema) -> Vec<String> {
+    validate_against_schema(result, schema, "Result")
+}
+
+fn validate_against_schema(value: &Value, schema: &JsonSchema, name: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let fields = match value {
+        Value::Object(fields) => fields,
+        _ => return vec![format!("{} must be an object, got: {}", name, value)],
+    };
+
+    let required = schema.required.as_deref().unwrap_or_default();
+    let mut properties: Vec<_> = schema.properties.iter().flatten().collect();
+    properties.sort_by_key(|(name, _)| *name);
+
+    for (name, property) in properties {
+        let validation_type = property
+            .x_any_validation
+            .as_ref()
+            .map_or(ValidationFieldType::Unknown, |validation| {
+                validation.r#type.clone()
+            });
+
+        match fields.get(name).filter(|value| !value.is_null()) {
+            None if required.contains(name) || validation_type == ValidationFieldType::Any => {
+                problems.push(format!("'{}' is required", name))
+            }
+            None => {}
+            Some(value) => {
+                if let Err(e) =
+                    Templater::validate_and_convert_value(value.clone(), &validation_type, name)
+                {
+                    problems.push(format!("'{}': {}", name, e.message));
+                }
+            }
+        }
+    }
+
+    if schema.additional_properties == Some(false) {
+        let mut unexpected: Vec<&String> = fields
+            .keys()
+            .filter(|name| {
+                !schema
+                    .properties
+                    .as_ref()
+                    .is_some_and(|properties| properties.contains_key(*name))
+            })
+            .collect();
+        unexpected.sort();
+        for name in unexpected {
+            problems.push(format!("'{}' is not allowed", name));
+        }
+    }
+
+    problems
+}
+
+// Finds dangling edges, actions the trigger can't reach, actions stuck in cycles with no way out,
+// and parallel branches longer than one action
+pub fn validate_workflow_graph(workflow: &WorkflowVersionDefinition) -> Vec<WorkflowGraphProblem> {
+    let mut problems = Vec::new();
+
+    // Edges, tasks and `{{actions.<id>}}` all find actions by action_id
+    let mut action_ids: HashSet<&str> = HashSet::new();
+    let mut duplicate_ids: HashSet<&str> = HashSet::new();
+    for action in &workflow.actions {
+        let action_id = action.action_id.as_str();
+        if !action_ids.insert(action_id) && duplicate_ids.insert(action_id) {
+            problems.push(WorkflowGraphProblem::DuplicateActionId {
+                action_id: action.action_id.clone(),
+            });
+        }
+    }
+
+    for edge in &workflow.edges {
+        for endpoint in [&edge.source, &edge.target] {
+            if !action_ids.contains(endpoint.as_str()) {
+                problems.push(WorkflowGraphProblem::DanglingEdge {
+                    edge_id: edge.id.clone(),
+                    missing_action_id: endpoint.clone(),
+                });
+            }
+        }
+    }
+
+    // Only walk edges between real actions so dangling ones don't hide other problems
+    let graph = create_workflow_graph(workflow);
+    let mut reverse_graph: HashMap<&str, Vec<&str>> = HashMap::new();
+    for edge in &workflow.edges {
+        if action_ids.contains(edge.source.as_str()) && action_ids.contains(edge.target.as_str()) {
+            reverse_graph
+                .entry(edge.target.as_str())
+                .or_default()
+                .push(edge.source.as_str());
+        }
+    }
+
+    // run_parallel_task runs each branch's one action, whatever comes after has to be the join all
+    // the branches share. A Parallel as a branch would start branches of its own
+    for parallel in workflow
+        .actions
+        .iter()
+        .filter(|action| action.r#type == ActionType::Parallel)
+    {
+        let branches: Vec<&str> = graph
+            .get(&parallel.action_id)
+            .into_iter()
+            .flatten()
+            .map(|edge| edge.target.as_str())
+            .collect();
+        let targets = |branch: &str| {
+            graph
+                .get(branch)
+                .into_iter()
+                .flatten()
+                .map(|edge| edge.target.as_str())
+                .collect::<HashSet<&str>>()
+        };
+        // The join is where most of the branches lead
+        let mut leads_to: HashMap<&str, usize> = HashMap::new();
+        for branch in &branches {
+            for target in targets(*branch) {
+                *leads_to.entry(target).or_default() += 1;
+            }
+        }
+        let join = leads_to
+            .iter()
+            .max_by_key(|(target, count)| (**count, std::cmp::Reverse(**target)))
+            .map(|(target, _)| *target);
+
+        for branch in &branches {
+            let nested = workflow
+                .actions
+                .iter()
+                .any(|action| action.action_id == *branch && action.r#type == ActionType::Parallel);
+            let too_long = targets(*branch)
+                .into_iter()
+                .any(|target| Some(target) != join || branches.contains(&target));
+            if nested || too_long {
+                problems.push(WorkflowGraphProblem::ParallelBranchTooLong {
+                    action_id: parallel.action_id.clone(),
+                    branch_action_id: branch.to_string(),
+                });
+            }
+        }
+    }
+
+    match get_trigger_node(workflow) {
+        Ok(trigger) => {
+            let mut reachable = HashSet::new();
+            let mut queue = VecDeque::from([trigger.action_id.as_str()]);
+            while let Some(action_id) = queue.pop_front() {
+                if !reachable.insert(action_id) {
+                    continue;
+                }
+                for edge in graph.get(action_id).into_iter().flatten() {
+                    if action_ids.contains(edge.target.as_str()) {
+                        queue.push_back(edge.target.as_str());
+                    }
+                }
+            }
+
+            for action in &workflow.actions {
+                if !reachable.contains(action.action_id.as_str()) {
+                    problems.push(WorkflowGraphProblem::UnreachableAction {
+                        action_id: action.action_id.clone(),
+                    });
+                }
+            }
+        }
+        Err(problem) => problems.push(problem),
+    }
+
+    // Terminals are actions with no outgoing edges. Walk backwards from them to find who can finish
+    let mut can_finish = HashSet::new();
+    let mut queue: VecDeque<&str> = workflow
+        .actions
+        .iter()
+        .map(|action| action.action_id.as_str())
+        .filter(|action_id| {
+            !graph
+                .get(*action_id)
+                .into_iter()
+                .flatten()
+                .any(|edge| action_ids.contains(edge.target.as_str()))
+        })
+        .collect();
+    while let Some(action_id) = queue.pop_front() {
+        if !can_finish.insert(action_id) {
+            continue;
+        }
+        for source in reverse_graph.get(action_id).into_iter().flatten() {
+            queue.push_back(*source);
+        }
+    }
+
+    for action in &workflow.actions {
+        if !can_finish.contains(action.action_id.as_str()) {
+            problems.push(WorkflowGraphProblem::NoPathToTerminal {
+                action_id: action.action_id.clone(),
+            });
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store::{action, edge};
+    use serde_json::json;
+
+    fn build_workflow(actions: Vec<Value>, edges: Vec<Value>) -> WorkflowVersionDefinition {
+        serde_json::from_value(json!({ "actions": actions, "edges": edges })).unwrap()
+    }
+
+    #[test]
+    fn test_valid_trigger() {
+        let workflow = build_workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", None),
+            ],
+            vec![edge("webhook", "http")],
+        );
+        let trigger = get_trigger_node(&workflow).unwrap();
+        assert_eq!(trigger.action_id, "webhook");
+        assert!(validate_workflow_graph(&workflow).is_empty());
+
+        // A trigger on its own doesn't need any edges
+        let single = build_workflow(vec![action("webhook", "trigger", None)], vec![]);
+        assert!(get_trigger_node(&single).is_ok());
+    }
+
+    #[test]
+    fn test_missing_trigger() {
+        let workflow = build_workflow(
+            vec![
+                action("http", "action", None),
+                action("email", "action", None),
+            ],
+            vec![edge("http", "email")],
+        );
+        let problem = get_trigger_node(&workflow).unwrap_err();
+        assert_eq!(problem, WorkflowGraphProblem::MissingTrigger);
+        assert!(problem.is_fatal());
+    }
+
+    #[test]
+    fn test_multiple_triggers() {
+        let workflow = build_workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("cron", "trigger", None),
+                action("http", "action", None),
+            ],
+            vec![edge("webhook", "http"), edge("cron", "http")],
+        );
+        assert_eq!(
+            get_trigger_node(&workflow).unwrap_err(),
+            WorkflowGraphProblem::MultipleTriggers {
+                action_ids: vec!["webhook".to_string(), "cron".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn test_trigger_with_no_outgoing_edge() {
+        let workflow = build_workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", None),
+            ],
+            vec![edge("http", "webhook")],
+        );
+        let problem = get_trigger_node(&workflow).unwrap_err();
+        assert_eq!(
+            problem,
+            WorkflowGraphProblem::TriggerHasNoEdges {
+                action_id: "webhook".to_string()
+            }
+        );
+        assert!(validate_workflow_graph(&workflow)
+            .iter()
+            .any(|problem| problem.is_fatal()));
+    }
+
+    #[test]
+    fn test_duplicate_action_ids() {
+        let workflow = build_workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", None),
+                action("http", "action", None),
+                action("http", "action", None),
+            ],
+            vec![edge("webhook", "http")],
+        );
+        let problems = validate_workflow_graph(&workflow);
+        assert_eq!(
+            problems,
+            vec![WorkflowGraphProblem::DuplicateActionId {
+                action_id: "http".to_string()
+            }]
+        );
+        assert!(problems[0].is_fatal());
+    }
+
+    #[test]
+    fn test_parallel_branches_are_single_actions() {
+        let fork_join = |edges: Vec<Value>| {
+            build_workflow(
+                vec![
+                    action("webhook", "trigger", None),
+                    action("fan_out", "parallel", None),
+                    action("a", "action", None),
+                    action("b", "action", None),
+                    action("c", "action", None),
+                    action("enrich", "action", None),
+                    action("join", "action", None),
+                ],
+                edges,
+            )
+        };
+        let workflow = fork_join(vec![
+            edge("webhook", "fan_out"),
+            edge("fan_out", "a"),
+            edge("fan_out", "b"),
+            edge("fan_out", "c"),
+            edge("fan_out", "enrich"),
+            edge("a", "join"),
+            edge("b", "join"),
+            edge("c", "join"),
+            edge("enrich", "join"),
+        ]);
+        assert!(validate_workflow_graph(&workflow).is_empty());
+
+        // Only a runs as part of its branch, enrich after it never would
+        let workflow = fork_join(vec![
+            edge("webhook", "fan_out"),
+            edge("fan_out", "a"),
+            edge("fan_out", "b"),
+            edge("fan_out", "c"),
+            edge("a", "enrich"),
+            edge("enrich", "join"),
+            edge("b", "join"),
+            edge("c", "join"),
+        ]);
+        let problems = validate_workflow_graph(&workflow);
+        assert_eq!(
+            problems,
+            vec![WorkflowGraphProblem::ParallelBranchTooLong {
+                action_id: "fan_out".to_string(),
+                branch_action_id: "a".to_string(),
+            }]
+        );
+        assert!(problems[0].is_fatal());
+    }
+
+    #[test]
+    fn test_validate_trigger_payload() {
+        let schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": {
+                "email": { "type": "string", "x-any-validation": { "type": "string" } },
+                "amount": { "type": "number", "x-any-validation": { "type": "number" } },
+                "plan": {
+                    "type": "string",
+                    "x-any-validation": { "type": { "one_of": ["free", "pro"] } }
+                },
+                "metadata": { "x-any-validation": { "type": "any" } },
+                "note": { "type": "string" }
+            },
+            "required": ["email"],
+            "additionalProperties": false
+        }))
+        .unwrap();
+
+        let valid = json!({
+            "email": "ada@example.com",
+            "amount": "12.50",
+            "plan": "pro",
+            "metadata": { "source": "signup" }
+        });
+        assert!(validate_trigger_payload(&valid, &schema).is_empty());
+
+        let invalid = json!({
+            "amount": "lots",
+            "plan": "enterprise",
+            "metadata": null,
+            "extra": true
+        });
+        let problems = validate_trigger_payload(&invalid, &schema);
+        assert_eq!(problems.len(), 5);
+        assert!(problems[0].starts_with("'amount': Cannot convert value to number"));
+        assert_eq!(problems[1], "'email' is required");
+        assert_eq!(problems[2], "'metadata' is required");
+        assert!(problems[3].starts_with("'plan': Value 'enterprise' is not one of"));
+        assert_eq!(problems[4], "'extra' is not allowed");
+
+        assert_eq!(
+            validate_trigger_payload(&json!([1, 2]), &schema),
+            vec!["Payload must be an object, got: [1,2]".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_normalize_trigger_payload() {
+        let canonical = json!({ "email": "ada@example.com", "amount": 1250 });
+
+        // One provider wraps the event in `data`, another posts it flat
+        let wrapped = json!({
+            "type": "invoice.paid",
+            "data": { "object": { "customer_email": "ada@example.com", "amount_due": 1250 } }
+        });
+        let wrapped_mapping = json!({
+            "email": "{{body.data.object.customer_email}}",
+            "amount": "{{body.data.object.amount_due}}"
+        });
+        assert_eq!(
+            normalize_trigger_payload(&wrapped_mapping, &wrapped).unwrap(),
+            canonical
+        );
+
+        let flat = json!({ "email": "ada@example.com", "amount": 1250, "source": "form" });
+        let flat_mapping = json!({ "email": "{{body.email}}", "amount": "{{body.amount}}" });
+        assert_eq!(
+            normalize_trigger_payload(&flat_mapping, &flat).unwrap(),
+            canonical
+        );
+
+        // A mapping can also pick out one part of the payload as it is
+        assert_eq!(
+            normalize_trigger_payload(&json!("{{body.data.object}}"), &wrapped).unwrap(),
+            wrapped["data"]["object"]
+        );
+
+        let error = normalize_trigger_payload(&wrapped_mapping, &flat).unwrap_err();
+        assert!(error.message.starts_with("Variable not found in context"));
+    }
 }
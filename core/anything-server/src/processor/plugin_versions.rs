@@ -0,0 +1,209 @@
+use node_semver::{Range, Version};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::system_plugins::registry::load_schema_templates;
+use crate::types::workflow_types::WorkflowVersionDefinition;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledPlugin {
+    pub plugin_name: String,
+    pub plugin_version: Version,
+    pub anything_action_version: Version,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnsatisfiedDependency {
+    pub action_id: String,
+    pub plugin_name: String,
+    pub plugin_version: String,
+    pub installed_versions: Vec<String>, // Empty when the plugin isn't installed at all
+}
+
+// What the registry provides, one entry per schema template
+pub fn installed_plugins() -> Result<Vec<InstalledPlugin>, String> {
+    let templates =
+        load_schema_templates().map_err(|e| format!("Failed to load schema templates: {}", e))?;
+
+    let mut installed = Vec::new();
+    for template in templates {
+        let definition = &template["action_template_definition"];
+        let version = |field: &str| {
+            definition[field]
+                .as_str()
+                .and_then(|version| Version::parse(version).ok())
+        };
+        if let (Some(plugin_name), Some(plugin_version), Some(anything_action_version)) = (
+            definition["plugin_name"].as_str(),
+            version("plugin_version"),
+            version("anything_action_version"),
+        ) {
+            installed.push(InstalledPlugin {
+                plugin_name: plugin_name.to_string(),
+                plugin_version,
+                anything_action_version,
+            });
+        }
+    }
+    Ok(installed)
+}
+
+// Points every action at an installed version of its plugin. An exact match is kept, otherwise
+// the newest installed version that is caret compatible (^) with the requested one is used along
+// with that version's anything_action_version. Nothing is changed unless every action can run
+pub fn remap_plugin_versions(
+    workflow: &mut WorkflowVersionDefinition,
+    installed: &[InstalledPlugin],
+) -> Result<(), Vec<UnsatisfiedDependency>> {
+    let mut remapped = Vec::new();
+    let mut unsatisfied = Vec::new();
+
+    for (index, action) in workflow.actions.iter().enumerate() {
+        let candidates: Vec<&InstalledPlugin> = installed
+            .iter()
+            .filter(|plugin| plugin.plugin_name == action.plugin_name.as_str())
+            .collect();
+
+        if candidates
+            .iter()
+            .any(|plugin| plugin.plugin_version == action.plugin_version)
+        {
+            continue;
+        }
+
+        let compatible = Range::parse(format!("^{}", action.plugin_version))
+            .ok()
+            .and_then(|range| {
+                candidates
+                    .iter()
+                    .filter(|plugin| range.satisfies(&plugin.plugin_version))
+                    .max_by(|a, b| a.plugin_version.cmp(&b.plugin_version))
+            });
+
+        match compatible {
+            Some(plugin) => remapped.push((index, *plugin)),
+            None => unsatisfied.push(UnsatisfiedDependency {
+                action_id: action.action_id.clone(),
+                plugin_name: action.plugin_name.to_string(),
+                plugin_version: action.plugin_version.to_string(),
+                installed_versions: candidates
+                    .iter()
+                    .map(|plugin| plugin.plugin_version.to_string())
+                    .collect(),
+            }),
+        }
+    }
+
+    if !unsatisfied.is_empty() {
+        return Err(unsatisfied);
+    }
+
+    for (index, plugin) in remapped {
+        let action = &mut workflow.actions[index];
+        action.plugin_version = plugin.plugin_version.clone();
+        action.anything_action_version = plugin.anything_action_version.clone();
+    }
+    Ok(())
+}
+
+// Writes the versions back into the definition as it was sent, so fields Action doesn't know
+// about survive the import
+pub fn apply_plugin_versions(flow_definition: &mut Value, workflow: &WorkflowVersionDefinition) {
+    if let Some(actions) = flow_definition
+        .get_mut("actions")
+        .and_then(Value::as_array_mut)
+    {
+        for (json, action) in actions.iter_mut().zip(&workflow.actions) {
+            json["plugin_version"] = Value::String(action.plugin_version.to_string());
+            json["anything_action_version"] =
+                Value::String(action.anything_action_version.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store::{action, edge};
+    use serde_json::json;
+
+    fn installed(plugin_name: &str, plugin_version: &str) -> InstalledPlugin {
+        InstalledPlugin {
+            plugin_name: plugin_name.to_string(),
+            plugin_version: Version::parse(plugin_version).unwrap(),
+            anything_action_version: Version::parse("0.2.0").unwrap(),
+        }
+    }
+
+    fn workflow(http_version: &str) -> (Value, WorkflowVersionDefinition) {
+        let mut http = action("http", "action", None);
+        http["plugin_version"] = json!(http_version);
+        http["position_note"] = json!("kept");
+        let mut webhook = action("webhook", "trigger", None);
+        webhook["plugin_name"] = json!("@anything/webhook");
+        let flow_definition = json!({
+            "actions": [webhook, http],
+            "edges": [edge("webhook", "http")]
+        });
+        let workflow = serde_json::from_value(flow_definition.clone()).unwrap();
+        (flow_definition, workflow)
+    }
+
+    #[test]
+    fn test_satisfiable_import_is_remapped() {
+        let installed = vec![
+            installed("@anything/webhook", "0.1.0"),
+            installed("@anything/http", "1.2.0"),
+            installed("@anything/http", "1.4.1"),
+            installed("@anything/http", "2.0.0"),
+        ];
+        let (mut flow_definition, mut workflow) = workflow("1.1.0");
+
+        remap_plugin_versions(&mut workflow, &installed).unwrap();
+        // The exact match stays as it was, http moves to the newest 1.x
+        assert_eq!(workflow.actions[0].plugin_version.to_string(), "0.1.0");
+        assert_eq!(
+            workflow.actions[0].anything_action_version.to_string(),
+            "0.1.0"
+        );
+        assert_eq!(workflow.actions[1].plugin_version.to_string(), "1.4.1");
+        assert_eq!(
+            workflow.actions[1].anything_action_version.to_string(),
+            "0.2.0"
+        );
+
+        apply_plugin_versions(&mut flow_definition, &workflow);
+        assert_eq!(flow_definition["actions"][1]["plugin_version"], "1.4.1");
+        assert_eq!(flow_definition["actions"][1]["position_note"], "kept");
+    }
+
+    #[test]
+    fn test_unsatisfiable_import_lists_dependencies() {
+        let installed = vec![
+            installed("@anything/http", "1.4.1"),
+            installed("@anything/http", "2.0.0"),
+        ];
+        let (_, mut workflow) = workflow("3.0.0");
+
+        let unsatisfied = remap_plugin_versions(&mut workflow, &installed).unwrap_err();
+        assert_eq!(
+            unsatisfied,
+            vec![
+                UnsatisfiedDependency {
+                    action_id: "webhook".to_string(),
+                    plugin_name: "@anything/webhook".to_string(),
+                    plugin_version: "0.1.0".to_string(),
+                    installed_versions: vec![],
+                },
+                UnsatisfiedDependency {
+                    action_id: "http".to_string(),
+                    plugin_name: "@anything/http".to_string(),
+                    plugin_version: "3.0.0".to_string(),
+                    installed_versions: vec!["1.4.1".to_string(), "2.0.0".to_string()],
+                },
+            ]
+        );
+        // Nothing was changed
+        assert_eq!(workflow.actions[1].plugin_version.to_string(), "3.0.0");
+    }
+}
@@ -1,79 +1,105 @@
+use crate::bundler::referenced_actions;
+use crate::processor::approvals::{cancel_paused_flow_session, pause_flow_session, requests_pause};
+use crate::processor::dead_letters::dead_letter_message;
 use crate::processor::execute_task::execute_task;
 use crate::processor::flow_session_cache::FlowSessionData;
-use crate::processor::parsing_utils::get_trigger_node;
+use crate::processor::large_results::{offload_large_result, resolve_results, truncate_result};
+use crate::processor::parallel::{parallel_children, run_parallel_task};
+use crate::processor::parsing_utils::{get_trigger_node, validate_workflow_graph};
+use crate::processor::run_workflow::{
+    flow_session_output, flow_session_output_task, register_session_responder,
+    resolve_flow_session_waiter, SessionResponder,
+};
+use crate::processor::stalled_sessions::fail_stalled_task;
+use crate::processor::write_retries::{write_flow_session_status, write_task_status};
+use crate::templater::Templater;
 use crate::AppState;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use uuid::Uuid;
 
-use crate::processor::db_calls::{
-    create_task, get_workflow_definition, update_flow_session_status, update_task_status,
-};
 use crate::types::{
-    action_types::ActionType,
+    action_types::{Action, ActionType},
+    react_flow_types::Edge,
     task_types::{
-        CreateTaskInput, FlowSessionStatus, Stage, TaskConfig, TaskStatus, TriggerSessionStatus,
+        CreateTaskInput, FlowSessionStatus, Stage, Task, TaskStatus, TriggerSessionStatus,
     },
-    workflow_types::WorkflowVersionDefinition,
+    workflow_types::{DatabaseFlowVersion, WorkflowVersionDefinition},
 };
 
 // Add this near your other type definitions
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProcessorMessage {
     pub workflow_id: Uuid,
     pub version_id: Option<Uuid>,
     pub flow_session_id: Uuid,
     pub trigger_session_id: Uuid,
     pub trigger_task: Option<CreateTaskInput>,
+    // Set by callers waiting on the outcome, neither survives a dead letter
+    #[serde(skip)]
+    pub response: Option<SessionResponder>,
+    #[serde(skip)]
+    pub deadline: Option<DateTime<Utc>>,
 }
 
 pub async fn processor(
     state: Arc<AppState>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("[PROCESSOR] Starting processor");
+    info!("[PROCESSOR] Starting processor");
 
-    // Create a shared set to track active flow sessions
-    let active_flow_sessions = Arc::new(Mutex::new(HashSet::new()));
-    // Get the receiver from the state
-    let mut rx = state.processor_receiver.lock().await;
+    // Shared with every other processor loop so a session only runs on one of them
+    let active_flow_sessions = state.active_flow_sessions.clone();
     // Guard againts too many workflows running at once
     let number_of_processors_semaphore = state.workflow_processor_semaphore.clone();
 
-    while let Some(message) = rx.recv().await {
+    loop {
+        // The receiver is only locked while waiting for the next message, so any number of
+        // processor loops can drain the same queue
+        let message = match state.processor_receiver.lock().await.recv().await {
+            Some(message) => message,
+            None => break,
+        };
+
         // Check if we received shutdown signal
         if state
             .shutdown_signal
             .load(std::sync::atomic::Ordering::SeqCst)
         {
-            println!("[PROCESSOR] Received shutdown signal, stopping processor");
+            info!("[PROCESSOR] Received shutdown signal, stopping processor");
             break;
         }
 
         let workflow_id = message.workflow_id;
         let version_id = message.version_id;
         let flow_session_id = message.flow_session_id;
-        let trigger_task = message.trigger_task;
-        let trigger_task_id = trigger_task.clone().unwrap().trigger_id;
+        let trigger_task = message.trigger_task.clone();
+        // Resumed sessions don't send a trigger task, they fall back to the workflow's trigger below
+        let trigger_task_id = trigger_task.as_ref().map(|task| task.trigger_id.clone());
         let trigger_session_id = message.trigger_session_id;
 
-        println!("[PROCESSOR] Received workflow_id: {}", flow_session_id);
+        info!("[PROCESSOR] Received flow_session_id: {}", flow_session_id);
+
+        // Before the duplicate check so a caller of a session that is already running still hears back
+        register_session_responder(&state, &message).await;
 
         // Check if this flow session is already being processed
         {
             // Use a scope block to automatically drop the lock when done
             let mut active_sessions = active_flow_sessions.lock().await;
             if !active_sessions.insert(flow_session_id) {
-                println!(
+                info!(
                     "[PROCESSOR] Flow session {} is already being processed, skipping",
                     flow_session_id
                 );
                 continue;
             }
-            println!(
+            debug!(
                 "[PROCESSOR] Added flow session {} to active sessions",
                 flow_session_id
             );
@@ -90,71 +116,122 @@ pub async fn processor(
         let client = state.anything_client.clone();
         let active_flow_sessions = Arc::clone(&active_flow_sessions);
 
+        // Everything logged while processing this flow session carries its ids
+        let flow_session_span = info_span!(
+            "flow_session",
+            %flow_session_id,
+            %workflow_id,
+            %trigger_session_id
+        );
+
         // Spawn a new task for this workflow
         //SPAWN NEW PROCESSOR FOR EACH WORKFLOW
-        tokio::spawn(async move {
-            println!(
+        let process_flow_session = async move {
+            info!(
                 "[PROCESSOR] Starting workflow processing for {}",
                 flow_session_id
             );
 
             let mut workflow_definition = None;
             let mut cached_tasks = None;
+            // The version the session started on. A session that has already run tasks keeps
+            // it even if another version is published meanwhile
+            let mut pinned_version_id = version_id;
 
             // Try to get from cache first using a read lock
             {
                 let cache = state.flow_session_cache.read().await;
-                println!(
+                debug!(
                     "[PROCESSOR] Checking cache for flow_session_id: {}",
                     flow_session_id
                 );
                 if let Some(session_data) = cache.get(&flow_session_id) {
+                    pinned_version_id = session_data.workflow_version_id.or(pinned_version_id);
                     if let Some(workflow) = &session_data.workflow {
-                        println!(
+                        debug!(
                             "[PROCESSOR] Found workflow in cache for flow_session_id: {}",
                             flow_session_id
                         );
                         workflow_definition = Some(workflow.clone());
                     }
                     //When we hydrate old tasks this will have items init from hydrate_processor
-                    cached_tasks = Some(session_data.tasks);
+                    cached_tasks = Some(session_data.into_tasks());
                 }
             }
 
             // Only fetch flow definition from DB if we didn't find it in cache
             if workflow_definition.is_none() {
-                println!(
+                debug!(
                 "[PROCESSOR] No workflow found in cache, fetching from DB for flow_session_id: {}",
                 flow_session_id
             );
 
-                let workflow =
-                    match get_workflow_definition(state.clone(), &workflow_id, version_id.as_ref())
+                // A session that isn't cached can still have tasks in the DB, e.g. it was
+                // started before a restart. Load them so the bundler sees their results
+                let mut session_tasks = Vec::new();
+                if cached_tasks.is_none() {
+                    session_tasks = match state
+                        .task_store
+                        .get_tasks_for_session(&flow_session_id)
                         .await
                     {
-                        Ok(w) => {
-                            println!("[PROCESSOR] Successfully fetched workflow from DB");
-                            w
-                        }
+                        Ok(tasks) => tasks,
                         Err(e) => {
-                            println!("[PROCESSOR] Error getting workflow definition: {}", e);
+                            // Starting over could run the trigger a second time
+                            error!("[PROCESSOR] Error getting tasks for session: {}", e);
+                            dead_letter_message(&state, &message, &e).await;
+                            active_flow_sessions.lock().await.remove(&flow_session_id);
                             return;
                         }
                     };
+                }
+
+                if pinned_version_id.is_none() {
+                    pinned_version_id = session_tasks.first().map(|task| task.flow_version_id);
+                }
+                let resolved =
+                    resolve_workflow_version(&state, &workflow_id, pinned_version_id.as_ref())
+                        .await;
+                let workflow = match resolved {
+                    Ok(w) => {
+                        debug!("[PROCESSOR] Successfully fetched workflow from DB");
+                        w
+                    }
+                    Err(e) => {
+                        error!("[PROCESSOR] Error getting workflow definition: {}", e);
+                        dead_letter_message(&state, &message, &e).await;
+                        state
+                            .flow_session_cache
+                            .write()
+                            .await
+                            .invalidate(&flow_session_id);
+                        active_flow_sessions.lock().await.remove(&flow_session_id);
+                        return;
+                    }
+                };
 
                 // Only update cache if there isn't already data there
-                //TODO: this feels like it could be wrong. In what situation is do we need to fetch worfklow but also no session in cache yet?
                 {
                     let mut cache = state.flow_session_cache.write().await;
                     if cache.get(&flow_session_id).is_none() {
-                        println!("[PROCESSOR] Creating new session data in cache");
-                        let session_data = FlowSessionData {
-                            workflow: Some(workflow.clone()),
-                            tasks: HashMap::new(),
+                        debug!("[PROCESSOR] Creating new session data in cache");
+                        let mut session_data = FlowSessionData::new(
+                            Some(workflow.clone()),
                             flow_session_id,
                             workflow_id,
-                            workflow_version_id: version_id,
-                        };
+                            Some(workflow.flow_version_id),
+                        );
+                        for task in session_tasks {
+                            session_data.insert_task(task);
+                        }
+                        if !session_data.tasks().is_empty() {
+                            info!(
+                                "[PROCESSOR] Loaded {} existing tasks for flow_session_id: {}",
+                                session_data.tasks().len(),
+                                flow_session_id
+                            );
+                            cached_tasks = Some(session_data.tasks().clone());
+                        }
                         cache.set(&flow_session_id, session_data);
                     }
                 }
@@ -162,7 +239,7 @@ pub async fn processor(
                 workflow_definition = Some(workflow);
             }
 
-            println!(
+            debug!(
                 "[PROCESSOR] Workflow definition status: {:?}",
                 workflow_definition.is_some()
             );
@@ -170,16 +247,63 @@ pub async fn processor(
             let workflow = match &workflow_definition {
                 Some(w) => w,
                 None => {
-                    println!("[PROCESSOR] No workflow definition found");
+                    error!("[PROCESSOR] No workflow definition found");
                     //This should never happen
                     return;
                 }
             };
 
-            println!("[PROCESSOR] Starting workflow execution");
+            // Unreachable actions are only a warning. Edges to actions that don't exist stop the run
+            let graph_problems = validate_workflow_graph(&workflow.flow_definition);
+            for problem in &graph_problems {
+                warn!("[PROCESSOR] Workflow graph problem: {}", problem);
+            }
+            let fatal_problem = graph_problems
+                .iter()
+                .find(|problem| problem.is_fatal())
+                .cloned();
 
-            // Create initial trigger task
-            let trigger_node = get_trigger_node(&workflow.flow_definition).unwrap();
+            // Every run starts from the trigger so a missing or disconnected one stops it here
+            let trigger_node = match (fatal_problem, get_trigger_node(&workflow.flow_definition)) {
+                (None, Ok(trigger_node)) => trigger_node,
+                (Some(problem), _) | (None, Err(problem)) => {
+                    warn!(
+                        "[PROCESSOR] Refusing to run invalid workflow for {}",
+                        flow_session_id
+                    );
+                    let mut completions = state.flow_completions.lock().await;
+                    if let Some(completion) = completions.remove(&flow_session_id.to_string()) {
+                        if completion.needs_response {
+                            let _ = completion
+                                .sender
+                                .send(json!({ "error": format!("Invalid workflow: {}", problem) }));
+                        }
+                    }
+                    drop(completions);
+                    state
+                        .flow_session_cache
+                        .write()
+                        .await
+                        .invalidate(&flow_session_id);
+                    active_flow_sessions.lock().await.remove(&flow_session_id);
+                    resolve_flow_session_waiter(
+                        &state,
+                        &flow_session_id,
+                        FlowSessionStatus::Failed,
+                        Some(json!({ "error": format!("Invalid workflow: {}", problem) })),
+                    )
+                    .await;
+                    return;
+                }
+            };
+            let trigger_task_id = trigger_task_id.unwrap_or_else(|| trigger_node.action_id.clone());
+
+            debug!("[PROCESSOR] Starting workflow execution");
+
+            // Stays Running if we stop early, e.g. on shutdown
+            let mut session_status = FlowSessionStatus::Running;
+            // What the session ended with when the cache can't tell us, see fail_uncached_task
+            let mut failure_output = None;
 
             //If there are no tasks in cache, we need to create the trigger task
             let mut current_task = if cached_tasks.is_none()
@@ -187,35 +311,31 @@ pub async fn processor(
             {
                 // Only create trigger task if there are no existing tasks in cache
                 let initial_task = if let Some(trigger_task) = trigger_task {
-                    trigger_task
+                    prepare_trigger_task(trigger_task, workflow, trigger_node, &trigger_session_id)
                 } else {
+                    let trigger = trigger_node.executable();
                     CreateTaskInput {
                         account_id: workflow.account_id.to_string(),
                         processing_order: 0,
                         task_status: TaskStatus::Running.as_str().to_string(),
                         flow_id: workflow_id.to_string(),
                         flow_version_id: workflow.flow_version_id.to_string(),
-                        action_label: trigger_node.label.clone(),
+                        action_label: trigger.label.to_string(),
                         trigger_id: trigger_task_id.clone(),
                         trigger_session_id: trigger_session_id.to_string(),
                         trigger_session_status: TriggerSessionStatus::Running.as_str().to_string(),
                         flow_session_id: flow_session_id.to_string(),
                         flow_session_status: FlowSessionStatus::Running.as_str().to_string(),
-                        action_id: trigger_node.action_id.clone(),
+                        action_id: trigger.action_id.to_string(),
                         r#type: ActionType::Trigger,
-                        plugin_name: trigger_node.plugin_name.clone(),
-                        plugin_version: trigger_node.plugin_version.clone(),
+                        plugin_name: trigger.plugin_name.clone(),
+                        plugin_version: trigger.plugin_version.clone(),
                         stage: if workflow.published {
                             Stage::Production.as_str().to_string()
                         } else {
                             Stage::Testing.as_str().to_string()
                         },
-                        config: TaskConfig {
-                            inputs: Some(trigger_node.inputs.clone().unwrap()),
-                            inputs_schema: Some(trigger_node.inputs_schema.clone().unwrap()),
-                            plugin_config: Some(trigger_node.plugin_config.clone()),
-                            plugin_config_schema: Some(trigger_node.plugin_config_schema.clone()),
-                        },
+                        config: trigger.task_config(),
                         result: None,
                         error: None,
                         started_at: Some(Utc::now()),
@@ -223,23 +343,61 @@ pub async fn processor(
                     }
                 };
 
+                // Linear workflows can create every task with the trigger task in one insert
+                let planned_actions = if state
+                    .batch_task_creation
+                    .load(std::sync::atomic::Ordering::SeqCst)
+                {
+                    plan_linear_tasks(&workflow.flow_definition, trigger_node)
+                } else {
+                    None
+                };
+                let created = match planned_actions {
+                    Some(planned_actions) if !planned_actions.is_empty() => {
+                        debug!(
+                            "[PROCESSOR] Creating {} planned tasks with the trigger task",
+                            planned_actions.len()
+                        );
+                        let mut task_inputs = vec![initial_task];
+                        for (i, action) in planned_actions.into_iter().enumerate() {
+                            let planned_task =
+                                planned_task_input(&task_inputs[0], action, i as i32 + 1);
+                            task_inputs.push(planned_task);
+                        }
+                        state.task_store.create_tasks_batch(&task_inputs).await
+                    }
+                    _ => state
+                        .task_store
+                        .create_task(&initial_task)
+                        .await
+                        .map(|task| vec![task]),
+                };
+
                 // Start with trigger task
-                match create_task(state.clone(), &initial_task).await {
-                    Ok(task) => {
-                        // Update cache with new task
-                        let mut cache = state.flow_session_cache.write().await;
-                        if cache.add_task(&flow_session_id, task.clone()) {
-                            Some(task)
-                        } else {
-                            println!(
-                                "[PROCESSOR] Failed to add task to cache for flow_session_id: {}",
-                                flow_session_id
-                            );
-                            Some(task)
+                match created {
+                    Ok(tasks) => {
+                        let task = tasks[0].clone();
+                        // Update cache with new tasks
+                        let added = {
+                            let mut cache = state.flow_session_cache.write().await;
+                            tasks
+                                .into_iter()
+                                .try_for_each(|task| cache.add_task(&flow_session_id, task))
+                        };
+                        match added {
+                            Ok(()) => Some(task),
+                            Err(e) => {
+                                failure_output = Some(
+                                    fail_uncached_task(&state, &flow_session_id, &task, e).await,
+                                );
+                                session_status = FlowSessionStatus::Failed;
+                                None
+                            }
                         }
                     }
                     Err(e) => {
-                        println!("[PROCESSOR] Error creating initial task: {}", e);
+                        error!("[PROCESSOR] Error creating initial task: {}", e);
+                        dead_letter_message(&state, &message, &e).await;
                         None
                     }
                 }
@@ -247,14 +405,19 @@ pub async fn processor(
                 // We have existing tasks - find the last incomplete task or the highest processing order
                 let existing_tasks = cached_tasks.as_ref().unwrap();
 
-                // First try to find an incomplete task
-                let incomplete_task = existing_tasks.values().find(|task| {
-                    task.task_status == TaskStatus::Running
-                        || task.task_status == TaskStatus::Pending
-                });
+                // First try to find an incomplete task. With planned tasks there can be several,
+                // the earliest one is next. A waiting approval pauses the session again
+                let incomplete_task = existing_tasks
+                    .values()
+                    .filter(|task| {
+                        task.task_status == TaskStatus::Running
+                            || task.task_status == TaskStatus::Pending
+                            || task.task_status == TaskStatus::Waiting
+                    })
+                    .min_by_key(|task| task.processing_order);
 
                 if let Some(task) = incomplete_task {
-                    println!(
+                    info!(
                         "[PROCESSOR] Resuming from incomplete task: {}",
                         task.task_id
                     );
@@ -268,30 +431,49 @@ pub async fn processor(
                         .max_by_key(|task| task.processing_order);
 
                     if let Some(task) = last_completed_task {
-                        println!(
+                        debug!(
                             "[PROCESSOR] All existing tasks completed, finding next task after: {}",
                             task.task_id
                         );
                         // Find the next action after this completed task using the graph
                         let graph = create_workflow_graph(&workflow.flow_definition);
-                        if let Some(neighbors) = graph.get(&task.action_id) {
-                            for neighbor_id in neighbors {
+                        let edges = graph.get(&task.action_id);
+                        let condition_tasks =
+                            condition_tasks(existing_tasks, edges.into_iter().flatten());
+                        let condition_context =
+                            match get_condition_context(state.clone(), condition_tasks).await {
+                                Ok(condition_context) => Some(condition_context),
+                                Err(e) => {
+                                    failure_output = Some(
+                                        fail_uncached_task(&state, &flow_session_id, task, e).await,
+                                    );
+                                    session_status = FlowSessionStatus::Failed;
+                                    None
+                                }
+                            };
+                        let mut next_task = None;
+                        if let (Some(edges), Some(condition_context)) = (edges, condition_context) {
+                            for edge in edges {
+                                if !edge_is_taken(edge, &condition_context) {
+                                    continue;
+                                }
                                 let next_action = workflow
                                     .flow_definition
                                     .actions
                                     .iter()
-                                    .find(|action| &action.action_id == neighbor_id);
+                                    .find(|action| action.action_id == edge.target);
 
                                 //We found the next action to run in graph. lets make a task for it
                                 if let Some(action) = next_action {
                                     // Create the next task
+                                    let action = action.executable();
                                     let next_task_input = CreateTaskInput {
                                         account_id: workflow.account_id.to_string(),
                                         processing_order: task.processing_order + 1,
                                         task_status: TaskStatus::Running.as_str().to_string(),
                                         flow_id: workflow_id.to_string(),
                                         flow_version_id: workflow.flow_version_id.to_string(),
-                                        action_label: action.label.clone(),
+                                        action_label: action.label.to_string(),
                                         trigger_id: trigger_task_id.clone(),
                                         trigger_session_id: trigger_session_id.to_string(),
                                         trigger_session_status: TriggerSessionStatus::Running
@@ -301,55 +483,59 @@ pub async fn processor(
                                         flow_session_status: FlowSessionStatus::Running
                                             .as_str()
                                             .to_string(),
-                                        action_id: action.action_id.clone(),
+                                        action_id: action.action_id.to_string(),
                                         r#type: action.r#type.clone(),
                                         plugin_name: action.plugin_name.clone(),
                                         plugin_version: action.plugin_version.clone(),
-                                        stage: if workflow.published {
-                                            Stage::Production.as_str().to_string()
-                                        } else {
-                                            Stage::Testing.as_str().to_string()
-                                        },
-                                        config: TaskConfig {
-                                            inputs: Some(action.inputs.clone().unwrap()),
-                                            inputs_schema: Some(
-                                                action.inputs_schema.clone().unwrap(),
-                                            ),
-                                            plugin_config: Some(action.plugin_config.clone()),
-                                            plugin_config_schema: Some(
-                                                action.plugin_config_schema.clone(),
-                                            ),
-                                        },
+                                        stage: task.stage.as_str().to_string(),
+                                        config: action.task_config(),
                                         result: None,
                                         error: None,
                                         started_at: Some(Utc::now()),
-                                        test_config: None,
+                                        test_config: action.test_config.cloned(),
                                     };
 
-                                    match create_task(state.clone(), &next_task_input).await {
+                                    next_task = match state
+                                        .task_store
+                                        .create_task(&next_task_input)
+                                        .await
+                                    {
                                         Ok(new_task) => {
-                                            let mut cache = state.flow_session_cache.write().await;
-                                            if cache.add_task(&flow_session_id, new_task.clone()) {
-                                                Some(new_task)
-                                            } else {
-                                                println!(
-                                                    "[PROCESSOR] Failed to add task to cache for flow_session_id: {}",
-                                                    flow_session_id
-                                                );
-                                                Some(new_task)
+                                            let added = state
+                                                .flow_session_cache
+                                                .write()
+                                                .await
+                                                .add_task(&flow_session_id, new_task.clone());
+                                            match added {
+                                                Ok(()) => Some(new_task),
+                                                Err(e) => {
+                                                    failure_output = Some(
+                                                        fail_uncached_task(
+                                                            &state,
+                                                            &flow_session_id,
+                                                            &new_task,
+                                                            e,
+                                                        )
+                                                        .await,
+                                                    );
+                                                    session_status = FlowSessionStatus::Failed;
+                                                    None
+                                                }
                                             }
                                         }
                                         Err(e) => {
-                                            println!("[PROCESSOR] Error creating next task: {}", e);
+                                            error!("[PROCESSOR] Error creating next task: {}", e);
                                             None
                                         }
                                     };
+                                    // Same as the main loop, only the first edge that is taken
+                                    break;
                                 }
                             }
                         }
-                        None // No next task found
+                        next_task // None if there is nothing left to run
                     } else {
-                        println!("[PROCESSOR] No existing tasks found in cache");
+                        debug!("[PROCESSOR] No existing tasks found in cache");
                         None
                     }
                 }
@@ -367,143 +553,290 @@ pub async fn processor(
                     .shutdown_signal
                     .load(std::sync::atomic::Ordering::SeqCst)
                 {
-                    println!("[PROCESSOR] Received shutdown signal, stopping task processing");
+                    info!("[PROCESSOR] Received shutdown signal, stopping task processing");
+                    break;
+                }
+
+                // Check if the user canceled this flow session before running the next task
+                if state
+                    .canceled_flow_sessions
+                    .read()
+                    .await
+                    .contains(&flow_session_id)
+                {
+                    info!(
+                        "[PROCESSOR] Flow session {} was canceled, stopping task processing",
+                        flow_session_id
+                    );
+                    (session_status, failure_output) =
+                        stop_canceled_session(state.clone(), &flow_session_id, &task).await;
+                    break;
+                }
+
+                // The session stops here until resume_flow_session completes the approval
+                if requests_pause(&task) {
+                    pause_flow_session(&state, &flow_session_id, &task).await;
+                    session_status = FlowSessionStatus::Paused;
                     break;
                 }
 
                 // Execute the current task and handle its result
-                println!("[PROCESSOR] Executing task: {}", task.task_id);
+                info!("[PROCESSOR] Executing task: {}", task.task_id);
+
+                // A Parallel task runs its children itself, what comes next hangs off them
+                let children = if task.r#type == ActionType::Parallel.as_str() {
+                    parallel_children(&workflow_def.actions, &graph, &task.action_id)
+                } else {
+                    Vec::new()
+                };
+                let processing_order = task.processing_order + children.len() as i32;
+
+                let task_span = info_span!(
+                    "task",
+                    task_id = %task.task_id,
+                    action_id = %task.action_id
+                );
 
-                let processing_order = task.processing_order;
+                let action = workflow_def
+                    .actions
+                    .iter()
+                    .find(|action| action.action_id == task.action_id);
 
-                let (task_result, bundled_context) =
-                    match execute_task(state.clone(), &client, &task).await {
-                        Ok(success_value) => {
-                            println!("[PROCESSOR] Task {} completed successfully", task.task_id);
-                            success_value
-                        }
-                        Err(error) => {
-                            println!("[PROCESSOR] Task {} failed: {:?}", task.task_id, error);
-
-                            // Update task status to failed
-                            let state_clone = state.clone();
-                            let task_id = task.task_id.clone();
-                            let error_clone = error.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = update_task_status(
-                                    state_clone,
+                let executed = if task.r#type == ActionType::Parallel.as_str() {
+                    run_parallel_task(state.clone(), &client, &flow_session_id, &task, &children)
+                        .instrument(task_span.clone())
+                        .await
+                } else {
+                    execute_task(state.clone(), &client, &task, action)
+                        .instrument(task_span.clone())
+                        .await
+                };
+                let (task_result, bundled_inputs, bundled_context, skipped) = match executed {
+                    Ok(success_value) => {
+                        info!("[PROCESSOR] Task {} completed successfully", task.task_id);
+                        success_value
+                    }
+                    Err(error) if error.canceled => {
+                        info!(
+                            "[PROCESSOR] Flow session {} was canceled during task {}",
+                            flow_session_id, task.task_id
+                        );
+                        (session_status, failure_output) =
+                            stop_canceled_session(state.clone(), &flow_session_id, &task).await;
+                        break;
+                    }
+                    Err(error) => {
+                        // Only the error itself, the context can hold rendered secrets
+                        warn!("[PROCESSOR] Task {} failed: {}", task.task_id, error.error);
+
+                        // Update task status to failed
+                        let state_clone = state.clone();
+                        let task_id = task.task_id.clone();
+                        let error_clone = error.clone();
+                        tokio::spawn(
+                            async move {
+                                write_task_status(
+                                    &state_clone,
                                     &task_id,
                                     &TaskStatus::Failed,
                                     Some(error_clone.context),
+                                    error_clone.bundled_inputs,
                                     None,
                                     Some(error_clone.error),
                                 )
                                 .await
-                                {
-                                    println!("[PROCESSOR] Failed to update task status: {}", e);
-                                }
-                            });
+                            }
+                            .instrument(task_span.clone()),
+                        );
 
-                            // Update flow session status to failed
-                            let state_clone = state.clone();
-                            let flow_session_id_clone = flow_session_id.clone();
-                            tokio::spawn(async move {
-                                if let Err(e) = update_flow_session_status(
+                        // Update flow session status to failed
+                        let state_clone = state.clone();
+                        let flow_session_id_clone = flow_session_id.clone();
+                        tokio::spawn(
+                            async move {
+                                write_flow_session_status(
                                     &state_clone,
                                     &flow_session_id_clone,
                                     &FlowSessionStatus::Failed,
                                     &TriggerSessionStatus::Failed,
                                 )
                                 .await
-                                {
-                                    println!(
-                                        "[PROCESSOR] Failed to update flow session status: {}",
-                                        e
-                                    );
-                                }
-                            });
+                            }
+                            .instrument(task_span.clone()),
+                        );
 
-                            // Update cache
-                            {
-                                let mut cache = state.flow_session_cache.write().await;
-                                let mut task_copy = task.clone();
-                                task_copy.result = Some(error.error.clone());
-                                task_copy.context = Some(error.context.clone());
-                                task_copy.task_status = TaskStatus::Failed;
-                                task_copy.ended_at = Some(Utc::now());
-                                let _ = cache.update_task(&flow_session_id, task_copy);
+                        // Update cache
+                        {
+                            let mut cache = state.flow_session_cache.write().await;
+                            let mut task_copy = task.clone();
+                            task_copy.result = Some(error.error.clone());
+                            task_copy.context = Some(error.context.clone());
+                            task_copy.bundled_inputs = error.bundled_inputs.clone();
+                            task_copy.task_status = TaskStatus::Failed;
+                            task_copy.ended_at = Some(Utc::now());
+                            info!(
+                                duration_ms = ?task_copy.duration_ms(),
+                                "[PROCESSOR] Task {} failed",
+                                task.task_id
+                            );
+                            // The session is failing either way, so only the store has to be right
+                            if let Err(e) = cache.update_task(&flow_session_id, task_copy) {
+                                warn!("[PROCESSOR] Failed to update task in cache: {}", e);
                             }
+                        }
 
-                            println!("[PROCESSOR] Workflow failed: {}", flow_session_id);
+                        warn!("[PROCESSOR] Workflow failed: {}", flow_session_id);
 
-                            // Send error response to webhook if needed
-                            let mut completions = state.flow_completions.lock().await;
-                            if let Some(completion) =
-                                completions.remove(&flow_session_id.to_string())
-                            {
-                                if completion.needs_response {
-                                    println!(
+                        // Send error response to webhook if needed
+                        let mut completions = state.flow_completions.lock().await;
+                        if let Some(completion) = completions.remove(&flow_session_id.to_string()) {
+                            if completion.needs_response {
+                                debug!(
                                     "[PROCESSOR] Sending error response through completion channel"
                                 );
-                                    let _ = completion.sender.send(error.error.clone());
-                                }
+                                let _ = completion.sender.send(error.error.clone());
                             }
-                            break; // Exit the while loop
                         }
-                    };
+                        session_status = FlowSessionStatus::Failed;
+                        break; // Exit the while loop
+                    }
+                };
+
+                // Big results are stored separately and both the db and cache keep a reference
+                let task_result = offload_large_result(state.clone(), &task, task_result).await;
+
+                // Past the cache's limit it only keeps a preview, the bundler reads the whole
+                // result from the store
+                let max_result_bytes = state.flow_session_cache.read().await.max_result_bytes();
+                let truncated_result = task_result
+                    .as_ref()
+                    .and_then(|result| truncate_result(result, max_result_bytes));
+                let result_truncated = truncated_result.is_some();
+
+                // Skipped tasks are done too, we still move on to their successors
+                let task_status = if skipped {
+                    TaskStatus::Skipped
+                } else {
+                    TaskStatus::Completed
+                };
+
+                //Update cache with result the same we do the db. these need to match!
+                // Picking the next action reads the cache, without the result it would end the
+                // session as if this was the last task
+                let updated = {
+                    let mut cache = state.flow_session_cache.write().await;
+                    let mut task_copy = task.clone();
+                    task_copy.result = truncated_result.or_else(|| task_result.clone());
+                    task_copy.context = Some(bundled_context.clone());
+                    task_copy.bundled_inputs = Some(bundled_inputs.clone());
+                    task_copy.task_status = task_status.clone();
+                    task_copy.ended_at = Some(Utc::now());
+                    info!(
+                        duration_ms = ?task_copy.duration_ms(),
+                        "[PROCESSOR] Task {} ended as {}",
+                        task.task_id,
+                        task_status.as_str()
+                    );
+                    cache.update_task(&flow_session_id, task_copy)
+                };
+                if let Err(e) = updated {
+                    failure_output =
+                        Some(fail_uncached_task(&state, &flow_session_id, &task, e).await);
+                    session_status = FlowSessionStatus::Failed;
+                    break;
+                }
 
                 // Spawn task status update to DB asynchronously
                 let state_clone = state.clone();
                 let task_id = task.task_id.clone();
-                let task_result_clone = task_result.clone();
-                let bundled_context_clone = bundled_context.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = update_task_status(
-                        state_clone,
+                let update_task = async move {
+                    write_task_status(
+                        &state_clone,
                         &task_id,
-                        &TaskStatus::Completed,
-                        Some(bundled_context_clone),
-                        task_result_clone.clone(),
+                        &task_status,
+                        Some(bundled_context),
+                        Some(bundled_inputs),
+                        task_result,
                         None,
                     )
                     .await
-                    {
-                        println!("[PROCESSOR] Failed to update task status: {}", e);
-                    }
-                });
-
-                //Update cache with result the same we do the db. these need to match!
-                {
-                    let mut cache = state.flow_session_cache.write().await;
-                    let mut task_copy = task.clone();
-                    task_copy.result = task_result;
-                    task_copy.context = Some(bundled_context);
-                    task_copy.task_status = TaskStatus::Completed;
-                    task_copy.ended_at = Some(Utc::now());
-                    let _ = cache.update_task(&flow_session_id, task_copy);
+                }
+                .instrument(task_span);
+                // A truncated result is only whole in the store, it has to be there before the
+                // next task is bundled
+                if result_truncated {
+                    update_task.await;
+                } else {
+                    tokio::spawn(update_task);
                 }
 
-                let next_action = if let Some(neighbors) = graph.get(&task.action_id) {
+                // After a Parallel its children have run, the next action comes after them
+                let next_sources: Vec<&str> = if children.is_empty() {
+                    vec![task.action_id.as_str()]
+                } else {
+                    children
+                        .iter()
+                        .map(|child| child.action_id.as_str())
+                        .collect()
+                };
+                let edges: Vec<&Edge> = next_sources
+                    .iter()
+                    .filter_map(|source| graph.get(*source))
+                    .flatten()
+                    .collect();
+                let next_action = if !edges.is_empty() {
+                    let condition_tasks = state
+                        .flow_session_cache
+                        .read()
+                        .await
+                        .get(&flow_session_id)
+                        .map(|session_data| {
+                            condition_tasks(session_data.tasks(), edges.iter().copied())
+                        })
+                        .unwrap_or_default();
+                    let condition_context =
+                        match get_condition_context(state.clone(), condition_tasks).await {
+                            Ok(condition_context) => condition_context,
+                            Err(e) => {
+                                failure_output = Some(
+                                    fail_uncached_task(&state, &flow_session_id, &task, e).await,
+                                );
+                                session_status = FlowSessionStatus::Failed;
+                                break;
+                            }
+                        };
+
                     let mut next_action = None;
-                    // Get the first unprocessed neighbor
-                    //TODO: this is where we would handle if we have multiple paths to take and can parallelize
-                    for neighbor_id in neighbors {
-                        let neighbor = workflow_def
-                            .actions
-                            .iter()
-                            .find(|action| &action.action_id == neighbor_id);
-
-                        if let Some(action) = neighbor {
-                            // Check if this task has already been processed
-                            let cache = state.flow_session_cache.read().await;
-                            if let Some(session_data) = cache.get(&flow_session_id) {
-                                if !session_data
-                                    .tasks
-                                    .iter()
-                                    .any(|(_, t)| t.action_id == action.action_id)
-                                {
-                                    next_action = Some(action.clone());
-                                    break;
+                    let cache = state.flow_session_cache.read().await;
+                    if let Some(session_data) = cache.get(&flow_session_id) {
+                        // Get the first unprocessed neighbor whose edge condition passes
+                        //TODO: this is where we would handle if we have multiple paths to take and can parallelize
+                        for edge in edges {
+                            if !edge_is_taken(edge, &condition_context) {
+                                continue;
+                            }
+
+                            let neighbor = workflow_def
+                                .actions
+                                .iter()
+                                .find(|action| action.action_id == edge.target);
+
+                            if let Some(action) = neighbor {
+                                // Check if this task has already been processed. Planned tasks
+                                // exist before they run and are still Pending
+                                match session_data.get_task_by_action_id(&action.action_id) {
+                                    None => {
+                                        next_action = Some((action.clone(), None));
+                                        break;
+                                    }
+                                    Some(planned_task)
+                                        if planned_task.task_status == TaskStatus::Pending =>
+                                    {
+                                        next_action =
+                                            Some((action.clone(), Some(planned_task.clone())));
+                                        break;
+                                    }
+                                    Some(_) => {}
                                 }
                             }
                         }
@@ -513,58 +846,73 @@ pub async fn processor(
                     None
                 };
 
+                let (next_action, planned_task) = match next_action {
+                    Some((next_action, planned_task)) => (Some(next_action), planned_task),
+                    None => (None, None),
+                };
+
                 // Create next task if available
-                current_task = if let Some(next_action) = next_action {
+                current_task = if let Some(planned_task) = planned_task {
+                    match start_planned_task(&state, &flow_session_id, planned_task.clone()).await {
+                        Ok(task) => Some(task),
+                        Err(e) => {
+                            failure_output = Some(
+                                fail_uncached_task(&state, &flow_session_id, &planned_task, e)
+                                    .await,
+                            );
+                            session_status = FlowSessionStatus::Failed;
+                            None
+                        }
+                    }
+                } else if let Some(next_action) = next_action {
+                    let next_action = next_action.executable();
                     let next_task_input = CreateTaskInput {
                         account_id: workflow.account_id.to_string(),
                         processing_order: processing_order + 1,
                         task_status: TaskStatus::Running.as_str().to_string(), //we create tasks when we start them
                         flow_id: workflow_id.to_string(),
                         flow_version_id: workflow.flow_version_id.to_string(),
-                        action_label: next_action.label.clone(),
+                        action_label: next_action.label.to_string(),
                         trigger_id: trigger_task_id.clone(),
                         trigger_session_id: trigger_session_id.to_string(),
                         trigger_session_status: TriggerSessionStatus::Pending.as_str().to_string(),
                         flow_session_id: flow_session_id.to_string(),
                         flow_session_status: FlowSessionStatus::Pending.as_str().to_string(),
-                        action_id: next_action.action_id,
-                        r#type: next_action.r#type,
+                        action_id: next_action.action_id.to_string(),
+                        r#type: next_action.r#type.clone(),
                         plugin_name: next_action.plugin_name.clone(),
                         plugin_version: next_action.plugin_version.clone(),
-                        stage: if workflow.published {
-                            Stage::Production.as_str().to_string()
-                        } else {
-                            Stage::Testing.as_str().to_string()
-                        },
-                        config: TaskConfig {
-                            inputs: Some(next_action.inputs.clone().unwrap()),
-                            inputs_schema: Some(next_action.inputs_schema.clone().unwrap()),
-                            plugin_config: Some(next_action.plugin_config.clone()),
-                            plugin_config_schema: Some(next_action.plugin_config_schema.clone()),
-                        },
+                        // Tasks run in the stage their session started in, e.g. staging set on the trigger task
+                        stage: task.stage.as_str().to_string(),
+                        config: next_action.task_config(),
                         result: None,
                         error: None,
-                        test_config: None,
+                        test_config: next_action.test_config.cloned(),
                         started_at: Some(Utc::now()),
                     };
 
-                    match create_task(state.clone(), &next_task_input).await {
+                    match state.task_store.create_task(&next_task_input).await {
                         Ok(new_task) => {
                             // Update cache
-                            {
-                                let mut cache = state.flow_session_cache.write().await;
-                                if let Some(mut session_data) = cache.get(&flow_session_id) {
-                                    session_data
-                                        .tasks
-                                        .insert(new_task.task_id.clone(), new_task.clone());
-                                    cache.set(&flow_session_id, session_data);
+                            let added = state
+                                .flow_session_cache
+                                .write()
+                                .await
+                                .add_task(&flow_session_id, new_task.clone());
+                            match added {
+                                Ok(()) => Some(new_task),
+                                Err(e) => {
+                                    failure_output = Some(
+                                        fail_uncached_task(&state, &flow_session_id, &new_task, e)
+                                            .await,
+                                    );
+                                    session_status = FlowSessionStatus::Failed;
+                                    None
                                 }
-                            } // Lock is dropped here
-                              // processing_order += 1;
-                            Some(new_task)
+                            }
                         }
                         Err(e) => {
-                            println!("[PROCESSOR] Error creating next task: {}", e);
+                            error!("[PROCESSOR] Error creating next task: {}", e);
                             None
                         }
                     }
@@ -572,34 +920,55 @@ pub async fn processor(
                     // No more tasks - workflow is complete
                     let state_clone = state.clone();
                     let flow_session_id_clone = flow_session_id.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = update_flow_session_status(
-                            &state_clone,
-                            &flow_session_id_clone,
-                            &FlowSessionStatus::Completed,
-                            &TriggerSessionStatus::Completed,
-                        )
-                        .await
-                        {
-                            println!("[PROCESSOR] Failed to update flow session status: {}", e);
+                    tokio::spawn(
+                        async move {
+                            write_flow_session_status(
+                                &state_clone,
+                                &flow_session_id_clone,
+                                &FlowSessionStatus::Completed,
+                                &TriggerSessionStatus::Completed,
+                            )
+                            .await
                         }
-                    });
+                        .in_current_span(),
+                    );
 
-                    println!("[PROCESSOR] Workflow completed: {}", flow_session_id);
+                    info!("[PROCESSOR] Workflow completed: {}", flow_session_id);
+                    session_status = FlowSessionStatus::Completed;
                     None
                 };
             }
 
-            println!(
+            info!(
                 "[PROCESSOR] Completed workflow processing for {}",
                 flow_session_id
             );
 
+            // Planned tasks that never got to run would stay Pending forever. A session that
+            // stopped early or paused keeps them so it can resume
+            if matches!(
+                session_status,
+                FlowSessionStatus::Failed | FlowSessionStatus::Canceled
+            ) {
+                cancel_planned_tasks(&state, &flow_session_id).await;
+            }
+
+            // Read before the tasks leave the cache, run_workflow_and_wait gets it once we're done
+            let output_task = state
+                .flow_session_cache
+                .read()
+                .await
+                .get(&flow_session_id)
+                .and_then(|session_data| flow_session_output_task(session_data.tasks()).cloned());
+            let output = flow_session_output(state.clone(), output_task)
+                .await
+                .or(failure_output);
+
             // Invalidate cache for completed flow session
             {
                 let mut cache = state.flow_session_cache.write().await;
                 cache.invalidate(&flow_session_id);
-                println!(
+                debug!(
                     "[PROCESSOR] Removed flow session {} from cache",
                     flow_session_id
                 );
@@ -607,24 +976,1554 @@ pub async fn processor(
 
             // // Remove the flow session from active sessions when done
             active_flow_sessions.lock().await.remove(&flow_session_id);
+            state
+                .canceled_flow_sessions
+                .write()
+                .await
+                .remove(&flow_session_id);
             drop(permit);
-        });
+
+            // Last, so a caller that resumes the session as soon as it hears back, e.g. after an
+            // approval, isn't dropped by the duplicate session check
+            resolve_flow_session_waiter(&state, &flow_session_id, session_status, output).await;
+        };
+        tokio::spawn(process_flow_session.instrument(flow_session_span));
         //END SPAWNED PROCESSOR
     }
 
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    Signaled,        // The processor stops the session, dropping the task in flight
+    Canceled,        // The session was paused at an approval and is canceled right away
+    NothingToCancel, // The session isn't running, e.g. it already ended
+}
+
+// Asks the processor to stop a running session, the task in flight included. Only sessions it is
+// working on right now are in the cache, one that isn't may still be paused at an approval
+pub async fn cancel_flow_session(state: &AppState, flow_session_id: &Uuid) -> CancelOutcome {
+    if state
+        .flow_session_cache
+        .read()
+        .await
+        .get(flow_session_id)
+        .is_none()
+    {
+        return cancel_paused_flow_session(state, flow_session_id).await;
+    }
+
+    info!("[PROCESSOR] Canceling flow session {}", flow_session_id);
+    state
+        .canceled_flow_sessions
+        .write()
+        .await
+        .insert(*flow_session_id);
+    state.flow_session_canceled.notify_waiters();
+    CancelOutcome::Signaled
+}
+
+// Resolves once the session is canceled, see execute_task
+pub async fn flow_session_canceled(state: &AppState, flow_session_id: &Uuid) {
+    loop {
+        // Registered before checking so a cancel in between isn't missed
+        let notified = state.flow_session_canceled.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if state
+            .canceled_flow_sessions
+            .read()
+            .await
+            .contains(flow_session_id)
+        {
+            return;
+        }
+        notified.await;
+    }
+}
+
+// Sessions fail_stalled_session canceled end Failed, any other cancel ends Canceled. Returns the
+// status the session ended with and its output if the cache can't tell
+async fn stop_canceled_session(
+    state: Arc<AppState>,
+    flow_session_id: &Uuid,
+    task: &Task,
+) -> (FlowSessionStatus, Option<Value>) {
+    if state
+        .stalled_flow_sessions
+        .write()
+        .await
+        .remove(flow_session_id)
+    {
+        let output = fail_stalled_task(&state, flow_session_id, task).await;
+        return (FlowSessionStatus::Failed, Some(output));
+    }
+    mark_flow_session_canceled(state, flow_session_id, task).await;
+    (FlowSessionStatus::Canceled, None)
+}
+
+// Marks the in flight task and the flow session as canceled so it reads differently than a failure
+async fn mark_flow_session_canceled(state: Arc<AppState>, flow_session_id: &Uuid, task: &Task) {
+    if let Err(e) = state
+        .task_store
+        .update_task_status(&task.task_id, &TaskStatus::Canceled, None, None, None, None)
+        .await
+    {
+        error!("[PROCESSOR] Failed to update task status: {}", e);
+    }
+
+    if let Err(e) = state
+        .task_store
+        .update_flow_session_status(
+            flow_session_id,
+            &FlowSessionStatus::Canceled,
+            &TriggerSessionStatus::Canceled,
+        )
+        .await
+    {
+        error!("[PROCESSOR] Failed to update flow session status: {}", e);
+    }
+
+    // Update cache
+    {
+        let mut cache = state.flow_session_cache.write().await;
+        let mut task_copy = task.clone();
+        task_copy.task_status = TaskStatus::Canceled;
+        task_copy.ended_at = Some(Utc::now());
+        if let Err(e) = cache.update_task(flow_session_id, task_copy) {
+            warn!("[PROCESSOR] Failed to update task in cache: {}", e);
+        }
+    }
+
+    // Let a waiting webhook know the workflow won't be responding
+    let mut completions = state.flow_completions.lock().await;
+    if let Some(completion) = completions.remove(&flow_session_id.to_string()) {
+        if completion.needs_response {
+            let _ = completion
+                .sender
+                .send(json!({ "error": "Workflow was canceled" }));
+        }
+    }
+}
+
+// Without a version_id the workflow's published version runs. The session records the version
+// it resolved to so every task refers to a concrete one
+pub async fn resolve_workflow_version(
+    state: &AppState,
+    workflow_id: &Uuid,
+    version_id: Option<&Uuid>,
+) -> Result<DatabaseFlowVersion, String> {
+    if version_id.is_some() {
+        return state
+            .task_store
+            .get_workflow_definition(workflow_id, version_id)
+            .await;
+    }
+
+    match state
+        .task_store
+        .get_workflow_definition(workflow_id, None)
+        .await
+    {
+        Ok(workflow) if workflow.published => Ok(workflow),
+        Ok(_) => Err(format!("Workflow {} has no published version", workflow_id)),
+        Err(e) => Err(format!(
+            "Failed to find a published version of workflow {}: {}",
+            workflow_id, e
+        )),
+    }
+}
+
+// Webhooks and other triggers build their task before the processor resolves the version, e.g.
+// without a version_id the published one runs. The task is pinned to the version and account
+// being run and config it was sent without comes from the trigger node, so execute_task bundles
+// it with the same secrets, accounts and system variables as a trigger task created here
+fn prepare_trigger_task(
+    mut trigger_task: CreateTaskInput,
+    workflow: &DatabaseFlowVersion,
+    trigger_node: &Action,
+    trigger_session_id: &Uuid,
+) -> CreateTaskInput {
+    trigger_task.account_id = workflow.account_id.to_string();
+    trigger_task.flow_version_id = workflow.flow_version_id.to_string();
+    // Every task in the session shares the message's trigger_session_id so a run can be traced
+    // from its trigger. Planned tasks copy it from here
+    trigger_task.trigger_session_id = trigger_session_id.to_string();
+
+    let config = &mut trigger_task.config;
+    if config.inputs.is_none() {
+        config.inputs = trigger_node.inputs.clone();
+    }
+    if config.inputs_schema.is_none() {
+        config.inputs_schema = trigger_node.inputs_schema.clone();
+    }
+    if config.plugin_config.is_none() {
+        config.plugin_config = Some(trigger_node.plugin_config.clone());
+    }
+    if config.plugin_config_schema.is_none() {
+        config.plugin_config_schema = Some(trigger_node.plugin_config_schema.clone());
+    }
+    trigger_task
+}
+
+// Every action that runs after the trigger, in order, if that doesn't depend on results. That is
+// when each action has at most one outgoing edge and none of them have a condition. Those are the
+// only workflows whose tasks can all be created up front
+pub fn plan_linear_tasks<'a>(
+    workflow_def: &'a WorkflowVersionDefinition,
+    trigger_node: &Action,
+) -> Option<Vec<&'a Action>> {
+    let graph = create_workflow_graph(workflow_def);
+    let mut planned = Vec::new();
+    let mut visited = HashSet::from([trigger_node.action_id.clone()]);
+    let mut current = trigger_node.action_id.clone();
+
+    while let Some(edges) = graph.get(&current) {
+        let edge = match edges.as_slice() {
+            [] => break,
+            [edge] if edge.condition.is_none() => edge,
+            _ => return None,
+        };
+        if !visited.insert(edge.target.clone()) {
+            return None;
+        }
+        let action = workflow_def
+            .actions
+            .iter()
+            .find(|action| action.action_id == edge.target)?;
+        planned.push(action);
+        current = action.action_id.clone();
+    }
+    Some(planned)
+}
+
+// A task created up front with the trigger task. It stays Pending until start_planned_task
+fn planned_task_input(
+    trigger_task: &CreateTaskInput,
+    action: &Action,
+    processing_order: i32,
+) -> CreateTaskInput {
+    let action = action.executable();
+    CreateTaskInput {
+        account_id: trigger_task.account_id.clone(),
+        processing_order,
+        task_status: TaskStatus::Pending.as_str().to_string(),
+        flow_id: trigger_task.flow_id.clone(),
+        flow_version_id: trigger_task.flow_version_id.clone(),
+        action_label: action.label.to_string(),
+        trigger_id: trigger_task.trigger_id.clone(),
+        trigger_session_id: trigger_task.trigger_session_id.clone(),
+        trigger_session_status: TriggerSessionStatus::Pending.as_str().to_string(),
+        flow_session_id: trigger_task.flow_session_id.clone(),
+        flow_session_status: FlowSessionStatus::Pending.as_str().to_string(),
+        action_id: action.action_id.to_string(),
+        r#type: action.r#type.clone(),
+        plugin_name: action.plugin_name.clone(),
+        plugin_version: action.plugin_version.clone(),
+        stage: trigger_task.stage.clone(),
+        config: action.task_config(),
+        result: None,
+        error: None,
+        started_at: None,
+        test_config: action.test_config.cloned(),
+    }
+}
+
+// Marks a planned task Running when its turn comes, the same as creating it would have.
+// The store is updated before the task runs so its Running update can't land after the result
+async fn start_planned_task(
+    state: &AppState,
+    flow_session_id: &Uuid,
+    mut task: Task,
+) -> Result<Task, String> {
+    task.task_status = TaskStatus::Running;
+    task.started_at = Some(Utc::now());
+    state
+        .flow_session_cache
+        .write()
+        .await
+        .update_task(flow_session_id, task.clone())?;
+
+    if let Err(e) = state
+        .task_store
+        .update_task_status(&task.task_id, &TaskStatus::Running, None, None, None, None)
+        .await
+    {
+        error!("[PROCESSOR] Failed to update task status: {}", e);
+    }
+    Ok(task)
+}
+
+// Only the store, the cache entry is about to be removed and the output ignores Pending tasks
+async fn cancel_planned_tasks(state: &AppState, flow_session_id: &Uuid) {
+    let planned_task_ids: Vec<Uuid> =
+        match state.flow_session_cache.read().await.get(flow_session_id) {
+            Some(session_data) => session_data
+                .tasks()
+                .values()
+                .filter(|task| task.task_status == TaskStatus::Pending)
+                .map(|task| task.task_id)
+                .collect(),
+            None => return,
+        };
+
+    for task_id in planned_task_ids {
+        if let Err(e) = state
+            .task_store
+            .update_task_status(&task_id, &TaskStatus::Canceled, None, None, None, None)
+            .await
+        {
+            error!("[PROCESSOR] Failed to update task status: {}", e);
+        }
+    }
+}
+
+// The task is in the store but its session is gone from the cache, e.g. the entry expired.
+// Everything after this reads the cache, so the task and session fail instead of running on without it
+async fn fail_uncached_task(
+    state: &AppState,
+    flow_session_id: &Uuid,
+    task: &Task,
+    error: String,
+) -> Value {
+    error!("[PROCESSOR] Failing task {}: {}", task.task_id, error);
+    let error = json!({ "message": error });
+
+    if let Err(e) = state
+        .task_store
+        .update_task_status(
+            &task.task_id,
+            &TaskStatus::Failed,
+            None,
+            None,
+            None,
+            Some(error.clone()),
+        )
+        .await
+    {
+        error!("[PROCESSOR] Failed to update task status: {}", e);
+    }
+
+    if let Err(e) = state
+        .task_store
+        .update_flow_session_status(
+            flow_session_id,
+            &FlowSessionStatus::Failed,
+            &TriggerSessionStatus::Failed,
+        )
+        .await
+    {
+        error!("[PROCESSOR] Failed to update flow session status: {}", e);
+    }
+
+    let mut completions = state.flow_completions.lock().await;
+    if let Some(completion) = completions.remove(&flow_session_id.to_string()) {
+        if completion.needs_response {
+            let _ = completion.sender.send(error.clone());
+        }
+    }
+    error
+}
+
 /// Creates a graph representation of the workflow
 pub fn create_workflow_graph(
     workflow_def: &WorkflowVersionDefinition,
-) -> HashMap<String, Vec<String>> {
-    let mut graph: HashMap<String, Vec<String>> = HashMap::new();
+) -> HashMap<String, Vec<Edge>> {
+    let mut graph: HashMap<String, Vec<Edge>> = HashMap::new();
     for edge in &workflow_def.edges {
         graph
             .entry(edge.source.clone())
             .or_insert_with(Vec::new)
-            .push(edge.target.clone());
+            .push(edge.clone());
+    }
+    // Prioritized edges first, then by target so the neighbor tried first doesn't depend on
+    // the order edges happen to be stored in
+    for edges in graph.values_mut() {
+        edges.sort_by_key(|edge| (edge.priority.is_none(), edge.priority, edge.target.clone()));
     }
     graph
 }
+
+// The completed tasks the edges' conditions read, all of them when that can't be worked out
+fn condition_tasks<'a>(
+    tasks: &HashMap<Uuid, Task>,
+    edges: impl IntoIterator<Item = &'a Edge>,
+) -> Vec<Task> {
+    let referenced =
+        referenced_actions(edges.into_iter().filter_map(|edge| edge.condition.as_ref()));
+    tasks
+        .values()
+        .filter(|task| task.task_status == TaskStatus::Completed)
+        .filter(|task| {
+            referenced
+                .as_ref()
+                .map_or(true, |action_ids| action_ids.contains(&task.action_id))
+        })
+        .cloned()
+        .collect()
+}
+
+// Edge conditions see completed tasks the same way templates do, e.g. "actions.check.result.ok == true".
+// Truncated previews and large results' references are swapped for the whole results first, a
+// condition on them would never be taken
+async fn get_condition_context(
+    state: Arc<AppState>,
+    mut tasks: Vec<Task>,
+) -> Result<Value, String> {
+    resolve_results(state, &mut tasks).await?;
+    let actions: serde_json::Map<String, Value> = tasks
+        .iter()
+        .filter_map(|task| {
+            serde_json::to_value(task)
+                .ok()
+                .map(|value| (task.action_id.clone(), value))
+        })
+        .collect();
+    Ok(json!({ "actions": actions }))
+}
+
+// Edges without a condition are always taken. A condition that can't be evaluated is not
+fn edge_is_taken(edge: &Edge, context: &Value) -> bool {
+    match &edge.condition {
+        Some(condition) => match Templater::evaluate_condition(context, condition) {
+            Ok(taken) => taken,
+            Err(e) => {
+                warn!(
+                    "[PROCESSOR] Failed to evaluate condition on edge {}: {}",
+                    edge.id, e
+                );
+                false
+            }
+        },
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::secrets::DecryptedSecret;
+    use crate::processor::approvals::APPROVAL_PLUGIN;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::flow_session_cache::FlowSessionCache;
+    use crate::processor::in_memory_task_store::{
+        action, edge, start_test_processor, test_app_state, InMemoryTaskStore,
+    };
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use crate::templater::is_truthy;
+    use crate::types::action_types::PluginName;
+    use crate::types::json_schema::ValidationFieldType;
+    use crate::types::task_types::TaskConfig;
+    use crate::FlowCompletion;
+    use node_semver::Version;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    fn task_input(flow_session_id: &Uuid) -> CreateTaskInput {
+        CreateTaskInput {
+            account_id: Uuid::new_v4().to_string(),
+            processing_order: 1,
+            task_status: TaskStatus::Running.as_str().to_string(),
+            flow_id: Uuid::new_v4().to_string(),
+            flow_version_id: Uuid::new_v4().to_string(),
+            action_label: "HTTP".to_string(),
+            trigger_id: "webhook".to_string(),
+            trigger_session_id: Uuid::new_v4().to_string(),
+            trigger_session_status: TriggerSessionStatus::Running.as_str().to_string(),
+            flow_session_id: flow_session_id.to_string(),
+            flow_session_status: FlowSessionStatus::Running.as_str().to_string(),
+            action_id: "http".to_string(),
+            r#type: ActionType::Action,
+            plugin_name: PluginName::new("@anything/http".to_string()).unwrap(),
+            plugin_version: Version::parse("0.1.0").unwrap(),
+            stage: Stage::Testing.as_str().to_string(),
+            config: TaskConfig {
+                inputs: None,
+                inputs_schema: None,
+                plugin_config: None,
+                plugin_config_schema: None,
+            },
+            result: None,
+            error: None,
+            started_at: Some(Utc::now()),
+            test_config: None,
+        }
+    }
+
+    #[test]
+    fn test_graph_neighbors_have_a_fixed_order() {
+        let mut edges = vec![
+            edge("webhook", "charlie"),
+            edge("webhook", "alpha"),
+            edge("webhook", "delta"),
+            edge("webhook", "bravo"),
+        ];
+        edges[2]["priority"] = json!(1);
+        edges[3]["priority"] = json!(1);
+        let actions = ["webhook", "alpha", "bravo", "charlie", "delta"]
+            .map(|action_id| action(action_id, "action", None));
+
+        for edges in [edges.clone(), edges.into_iter().rev().collect()] {
+            let workflow: WorkflowVersionDefinition =
+                serde_json::from_value(json!({ "actions": actions, "edges": edges })).unwrap();
+            let graph = create_workflow_graph(&workflow);
+            let targets: Vec<&str> = graph["webhook"]
+                .iter()
+                .map(|edge| edge.target.as_str())
+                .collect();
+            assert_eq!(targets, vec!["bravo", "delta", "alpha", "charlie"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mark_flow_session_canceled_updates_store() {
+        let store = Arc::new(InMemoryTaskStore::new());
+        let state = test_app_state(store.clone());
+        let flow_session_id = Uuid::new_v4();
+
+        let task = store
+            .create_task(&task_input(&flow_session_id))
+            .await
+            .unwrap();
+
+        let (sender, receiver) = oneshot::channel();
+        state.flow_completions.lock().await.insert(
+            flow_session_id.to_string(),
+            FlowCompletion {
+                sender,
+                needs_response: true,
+            },
+        );
+
+        mark_flow_session_canceled(state.clone(), &flow_session_id, &task).await;
+
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].task_status, TaskStatus::Canceled);
+        assert!(tasks[0].ended_at.is_some());
+        assert!(matches!(
+            tasks[0].flow_session_status,
+            FlowSessionStatus::Canceled
+        ));
+        assert!(matches!(
+            tasks[0].trigger_session_status,
+            TriggerSessionStatus::Canceled
+        ));
+
+        // A webhook waiting on this session hears that it won't get a response
+        assert_eq!(
+            receiver.await.unwrap(),
+            json!({ "error": "Workflow was canceled" })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_running_flow_session() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "slow",
+                    "action",
+                    Some(json!({ "mock_result": {}, "mock_delay_ms": 200 })),
+                ),
+                action("http", "action", Some(json!({ "mock_result": {} }))),
+            ],
+            vec![edge("webhook", "slow"), edge("slow", "http")],
+        )
+        .await;
+
+        let flow_session_id = Uuid::new_v4();
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(flow_session_id, sender);
+        state
+            .processor_sender
+            .send(ProcessorMessage {
+                workflow_id,
+                version_id: Some(flow_version_id),
+                flow_session_id,
+                trigger_session_id: Uuid::new_v4(),
+                trigger_task: None,
+                response: None,
+                deadline: None,
+            })
+            .await
+            .unwrap();
+
+        // Nothing to cancel until the processor has picked it up
+        while state
+            .flow_session_cache
+            .read()
+            .await
+            .get(&flow_session_id)
+            .is_none()
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            cancel_flow_session(&state, &flow_session_id).await,
+            CancelOutcome::Signaled
+        );
+
+        let outcome = receiver.await.unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Canceled));
+        // Whatever was running is canceled and nothing after it runs
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.last().unwrap().task_status, TaskStatus::Canceled);
+
+        assert_eq!(
+            cancel_flow_session(&state, &flow_session_id).await,
+            CancelOutcome::NothingToCancel
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_drops_the_task_in_flight() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "slow",
+                    "action",
+                    Some(json!({ "mock_result": {}, "mock_delay_ms": 60_000 })),
+                ),
+                action("http", "action", Some(json!({ "mock_result": {} }))),
+            ],
+            vec![edge("webhook", "slow"), edge("slow", "http")],
+        )
+        .await;
+
+        let flow_session_id = Uuid::new_v4();
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(flow_session_id, sender);
+        state
+            .processor_sender
+            .send(ProcessorMessage {
+                workflow_id,
+                version_id: Some(flow_version_id),
+                flow_session_id,
+                trigger_session_id: Uuid::new_v4(),
+                trigger_task: None,
+                response: None,
+                deadline: None,
+            })
+            .await
+            .unwrap();
+
+        // Wait for the slow task to be running
+        let started = tokio::time::Instant::now();
+        let slow_task = loop {
+            let slow_task = state
+                .flow_session_cache
+                .read()
+                .await
+                .get(&flow_session_id)
+                .and_then(|session| session.get_task_by_action_id("slow").cloned());
+            if let Some(slow_task) = slow_task {
+                break slow_task;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(
+            cancel_flow_session(&state, &flow_session_id).await,
+            CancelOutcome::Signaled
+        );
+
+        // The clock only moves once everything is idle, a task that wasn't dropped would finish by
+        // jumping it past the mock's delay
+        let outcome = receiver.await.unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Canceled));
+        assert!(started.elapsed() < Duration::from_secs(60));
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        let slow = tasks
+            .iter()
+            .find(|task| task.task_id == slow_task.task_id)
+            .unwrap();
+        assert_eq!(slow.task_status, TaskStatus::Canceled);
+        assert!(slow.result.is_none());
+        assert!(!tasks.iter().any(|task| task.action_id == "http"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_miss_loads_session_tasks_from_store() {
+        let mut http = action(
+            "http",
+            "action",
+            Some(json!({ "mock_result": { "ok": true } })),
+        );
+        http["inputs"] = json!({ "name": "{{actions.webhook.result.body.name}}" });
+        http["inputs_schema"] = json!({
+            "type": "object",
+            "properties": { "name": { "x-any-validation": { "type": "string" } } }
+        });
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![action("webhook", "trigger", None), http],
+            vec![edge("webhook", "http")],
+        )
+        .await;
+
+        // The trigger ran before a restart so it's in the store but not the cache
+        let flow_session_id = Uuid::new_v4();
+        let trigger_session_id = Uuid::new_v4();
+        let mut trigger_input = task_input(&flow_session_id);
+        trigger_input.action_id = "webhook".to_string();
+        trigger_input.r#type = ActionType::Trigger;
+        trigger_input.processing_order = 0;
+        trigger_input.flow_id = workflow_id.to_string();
+        trigger_input.flow_version_id = flow_version_id.to_string();
+        trigger_input.trigger_session_id = trigger_session_id.to_string();
+        let trigger = store.create_task(&trigger_input).await.unwrap();
+        store
+            .update_task_status(
+                &trigger.task_id,
+                &TaskStatus::Completed,
+                None,
+                None,
+                Some(json!({ "body": { "name": "ada" } })),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(state
+            .flow_session_cache
+            .read()
+            .await
+            .get(&flow_session_id)
+            .is_none());
+
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(flow_session_id, sender);
+        state
+            .processor_sender
+            .send(ProcessorMessage {
+                workflow_id,
+                version_id: Some(flow_version_id),
+                flow_session_id,
+                trigger_session_id,
+                trigger_task: None,
+                response: None,
+                deadline: None,
+            })
+            .await
+            .unwrap();
+
+        let outcome = receiver.await.unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(outcome.output, Some(json!({ "ok": true })));
+
+        // The trigger isn't run again and the next task was bundled with its result
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].task_id, trigger.task_id);
+        assert_eq!(tasks[1].action_id, "http");
+        assert_eq!(tasks[1].bundled_inputs, Some(json!({ "name": "ada" })));
+    }
+
+    #[tokio::test]
+    async fn test_tasks_share_the_trigger_session_id() {
+        let step = |action_id: &str| {
+            action(
+                action_id,
+                "action",
+                Some(json!({ "mock_result": { "ok": true } })),
+            )
+        };
+        // Linear flows are planned up front, branching ones create tasks as they go
+        for edges in [
+            vec![edge("webhook", "a"), edge("a", "b")],
+            vec![edge("webhook", "a"), edge("webhook", "b")],
+        ] {
+            let (store, state, workflow_id, flow_version_id) = start_test_processor(
+                vec![action("webhook", "trigger", None), step("a"), step("b")],
+                edges,
+            )
+            .await;
+
+            // The trigger task was built with an id of its own, the message's wins
+            let flow_session_id = Uuid::new_v4();
+            let trigger_session_id = Uuid::new_v4();
+            let mut trigger_task = task_input(&flow_session_id);
+            trigger_task.action_id = "webhook".to_string();
+            trigger_task.r#type = ActionType::Trigger;
+            trigger_task.processing_order = 0;
+            trigger_task.trigger_session_id = Uuid::new_v4().to_string();
+            trigger_task.result = Some(json!({ "body": {} }));
+
+            let (sender, receiver) = oneshot::channel();
+            state
+                .flow_session_waiters
+                .lock()
+                .await
+                .insert(flow_session_id, sender);
+            state
+                .processor_sender
+                .send(ProcessorMessage {
+                    workflow_id,
+                    version_id: Some(flow_version_id),
+                    flow_session_id,
+                    trigger_session_id,
+                    trigger_task: Some(trigger_task),
+                    response: None,
+                    deadline: None,
+                })
+                .await
+                .unwrap();
+
+            let outcome = receiver.await.unwrap();
+            assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+
+            let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+            assert_eq!(tasks.len(), 3);
+            for task in &tasks {
+                assert_eq!(
+                    task.trigger_session_id,
+                    trigger_session_id.to_string(),
+                    "{}",
+                    task.action_id
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_trigger_task_is_bundled_with_secrets() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+
+        // Webhooks run the published version and don't send a version_id
+        let unpublished = store
+            .get_workflow_definition(&workflow_id, Some(&flow_version_id))
+            .await
+            .unwrap();
+        let mut webhook = action("webhook", "trigger", None);
+        webhook["inputs"] = json!({ "token": "{{secrets.API_KEY}}" });
+        webhook["inputs_schema"] = json!({
+            "type": "object",
+            "properties": { "token": { "x-any-validation": { "type": "string" } } }
+        });
+        let published_version_id = Uuid::new_v4();
+        store
+            .add_workflow(DatabaseFlowVersion {
+                flow_version_id: published_version_id,
+                published: true,
+                flow_definition: serde_json::from_value(
+                    json!({ "actions": [webhook], "edges": [] }),
+                )
+                .unwrap(),
+                ..unpublished.clone()
+            })
+            .await;
+
+        let secret: DecryptedSecret = serde_json::from_value(json!({
+            "secret_id": Uuid::new_v4(),
+            "secret_name": "API_KEY",
+            "secret_value": "sk-test",
+            "secret_description": null,
+            "stage": null
+        }))
+        .unwrap();
+        state
+            .bundler_secrets_cache
+            .write()
+            .await
+            .set(&unpublished.account_id.to_string(), vec![secret]);
+
+        // Built by the webhook handler without the trigger's config
+        let flow_session_id = Uuid::new_v4();
+        let trigger_session_id = Uuid::new_v4();
+        let mut trigger_task = task_input(&flow_session_id);
+        trigger_task.action_id = "webhook".to_string();
+        trigger_task.r#type = ActionType::Trigger;
+        trigger_task.processing_order = 0;
+        trigger_task.trigger_session_id = trigger_session_id.to_string();
+        trigger_task.result = Some(json!({ "body": {} }));
+
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(flow_session_id, sender);
+        state
+            .processor_sender
+            .send(ProcessorMessage {
+                workflow_id,
+                version_id: None,
+                flow_session_id,
+                trigger_session_id,
+                trigger_task: Some(trigger_task),
+                response: None,
+                deadline: None,
+            })
+            .await
+            .unwrap();
+
+        let outcome = receiver.await.unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(outcome.output, Some(json!({ "body": {} })));
+
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].flow_version_id, published_version_id);
+        assert_eq!(tasks[0].account_id, unpublished.account_id);
+        assert_eq!(tasks[0].bundled_inputs, Some(json!({ "token": "sk-test" })));
+    }
+
+    #[tokio::test]
+    async fn test_missing_version_resolves_to_the_published_one() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+
+        // Only an unpublished version so far
+        assert_eq!(
+            resolve_workflow_version(&state, &workflow_id, None)
+                .await
+                .unwrap_err(),
+            format!(
+                "Failed to find a published version of workflow {}: No workflow version found",
+                workflow_id
+            )
+        );
+        let unpublished = resolve_workflow_version(&state, &workflow_id, Some(&flow_version_id))
+            .await
+            .unwrap();
+        assert_eq!(unpublished.flow_version_id, flow_version_id);
+
+        let published_version_id = Uuid::new_v4();
+        store
+            .add_workflow(DatabaseFlowVersion {
+                flow_version_id: published_version_id,
+                published: true,
+                ..unpublished
+            })
+            .await;
+        let published = resolve_workflow_version(&state, &workflow_id, None)
+            .await
+            .unwrap();
+        assert_eq!(published.flow_version_id, published_version_id);
+    }
+
+    #[tokio::test]
+    async fn test_session_keeps_its_version_when_another_is_published() {
+        let mut approval = action("approval", "action", None);
+        approval["plugin_name"] = json!(APPROVAL_PLUGIN);
+        let notify = |version: i64| {
+            action(
+                "notify",
+                "action",
+                Some(json!({ "mock_result": { "version": version } })),
+            )
+        };
+        let (store, state, workflow_id, first_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                approval.clone(),
+                notify(1),
+            ],
+            vec![edge("webhook", "approval"), edge("approval", "notify")],
+        )
+        .await;
+        store.publish_workflow(&first_version_id).await;
+
+        // Started without a version_id so it runs the published one
+        let paused = run_workflow_and_wait(state.clone(), workflow_id, None, None, json!({}))
+            .await
+            .unwrap();
+        assert!(matches!(paused.status, FlowSessionStatus::Paused));
+        let flow_session_id = paused.flow_session_id;
+
+        let first_version = store
+            .get_workflow_definition(&workflow_id, Some(&first_version_id))
+            .await
+            .unwrap();
+        let second_version_id = Uuid::new_v4();
+        store
+            .add_workflow(DatabaseFlowVersion {
+                flow_version_id: second_version_id,
+                flow_definition: serde_json::from_value(json!({
+                    "actions": [action("webhook", "trigger", None), approval, notify(2)],
+                    "edges": [edge("webhook", "approval"), edge("approval", "notify")]
+                }))
+                .unwrap(),
+                ..first_version
+            })
+            .await;
+        store.publish_workflow(&second_version_id).await;
+
+        // Picked up again without a version_id and nothing cached, e.g. replayed after a restart
+        let approval_task = store
+            .get_tasks_for_session(&flow_session_id)
+            .await
+            .unwrap()
+            .remove(1);
+        store
+            .update_task_status(
+                &approval_task.task_id,
+                &TaskStatus::Completed,
+                None,
+                None,
+                Some(json!({})),
+                None,
+            )
+            .await
+            .unwrap();
+        let (sender, receiver) = oneshot::channel();
+        state
+            .flow_session_waiters
+            .lock()
+            .await
+            .insert(flow_session_id, sender);
+        state
+            .processor_sender
+            .send(ProcessorMessage {
+                workflow_id,
+                version_id: None,
+                flow_session_id,
+                trigger_session_id: Uuid::parse_str(&approval_task.trigger_session_id).unwrap(),
+                trigger_task: None,
+                response: None,
+                deadline: None,
+            })
+            .await
+            .unwrap();
+
+        let outcome = receiver.await.unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks[2].action_id, "notify");
+        assert_eq!(tasks[2].result, Some(json!({ "version": 1 })));
+        assert!(tasks
+            .iter()
+            .all(|task| task.flow_version_id == first_version_id));
+    }
+
+    #[tokio::test]
+    async fn test_cache_insert_failure_fails_the_task() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", Some(json!({ "mock_result": {} }))),
+            ],
+            vec![edge("webhook", "http")],
+        )
+        .await;
+
+        // Sessions expire as soon as they're set so create_task succeeds but the cache insert doesn't
+        *state.flow_session_cache.write().await = FlowSessionCache::new(Duration::ZERO);
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+
+        let error = json!({
+            "message": format!("Flow session {} is not in the cache", outcome.flow_session_id)
+        });
+        assert!(matches!(outcome.status, FlowSessionStatus::Failed));
+        assert_eq!(outcome.output, Some(error.clone()));
+
+        // The trigger is failed rather than left running and nothing runs after it
+        let tasks = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].task_status, TaskStatus::Failed);
+        assert_eq!(tasks[0].error, Some(error));
+        assert!(matches!(
+            tasks[0].flow_session_status,
+            FlowSessionStatus::Failed
+        ));
+    }
+
+    #[test]
+    fn test_plan_linear_tasks() {
+        let plan = |edges: Vec<Value>| -> Option<Vec<String>> {
+            let workflow: WorkflowVersionDefinition = serde_json::from_value(json!({
+                "actions": [
+                    action("webhook", "trigger", None),
+                    action("a", "action", None),
+                    action("b", "action", None)
+                ],
+                "edges": edges
+            }))
+            .unwrap();
+            let trigger_node = get_trigger_node(&workflow).unwrap();
+            plan_linear_tasks(&workflow, trigger_node).map(|actions| {
+                actions
+                    .into_iter()
+                    .map(|action| action.action_id.clone())
+                    .collect()
+            })
+        };
+
+        assert_eq!(
+            plan(vec![edge("webhook", "a"), edge("a", "b")]),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(plan(vec![]), Some(vec![]));
+
+        // What runs next depends on results, so nothing can be planned
+        assert_eq!(plan(vec![edge("webhook", "a"), edge("webhook", "b")]), None);
+        let mut conditional = edge("a", "b");
+        conditional["condition"] = json!("actions.a.result.ok == true");
+        assert_eq!(plan(vec![edge("webhook", "a"), conditional]), None);
+        assert_eq!(
+            plan(vec![edge("webhook", "a"), edge("a", "b"), edge("b", "a")]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tasks_built_from_the_executable_view_match_the_action() {
+        let mut definition = action("http", "action", Some(json!({ "mock_result": {} })));
+        definition["inputs"] = json!({ "url": "{{variables.url}}" });
+        definition["inputs_schema"] = json!({
+            "type": "object",
+            "properties": { "url": { "x-any-validation": { "type": "string" } } }
+        });
+        definition["plugin_config"] = json!({ "method": "GET" });
+        definition["plugin_config_schema"] = json!({ "type": "object" });
+        let mut with_presentation = definition.clone();
+        with_presentation["description"] = json!("Fetches the page");
+        with_presentation["presentation"] = json!({ "position": { "x": 120.0, "y": 40.0 } });
+        with_presentation["handles"] = json!([{ "id": "a", "type": "target", "position": "top" }]);
+        let bare: Action = serde_json::from_value(definition).unwrap();
+        let with_presentation: Action = serde_json::from_value(with_presentation).unwrap();
+
+        let trigger_task = task_input(&Uuid::new_v4());
+        let planned = planned_task_input(&trigger_task, &with_presentation, 1);
+        assert_eq!(planned.action_id, with_presentation.action_id);
+        assert_eq!(planned.action_label, with_presentation.label);
+        assert_eq!(planned.test_config, with_presentation.test_config);
+        assert_eq!(
+            serde_json::to_value(&planned.config).unwrap(),
+            json!({
+                "inputs": with_presentation.inputs,
+                "inputs_schema": with_presentation.inputs_schema,
+                "plugin_config": with_presentation.plugin_config,
+                "plugin_config_schema": with_presentation.plugin_config_schema
+            })
+        );
+        // What the editor adds doesn't change the task
+        assert_eq!(
+            serde_json::to_value(&planned).unwrap(),
+            serde_json::to_value(planned_task_input(&trigger_task, &bare, 1)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_edge_conditions_and_if_blocks_agree_on_truthiness() {
+        let mut conditional = edge("check", "next");
+        conditional["condition"] = json!("actions.check.result.value");
+        let conditional: Edge = serde_json::from_value(conditional).unwrap();
+
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({ "branch": "{{#if actions.check.result.value}}yes{{else}}no{{/if}}" }),
+        );
+        let mut validations = HashMap::new();
+        validations.insert("branch".to_string(), ValidationFieldType::String);
+
+        for value in [
+            json!("0"),
+            json!(""),
+            json!(0),
+            json!({}),
+            json!([]),
+            json!(null),
+            json!([0]),
+            json!("false"),
+        ] {
+            let context = json!({ "actions": { "check": { "result": { "value": value } } } });
+            let taken = edge_is_taken(&conditional, &context);
+            let rendered = templater
+                .render("test_template", &context, validations.clone())
+                .unwrap();
+            assert_eq!(taken, is_truthy(&value), "{}", value);
+            assert_eq!(
+                rendered["branch"],
+                if taken { "yes" } else { "no" },
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_edges_follow_the_completed_result() {
+        let ran = |ok: bool| async move {
+            let mut to_yes = edge("check", "yes");
+            to_yes["condition"] = json!("actions.check.result.ok == true");
+            let mut to_no = edge("check", "no");
+            to_no["condition"] = json!("actions.check.result.ok == false");
+            let (store, state, workflow_id, flow_version_id) = start_test_processor(
+                vec![
+                    action("webhook", "trigger", None),
+                    action(
+                        "check",
+                        "action",
+                        Some(json!({ "mock_result": { "ok": ok } })),
+                    ),
+                    action("yes", "action", Some(json!({ "mock_result": {} }))),
+                    action("no", "action", Some(json!({ "mock_result": {} }))),
+                    action("after", "action", Some(json!({ "mock_result": {} }))),
+                ],
+                vec![
+                    edge("webhook", "check"),
+                    to_yes,
+                    to_no,
+                    edge("yes", "after"),
+                    edge("no", "after"),
+                ],
+            )
+            .await;
+            let outcome =
+                run_workflow_and_wait(state, workflow_id, Some(flow_version_id), None, json!({}))
+                    .await
+                    .unwrap();
+            assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+            let mut ran: Vec<String> = store
+                .get_tasks_for_session(&outcome.flow_session_id)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|task| task.action_id)
+                .collect();
+            ran.sort();
+            ran
+        };
+
+        // The skipped branch never gets a task, the unconditional edge after either branch is taken
+        assert_eq!(ran(true).await, vec!["after", "check", "webhook", "yes"]);
+        assert_eq!(ran(false).await, vec!["after", "check", "no", "webhook"]);
+    }
+
+    #[tokio::test]
+    async fn test_conditions_read_results_past_the_size_limits_whole() {
+        let rows: Vec<Value> = (0..200).map(|id| json!({ "id": id })).collect();
+        // Offloaded to the store, then only a preview in the cache
+        for (offload_threshold_bytes, max_result_bytes) in [(1024, 0), (0, 1024)] {
+            let mut to_yes = edge("check", "yes");
+            to_yes["condition"] = json!("actions.check.result.ok == true");
+            let mut to_no = edge("check", "no");
+            to_no["condition"] = json!("actions.check.result.ok != true");
+            let (store, state, workflow_id, flow_version_id) = start_test_processor(
+                vec![
+                    action("webhook", "trigger", None),
+                    action(
+                        "check",
+                        "action",
+                        Some(json!({ "mock_result": { "ok": true, "rows": rows.clone() } })),
+                    ),
+                    action("yes", "action", Some(json!({ "mock_result": {} }))),
+                    action("no", "action", Some(json!({ "mock_result": {} }))),
+                ],
+                vec![edge("webhook", "check"), to_yes, to_no],
+            )
+            .await;
+            state
+                .offload_threshold_bytes
+                .store(offload_threshold_bytes, std::sync::atomic::Ordering::SeqCst);
+            state
+                .flow_session_cache
+                .write()
+                .await
+                .set_max_result_bytes(max_result_bytes);
+
+            let outcome =
+                run_workflow_and_wait(state, workflow_id, Some(flow_version_id), None, json!({}))
+                    .await
+                    .unwrap();
+            assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+            let mut ran: Vec<String> = store
+                .get_tasks_for_session(&outcome.flow_session_id)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|task| task.action_id)
+                .collect();
+            ran.sort();
+            assert_eq!(ran, vec!["check", "webhook", "yes"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_linear_workflow_tasks_are_created_in_one_batch() {
+        let step = |action_id: &str| {
+            action(
+                action_id,
+                "action",
+                Some(json!({ "mock_result": { "step": action_id } })),
+            )
+        };
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                step("a"),
+                step("b"),
+                step("c"),
+            ],
+            vec![edge("webhook", "a"), edge("a", "b"), edge("b", "c")],
+        )
+        .await;
+
+        let run = || {
+            run_workflow_and_wait(
+                state.clone(),
+                workflow_id,
+                Some(flow_version_id),
+                None,
+                json!({ "body": {} }),
+            )
+        };
+
+        // One insert per task
+        let per_step = run().await.unwrap();
+        assert!(matches!(per_step.status, FlowSessionStatus::Completed));
+        assert_eq!(store.create_requests(), 4);
+
+        // One insert for the whole session, the tasks still run and end the same way
+        state
+            .batch_task_creation
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        let batched = run().await.unwrap();
+        assert!(matches!(batched.status, FlowSessionStatus::Completed));
+        assert_eq!(batched.output, per_step.output);
+        assert_eq!(batched.output, Some(json!({ "step": "c" })));
+        assert_eq!(store.create_requests(), 5);
+
+        let tasks = store
+            .get_tasks_for_session(&batched.flow_session_id)
+            .await
+            .unwrap();
+        let action_ids: Vec<&str> = tasks.iter().map(|task| task.action_id.as_str()).collect();
+        assert_eq!(action_ids, vec!["webhook", "a", "b", "c"]);
+        for task in &tasks {
+            assert_eq!(task.task_status, TaskStatus::Completed);
+            assert!(task.started_at.is_some());
+        }
+    }
+
+    // cargo test --release bench_batched_task_creation -- --ignored --nocapture
+    #[tokio::test]
+    #[ignore]
+    async fn bench_batched_task_creation() {
+        const STEPS: usize = 50;
+        const ROUND_TRIP: Duration = Duration::from_millis(5);
+
+        let mut actions = vec![action("webhook", "trigger", None)];
+        let mut edges = Vec::new();
+        let mut previous = "webhook".to_string();
+        for i in 0..STEPS {
+            let action_id = format!("step_{}", i);
+            actions.push(action(
+                &action_id,
+                "action",
+                Some(json!({ "mock_result": { "step": i } })),
+            ));
+            edges.push(edge(&previous, &action_id));
+            previous = action_id;
+        }
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(actions, edges).await;
+        store.set_create_latency(ROUND_TRIP);
+
+        let mut elapsed = Vec::new();
+        for batched in [false, true] {
+            state
+                .batch_task_creation
+                .store(batched, std::sync::atomic::Ordering::SeqCst);
+            let creates_before = store.create_requests();
+            let started = std::time::Instant::now();
+            let outcome = run_workflow_and_wait(
+                state.clone(),
+                workflow_id,
+                Some(flow_version_id),
+                None,
+                json!({ "body": {} }),
+            )
+            .await
+            .unwrap();
+            assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+            elapsed.push((started.elapsed(), store.create_requests() - creates_before));
+        }
+
+        assert_eq!(elapsed[0].1, STEPS + 1);
+        assert_eq!(elapsed[1].1, 1);
+        println!(
+            "[BENCH] {} step session with {:?} per insert: per step {:?} ({} inserts), batched {:?} ({} insert)",
+            STEPS, ROUND_TRIP, elapsed[0].0, elapsed[0].1, elapsed[1].0, elapsed[1].1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_batched_session_cancels_planned_tasks() {
+        // Inputs without a validation fail to bundle
+        let mut broken = action("broken", "action", Some(json!({ "mock_result": {} })));
+        broken["inputs"] = json!({ "url": "https://example.com" });
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                broken,
+                action("after", "action", Some(json!({ "mock_result": {} }))),
+            ],
+            vec![edge("webhook", "broken"), edge("broken", "after")],
+        )
+        .await;
+        state
+            .batch_task_creation
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Failed));
+        assert!(outcome.output.is_some());
+
+        let tasks = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap();
+        let statuses: Vec<&TaskStatus> = tasks.iter().map(|task| &task.task_status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                &TaskStatus::Completed,
+                &TaskStatus::Failed,
+                &TaskStatus::Canceled
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_two_processors_drain_one_queue() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "step",
+                    "action",
+                    Some(json!({ "mock_result": { "ok": true } })),
+                ),
+            ],
+            vec![edge("webhook", "step")],
+        )
+        .await;
+        // start_test_processor already runs one
+        tokio::spawn(processor(state.clone()));
+
+        let runs = (0..20).map(|_| {
+            run_workflow_and_wait(
+                state.clone(),
+                workflow_id,
+                Some(flow_version_id),
+                None,
+                json!({}),
+            )
+        });
+        // On paused time the clock only reaches the timeout once nothing can make progress
+        let outcomes =
+            tokio::time::timeout(Duration::from_secs(10), futures::future::join_all(runs))
+                .await
+                .expect("processors stopped taking messages");
+
+        for outcome in outcomes {
+            let outcome = outcome.unwrap();
+            assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+            assert_eq!(outcome.output, Some(json!({ "ok": true })));
+            // Each session ran once, on one of the processors
+            let tasks = store
+                .get_tasks_for_session(&outcome.flow_session_id)
+                .await
+                .unwrap();
+            assert_eq!(tasks.len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_results_are_read_whole_from_the_store() {
+        let rows: Vec<Value> = (0..200)
+            .map(|id| json!({ "id": id, "name": format!("row {}", id) }))
+            .collect();
+        let mut count = action("count", "action", None);
+        count["plugin_name"] = json!("@anything/transform");
+        count["inputs"] = json!({ "rows": "{{actions.fetch.result.rows}}" });
+        count["inputs_schema"] = json!({
+            "type": "object",
+            "properties": { "rows": { "x-any-validation": { "type": "array" } } }
+        });
+        count["plugin_config"] = json!({ "output": { "count": "{{inputs.rows | length}}" } });
+        count["plugin_config_schema"] = json!({
+            "type": "object",
+            "properties": { "output": { "x-any-validation": { "type": "any" } } }
+        });
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "fetch",
+                    "action",
+                    Some(json!({ "mock_result": { "rows": rows.clone() } })),
+                ),
+                count,
+            ],
+            vec![edge("webhook", "fetch"), edge("fetch", "count")],
+        )
+        .await;
+        state
+            .flow_session_cache
+            .write()
+            .await
+            .set_max_result_bytes(1024);
+
+        let truncated = truncate_result(&json!({ "rows": rows.clone() }), 1024).unwrap();
+        assert_eq!(truncated["result_truncated"], true);
+        assert_eq!(truncated["preview"].as_str().unwrap().len(), 1024);
+        assert!(truncate_result(&json!({ "rows": [] }), 1024).is_none());
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({}),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(outcome.output, Some(json!({ "count": 200 })));
+
+        let tasks = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap();
+        let fetch = tasks.iter().find(|task| task.action_id == "fetch").unwrap();
+        assert_eq!(fetch.result, Some(json!({ "rows": rows })));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32, // How many calls can go out back to back before they're spaced out
+}
+
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// A token bucket per plugin_name so tasks hitting the same external API wait their turn instead
+// of getting 429s. Plugins without a limit are never held up
+pub struct PluginRateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl PluginRateLimiter {
+    pub fn new(limits: HashMap<String, RateLimit>) -> Self {
+        let buckets = limits
+            .into_iter()
+            .map(|(plugin_name, limit)| (plugin_name, Self::bucket(limit)))
+            .collect();
+        Self {
+            buckets: Mutex::new(buckets),
+        }
+    }
+
+    // PLUGIN_RATE_LIMITS is a comma separated list of plugin=per_second or plugin=per_second:burst,
+    // e.g. "@anything/http=10:5,@anything/slack=1". The burst defaults to 1
+    pub fn from_env() -> Result<Self, String> {
+        let limits = match env::var("PLUGIN_RATE_LIMITS") {
+            Ok(limits) => parse_rate_limits(&limits)?,
+            Err(_) => HashMap::new(),
+        };
+        Ok(Self::new(limits))
+    }
+
+    pub async fn set_limit(&self, plugin_name: &str, limit: RateLimit) {
+        self.buckets
+            .lock()
+            .await
+            .insert(plugin_name.to_string(), Self::bucket(limit));
+    }
+
+    // Waits until the plugin has a token to spend
+    pub async fn acquire(&self, plugin_name: &str) {
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().await;
+                let bucket = match buckets.get_mut(plugin_name) {
+                    Some(bucket) => bucket,
+                    None => return,
+                };
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * bucket.limit.per_second)
+                    .min(bucket.limit.burst as f64);
+                bucket.refilled_at = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.limit.per_second)
+            };
+
+            debug!(
+                "[PROCESSOR] Rate limited {}, waiting {:?}",
+                plugin_name, wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn bucket(limit: RateLimit) -> Bucket {
+        Bucket {
+            limit,
+            tokens: limit.burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+}
+
+fn parse_rate_limits(limits: &str) -> Result<HashMap<String, RateLimit>, String> {
+    let mut parsed = HashMap::new();
+    for entry in limits
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let invalid = || format!("Invalid PLUGIN_RATE_LIMITS entry '{}'", entry);
+        let (plugin_name, limit) = entry.split_once('=').ok_or_else(invalid)?;
+        let (per_second, burst) = limit.split_once(':').unwrap_or((limit, "1"));
+        let per_second: f64 = per_second.trim().parse().map_err(|_| invalid())?;
+        let burst: u32 = burst.trim().parse().map_err(|_| invalid())?;
+        if per_second <= 0.0 || !per_second.is_finite() || burst == 0 {
+            return Err(invalid());
+        }
+        parsed.insert(
+            plugin_name.trim().to_string(),
+            RateLimit { per_second, burst },
+        );
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_rate_limits() {
+        let limits = parse_rate_limits("@anything/http=10:5, @anything/slack=0.5").unwrap();
+        assert_eq!(
+            limits.get("@anything/http"),
+            Some(&RateLimit {
+                per_second: 10.0,
+                burst: 5
+            })
+        );
+        assert_eq!(
+            limits.get("@anything/slack"),
+            Some(&RateLimit {
+                per_second: 0.5,
+                burst: 1
+            })
+        );
+        assert!(parse_rate_limits("").unwrap().is_empty());
+        assert!(parse_rate_limits("@anything/http").is_err());
+        assert!(parse_rate_limits("@anything/http=0").is_err());
+        assert!(parse_rate_limits("@anything/http=1:x").is_err());
+    }
+
+    // On paused time waits take exactly as long as the limiter asks for
+    #[tokio::test(start_paused = true)]
+    async fn test_tasks_for_a_limited_plugin_are_spaced_out() {
+        let limiter = Arc::new(PluginRateLimiter::new(HashMap::new()));
+        limiter
+            .set_limit(
+                "@anything/http",
+                RateLimit {
+                    per_second: 5.0,
+                    burst: 1,
+                },
+            )
+            .await;
+
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire("@anything/http").await;
+                    start.elapsed()
+                })
+            })
+            .collect();
+        let mut acquired = Vec::new();
+        for task in tasks {
+            acquired.push(task.await.unwrap());
+        }
+        acquired.sort();
+
+        // The first goes right away and the second waits for the next token, 200ms later
+        assert_eq!(acquired[0], Duration::ZERO);
+        assert!(acquired[1] >= Duration::from_millis(200));
+        assert!(acquired[1] < Duration::from_millis(205));
+
+        // Other plugins are unlimited
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire("@anything/javascript").await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
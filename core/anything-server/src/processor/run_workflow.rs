@@ -0,0 +1,617 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::processor::flow_session_cache::FlowSessionData;
+use crate::processor::large_results::resolve_results;
+use crate::processor::parsing_utils::get_trigger_node;
+use crate::processor::processor::{resolve_workflow_version, ProcessorMessage};
+use crate::types::{
+    action_types::ActionType,
+    task_types::{
+        CreateTaskInput, FlowSessionStatus, Stage, Task, TaskConfig, TaskStatus,
+        TriggerSessionStatus,
+    },
+};
+use crate::AppState;
+
+#[derive(Debug, Clone)]
+pub struct FlowSessionOutcome {
+    pub flow_session_id: Uuid,
+    pub status: FlowSessionStatus, // Running if the processor stopped before the session finished, e.g. on shutdown
+    pub output: Option<Value>,
+    pub duration_ms: Option<i64>, // Summed over the session's tasks, None if the session never got cached
+}
+
+// Where the processor sends a session's outcome. The sender sits behind a shared Option so the
+// message stays Clone, whoever takes it first gets it
+#[derive(Debug, Clone)]
+pub struct SessionResponder(Arc<Mutex<Option<oneshot::Sender<FlowSessionOutcome>>>>);
+
+impl SessionResponder {
+    pub fn new() -> (Self, oneshot::Receiver<FlowSessionOutcome>) {
+        let (sender, receiver) = oneshot::channel();
+        (Self(Arc::new(Mutex::new(Some(sender)))), receiver)
+    }
+
+    pub fn take(&self) -> Option<oneshot::Sender<FlowSessionOutcome>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+#[derive(Debug)]
+pub enum RunWorkflowError {
+    Failed(String),
+    TimedOut { flow_session_id: Uuid }, // The session keeps running, only the caller stopped waiting
+}
+
+impl RunWorkflowError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RunWorkflowError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RunWorkflowError::TimedOut { .. } => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl fmt::Display for RunWorkflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunWorkflowError::Failed(error) => write!(f, "{}", error),
+            RunWorkflowError::TimedOut { flow_session_id } => write!(
+                f,
+                "Timed out waiting for flow session {}, it is still running",
+                flow_session_id
+            ),
+        }
+    }
+}
+
+// Runs a workflow with `inputs` as the trigger result and waits for the session to end.
+// The output is the result of the Response or Output action if one ran, otherwise the last task's
+// result. For a failed session that is the error. Without a version_id the published version runs.
+// Without a stage published workflows run in production and unpublished ones in testing
+pub async fn run_workflow_and_wait(
+    state: Arc<AppState>,
+    workflow_id: Uuid,
+    version_id: Option<Uuid>,
+    stage: Option<Stage>,
+    inputs: Value,
+) -> Result<FlowSessionOutcome, String> {
+    let (flow_session_id, receiver) =
+        submit_workflow(&state, workflow_id, version_id, stage, inputs, None).await?;
+    receiver
+        .await
+        .map_err(|_| stopped_without_outcome(&flow_session_id))
+}
+
+// Like run_workflow_and_wait but gives up after `timeout` so a handler can answer with a 504.
+// The session isn't canceled, it finishes in the background and its outcome is dropped
+pub async fn run_workflow_with_timeout(
+    state: Arc<AppState>,
+    workflow_id: Uuid,
+    version_id: Option<Uuid>,
+    stage: Option<Stage>,
+    inputs: Value,
+    timeout: Duration,
+) -> Result<FlowSessionOutcome, RunWorkflowError> {
+    let deadline = chrono::Duration::from_std(timeout)
+        .map(|timeout| Utc::now() + timeout)
+        .map_err(|e| RunWorkflowError::Failed(format!("Invalid timeout: {}", e)))?;
+    let (flow_session_id, receiver) = submit_workflow(
+        &state,
+        workflow_id,
+        version_id,
+        stage,
+        inputs,
+        Some(deadline),
+    )
+    .await
+    .map_err(RunWorkflowError::Failed)?;
+
+    match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(outcome)) => Ok(outcome),
+        Ok(Err(_)) => Err(RunWorkflowError::Failed(stopped_without_outcome(
+            &flow_session_id,
+        ))),
+        Err(_) => {
+            warn!(
+                "[PROCESSOR] Stopped waiting for flow session {} after {:?}",
+                flow_session_id, timeout
+            );
+            Err(RunWorkflowError::TimedOut { flow_session_id })
+        }
+    }
+}
+
+fn stopped_without_outcome(flow_session_id: &Uuid) -> String {
+    format!(
+        "Flow session {} stopped without reporting an outcome",
+        flow_session_id
+    )
+}
+
+async fn submit_workflow(
+    state: &AppState,
+    workflow_id: Uuid,
+    version_id: Option<Uuid>,
+    stage: Option<Stage>,
+    inputs: Value,
+    deadline: Option<DateTime<Utc>>,
+) -> Result<(Uuid, oneshot::Receiver<FlowSessionOutcome>), String> {
+    let workflow = resolve_workflow_version(state, &workflow_id, version_id.as_ref()).await?;
+    let trigger_node =
+        get_trigger_node(&workflow.flow_definition).map_err(|problem| problem.to_string())?;
+
+    let flow_session_id = Uuid::new_v4();
+    let trigger_session_id = Uuid::new_v4();
+
+    let trigger_task = CreateTaskInput {
+        account_id: workflow.account_id.to_string(),
+        processing_order: 0,
+        task_status: TaskStatus::Running.as_str().to_string(),
+        flow_id: workflow_id.to_string(),
+        flow_version_id: workflow.flow_version_id.to_string(),
+        action_label: trigger_node.label.clone(),
+        trigger_id: trigger_node.action_id.clone(),
+        trigger_session_id: trigger_session_id.to_string(),
+        trigger_session_status: TriggerSessionStatus::Running.as_str().to_string(),
+        flow_session_id: flow_session_id.to_string(),
+        flow_session_status: FlowSessionStatus::Running.as_str().to_string(),
+        action_id: trigger_node.action_id.clone(),
+        r#type: ActionType::Trigger,
+        plugin_name: trigger_node.plugin_name.clone(),
+        plugin_version: trigger_node.plugin_version.clone(),
+        stage: match stage {
+            Some(stage) => stage.as_str().to_string(),
+            None if workflow.published => Stage::Production.as_str().to_string(),
+            None => Stage::Testing.as_str().to_string(),
+        },
+        config: TaskConfig {
+            inputs: trigger_node.inputs.clone(),
+            inputs_schema: trigger_node.inputs_schema.clone(),
+            plugin_config: Some(trigger_node.plugin_config.clone()),
+            plugin_config_schema: Some(trigger_node.plugin_config_schema.clone()),
+        },
+        result: Some(inputs),
+        error: None,
+        started_at: Some(Utc::now()),
+        test_config: None,
+    };
+
+    let (response, receiver) = SessionResponder::new();
+
+    // Put the workflow in the cache so the processor doesn't fetch it again
+    state.flow_session_cache.write().await.set(
+        &flow_session_id,
+        FlowSessionData::new(
+            Some(workflow.clone()),
+            flow_session_id,
+            workflow_id,
+            Some(workflow.flow_version_id),
+        ),
+    );
+
+    let processor_message = ProcessorMessage {
+        workflow_id,
+        version_id,
+        flow_session_id,
+        trigger_session_id,
+        trigger_task: Some(trigger_task),
+        response: Some(response),
+        deadline,
+    };
+
+    info!(
+        "[PROCESSOR] Submitting flow session {} and waiting for it to end",
+        flow_session_id
+    );
+
+    if let Err(e) = state.processor_sender.send(processor_message).await {
+        state
+            .flow_session_cache
+            .write()
+            .await
+            .invalidate(&flow_session_id);
+        return Err(format!("Failed to send message to processor: {}", e));
+    }
+
+    Ok((flow_session_id, receiver))
+}
+
+// Called when the processor picks up a message. The responder becomes the session's waiter so it
+// is resolved like any other, unless the caller's deadline already passed or someone else waits
+pub async fn register_session_responder(state: &AppState, message: &ProcessorMessage) {
+    let sender = match message
+        .response
+        .as_ref()
+        .and_then(|response| response.take())
+    {
+        Some(sender) => sender,
+        None => return,
+    };
+
+    if message
+        .deadline
+        .is_some_and(|deadline| deadline <= Utc::now())
+    {
+        warn!(
+            "[PROCESSOR] Caller stopped waiting on flow session {} before it started",
+            message.flow_session_id
+        );
+        return;
+    }
+
+    state
+        .flow_session_waiters
+        .lock()
+        .await
+        .entry(message.flow_session_id)
+        .or_insert(sender);
+}
+
+// The task whose result run_workflow_and_wait hands back as the session's output. Planned tasks
+// that never ran are still Pending and a paused session's approval is Waiting, neither counts
+pub fn flow_session_output_task(tasks: &HashMap<Uuid, Task>) -> Option<&Task> {
+    let is_response = |task: &&Task| {
+        task.r#type == ActionType::Response.as_str() || task.r#type == ActionType::Output.as_str()
+    };
+    let ran = || {
+        tasks.values().filter(|task| {
+            task.task_status != TaskStatus::Pending && task.task_status != TaskStatus::Waiting
+        })
+    };
+
+    ran()
+        .filter(is_response)
+        .max_by_key(|task| task.processing_order)
+        .or_else(|| ran().max_by_key(|task| task.processing_order))
+}
+
+// The output task's result. A truncated preview or large result's reference is swapped for the
+// whole result, should that fail there's no output rather than part of it
+pub async fn flow_session_output(state: Arc<AppState>, task: Option<Task>) -> Option<Value> {
+    let mut tasks = [task?];
+    if let Err(e) = resolve_results(state, &mut tasks).await {
+        error!(
+            "[PROCESSOR] Failed to read the output of task {}: {}",
+            tasks[0].task_id, e
+        );
+        return None;
+    }
+    let [task] = tasks;
+    task.result
+}
+
+pub async fn resolve_flow_session_waiter(
+    state: &AppState,
+    flow_session_id: &Uuid,
+    status: FlowSessionStatus,
+    output: Option<Value>,
+) {
+    let waiter = state
+        .flow_session_waiters
+        .lock()
+        .await
+        .remove(flow_session_id);
+
+    if let Some(sender) = waiter {
+        let duration_ms = state
+            .flow_session_cache
+            .read()
+            .await
+            .get(flow_session_id)
+            .map(|session_data| session_data.total_duration_ms());
+        let outcome = FlowSessionOutcome {
+            flow_session_id: *flow_session_id,
+            status,
+            output,
+            duration_ms,
+        };
+        if sender.send(outcome).is_err() {
+            warn!(
+                "[PROCESSOR] Nobody is waiting on flow session {} anymore",
+                flow_session_id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{
+        action, edge, start_test_processor, task, test_app_state, InMemoryTaskStore,
+    };
+    use crate::processor::large_results::{CreateLargeResultInput, LARGE_RESULT_REF_KEY};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_run_two_step_workflow_and_wait() {
+        let (_, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "http",
+                    "action",
+                    Some(json!({ "mock_result": { "status": 200 } })),
+                ),
+            ],
+            vec![edge("webhook", "http")],
+        )
+        .await;
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": { "name": "anything" } }),
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(outcome.output, Some(json!({ "status": 200 })));
+        assert!(state.flow_session_waiters.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_workflow_with_timeout_finishes_in_time() {
+        let (_, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "http",
+                    "action",
+                    Some(json!({ "mock_result": { "ok": true } })),
+                ),
+            ],
+            vec![edge("webhook", "http")],
+        )
+        .await;
+
+        let outcome = run_workflow_with_timeout(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert_eq!(outcome.output, Some(json!({ "ok": true })));
+        assert!(state.flow_session_waiters.lock().await.is_empty());
+    }
+
+    // On paused time the timeout and the action's delay fire in order without waiting for them
+    #[tokio::test(start_paused = true)]
+    async fn test_run_workflow_with_timeout_gives_up_and_the_session_keeps_running() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "slow",
+                    "action",
+                    Some(json!({ "mock_result": { "ok": true }, "mock_delay_ms": 300 })),
+                ),
+            ],
+            vec![edge("webhook", "slow")],
+        )
+        .await;
+
+        let error = run_workflow_with_timeout(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::GATEWAY_TIMEOUT);
+        let flow_session_id = match error {
+            RunWorkflowError::TimedOut { flow_session_id } => flow_session_id,
+            other => panic!("expected a timeout, got {}", other),
+        };
+
+        // The session finishes without anyone listening, once the action's 300ms are up
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert!(tasks
+            .iter()
+            .all(|task| task.task_status == TaskStatus::Completed));
+        assert!(state.flow_session_waiters.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_task_durations_are_reported() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "slow",
+                    "action",
+                    Some(json!({ "mock_result": {}, "mock_delay_ms": 20 })),
+                ),
+            ],
+            vec![edge("webhook", "slow")],
+        )
+        .await;
+
+        let outcome = run_workflow_and_wait(
+            state,
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+        assert!(outcome.duration_ms.unwrap() >= 20);
+
+        let tasks = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap();
+        assert!(tasks.iter().all(|task| task.duration_ms().unwrap() >= 0));
+        assert!(tasks[1].duration_ms().unwrap() >= 20);
+    }
+
+    #[tokio::test]
+    async fn test_results_are_checked_against_the_output_schema() {
+        let run = |mock_result: Value| async move {
+            let mut lookup = action(
+                "lookup",
+                "action",
+                Some(json!({ "mock_result": mock_result })),
+            );
+            lookup["output_schema"] = json!({
+                "type": "object",
+                "properties": {
+                    "id": { "x-any-validation": { "type": "number" } },
+                    "email": { "x-any-validation": { "type": "string" } }
+                },
+                "required": ["id"]
+            });
+            let (_, state, workflow_id, flow_version_id) = start_test_processor(
+                vec![action("webhook", "trigger", None), lookup],
+                vec![edge("webhook", "lookup")],
+            )
+            .await;
+            run_workflow_and_wait(state, workflow_id, Some(flow_version_id), None, json!({}))
+                .await
+                .unwrap()
+        };
+
+        let conforming = run(json!({ "id": 7, "email": "ada@example.com" })).await;
+        assert!(matches!(conforming.status, FlowSessionStatus::Completed));
+        assert_eq!(
+            conforming.output,
+            Some(json!({ "id": 7, "email": "ada@example.com" }))
+        );
+
+        // The failed task's error is the session's output
+        let off_contract = run(json!({ "email": 42 })).await;
+        assert!(matches!(off_contract.status, FlowSessionStatus::Failed));
+        let error = off_contract.output.unwrap();
+        assert_eq!(
+            error["message"],
+            "Result does not match the action's output_schema"
+        );
+        assert_eq!(error["problems"].as_array().unwrap().len(), 2);
+        assert_eq!(error["result"], json!({ "email": 42 }));
+    }
+
+    #[tokio::test]
+    async fn test_skip_on_empty_action() {
+        let mut batch = action(
+            "batch",
+            "action",
+            Some(json!({ "mock_result": { "sent": true } })),
+        );
+        batch["inputs"] = json!({ "items": "{{actions.webhook.result.items}}" });
+        batch["inputs_schema"] = json!({
+            "type": "object",
+            "properties": { "items": { "x-any-validation": { "type": "array" } } }
+        });
+        batch["skip_on_empty"] = json!("items");
+
+        let (_, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![action("webhook", "trigger", None), batch],
+            vec![edge("webhook", "batch")],
+        )
+        .await;
+
+        // Skipped tasks have no result but the session still completes
+        let skipped = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "items": [] }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(skipped.status, FlowSessionStatus::Completed));
+        assert_eq!(skipped.output, None);
+
+        let sent = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "items": [{ "id": 1 }] }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(sent.status, FlowSessionStatus::Completed));
+        assert_eq!(sent.output, Some(json!({ "sent": true })));
+    }
+
+    #[tokio::test]
+    async fn test_flow_session_output_prefers_response() {
+        let store = Arc::new(InMemoryTaskStore::new());
+        let state = test_app_state(store.clone());
+        let output = |tasks: &HashMap<Uuid, Task>| {
+            flow_session_output(state.clone(), flow_session_output_task(tasks).cloned())
+        };
+        let completed = |action_type: &str, processing_order: i32, result: Value| -> Task {
+            task(
+                "action",
+                action_type,
+                json!({
+                    "task_status": "completed",
+                    "processing_order": processing_order,
+                    "result": result
+                }),
+            )
+        };
+
+        let mut tasks = HashMap::new();
+        for task in [
+            completed("trigger", 0, json!({ "body": {} })),
+            completed("response", 1, json!({ "ok": true })),
+            completed("action", 2, json!({ "status": 200 })),
+        ] {
+            tasks.insert(task.task_id, task);
+        }
+        assert_eq!(output(&tasks).await, Some(json!({ "ok": true })));
+
+        tasks.retain(|_, task| task.r#type != "response");
+        assert_eq!(output(&tasks).await, Some(json!({ "status": 200 })));
+
+        // An offloaded output is handed back whole
+        let rows = json!({ "rows": [1, 2, 3] });
+        let result_id = store
+            .create_large_result(&CreateLargeResultInput {
+                account_id: Uuid::new_v4().to_string(),
+                task_id: Uuid::new_v4().to_string(),
+                flow_session_id: Uuid::new_v4().to_string(),
+                value: rows.clone(),
+                size_bytes: rows.to_string().len(),
+            })
+            .await
+            .unwrap();
+        let offloaded = completed(
+            "output",
+            3,
+            json!({ LARGE_RESULT_REF_KEY: result_id.to_string(), "size_bytes": 20 }),
+        );
+        tasks.insert(offloaded.task_id, offloaded);
+        assert_eq!(output(&tasks).await, Some(rows));
+    }
+}
@@ -0,0 +1,247 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::env;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::processor::processor::{cancel_flow_session, CancelOutcome};
+use crate::processor::write_retries::{write_flow_session_status, write_task_status};
+use crate::types::task_types::{FlowSessionStatus, Task, TaskStatus, TriggerSessionStatus};
+use crate::AppState;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_STALLED_AFTER: Duration = Duration::from_secs(3600);
+
+// Active sessions whose tasks haven't started or ended anything for `stalled_after` as of `now`,
+// e.g. a plugin that never returns or a write that was lost. Sessions that aren't cached are left
+// alone, there's nothing to tell when they last moved. Each stalled session is logged as an event
+// and how many there were is kept in stalled_flow_session_count
+pub async fn find_stalled_sessions(
+    state: &AppState,
+    stalled_after: Duration,
+    now: DateTime<Utc>,
+) -> Vec<Uuid> {
+    let active: Vec<Uuid> = state
+        .active_flow_sessions
+        .lock()
+        .await
+        .iter()
+        .copied()
+        .collect();
+    let cache = state.flow_session_cache.read().await;
+
+    let mut stalled = Vec::new();
+    for flow_session_id in active {
+        let Some(session_data) = cache.get(&flow_session_id) else {
+            continue;
+        };
+        let last_update = session_data
+            .tasks()
+            .values()
+            .flat_map(|task| [task.created_at, task.started_at, task.ended_at])
+            .flatten()
+            .max();
+        let Some(last_update) = last_update else {
+            continue;
+        };
+        let idle = (now - last_update).to_std().unwrap_or_default();
+        if idle >= stalled_after {
+            warn!(
+                flow_session_id = %flow_session_id,
+                stalled_ms = idle.as_millis() as u64,
+                "[PROCESSOR] Flow session has stalled"
+            );
+            stalled.push(flow_session_id);
+        }
+    }
+    state
+        .stalled_flow_session_count
+        .store(stalled.len(), Ordering::Relaxed);
+    stalled
+}
+
+// Cancels the session the way cancel_flow_session does, so the hung task is dropped and its slot
+// freed, except the processor then ends it Failed (see fail_stalled_task) instead of Canceled
+pub async fn fail_stalled_session(state: &AppState, flow_session_id: &Uuid) -> CancelOutcome {
+    warn!(
+        "[PROCESSOR] Failing stalled flow session {}",
+        flow_session_id
+    );
+    state
+        .stalled_flow_sessions
+        .write()
+        .await
+        .insert(*flow_session_id);
+    let outcome = cancel_flow_session(state, flow_session_id).await;
+    if outcome != CancelOutcome::Signaled {
+        state
+            .stalled_flow_sessions
+            .write()
+            .await
+            .remove(flow_session_id);
+    }
+    outcome
+}
+
+// What the processor does with the task it stopped when the session was canceled for stalling.
+// Returns the session's output, the processor hands it to whoever waits on the session
+pub async fn fail_stalled_task(state: &AppState, flow_session_id: &Uuid, task: &Task) -> Value {
+    let output = json!({ "error": "Flow session stalled" });
+
+    write_task_status(
+        state,
+        &task.task_id,
+        &TaskStatus::Failed,
+        None,
+        None,
+        None,
+        Some(json!({ "message": "Flow session stalled" })),
+    )
+    .await;
+    write_flow_session_status(
+        state,
+        flow_session_id,
+        &FlowSessionStatus::Failed,
+        &TriggerSessionStatus::Failed,
+    )
+    .await;
+
+    {
+        let mut cache = state.flow_session_cache.write().await;
+        let mut task_copy = task.clone();
+        task_copy.task_status = TaskStatus::Failed;
+        task_copy.ended_at = Some(Utc::now());
+        if let Err(e) = cache.update_task(flow_session_id, task_copy) {
+            warn!("[PROCESSOR] Failed to update task in cache: {}", e);
+        }
+    }
+
+    let mut completions = state.flow_completions.lock().await;
+    if let Some(completion) = completions.remove(&flow_session_id.to_string()) {
+        if completion.needs_response {
+            let _ = completion.sender.send(output.clone());
+        }
+    }
+    output
+}
+
+// STALLED_SESSION_AFTER_SECS is how long a session can go without a task update, an hour by
+// default. Stalled sessions are only logged unless FAIL_STALLED_SESSIONS is true
+pub async fn stalled_session_loop(state: Arc<AppState>) {
+    let stalled_after = env::var("STALLED_SESSION_AFTER_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_STALLED_AFTER);
+    let fail_stalled = env::var("FAIL_STALLED_SESSIONS").is_ok_and(|value| value == "true");
+    info!(
+        "[PROCESSOR] Checking for flow sessions stalled for {:?}",
+        stalled_after
+    );
+
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        let stalled = find_stalled_sessions(&state, stalled_after, Utc::now()).await;
+        if fail_stalled {
+            for flow_session_id in &stalled {
+                fail_stalled_session(&state, flow_session_id).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{action, edge, start_test_processor};
+    use crate::processor::run_workflow::run_workflow_and_wait;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_session_is_found_and_failed() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action(
+                    "hung",
+                    "action",
+                    Some(json!({ "mock_result": {}, "mock_delay_ms": 60_000 })),
+                ),
+            ],
+            vec![edge("webhook", "hung")],
+        )
+        .await;
+        let run = tokio::spawn(run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({}),
+        ));
+
+        let flow_session_id = loop {
+            let active = state
+                .active_flow_sessions
+                .lock()
+                .await
+                .iter()
+                .next()
+                .copied();
+            if let Some(flow_session_id) = active {
+                let hung_started = state
+                    .flow_session_cache
+                    .read()
+                    .await
+                    .get(&flow_session_id)
+                    .is_some_and(|session| session.get_task_by_action_id("hung").is_some());
+                if hung_started {
+                    break flow_session_id;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let now = Utc::now();
+        assert!(find_stalled_sessions(&state, Duration::from_secs(60), now)
+            .await
+            .is_empty());
+        assert_eq!(state.stalled_flow_session_count.load(Ordering::Relaxed), 0);
+        let later = now + chrono::Duration::seconds(61);
+        assert_eq!(
+            find_stalled_sessions(&state, Duration::from_secs(60), later).await,
+            vec![flow_session_id]
+        );
+        assert_eq!(state.stalled_flow_session_count.load(Ordering::Relaxed), 1);
+
+        // The session holds one of the processor's slots until the hung task lets go of it
+        let free_permits = state.workflow_processor_semaphore.available_permits();
+        assert_eq!(
+            fail_stalled_session(&state, &flow_session_id).await,
+            CancelOutcome::Signaled
+        );
+        let outcome = run.await.unwrap().unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Failed));
+        assert_eq!(
+            outcome.output,
+            Some(json!({ "error": "Flow session stalled" }))
+        );
+        assert!(!state
+            .active_flow_sessions
+            .lock()
+            .await
+            .contains(&flow_session_id));
+        assert_eq!(
+            state.workflow_processor_semaphore.available_permits(),
+            free_permits + 1
+        );
+        let tasks = store.get_tasks_for_session(&flow_session_id).await.unwrap();
+        assert!(tasks
+            .iter()
+            .all(|task| matches!(task.flow_session_status, FlowSessionStatus::Failed)));
+        let hung = tasks.iter().find(|task| task.action_id == "hung").unwrap();
+        assert_eq!(hung.task_status, TaskStatus::Failed);
+        assert!(state.stalled_flow_sessions.read().await.is_empty());
+    }
+}
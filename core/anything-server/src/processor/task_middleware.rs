@@ -0,0 +1,110 @@
+use serde_json::Value;
+
+use crate::processor::execute_task::TaskResult;
+use crate::types::task_types::Task;
+
+// What a task runs with once bundling is done. Middleware can change it before the plugin sees it
+#[derive(Debug, Clone)]
+pub struct BundledInput {
+    pub inputs: Value,
+    pub plugin_config: Value,
+}
+
+// Runs around every task execute_task runs, e.g. for logging, metrics or redaction, in the order
+// it was registered on AppState. before_execute only runs when bundling worked, after_execute
+// sees every result including bundling failures and skipped tasks
+pub trait TaskMiddleware: Send + Sync {
+    fn before_execute(&self, _task: &Task, _input: &mut BundledInput) {}
+
+    fn after_execute(&self, _task: &Task, _result: &TaskResult) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::db_calls::TaskStore;
+    use crate::processor::in_memory_task_store::{action, edge, start_test_processor};
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use crate::types::task_types::FlowSessionStatus;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TaskMiddleware for Recorder {
+        fn before_execute(&self, task: &Task, input: &mut BundledInput) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} before {}", self.name, task.action_id));
+            input.inputs[self.name] = json!(true);
+        }
+
+        fn after_execute(&self, task: &Task, result: &TaskResult) {
+            self.calls.lock().unwrap().push(format!(
+                "{} after {} ok={}",
+                self.name,
+                task.action_id,
+                result.is_ok()
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_runs_in_order_and_can_change_inputs() {
+        let (store, state, workflow_id, flow_version_id) = start_test_processor(
+            vec![
+                action("webhook", "trigger", None),
+                action("http", "action", Some(json!({ "mock_result": {} }))),
+            ],
+            vec![edge("webhook", "http")],
+        )
+        .await;
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        for name in ["first", "second"] {
+            state.task_middleware.write().await.push(Arc::new(Recorder {
+                name,
+                calls: calls.clone(),
+            }));
+        }
+
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({ "body": {} }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(outcome.status, FlowSessionStatus::Completed));
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "first before webhook",
+                "second before webhook",
+                "first after webhook ok=true",
+                "second after webhook ok=true",
+                "first before http",
+                "second before http",
+                "first after http ok=true",
+                "second after http ok=true",
+            ]
+        );
+
+        // The task ran with and recorded what the middleware changed
+        let tasks = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            tasks[1].bundled_inputs,
+            Some(json!({ "first": true, "second": true }))
+        );
+    }
+}
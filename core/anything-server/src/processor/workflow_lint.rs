@@ -0,0 +1,637 @@
+use postgrest::Postgrest;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use crate::bundler::plan_bundle;
+use crate::bundler::secrets::get_decrypted_secrets;
+use crate::templater::{TemplateError, Templater};
+use crate::types::{
+    action_types::{Action, ActionType},
+    json_schema::JsonSchema,
+    task_types::Stage,
+    workflow_types::WorkflowVersionDefinition,
+};
+use crate::AppState;
+
+// What the bundler puts in the context inputs are rendered with. `loop` is only set for tasks a
+// Loop runs, see LoopIteration. plugin_config only sees `inputs`
+const INPUT_NAMESPACES: [&str; 6] = ["actions", "accounts", "secrets", "system", "config", "loop"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LintIssue {
+    pub action_id: String,
+    pub field: String, // e.g. "inputs.url" or "plugin_config.body"
+    pub message: String,
+}
+
+// Statically checks every `{{ }}` in the workflow without running it. References have to point at
+// a namespace the bundler provides, and `actions.<id>` at an action that runs before this one.
+// Literal values are checked against their x-any-validation type
+pub fn lint_workflow(workflow: &WorkflowVersionDefinition) -> Vec<LintIssue> {
+    lint(workflow, None)
+}
+
+// The lint plus what needs the account to check, for the editor to call before a run. Every
+// `secrets.<NAME>` has to be one of its secrets, then each action the lint passes is bundled in
+// plan mode (see plan_bundle) with `inputs` as the trigger's result. Template, account and
+// validation errors from the bundler are issues. Nothing is run or written.
+// Other actions' results are only known for the ones with a mock_result, references to the rest
+// and to `loop.*` can't be checked before the run
+pub async fn preflight_workflow(
+    state: Arc<AppState>,
+    client: &Postgrest,
+    account_id: &str,
+    stage: &Stage,
+    workflow: &WorkflowVersionDefinition,
+    inputs: &Value,
+) -> Result<Vec<LintIssue>, String> {
+    let secrets = get_decrypted_secrets(state.clone(), account_id)
+        .await
+        .map_err(|e| format!("Failed to fetch secrets: {}", e))?;
+    let secret_names: HashSet<&str> = secrets
+        .iter()
+        .map(|secret| secret.secret_name.as_str())
+        .collect();
+    let mut issues = lint(workflow, Some(&secret_names));
+
+    let planned_actions: Map<String, Value> = workflow
+        .actions
+        .iter()
+        .filter_map(|action| {
+            let result = match action.r#type {
+                ActionType::Trigger => inputs.clone(),
+                _ => action.test_config.as_ref()?.get("mock_result")?.clone(),
+            };
+            Some((action.action_id.clone(), json!({ "result": result })))
+        })
+        .collect();
+
+    for action in &workflow.actions {
+        // Its lint issues would only come back as the bundler's first error
+        if issues
+            .iter()
+            .any(|issue| issue.action_id == action.action_id)
+        {
+            continue;
+        }
+        let error = match plan_bundle(
+            state.clone(),
+            client,
+            account_id,
+            stage,
+            action,
+            &planned_actions,
+        )
+        .await
+        {
+            Ok(_) => continue,
+            Err(error) => error,
+        };
+
+        let issue = match error.downcast_ref::<TemplateError>() {
+            Some(e) if reads_unknown_value(&e.variable, &planned_actions) => None,
+            Some(e) => Some(LintIssue {
+                action_id: action.action_id.clone(),
+                field: field_with_variable(action, &e.variable),
+                message: e.message.clone(),
+            }),
+            None => Some(LintIssue {
+                action_id: action.action_id.clone(),
+                field: "inputs".to_string(),
+                message: error.to_string(),
+            }),
+        };
+        issues.extend(issue);
+    }
+
+    Ok(issues)
+}
+
+// Values only a run has, an action's result without a mock_result or the loop's item
+fn reads_unknown_value(variable: &str, planned_actions: &Map<String, Value>) -> bool {
+    Templater::referenced_paths(variable).iter().any(|path| {
+        let mut segments = path.split(['.', '[']);
+        match (segments.next(), segments.next()) {
+            (Some("loop"), _) => true,
+            (Some("actions"), Some(action_id)) => !planned_actions.contains_key(action_id),
+            _ => false,
+        }
+    })
+}
+
+// e.g. "inputs.url" for the field the bundler failed on. The bundler only says which variable, or
+// which field for a validation error
+fn field_with_variable(action: &Action, variable: &str) -> String {
+    let mut fields = Vec::new();
+    for (section, values) in [
+        ("inputs", action.inputs.as_ref()),
+        ("plugin_config", Some(&action.plugin_config)),
+    ] {
+        if let Some(Value::Object(values)) = values {
+            fields.extend(values.iter().map(|(key, value)| (section, key, value)));
+        }
+    }
+
+    let uses_variable = |key: &str, value: &Value| {
+        let mut templater = Templater::new();
+        templater.add_template(key, value.clone());
+        templater
+            .get_template_variables(key)
+            .is_ok_and(|variables| variables.iter().any(|v| v.contains(variable)))
+    };
+    fields
+        .iter()
+        .find(|(_, key, _)| *key == variable)
+        .or_else(|| {
+            fields
+                .iter()
+                .find(|(_, key, value)| uses_variable(key, value))
+        })
+        .map_or_else(
+            || "inputs".to_string(),
+            |(section, key, _)| format!("{}.{}", section, key),
+        )
+}
+
+fn lint(
+    workflow: &WorkflowVersionDefinition,
+    secret_names: Option<&HashSet<&str>>,
+) -> Vec<LintIssue> {
+    let action_ids: HashSet<&str> = workflow
+        .actions
+        .iter()
+        .map(|action| action.action_id.as_str())
+        .collect();
+
+    let mut issues = Vec::new();
+    for action in &workflow.actions {
+        let upstream = upstream_actions(workflow, &action.action_id);
+        let input_names: HashSet<&str> = match &action.inputs {
+            Some(Value::Object(inputs)) => inputs.keys().map(String::as_str).collect(),
+            _ => HashSet::new(),
+        };
+
+        let check_reference = |path: &str, in_plugin_config: bool| -> Option<String> {
+            let mut segments = path.split(['.', '[']);
+            let namespace = segments.next().unwrap_or_default();
+            let name = segments.next().unwrap_or_default();
+
+            if in_plugin_config {
+                return match namespace {
+                    "inputs" if input_names.contains(name) => None,
+                    "inputs" => Some(format!("'{}' is not one of this action's inputs", path)),
+                    _ => Some(format!(
+                        "Unknown reference '{}'. Plugin config can only use inputs",
+                        path
+                    )),
+                };
+            }
+
+            match namespace {
+                "actions" if name == action.action_id => {
+                    Some(format!("'{}' references this action's own result", path))
+                }
+                "actions" if !action_ids.contains(name) => Some(format!(
+                    "'{}' references an action that doesn't exist",
+                    path
+                )),
+                "actions" if !upstream.contains(name) => Some(format!(
+                    "'{}' references an action that doesn't run before this one",
+                    path
+                )),
+                "secrets" if secret_names.is_some_and(|names| !names.contains(name)) => {
+                    Some(format!("'{}' references a secret that doesn't exist", path))
+                }
+                namespace if INPUT_NAMESPACES.contains(&namespace) => None,
+                _ => Some(format!(
+                    "Unknown reference '{}'. Expected one of {}",
+                    path,
+                    INPUT_NAMESPACES.join(", ")
+                )),
+            }
+        };
+
+        for (field, value, schema, in_plugin_config) in [
+            (
+                "inputs",
+                action.inputs.as_ref(),
+                action.inputs_schema.as_ref(),
+                false,
+            ),
+            (
+                "plugin_config",
+                Some(&action.plugin_config),
+                Some(&action.plugin_config_schema),
+                true,
+            ),
+        ] {
+            let fields = match value {
+                Some(Value::Object(fields)) => fields,
+                _ => continue,
+            };
+
+            for (key, value) in fields {
+                let field = format!("{}.{}", field, key);
+                let mut issue = |message: String| {
+                    issues.push(LintIssue {
+                        action_id: action.action_id.clone(),
+                        field: field.clone(),
+                        message,
+                    })
+                };
+
+                let mut templater = Templater::new();
+                templater.add_template(&field, value.clone());
+                let variables = match templater.get_template_variables(&field) {
+                    Ok(variables) => variables,
+                    Err(e) => {
+                        issue(e.message);
+                        continue;
+                    }
+                };
+
+                if variables.is_empty() {
+                    if let Some(message) = check_literal_type(schema, key, value) {
+                        issue(message);
+                    }
+                    continue;
+                }
+
+                for variable in &variables {
+                    for path in Templater::referenced_paths(variable) {
+                        if let Some(message) = check_reference(path, in_plugin_config) {
+                            issue(message);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    issues
+}
+
+// Every action with a path to action_id. Those are the only ones guaranteed to have run before it
+fn upstream_actions<'a>(
+    workflow: &'a WorkflowVersionDefinition,
+    action_id: &str,
+) -> HashSet<&'a str> {
+    let mut incoming: HashMap<&str, Vec<&str>> = HashMap::new();
+    for edge in &workflow.edges {
+        incoming
+            .entry(edge.target.as_str())
+            .or_default()
+            .push(edge.source.as_str());
+    }
+
+    let mut upstream = HashSet::new();
+    let mut queue: VecDeque<&str> = incoming.get(action_id).cloned().unwrap_or_default().into();
+    while let Some(source) = queue.pop_front() {
+        if source != action_id && upstream.insert(source) {
+            queue.extend(incoming.get(source).into_iter().flatten());
+        }
+    }
+    upstream
+}
+
+fn check_literal_type(schema: Option<&JsonSchema>, key: &str, value: &Value) -> Option<String> {
+    let validation_type = schema?
+        .properties
+        .as_ref()?
+        .get(key)?
+        .x_any_validation
+        .as_ref()?
+        .r#type
+        .clone();
+    Templater::validate_and_convert_value(value.clone(), &validation_type, key)
+        .err()
+        .map(|e| e.message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::secrets::InMemorySecretProvider;
+    use crate::processor::in_memory_task_store::{
+        account, action, edge, test_app_state_with_secret_provider, InMemoryTaskStore,
+    };
+
+    fn workflow(actions: Vec<Value>, edges: Vec<Value>) -> WorkflowVersionDefinition {
+        serde_json::from_value(json!({ "actions": actions, "edges": edges })).unwrap()
+    }
+
+    fn http(action_id: &str, inputs: Value) -> Value {
+        let mut http = action(action_id, "action", None);
+        http["inputs"] = inputs;
+        http["plugin_config"] = json!({ "url": "{{inputs.url}}" });
+        http
+    }
+
+    #[test]
+    fn test_valid_references() {
+        let workflow = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                http(
+                    "fetch",
+                    json!({ "url": "https://{{system.host}}/{{actions.webhook.result.body.id}}" }),
+                ),
+                http(
+                    "post",
+                    json!({
+                        "url": "{{actions.fetch.result.ok ? actions.webhook.result.url : 'none'}}",
+                        "token": "{{secrets.API_KEY}}",
+                        "items": "{{#each actions.fetch.result.items}}{{this.id}}{{/each}}"
+                    }),
+                ),
+            ],
+            vec![edge("webhook", "fetch"), edge("fetch", "post")],
+        );
+        assert_eq!(lint_workflow(&workflow), vec![]);
+    }
+
+    #[test]
+    fn test_loop_child_references_its_iteration() {
+        let workflow = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                action("each_order", "loop", None),
+                http(
+                    "fetch",
+                    json!({
+                        "url": "https://example.com/orders/{{loop.item.id}}?page={{loop.index}}",
+                        "previous": "{{loop.first ? 'none' : loop.prev_result.status}}",
+                        "done": "{{loop.last}}"
+                    }),
+                ),
+            ],
+            vec![edge("webhook", "each_order"), edge("each_order", "fetch")],
+        );
+        assert_eq!(lint_workflow(&workflow), vec![]);
+    }
+
+    #[test]
+    fn test_unresolved_references() {
+        let mut post = http(
+            "post",
+            json!({
+                "url": "{{actions.missing.result.url}}",
+                "body": "{{actions.later.result}}",
+                "user": "{{variables.user}}"
+            }),
+        );
+        post["plugin_config"] = json!({ "url": "{{inputs.url}}", "body": "{{inputs.payload}}" });
+        let workflow = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                post,
+                action("later", "action", None),
+            ],
+            vec![edge("webhook", "post"), edge("post", "later")],
+        );
+
+        let mut issues = lint_workflow(&workflow);
+        issues.sort_by(|a, b| a.field.cmp(&b.field));
+        let messages: Vec<(&str, &str)> = issues
+            .iter()
+            .map(|issue| (issue.field.as_str(), issue.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    "inputs.body",
+                    "'actions.later.result' references an action that doesn't run before this one"
+                ),
+                (
+                    "inputs.url",
+                    "'actions.missing.result.url' references an action that doesn't exist"
+                ),
+                (
+                    "inputs.user",
+                    "Unknown reference 'variables.user'. Expected one of actions, accounts, secrets, system, config, loop"
+                ),
+                (
+                    "plugin_config.body",
+                    "'inputs.payload' is not one of this action's inputs"
+                ),
+            ]
+        );
+        assert!(issues.iter().all(|issue| issue.action_id == "post"));
+    }
+
+    #[test]
+    fn test_unclosed_brace_and_literal_type() {
+        let mut fetch = http(
+            "fetch",
+            json!({ "url": "https://{{actions.webhook.result.host", "timeout": "soon" }),
+        );
+        fetch["inputs_schema"] = json!({
+            "type": "object",
+            "properties": {
+                "timeout": { "type": "number", "x-any-validation": { "type": "number" } }
+            }
+        });
+        let workflow = workflow(
+            vec![action("webhook", "trigger", None), fetch],
+            vec![edge("webhook", "fetch")],
+        );
+
+        let mut issues = lint_workflow(&workflow);
+        issues.sort_by(|a, b| a.field.cmp(&b.field));
+        assert_eq!(
+            issues,
+            vec![
+                LintIssue {
+                    action_id: "fetch".to_string(),
+                    field: "inputs.timeout".to_string(),
+                    message: "Cannot convert value to number: soon".to_string(),
+                },
+                LintIssue {
+                    action_id: "fetch".to_string(),
+                    field: "inputs.url".to_string(),
+                    message: "Unclosed template variable".to_string(),
+                },
+            ]
+        );
+    }
+
+    // Every field of the inputs and plugin config is a string, so they bundle
+    fn validated(mut action: Value) -> Value {
+        for (field, schema) in [
+            ("inputs", "inputs_schema"),
+            ("plugin_config", "plugin_config_schema"),
+        ] {
+            let properties: Map<String, Value> = action[field]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(key, _)| {
+                    (
+                        key.clone(),
+                        json!({ "x-any-validation": { "type": "string" } }),
+                    )
+                })
+                .collect();
+            action[schema] = json!({ "type": "object", "properties": properties });
+        }
+        action
+    }
+
+    async fn preflight_state(account_id: &str, secret_names: &[&str]) -> Arc<AppState> {
+        let secrets = secret_names
+            .iter()
+            .map(|name| {
+                serde_json::from_value(json!({
+                    "secret_id": uuid::Uuid::new_v4(),
+                    "secret_name": name,
+                    "secret_value": "sk-test",
+                    "secret_description": null
+                }))
+                .unwrap()
+            })
+            .collect();
+        let state = test_app_state_with_secret_provider(
+            Arc::new(InMemoryTaskStore::new()),
+            Arc::new(InMemorySecretProvider::new(HashMap::from([(
+                account_id.to_string(),
+                secrets,
+            )]))),
+        );
+        let mut account = account(account_id, "slack");
+        account.access_token = "xoxb-test".to_string();
+        state
+            .bundler_accounts_cache
+            .write()
+            .await
+            .set(account_id, vec![account]);
+        state
+    }
+
+    async fn preflight(
+        state: &Arc<AppState>,
+        account_id: &str,
+        workflow: &WorkflowVersionDefinition,
+        inputs: Value,
+    ) -> Vec<LintIssue> {
+        preflight_workflow(
+            state.clone(),
+            &state.anything_client,
+            account_id,
+            &Stage::Testing,
+            workflow,
+            &inputs,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preflight_checks_secrets_exist() {
+        let account_id = uuid::Uuid::new_v4().to_string();
+        let workflow = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                validated(http(
+                    "post",
+                    json!({
+                        "url": "https://example.com/{{system.run.action_id}}",
+                        "token": "{{secrets.API_KEY}}",
+                        "signing_key": "{{secrets.SIGNING_KEY}}"
+                    }),
+                )),
+            ],
+            vec![edge("webhook", "post")],
+        );
+
+        let state = preflight_state(&account_id, &["API_KEY", "SIGNING_KEY"]).await;
+        assert_eq!(
+            preflight(&state, &account_id, &workflow, json!({})).await,
+            vec![]
+        );
+
+        // Reported once by the lint, the bundler's error for it would only repeat it
+        let state = preflight_state(&account_id, &["API_KEY"]).await;
+        assert_eq!(
+            preflight(&state, &account_id, &workflow, json!({})).await,
+            vec![LintIssue {
+                action_id: "post".to_string(),
+                field: "inputs.signing_key".to_string(),
+                message: "'secrets.SIGNING_KEY' references a secret that doesn't exist".to_string(),
+            }]
+        );
+        // The lint alone doesn't know the account's secrets
+        assert_eq!(lint_workflow(&workflow), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_bundles_actions_with_the_run_inputs() {
+        let account_id = uuid::Uuid::new_v4().to_string();
+        let mut fetch = validated(http(
+            "fetch",
+            json!({
+                "url": "https://example.com/{{actions.webhook.result.body.id}}",
+                "token": "Bearer {{accounts.slack.access_token}}",
+                "timeout": "{{actions.webhook.result.body.timeout}}"
+            }),
+        ));
+        fetch["inputs_schema"]["properties"]["timeout"] =
+            json!({ "x-any-validation": { "type": "number" } });
+        fetch["test_config"] = json!({ "mock_result": { "id": 7 } });
+        let workflow = workflow(
+            vec![
+                action("webhook", "trigger", None),
+                fetch,
+                validated(http(
+                    "enrich",
+                    json!({ "url": "https://example.com/{{actions.fetch.result.id}}" }),
+                )),
+                // enrich has no mock_result, what it returns is only known once it runs
+                validated(http(
+                    "post",
+                    json!({ "url": "https://example.com/{{actions.enrich.result.name}}" }),
+                )),
+            ],
+            vec![
+                edge("webhook", "fetch"),
+                edge("fetch", "enrich"),
+                edge("enrich", "post"),
+            ],
+        );
+        let state = preflight_state(&account_id, &[]).await;
+
+        let inputs = json!({ "body": { "id": 7, "timeout": "30" } });
+        assert_eq!(
+            preflight(&state, &account_id, &workflow, inputs).await,
+            vec![]
+        );
+
+        let issue = |field: &str, message: &str| LintIssue {
+            action_id: "fetch".to_string(),
+            field: field.to_string(),
+            message: message.to_string(),
+        };
+        assert_eq!(
+            preflight(&state, &account_id, &workflow, json!({ "body": {} })).await,
+            vec![issue(
+                "inputs.url",
+                "Variable not found in context: actions.webhook.result.body.id"
+            )]
+        );
+        assert_eq!(
+            preflight(
+                &state,
+                &account_id,
+                &workflow,
+                json!({ "body": { "id": 7, "timeout": "soon" } })
+            )
+            .await,
+            vec![issue(
+                "inputs.timeout",
+                "Cannot convert value to number: soon"
+            )]
+        );
+    }
+}
@@ -0,0 +1,834 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::processor::db_calls::TaskStore;
+use crate::types::task_types::{FlowSessionStatus, TaskStatus, TriggerSessionStatus};
+use crate::AppState;
+
+const DEFAULT_MAX_PENDING_WRITES: usize = 10_000;
+const DEFAULT_QUEUE_FILE: &str = "write_retries.json";
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(200);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+
+// A status write the processor doesn't wait for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum StatusWrite {
+    Task {
+        task_id: Uuid,
+        status: TaskStatus,
+        context: Option<Value>,
+        bundled_inputs: Option<Value>,
+        result: Option<Value>,
+        error: Option<Value>,
+    },
+    FlowSession {
+        flow_session_id: Uuid,
+        flow_session_status: FlowSessionStatus,
+        trigger_session_status: TriggerSessionStatus,
+    },
+}
+
+// What a write updates, writes to the same target have to land in the order they were made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Target {
+    Task(Uuid),
+    FlowSession(Uuid),
+}
+
+impl StatusWrite {
+    fn target(&self) -> Target {
+        match self {
+            StatusWrite::Task { task_id, .. } => Target::Task(*task_id),
+            StatusWrite::FlowSession {
+                flow_session_id, ..
+            } => Target::FlowSession(*flow_session_id),
+        }
+    }
+
+    // Like the store, only what the newer write sets replaces what this one had
+    fn merge(&mut self, newer: StatusWrite) {
+        match (self, newer) {
+            (
+                StatusWrite::Task {
+                    status,
+                    context,
+                    bundled_inputs,
+                    result,
+                    error,
+                    ..
+                },
+                StatusWrite::Task {
+                    status: newer_status,
+                    context: newer_context,
+                    bundled_inputs: newer_bundled_inputs,
+                    result: newer_result,
+                    error: newer_error,
+                    ..
+                },
+            ) => {
+                *status = newer_status;
+                if newer_context.is_some() {
+                    *context = newer_context;
+                }
+                if newer_bundled_inputs.is_some() {
+                    *bundled_inputs = newer_bundled_inputs;
+                }
+                if newer_result.is_some() {
+                    *result = newer_result;
+                }
+                if newer_error.is_some() {
+                    *error = newer_error;
+                }
+            }
+            (write, newer) => *write = newer,
+        }
+    }
+
+    async fn apply(&self, task_store: &dyn TaskStore) -> Result<(), String> {
+        match self {
+            StatusWrite::Task {
+                task_id,
+                status,
+                context,
+                bundled_inputs,
+                result,
+                error,
+            } => {
+                task_store
+                    .update_task_status(
+                        task_id,
+                        status,
+                        context.clone(),
+                        bundled_inputs.clone(),
+                        result.clone(),
+                        error.clone(),
+                    )
+                    .await
+            }
+            StatusWrite::FlowSession {
+                flow_session_id,
+                flow_session_status,
+                trigger_session_status,
+            } => {
+                task_store
+                    .update_flow_session_status(
+                        flow_session_id,
+                        flow_session_status,
+                        trigger_session_status,
+                    )
+                    .await
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            StatusWrite::Task {
+                task_id, status, ..
+            } => format!("task {} status {}", task_id, status.as_str()),
+            StatusWrite::FlowSession {
+                flow_session_id,
+                flow_session_status,
+                ..
+            } => format!(
+                "flow session {} status {}",
+                flow_session_id,
+                flow_session_status.as_str()
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PendingWrite {
+    write: StatusWrite,
+    attempts: u32,
+    sequence: u64, // Of the newest write merged into this one
+    #[serde(skip, default = "Instant::now")]
+    next_attempt: Instant, // A queue loaded from its file retries everything right away
+}
+
+impl PendingWrite {
+    // What's saved to the file. Contexts and bundled inputs hold request headers and rendered
+    // secrets so they're left out, a retry loaded back from the file keeps the store's copy of them
+    fn saved(&self) -> PendingWrite {
+        let write = match &self.write {
+            StatusWrite::Task {
+                task_id,
+                status,
+                result,
+                error,
+                ..
+            } => StatusWrite::Task {
+                task_id: *task_id,
+                status: status.clone(),
+                context: None,
+                bundled_inputs: None,
+                result: result.clone(),
+                error: error.clone(),
+            },
+            write => write.clone(),
+        };
+        PendingWrite {
+            write,
+            attempts: self.attempts,
+            sequence: self.sequence,
+            next_attempt: self.next_attempt,
+        }
+    }
+}
+
+// Writes to a target that were sent to the store and haven't come back yet
+#[derive(Default)]
+struct InFlight {
+    writes: usize,
+    landed: u64, // Sequence of the newest of them the store took, 0 for none
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<PendingWrite>,
+    in_flight: HashMap<Target, InFlight>,
+    next_sequence: u64,
+}
+
+// Status writes that failed, retried by write_retry_loop with backoff until the store takes
+// them. Every write is numbered when it's made. A later write for the same task or session is
+// merged into the pending one, and a failed write is dropped when a newer one for its target
+// already landed, so an old status can't land after a new one.
+// With a file the queue is saved to it SAVE_DELAY after it changes and loaded back at startup, so
+// pending writes outlast a restart as well as a DB outage
+pub struct WriteRetryQueue {
+    queue: Mutex<Queue>,
+    max_pending: usize,
+    file: Option<PathBuf>,
+    added: Notify,
+    changed: Notify, // Since the last save
+}
+
+impl WriteRetryQueue {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            queue: Mutex::new(Queue {
+                next_sequence: 1,
+                ..Queue::default()
+            }),
+            max_pending,
+            file: None,
+            added: Notify::new(),
+            changed: Notify::new(),
+        }
+    }
+
+    // Starts with the writes saved in `file`. A missing file is an empty queue, an unreadable
+    // one is logged and replaced
+    pub fn with_file(max_pending: usize, file: PathBuf) -> Self {
+        let pending: VecDeque<PendingWrite> = match std::fs::read_to_string(&file) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                error!(
+                    "[PROCESSOR] Write retry queue file {} is invalid, starting empty: {}",
+                    file.display(),
+                    e
+                );
+                VecDeque::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => {
+                error!(
+                    "[PROCESSOR] Failed to read write retry queue file {}, starting empty: {}",
+                    file.display(),
+                    e
+                );
+                VecDeque::new()
+            }
+        };
+        if !pending.is_empty() {
+            info!(
+                "[PROCESSOR] Loaded {} pending status writes from {}",
+                pending.len(),
+                file.display()
+            );
+        }
+        let next_sequence = pending.iter().map(|p| p.sequence).max().unwrap_or(0) + 1;
+        Self {
+            queue: Mutex::new(Queue {
+                pending,
+                in_flight: HashMap::new(),
+                next_sequence,
+            }),
+            max_pending,
+            file: Some(file),
+            added: Notify::new(),
+            changed: Notify::new(),
+        }
+    }
+
+    // WRITE_RETRY_QUEUE_SIZE caps how many writes are held, the oldest is dropped past it.
+    // WRITE_RETRY_QUEUE_FILE is where they're saved, write_retries.json by default
+    pub fn from_env() -> Self {
+        let max_pending = env::var("WRITE_RETRY_QUEUE_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_MAX_PENDING_WRITES);
+        let file =
+            env::var("WRITE_RETRY_QUEUE_FILE").unwrap_or_else(|_| DEFAULT_QUEUE_FILE.to_string());
+        Self::with_file(max_pending, PathBuf::from(file))
+    }
+
+    pub async fn pending_writes(&self) -> usize {
+        self.queue.lock().await.pending.len()
+    }
+
+    // The result a task's pending write sets, the store's copy is stale until it lands
+    pub async fn pending_result(&self, task_id: &Uuid) -> Option<Value> {
+        let queue = self.queue.lock().await;
+        queue
+            .pending
+            .iter()
+            .find_map(|pending| match &pending.write {
+                StatusWrite::Task {
+                    task_id: pending_task_id,
+                    result,
+                    ..
+                } if pending_task_id == task_id => result.clone(),
+                _ => None,
+            })
+    }
+
+    // Numbers the write. If one for its target is pending it's merged in and None is returned,
+    // otherwise it's counted as in flight until finish_write
+    async fn start_write(&self, write: &StatusWrite) -> Option<u64> {
+        let mut queue = self.queue.lock().await;
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        let target = write.target();
+        match queue
+            .pending
+            .iter_mut()
+            .find(|pending| pending.write.target() == target)
+        {
+            Some(pending) => {
+                pending.write.merge(write.clone());
+                pending.sequence = sequence;
+                self.changed();
+                None
+            }
+            None => {
+                queue.in_flight.entry(target).or_default().writes += 1;
+                Some(sequence)
+            }
+        }
+    }
+
+    // Queues the write if the store failed it, unless a newer write to its target already landed
+    async fn finish_write(&self, write: StatusWrite, sequence: u64, written: Result<(), String>) {
+        let mut queue = self.queue.lock().await;
+        let target = write.target();
+        let landed = match queue.in_flight.get_mut(&target) {
+            Some(in_flight) => {
+                in_flight.writes -= 1;
+                if written.is_ok() {
+                    in_flight.landed = in_flight.landed.max(sequence);
+                }
+                let landed = in_flight.landed;
+                if in_flight.writes == 0 {
+                    queue.in_flight.remove(&target);
+                }
+                landed
+            }
+            None => 0,
+        };
+
+        let queued = match written {
+            Ok(()) => {
+                // A write made before this one failed while it was in flight
+                let before = queue.pending.len();
+                queue.pending.retain(|pending| {
+                    pending.write.target() != target || pending.sequence > sequence
+                });
+                if queue.pending.len() != before {
+                    warn!(
+                        "[PROCESSOR] Dropped a pending write older than {}",
+                        write.describe()
+                    );
+                    self.changed();
+                }
+                false
+            }
+            Err(_) if landed > sequence => {
+                warn!(
+                    "[PROCESSOR] Dropped failed {}, a newer write already landed",
+                    write.describe()
+                );
+                false
+            }
+            Err(e) => {
+                warn!(
+                    "[PROCESSOR] Failed to write {}, will retry: {}",
+                    write.describe(),
+                    e
+                );
+                match queue
+                    .pending
+                    .iter_mut()
+                    .find(|pending| pending.write.target() == target)
+                {
+                    // Another write to the target that was in flight failed first
+                    Some(pending) if pending.sequence < sequence => {
+                        pending.write.merge(write);
+                        pending.sequence = sequence;
+                    }
+                    Some(pending) => {
+                        let mut older = write;
+                        older.merge(pending.write.clone());
+                        pending.write = older;
+                    }
+                    None => {
+                        if queue.pending.len() >= self.max_pending {
+                            if let Some(dropped) = queue.pending.pop_front() {
+                                error!(
+                                    "[PROCESSOR] Write retry queue is full, dropping {}",
+                                    dropped.write.describe()
+                                );
+                            }
+                        }
+                        queue.pending.push_back(PendingWrite {
+                            write,
+                            attempts: 1,
+                            sequence,
+                            next_attempt: Instant::now() + FIRST_RETRY_DELAY,
+                        });
+                    }
+                }
+                self.changed();
+                true
+            }
+        };
+        drop(queue);
+        if queued {
+            self.added.notify_one();
+        }
+    }
+
+    fn changed(&self) {
+        if self.file.is_some() {
+            self.changed.notify_one();
+        }
+    }
+
+    // Written to a temporary file first so a crash mid-write leaves the last saved queue. The
+    // lock is only held to copy the writes
+    async fn save(&self) {
+        let Some(file) = self.file.clone() else {
+            return;
+        };
+        let pending: Vec<PendingWrite> = {
+            let queue = self.queue.lock().await;
+            queue.pending.iter().map(PendingWrite::saved).collect()
+        };
+        let saved = tokio::task::spawn_blocking({
+            let file = file.clone();
+            move || {
+                let contents = serde_json::to_vec(&pending).map_err(|e| e.to_string())?;
+                let temporary = file.with_extension("tmp");
+                std::fs::write(&temporary, contents)
+                    .and_then(|_| std::fs::rename(&temporary, &file))
+                    .map_err(|e| e.to_string())
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|saved| saved);
+        if let Err(e) = saved {
+            error!(
+                "[PROCESSOR] Failed to save write retry queue to {}: {}",
+                file.display(),
+                e
+            );
+        }
+    }
+
+    async fn next_attempt(&self) -> Option<Instant> {
+        self.queue
+            .lock()
+            .await
+            .pending
+            .iter()
+            .map(|pending| pending.next_attempt)
+            .min()
+    }
+}
+
+pub async fn write_task_status(
+    state: &AppState,
+    task_id: &Uuid,
+    status: &TaskStatus,
+    context: Option<Value>,
+    bundled_inputs: Option<Value>,
+    result: Option<Value>,
+    error: Option<Value>,
+) {
+    write_status(
+        state,
+        StatusWrite::Task {
+            task_id: *task_id,
+            status: status.clone(),
+            context,
+            bundled_inputs,
+            result,
+            error,
+        },
+    )
+    .await
+}
+
+pub async fn write_flow_session_status(
+    state: &AppState,
+    flow_session_id: &Uuid,
+    flow_session_status: &FlowSessionStatus,
+    trigger_session_status: &TriggerSessionStatus,
+) {
+    write_status(
+        state,
+        StatusWrite::FlowSession {
+            flow_session_id: *flow_session_id,
+            flow_session_status: flow_session_status.clone(),
+            trigger_session_status: trigger_session_status.clone(),
+        },
+    )
+    .await
+}
+
+// Writes now, or queues the write for write_retry_loop if the store fails
+async fn write_status(state: &AppState, write: StatusWrite) {
+    let Some(sequence) = state.write_retries.start_write(&write).await else {
+        return;
+    };
+    let written = write.apply(state.task_store.as_ref()).await;
+    state
+        .write_retries
+        .finish_write(write, sequence, written)
+        .await;
+}
+
+pub async fn write_retry_loop(state: Arc<AppState>) {
+    tokio::join!(retry_loop(&state), save_loop(&state.write_retries));
+}
+
+async fn retry_loop(state: &AppState) {
+    loop {
+        match state.write_retries.next_attempt().await {
+            Some(next_attempt) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(next_attempt) => {}
+                    _ = state.write_retries.added.notified() => continue,
+                }
+            }
+            None => state.write_retries.added.notified().await,
+        }
+        retry_due_writes(state).await;
+    }
+}
+
+// Changes made while a save waits are saved with it, so a burst of failures is one write
+async fn save_loop(retries: &WriteRetryQueue) {
+    loop {
+        retries.changed.notified().await;
+        tokio::time::sleep(SAVE_DELAY).await;
+        retries.save().await;
+    }
+}
+
+// Retries every write whose backoff is up. Targets with a write in flight wait for it so the
+// retry can't land before it. The lock isn't held while writing, a write merged in meanwhile
+// keeps its entry pending so it's written too
+async fn retry_due_writes(state: &AppState) {
+    let retries = &state.write_retries;
+    let now = Instant::now();
+    let due: Vec<(StatusWrite, u64)> = {
+        let queue = retries.queue.lock().await;
+        queue
+            .pending
+            .iter()
+            .filter(|pending| {
+                pending.next_attempt <= now
+                    && !queue.in_flight.contains_key(&pending.write.target())
+            })
+            .map(|pending| (pending.write.clone(), pending.sequence))
+            .collect()
+    };
+
+    for (write, sequence) in due {
+        let written = write.apply(state.task_store.as_ref()).await;
+        let mut queue = retries.queue.lock().await;
+        let target = write.target();
+        let Some(index) = queue
+            .pending
+            .iter()
+            .position(|pending| pending.write.target() == target)
+        else {
+            continue;
+        };
+        match written {
+            Ok(()) if queue.pending[index].sequence == sequence => {
+                let retried = queue.pending.remove(index).unwrap();
+                retries.changed();
+                info!(
+                    "[PROCESSOR] Wrote {} after {} attempts",
+                    write.describe(),
+                    retried.attempts + 1
+                );
+            }
+            Ok(()) => queue.pending[index].next_attempt = Instant::now(),
+            Err(e) => {
+                let retry = &mut queue.pending[index];
+                retry.attempts += 1;
+                let delay = FIRST_RETRY_DELAY
+                    .saturating_mul(2u32.saturating_pow(retry.attempts - 1))
+                    .min(MAX_RETRY_DELAY);
+                retry.next_attempt = Instant::now() + delay;
+                warn!(
+                    "[PROCESSOR] Retry {} of {} failed, next in {:?}: {}",
+                    retry.attempts,
+                    write.describe(),
+                    delay,
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store::{action, start_test_processor};
+    use crate::processor::run_workflow::run_workflow_and_wait;
+    use serde_json::json;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_status_write_is_retried_until_it_lands() {
+        let (store, state, workflow_id, flow_version_id) =
+            start_test_processor(vec![action("webhook", "trigger", None)], vec![]).await;
+        let outcome = run_workflow_and_wait(
+            state.clone(),
+            workflow_id,
+            Some(flow_version_id),
+            None,
+            json!({}),
+        )
+        .await
+        .unwrap();
+        // The processor's own writes are spawned, they land before any write fails
+        let task_id = loop {
+            let task = store
+                .get_tasks_for_session(&outcome.flow_session_id)
+                .await
+                .unwrap()
+                .remove(0);
+            if matches!(task.flow_session_status, FlowSessionStatus::Completed) {
+                break task.task_id;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+
+        store.fail_status_writes(1);
+        write_task_status(
+            &state,
+            &task_id,
+            &TaskStatus::Failed,
+            None,
+            None,
+            None,
+            Some(json!({ "message": "boom" })),
+        )
+        .await;
+        assert_eq!(state.write_retries.pending_writes().await, 1);
+        // A later write for the task joins the pending one instead of racing it
+        write_flow_session_status(
+            &state,
+            &outcome.flow_session_id,
+            &FlowSessionStatus::Failed,
+            &TriggerSessionStatus::Failed,
+        )
+        .await;
+        write_task_status(
+            &state,
+            &task_id,
+            &TaskStatus::Failed,
+            None,
+            None,
+            Some(json!({ "partial": true })),
+            None,
+        )
+        .await;
+        assert_eq!(state.write_retries.pending_writes().await, 1);
+
+        tokio::spawn(write_retry_loop(state.clone()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while state.write_retries.pending_writes().await > 0 {
+            assert!(Instant::now() < deadline, "the write was never retried");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let task = store
+            .get_tasks_for_session(&outcome.flow_session_id)
+            .await
+            .unwrap()
+            .remove(0);
+        assert_eq!(task.task_status, TaskStatus::Failed);
+        assert_eq!(task.error, Some(json!({ "message": "boom" })));
+        assert_eq!(task.result, Some(json!({ "partial": true })));
+        assert!(matches!(
+            task.flow_session_status,
+            FlowSessionStatus::Failed
+        ));
+    }
+
+    fn task_write(task_id: Uuid, status: TaskStatus, result: Option<Value>) -> StatusWrite {
+        StatusWrite::Task {
+            task_id,
+            status,
+            context: None,
+            bundled_inputs: None,
+            result,
+            error: None,
+        }
+    }
+
+    async fn pending(retries: &WriteRetryQueue) -> Vec<Value> {
+        let queue = retries.queue.lock().await;
+        queue
+            .pending
+            .iter()
+            .map(|pending| serde_json::to_value(&pending.write).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_failed_write_is_dropped_when_a_newer_one_landed() {
+        let retries = WriteRetryQueue::new(10);
+        let task_id = Uuid::new_v4();
+        let running = task_write(task_id, TaskStatus::Running, None);
+        let completed = task_write(task_id, TaskStatus::Completed, Some(json!({ "ok": true })));
+
+        // The running write is still in flight when the completed one is made
+        let running_sequence = retries.start_write(&running).await.unwrap();
+        let completed_sequence = retries.start_write(&completed).await.unwrap();
+        retries
+            .finish_write(completed, completed_sequence, Ok(()))
+            .await;
+        retries
+            .finish_write(running, running_sequence, Err("timed out".to_string()))
+            .await;
+
+        assert_eq!(retries.pending_writes().await, 0);
+        assert!(retries.queue.lock().await.in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_writes_that_fail_out_of_order_are_queued_in_order() {
+        let retries = WriteRetryQueue::new(10);
+        let task_id = Uuid::new_v4();
+        let mut running = task_write(task_id, TaskStatus::Running, None);
+        if let StatusWrite::Task { context, .. } = &mut running {
+            *context = Some(json!({ "attempt": 1 }));
+        }
+        let completed = task_write(task_id, TaskStatus::Completed, Some(json!({ "ok": true })));
+
+        let running_sequence = retries.start_write(&running).await.unwrap();
+        let completed_sequence = retries.start_write(&completed).await.unwrap();
+        retries
+            .finish_write(completed, completed_sequence, Err("timed out".to_string()))
+            .await;
+        retries
+            .finish_write(running, running_sequence, Err("timed out".to_string()))
+            .await;
+
+        // The newer status wins, what only the older write set is kept
+        assert_eq!(
+            pending(&retries).await,
+            vec![json!({
+                "kind": "task",
+                "task_id": task_id,
+                "status": "completed",
+                "context": { "attempt": 1 },
+                "bundled_inputs": null,
+                "result": { "ok": true },
+                "error": null
+            })]
+        );
+        assert_eq!(
+            retries.queue.lock().await.pending[0].sequence,
+            completed_sequence
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pending_writes_are_loaded_back_from_the_file() {
+        let file = std::env::temp_dir().join(format!("write_retries_{}.json", Uuid::new_v4()));
+        let task_id = Uuid::new_v4();
+
+        let retries = WriteRetryQueue::with_file(10, file.clone());
+        let mut running = task_write(task_id, TaskStatus::Running, None);
+        if let StatusWrite::Task {
+            context,
+            bundled_inputs,
+            ..
+        } = &mut running
+        {
+            *context = Some(json!({ "headers": { "Authorization": "Bearer sk_live_123" } }));
+            *bundled_inputs = Some(json!({ "api_key": "sk_live_123" }));
+        }
+        let sequence = retries.start_write(&running).await.unwrap();
+        retries
+            .finish_write(running, sequence, Err("connection refused".to_string()))
+            .await;
+        retries.save().await;
+        drop(retries);
+
+        // Headers and rendered secrets never reach the file
+        assert!(!std::fs::read_to_string(&file)
+            .unwrap()
+            .contains("sk_live_123"));
+        let restarted = WriteRetryQueue::with_file(10, file.clone());
+        assert_eq!(
+            pending(&restarted).await,
+            vec![serde_json::to_value(task_write(task_id, TaskStatus::Running, None)).unwrap()]
+        );
+        // A write made after the restart is merged in and numbered after the saved ones
+        let completed = task_write(task_id, TaskStatus::Completed, Some(json!({ "ok": true })));
+        assert_eq!(restarted.start_write(&completed).await, None);
+        assert!(restarted.queue.lock().await.pending[0].sequence > sequence);
+        restarted.save().await;
+
+        let restarted = WriteRetryQueue::with_file(10, file.clone());
+        assert_eq!(restarted.pending_writes().await, 1);
+        assert_eq!(pending(&restarted).await[0]["status"], "completed");
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_file_starts_an_empty_queue() {
+        let file = std::env::temp_dir().join(format!("write_retries_{}.json", Uuid::new_v4()));
+        std::fs::write(&file, "not json").unwrap();
+        let retries = WriteRetryQueue::with_file(10, file.clone());
+        assert!(retries.queue.try_lock().unwrap().pending.is_empty());
+        std::fs::remove_file(&file).unwrap();
+
+        let missing = std::env::temp_dir().join(format!("write_retries_{}.json", Uuid::new_v4()));
+        let retries = WriteRetryQueue::with_file(10, missing);
+        assert!(retries.queue.try_lock().unwrap().pending.is_empty());
+    }
+}
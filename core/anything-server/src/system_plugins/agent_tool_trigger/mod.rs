@@ -13,7 +13,7 @@ use std::time::Duration;
 
 use dotenv::dotenv;
 use serde_json::{json, Value};
-use std::{collections::HashMap, env, sync::Arc};
+use std::{env, sync::Arc};
 use uuid::Uuid;
 
 use crate::{
@@ -193,13 +193,12 @@ pub async fn run_workflow_as_tool_call_and_respond(
     }
 
     //Set the flow data in the cache of the processor so we don't do it again
-    let flow_session_data = FlowSessionData {
-        workflow: Some(workflow_version.clone()),
-        tasks: HashMap::new(),
-        flow_session_id: flow_session_id,
-        workflow_id: Uuid::parse_str(&workflow_id).unwrap(),
-        workflow_version_id: Some(workflow_version.flow_version_id),
-    };
+    let flow_session_data = FlowSessionData::new(
+        Some(workflow_version.clone()),
+        flow_session_id,
+        Uuid::parse_str(&workflow_id).unwrap(),
+        Some(workflow_version.flow_version_id),
+    );
 
     println!("[TOOL_CALL_API] Setting flow session data in cache");
     // Set the flow session data in cache
@@ -215,6 +214,8 @@ pub async fn run_workflow_as_tool_call_and_respond(
         flow_session_id: flow_session_id,
         trigger_session_id: trigger_session_id,
         trigger_task: Some(task),
+        response: None,
+        deadline: None,
     };
 
     if let Err(e) = state.processor_sender.send(processor_message).await {
@@ -4,6 +4,7 @@ pub mod input;
 pub mod javascript;
 pub mod output;
 pub mod registry;
+pub mod transform;
 pub mod webhook_response;
 pub mod webhook_trigger;
 pub mod agent_tool_trigger;
@@ -0,0 +1,103 @@
+use serde_json::Value;
+
+// Reshapes data without calling anything. The work is done by the bundler: plugin_config.output
+// is a mapping template rendered against the action's inputs like any other plugin config, e.g.
+//
+//     "inputs": { "order": "{{actions.fetch_order.result}}" },
+//     "plugin_config": {
+//         "output": {
+//             "customer": "{{inputs.order.customer.first_name}} {{inputs.order.customer.last_name}}",
+//             "skus": "{{ jmes: inputs.order.lines[*].sku }}",
+//             "total": "{{inputs.order.lines | map: \"price\" | sum}}"
+//         }
+//     }
+//
+// so the rendered output is the result
+pub fn process_transform_task(
+    bundled_plugin_config: &Value,
+) -> Result<Option<Value>, Box<dyn std::error::Error + Send + Sync>> {
+    match bundled_plugin_config.get("output") {
+        Some(output) => Ok(Some(output.clone())),
+        None => Err("Transform is missing plugin_config.output".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::bundle_plugin_config;
+    use crate::types::json_schema::JsonSchema;
+    use serde_json::json;
+
+    fn transform(inputs: Value, output: Value) -> Value {
+        let plugin_config = json!({ "output": output });
+        let plugin_config_schema: JsonSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": { "output": { "x-any-validation": { "type": "any" } } }
+        }))
+        .unwrap();
+        let bundled = bundle_plugin_config(
+            inputs,
+            Some(&plugin_config),
+            Some(&plugin_config_schema),
+            &[],
+        )
+        .unwrap();
+        process_transform_task(&bundled).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_maps_fields_into_a_new_shape() {
+        let inputs = json!({
+            "order": {
+                "customer": { "first_name": "Ada", "last_name": "Lovelace" },
+                "lines": [
+                    { "sku": "A-1", "price": 5 },
+                    { "sku": "B-2", "price": 7 }
+                ],
+                "shipping": null
+            }
+        });
+
+        let output = transform(
+            inputs,
+            json!({
+                "customer": "{{inputs.order.customer.first_name}} {{inputs.order.customer.last_name}}",
+                "skus": "{{ jmes: inputs.order.lines[*].sku }}",
+                "total": "{{inputs.order.lines | map: \"price\" | sum}}",
+                "shipping": "{{inputs.order.shipping ?? 'standard'}}",
+                "line_count": "{{inputs.order.lines | length}}"
+            }),
+        );
+        assert_eq!(
+            output,
+            json!({
+                "customer": "Ada Lovelace",
+                "skus": ["A-1", "B-2"],
+                "total": 12,
+                "shipping": "standard",
+                "line_count": 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_whole_value_and_array_outputs() {
+        let inputs = json!({ "rows": [{ "id": 1, "name": "a" }, { "id": 2, "name": "b" }] });
+
+        // A single `{{ }}` keeps the value's type
+        assert_eq!(
+            transform(inputs.clone(), json!("{{inputs.rows | map: \"id\"}}")),
+            json!([1, 2])
+        );
+        assert_eq!(
+            transform(
+                inputs,
+                json!([{ "first": "{{inputs.rows[0].name}}" }, "{{inputs.rows[1]}}"])
+            ),
+            json!([{ "first": "a" }, { "id": 2, "name": "b" }])
+        );
+
+        assert!(process_transform_task(&json!({})).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 
-use serde_json::Value;
+use serde_json::{json, Value};
+use tracing::warn;
 
 use crate::AppState;
 
@@ -80,8 +81,71 @@ pub fn deep_parse_json(input: &str) -> Result<Value, serde_json::Error> {
     Ok(Value::String(input.to_string()))
 }
 
-//TODO: Something in here I thik is what makes it so we can only support returning JSON from webhooks.
-//For now just going to make webhooks only return json
+// What goes in the response's body for the configured content type. JSON bodies keep their
+// structure, everything else is the string that's sent. Parameters like "; charset=utf-8" are ignored
+pub fn render_response_body(content_type: &str, bundled_context: &Value) -> Value {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let field = |name: &str| bundled_context.get(name).unwrap_or(&Value::Null);
+
+    match media_type.as_str() {
+        "application/json" => match field("json_body") {
+            Value::Null => json!({}),
+            Value::String(body) => deep_parse_json(body).unwrap_or_else(|e| {
+                warn!("[PROCESS RESPONSE] Failed to parse JSON body: {}", e);
+                Value::String(body.clone())
+            }),
+            body => body.clone(),
+        },
+        // Anything that isn't already text is sent as its JSON
+        "text/plain" => match field("text_body") {
+            Value::Null => Value::String(String::new()),
+            Value::String(body) => Value::String(body.clone()),
+            body => Value::String(body.to_string()),
+        },
+        // Form fields come from the JSON body, a string is taken to be encoded already
+        "application/x-www-form-urlencoded" => match field("json_body") {
+            Value::String(body) => match deep_parse_json(body) {
+                Ok(Value::Object(fields)) => Value::String(encode_form(&fields)),
+                _ => Value::String(body.clone()),
+            },
+            Value::Object(fields) => Value::String(encode_form(fields)),
+            _ => Value::String(String::new()),
+        },
+        "text/html" => Value::String(field("html_body").as_str().unwrap_or("").to_string()),
+        "text/xml" => Value::String(field("xml_body").as_str().unwrap_or("").to_string()),
+        _ => Value::String(field("json_body").as_str().unwrap_or("{}").to_string()),
+    }
+}
+
+// Arrays repeat their key, e.g. {"tag": ["a", "b"]} is tag=a&tag=b
+fn encode_form(fields: &serde_json::Map<String, Value>) -> String {
+    let mut pairs = Vec::new();
+    for (key, value) in fields {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::Null => String::new(),
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            pairs.push(format!(
+                "{}={}",
+                urlencoding::encode(key),
+                urlencoding::encode(&value)
+            ));
+        }
+    }
+    pairs.join("&")
+}
+
 pub async fn process_webhook_response_task(
     state: Arc<AppState>,
     flow_session_id: String,
@@ -109,33 +173,8 @@ pub async fn process_webhook_response_task(
         .and_then(|v| v.as_str())
         .unwrap_or("");
 
-    // Get body based on content type
-    let body = match content_type {
-        "application/json" => bundled_context
-            .get("json_body")
-            .map(|v| v.to_string())
-            .unwrap_or_else(|| "{}".to_string()),
-        "text/plain" => bundled_context
-            .get("text_body")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string(),
-        "text/html" => bundled_context
-            .get("html_body")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string(),
-        "text/xml" => bundled_context
-            .get("xml_body")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string(),
-        _ => bundled_context
-            .get("json_body")
-            .and_then(|v| v.as_str())
-            .unwrap_or("{}")
-            .to_string(),
-    };
+    // Serialized the way the content type says, the webhook sends it as is
+    let body = render_response_body(content_type, bundled_context);
 
     println!("[PROCESS RESPONSE] Status code: {}", status_code);
     println!("[PROCESS RESPONSE] Content type: {}", content_type);
@@ -176,21 +215,9 @@ pub async fn process_webhook_response_task(
 
     response.insert("headers".to_string(), Value::Object(headers_map));
 
-    // Parse and add body if present
-    if !body.is_empty() {
-        if content_type == "application/json" {
-            match deep_parse_json(&body) {
-                Ok(parsed_body) => {
-                    response.insert("body".to_string(), parsed_body);
-                }
-                Err(e) => {
-                    println!("[PROCESS RESPONSE] Failed to parse JSON body: {}", e);
-                    response.insert("body".to_string(), Value::String(body.to_string()));
-                }
-            }
-        } else {
-            response.insert("body".to_string(), Value::String(body.to_string()));
-        }
+    // Add body if present
+    if body != Value::String(String::new()) {
+        response.insert("body".to_string(), body);
     }
 
     println!("[PROCESS RESPONSE] Generated response: {:?}", response);
@@ -206,3 +233,69 @@ pub async fn process_webhook_response_task(
 
     Ok(Some(Value::Object(response)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_body_keeps_its_structure() {
+        let context = json!({ "json_body": "{\"ok\": true, \"items\": [1, 2]}" });
+        assert_eq!(
+            render_response_body("application/json", &context),
+            json!({ "ok": true, "items": [1, 2] })
+        );
+        assert_eq!(
+            render_response_body("application/json", &json!({ "json_body": { "ok": true } })),
+            json!({ "ok": true })
+        );
+        assert_eq!(
+            render_response_body("application/json", &json!({})),
+            json!({})
+        );
+    }
+
+    #[test]
+    fn test_text_body_is_sent_as_is() {
+        assert_eq!(
+            render_response_body(
+                "text/plain; charset=utf-8",
+                &json!({ "text_body": "hello" })
+            ),
+            json!("hello")
+        );
+        assert_eq!(
+            render_response_body("text/plain", &json!({ "text_body": { "count": 2 } })),
+            json!("{\"count\":2}")
+        );
+        assert_eq!(render_response_body("text/plain", &json!({})), json!(""));
+    }
+
+    #[test]
+    fn test_form_body_is_url_encoded() {
+        let context = json!({
+            "json_body": { "name": "Ada Lovelace", "tags": ["a&b", "c"], "count": 3, "empty": null }
+        });
+        let body = render_response_body("application/x-www-form-urlencoded", &context);
+        let mut pairs: Vec<&str> = body.as_str().unwrap().split('&').collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            vec![
+                "count=3",
+                "empty=",
+                "name=Ada%20Lovelace",
+                "tags=a%26b",
+                "tags=c"
+            ]
+        );
+
+        assert_eq!(
+            render_response_body(
+                "application/x-www-form-urlencoded",
+                &json!({ "json_body": "a=1&b=2" })
+            ),
+            json!("a=1&b=2")
+        );
+    }
+}
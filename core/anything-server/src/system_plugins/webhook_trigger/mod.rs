@@ -1,3 +1,4 @@
 pub mod webhook_trigger;
 pub use webhook_trigger::*;
+pub mod webhook_signature;
 pub mod webhook_trigger_utils;
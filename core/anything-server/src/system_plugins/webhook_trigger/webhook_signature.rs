@@ -0,0 +1,263 @@
+use chrono::Utc;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+// Stripe's own libraries reject signatures older than five minutes
+pub const STRIPE_SIGNATURE_TOLERANCE_SECS: i64 = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureScheme {
+    HmacSha256Hex, // Whole header is the hex digest of the body
+    Stripe,        // t=<timestamp>,v1=<hex digest of "{t}.{body}">
+    GitHub,        // sha256=<hex digest of the body>
+}
+
+impl SignatureScheme {
+    pub fn parse(scheme: &str) -> Option<Self> {
+        match scheme {
+            "hmac_sha256_hex" => Some(SignatureScheme::HmacSha256Hex),
+            "stripe" => Some(SignatureScheme::Stripe),
+            "github" => Some(SignatureScheme::GitHub),
+            _ => None,
+        }
+    }
+
+    // Header to read when the trigger doesn't name one
+    pub fn default_header(&self) -> &'static str {
+        match self {
+            SignatureScheme::HmacSha256Hex => "x-signature",
+            SignatureScheme::Stripe => "stripe-signature",
+            SignatureScheme::GitHub => "x-hub-signature-256",
+        }
+    }
+}
+
+// Checks the signature a provider sent against the raw request body.
+// Must be given the body bytes as received, not the parsed and re-serialized JSON
+pub fn verify_signature(
+    secret: &str,
+    raw_body: &[u8],
+    header_value: &str,
+    scheme: &SignatureScheme,
+) -> bool {
+    verify_signature_at(
+        secret,
+        raw_body,
+        header_value,
+        scheme,
+        Utc::now().timestamp(),
+    )
+}
+
+fn verify_signature_at(
+    secret: &str,
+    raw_body: &[u8],
+    header_value: &str,
+    scheme: &SignatureScheme,
+    now: i64,
+) -> bool {
+    if secret.is_empty() {
+        return false;
+    }
+
+    let header_value = header_value.trim();
+
+    match scheme {
+        SignatureScheme::HmacSha256Hex => signature_matches(secret, raw_body, header_value),
+        SignatureScheme::GitHub => match header_value.strip_prefix("sha256=") {
+            Some(signature) => signature_matches(secret, raw_body, signature),
+            None => false,
+        },
+        SignatureScheme::Stripe => {
+            let mut timestamp = None;
+            let mut signatures = Vec::new();
+            for part in header_value.split(',') {
+                match part.trim().split_once('=') {
+                    Some(("t", value)) => timestamp = Some(value),
+                    Some(("v1", value)) => signatures.push(value),
+                    _ => {}
+                }
+            }
+
+            let timestamp = match timestamp {
+                Some(timestamp) => timestamp,
+                None => return false,
+            };
+            match timestamp.parse::<i64>() {
+                Ok(sent_at) if (now - sent_at).abs() <= STRIPE_SIGNATURE_TOLERANCE_SECS => {}
+                _ => return false,
+            }
+
+            // Sign the timestamp as sent so formatting differences can't change the payload
+            let mut signed_payload = Vec::with_capacity(timestamp.len() + 1 + raw_body.len());
+            signed_payload.extend_from_slice(timestamp.as_bytes());
+            signed_payload.push(b'.');
+            signed_payload.extend_from_slice(raw_body);
+
+            // Stripe sends several v1 signatures while a secret is being rolled
+            signatures
+                .iter()
+                .any(|signature| signature_matches(secret, &signed_payload, signature))
+        }
+    }
+}
+
+fn signature_matches(secret: &str, payload: &[u8], signature_hex: &str) -> bool {
+    let expected = match hmac_sha256(secret.as_bytes(), payload) {
+        Some(expected) => expected,
+        None => return false,
+    };
+    let provided = match decode_hex(signature_hex) {
+        Some(provided) => provided,
+        None => return false,
+    };
+
+    // memcmp::eq panics on different lengths and the length of a digest is no secret
+    provided.len() == expected.len() && openssl::memcmp::eq(&provided, &expected)
+}
+
+fn hmac_sha256(key: &[u8], payload: &[u8]) -> Option<Vec<u8>> {
+    let key = PKey::hmac(key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(payload).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "whsec_test";
+    const BODY: &[u8] = br#"{"id":"evt_1"}"#;
+    const BODY_SIGNATURE: &str = "030fa3b2413d1993c551364bd53bb9b3edb5c0c34d55dba6ada6041245632811";
+    const STRIPE_TIMESTAMP: i64 = 1700000000;
+    const STRIPE_SIGNATURE: &str =
+        "c89214b5b5da833daed6f0b8c5bb6bd58cea9022bd80ccc78230f3942d632925";
+
+    #[test]
+    fn test_hmac_sha256_hex() {
+        let scheme = SignatureScheme::HmacSha256Hex;
+        assert!(verify_signature(SECRET, BODY, BODY_SIGNATURE, &scheme));
+        assert!(verify_signature(
+            SECRET,
+            BODY,
+            &BODY_SIGNATURE.to_uppercase(),
+            &scheme
+        ));
+
+        assert!(!verify_signature(
+            SECRET,
+            br#"{"id":"evt_2"}"#,
+            BODY_SIGNATURE,
+            &scheme
+        ));
+        assert!(!verify_signature(
+            "whsec_other",
+            BODY,
+            BODY_SIGNATURE,
+            &scheme
+        ));
+        assert!(!verify_signature("", BODY, BODY_SIGNATURE, &scheme));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &BODY_SIGNATURE[..62],
+            &scheme
+        ));
+        assert!(!verify_signature(SECRET, BODY, "not hex", &scheme));
+        assert!(!verify_signature(SECRET, BODY, "", &scheme));
+    }
+
+    #[test]
+    fn test_github_signature() {
+        let scheme = SignatureScheme::GitHub;
+        let header = format!("sha256={}", BODY_SIGNATURE);
+        assert!(verify_signature(SECRET, BODY, &header, &scheme));
+
+        assert!(!verify_signature(SECRET, BODY, BODY_SIGNATURE, &scheme));
+        assert!(!verify_signature(
+            SECRET,
+            br#"{"id":"evt_1" }"#,
+            &header,
+            &scheme
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &format!("sha1={}", BODY_SIGNATURE),
+            &scheme
+        ));
+    }
+
+    #[test]
+    fn test_stripe_signature() {
+        let scheme = SignatureScheme::Stripe;
+        let header = format!("t={},v1={}", STRIPE_TIMESTAMP, STRIPE_SIGNATURE);
+        let verify = |header: &str, body: &[u8], now: i64| {
+            verify_signature_at(SECRET, body, header, &scheme, now)
+        };
+
+        assert!(verify(&header, BODY, STRIPE_TIMESTAMP));
+        assert!(verify(&header, BODY, STRIPE_TIMESTAMP + 300));
+
+        // Any matching v1 is enough, other schemes are ignored
+        let rolled = format!(
+            "t={}, v1={}, v1={}, v0=abc",
+            STRIPE_TIMESTAMP, BODY_SIGNATURE, STRIPE_SIGNATURE
+        );
+        assert!(verify(&rolled, BODY, STRIPE_TIMESTAMP));
+
+        assert!(!verify(&header, br#"{"id":"evt_2"}"#, STRIPE_TIMESTAMP));
+        assert!(!verify(&header, BODY, STRIPE_TIMESTAMP + 301));
+        assert!(!verify(&header, BODY, STRIPE_TIMESTAMP - 301));
+
+        // The timestamp is signed so it can't be moved forward
+        let replayed = format!("t={},v1={}", STRIPE_TIMESTAMP + 60, STRIPE_SIGNATURE);
+        assert!(!verify(&replayed, BODY, STRIPE_TIMESTAMP + 60));
+
+        assert!(!verify(
+            &format!("v1={}", STRIPE_SIGNATURE),
+            BODY,
+            STRIPE_TIMESTAMP
+        ));
+        assert!(!verify(
+            &format!("t={}", STRIPE_TIMESTAMP),
+            BODY,
+            STRIPE_TIMESTAMP
+        ));
+        assert!(!verify(
+            &format!("t=soon,v1={}", STRIPE_SIGNATURE),
+            BODY,
+            STRIPE_TIMESTAMP
+        ));
+        assert!(!verify(STRIPE_SIGNATURE, BODY, STRIPE_TIMESTAMP));
+    }
+
+    #[test]
+    fn test_parse_scheme() {
+        assert_eq!(
+            SignatureScheme::parse("stripe"),
+            Some(SignatureScheme::Stripe)
+        );
+        assert_eq!(
+            SignatureScheme::parse("github"),
+            Some(SignatureScheme::GitHub)
+        );
+        assert_eq!(
+            SignatureScheme::parse("hmac_sha256_hex"),
+            Some(SignatureScheme::HmacSha256Hex)
+        );
+        assert_eq!(SignatureScheme::parse("sha1"), None);
+    }
+}
@@ -1,4 +1,5 @@
 use axum::{
+    body::Bytes,
     extract::{Path, Query, State},
     http::{HeaderMap, Method, StatusCode},
     response::IntoResponse,
@@ -10,7 +11,7 @@ use chrono::Utc;
 use std::time::Duration;
 
 use dotenv::dotenv;
-use serde_json::{json, Value};
+use serde_json::json;
 use std::{collections::HashMap, env, sync::Arc};
 use uuid::Uuid;
 
@@ -32,10 +33,12 @@ use crate::{
 
 use tokio::sync::oneshot;
 use tokio::time::timeout;
+use tracing::debug;
 
 use super::webhook_trigger_utils::{
-    convert_request_to_payload, parse_response_action_response_into_api_response,
-    validate_request_method, validate_required_input_and_response_plugins, validate_security_model,
+    parse_response_action_response_into_api_response, prepare_webhook_delivery,
+    release_webhook_delivery, validate_request_method,
+    validate_required_input_and_response_plugins, validate_security_model,
 };
 
 //One Minute
@@ -47,7 +50,7 @@ pub async fn run_workflow_and_respond(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     query: Option<Query<HashMap<String, String>>>,
-    body: Option<Json<Value>>,
+    raw_body: Bytes,
 ) -> impl IntoResponse {
     println!("[WEBHOOK API] Handling run workflow and respond");
     // println!("[WEBHOOK API] Payload: {:?}", payload);
@@ -164,7 +167,8 @@ pub async fn run_workflow_and_respond(
     println!("[WEBHOOK API] Bundled context: {:?}", rendered_inputs);
 
     //Validate security model
-    if let Some(response) = validate_security_model(&rendered_inputs, &headers, state.clone()).await
+    if let Some(response) =
+        validate_security_model(&rendered_inputs, &headers, &raw_body, state.clone()).await
     {
         return response.into_response();
     }
@@ -174,7 +178,22 @@ pub async fn run_workflow_and_respond(
         return response.into_response();
     }
 
-    let processed_payload = convert_request_to_payload(method.clone(), query, body);
+    let delivery = match prepare_webhook_delivery(
+        state.clone(),
+        &trigger_node,
+        &workflow_id,
+        &rendered_inputs,
+        &headers,
+        method.clone(),
+        query,
+        &raw_body,
+        &flow_session_id.to_string(),
+    )
+    .await
+    {
+        Ok(delivery) => delivery,
+        Err(response) => return response,
+    };
 
     // Create a task to initiate the flow
     println!("[WEBHOOK API] Creating task for workflow execution");
@@ -200,11 +219,7 @@ pub async fn run_workflow_and_respond(
             Stage::Testing.as_str().to_string()
         },
         config: task_config,
-        result: Some(json!({
-            "headers": headers.iter().map(|(k,v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()).into_owned())).collect::<HashMap<_,_>>(),
-            "body": processed_payload.clone(),
-            "method": method.to_string(),
-        })),
+        result: Some(delivery.trigger_result.clone()),
         error: None,
         test_config: None,
         started_at: Some(Utc::now()),
@@ -230,13 +245,12 @@ pub async fn run_workflow_and_respond(
     }
 
     //Set the flow data in the cache of the processor so we don't do it again
-    let flow_session_data = FlowSessionData {
-        workflow: Some(workflow_version.clone()),
-        tasks: HashMap::new(),
-        flow_session_id: flow_session_id,
-        workflow_id: Uuid::parse_str(&workflow_id).unwrap(),
-        workflow_version_id: Some(workflow_version.flow_version_id),
-    };
+    let flow_session_data = FlowSessionData::new(
+        Some(workflow_version.clone()),
+        flow_session_id,
+        Uuid::parse_str(&workflow_id).unwrap(),
+        Some(workflow_version.flow_version_id),
+    );
 
     println!("[TEST WORKFLOW] Setting flow session data in cache");
     // Set the flow session data in cache
@@ -252,10 +266,13 @@ pub async fn run_workflow_and_respond(
         flow_session_id: flow_session_id,
         trigger_session_id: trigger_session_id,
         trigger_task: Some(task),
+        response: None,
+        deadline: None,
     };
 
     if let Err(e) = state.processor_sender.send(processor_message).await {
         println!("[TEST WORKFLOW] Failed to send message to processor: {}", e);
+        release_webhook_delivery(&state, &delivery).await;
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to send message to processor: {}", e),
@@ -308,10 +325,13 @@ pub async fn run_workflow_version_and_respond(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     query: Option<Query<HashMap<String, String>>>,
-    body: Option<Json<Value>>,
+    raw_body: Bytes,
 ) -> impl IntoResponse {
     println!("[WEBHOOK API] Handling run workflow and respond");
-    println!("[WEBHOOK API] Payload: {:?}", body);
+    debug!(
+        "[WEBHOOK API] Payload: {}",
+        String::from_utf8_lossy(&raw_body)
+    );
 
     println!("[WEBHOOK API] Workflow ID: {}: ", workflow_id);
 
@@ -420,7 +440,8 @@ pub async fn run_workflow_version_and_respond(
     println!("[WEBHOOK API] Bundled context: {:?}", rendered_inputs);
 
     //Validate security model
-    if let Some(response) = validate_security_model(&rendered_inputs, &headers, state.clone()).await
+    if let Some(response) =
+        validate_security_model(&rendered_inputs, &headers, &raw_body, state.clone()).await
     {
         return response.into_response();
     }
@@ -430,7 +451,22 @@ pub async fn run_workflow_version_and_respond(
         return response.into_response();
     }
 
-    let processed_payload = convert_request_to_payload(method.clone(), query, body);
+    let delivery = match prepare_webhook_delivery(
+        state.clone(),
+        &trigger_node,
+        &workflow_id,
+        &rendered_inputs,
+        &headers,
+        method.clone(),
+        query,
+        &raw_body,
+        &flow_session_id.to_string(),
+    )
+    .await
+    {
+        Ok(delivery) => delivery,
+        Err(response) => return response,
+    };
 
     // Create a task to initiate the flow
     println!("[WEBHOOK API] Creating task for workflow execution");
@@ -442,7 +478,7 @@ pub async fn run_workflow_version_and_respond(
         flow_version_id: workflow_version.flow_version_id.to_string(),
         action_label: trigger_node.label.clone(),
         trigger_id: trigger_node.action_id.clone(),
-        trigger_session_id: Uuid::new_v4().to_string(),
+        trigger_session_id: trigger_session_id.to_string(),
         trigger_session_status: TriggerSessionStatus::Running.as_str().to_string(),
         flow_session_id: flow_session_id.clone(),
         flow_session_status: FlowSessionStatus::Running.as_str().to_string(),
@@ -457,11 +493,7 @@ pub async fn run_workflow_version_and_respond(
         },
         config: task_config,
 
-        result: Some(json!({
-            "headers": headers.iter().map(|(k,v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()).into_owned())).collect::<HashMap<_,_>>(),
-            "body": processed_payload.clone(),
-            "method": method.to_string(),
-        })),
+        result: Some(delivery.trigger_result.clone()),
         error: None,
         test_config: None,
         started_at: Some(Utc::now()),
@@ -485,13 +517,12 @@ pub async fn run_workflow_version_and_respond(
     }
 
     //Set the flow data in the cache of the processor so we don't do it again
-    let flow_session_data = FlowSessionData {
-        workflow: Some(workflow_version.clone()),
-        tasks: HashMap::new(),
-        flow_session_id: Uuid::parse_str(&flow_session_id).unwrap(),
-        workflow_id: Uuid::parse_str(&workflow_id).unwrap(),
-        workflow_version_id: Some(Uuid::parse_str(&workflow_version_id).unwrap()),
-    };
+    let flow_session_data = FlowSessionData::new(
+        Some(workflow_version.clone()),
+        Uuid::parse_str(&flow_session_id).unwrap(),
+        Uuid::parse_str(&workflow_id).unwrap(),
+        Some(Uuid::parse_str(&workflow_version_id).unwrap()),
+    );
 
     println!("[TEST WORKFLOW] Setting flow session data in cache");
     // Set the flow session data in cache
@@ -510,10 +541,13 @@ pub async fn run_workflow_version_and_respond(
         flow_session_id: Uuid::parse_str(&flow_session_id).unwrap(),
         trigger_session_id: trigger_session_id,
         trigger_task: Some(task.clone()),
+        response: None,
+        deadline: None,
     };
 
     if let Err(e) = state.processor_sender.send(processor_message).await {
         println!("[TEST WORKFLOW] Failed to send message to processor: {}", e);
+        release_webhook_delivery(&state, &delivery).await;
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to send message to processor: {}", e),
@@ -566,10 +600,13 @@ pub async fn run_workflow(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     query: Option<Query<HashMap<String, String>>>,
-    body: Option<Json<Value>>,
+    raw_body: Bytes,
 ) -> impl IntoResponse {
     println!("[WEBHOOK API] Handling run workflow and respond");
-    println!("[WEBHOOK API] Payload: {:?}", body);
+    debug!(
+        "[WEBHOOK API] Payload: {}",
+        String::from_utf8_lossy(&raw_body)
+    );
 
     println!("[WEBHOOK API] Workflow ID: {}: ", workflow_id);
 
@@ -681,7 +718,8 @@ pub async fn run_workflow(
     println!("[WEBHOOK API] Bundled context: {:?}", rendered_inputs);
 
     //Validate security model
-    if let Some(response) = validate_security_model(&rendered_inputs, &headers, state.clone()).await
+    if let Some(response) =
+        validate_security_model(&rendered_inputs, &headers, &raw_body, state.clone()).await
     {
         return response.into_response();
     }
@@ -691,7 +729,22 @@ pub async fn run_workflow(
         return response.into_response();
     }
 
-    let processed_payload = convert_request_to_payload(method.clone(), query, body);
+    let delivery = match prepare_webhook_delivery(
+        state.clone(),
+        &trigger_node,
+        &workflow_id,
+        &rendered_inputs,
+        &headers,
+        method.clone(),
+        query,
+        &raw_body,
+        &flow_session_id.to_string(),
+    )
+    .await
+    {
+        Ok(delivery) => delivery,
+        Err(response) => return response,
+    };
 
     // Create a task to initiate the flow
     println!("[WEBHOOK API] Creating task for workflow execution");
@@ -703,7 +756,7 @@ pub async fn run_workflow(
         flow_version_id: workflow_version.flow_version_id.to_string(),
         action_label: trigger_node.label.clone(),
         trigger_id: trigger_node.action_id.clone(),
-        trigger_session_id: Uuid::new_v4().to_string(),
+        trigger_session_id: trigger_session_id.to_string(),
         trigger_session_status: TriggerSessionStatus::Running.as_str().to_string(),
         flow_session_id: flow_session_id.clone(),
         flow_session_status: FlowSessionStatus::Running.as_str().to_string(),
@@ -717,11 +770,7 @@ pub async fn run_workflow(
             Stage::Testing.as_str().to_string()
         },
         config: task_config,
-        result: Some(json!({
-            "headers": headers.iter().map(|(k,v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()).into_owned())).collect::<HashMap<_,_>>(),
-            "body": processed_payload.clone(),
-            "method": method.to_string(),
-        })),
+        result: Some(delivery.trigger_result.clone()),
         error: None,
         test_config: None,
         started_at: Some(Utc::now()),
@@ -730,13 +779,12 @@ pub async fn run_workflow(
     println!("[WEBHOOK API] Task to be created: {:?}", task);
 
     //Set the flow data in the cache of the processor so we don't do it again
-    let flow_session_data = FlowSessionData {
-        workflow: Some(workflow_version.clone()),
-        tasks: HashMap::new(),
-        flow_session_id: Uuid::parse_str(&flow_session_id).unwrap(),
-        workflow_id: Uuid::parse_str(&workflow_id).unwrap(),
-        workflow_version_id: Some(workflow_version.flow_version_id),
-    };
+    let flow_session_data = FlowSessionData::new(
+        Some(workflow_version.clone()),
+        Uuid::parse_str(&flow_session_id).unwrap(),
+        Uuid::parse_str(&workflow_id).unwrap(),
+        Some(workflow_version.flow_version_id),
+    );
 
     println!("[TEST WORKFLOW] Setting flow session data in cache");
     // Set the flow session data in cache
@@ -755,10 +803,13 @@ pub async fn run_workflow(
         flow_session_id: Uuid::parse_str(&flow_session_id).unwrap(),
         trigger_session_id: trigger_session_id,
         trigger_task: Some(task.clone()),
+        response: None,
+        deadline: None,
     };
 
     if let Err(e) = state.processor_sender.send(processor_message).await {
         println!("[TEST WORKFLOW] Failed to send message to processor: {}", e);
+        release_webhook_delivery(&state, &delivery).await;
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to send message to processor: {}", e),
@@ -783,10 +834,13 @@ pub async fn run_workflow_version(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     query: Option<Query<HashMap<String, String>>>,
-    body: Option<Json<Value>>,
+    raw_body: Bytes,
 ) -> impl IntoResponse {
     println!("[WEBHOOK API] Handling run workflow and respond");
-    println!("[WEBHOOK API] Payload: {:?}", body);
+    debug!(
+        "[WEBHOOK API] Payload: {}",
+        String::from_utf8_lossy(&raw_body)
+    );
 
     println!("[WEBHOOK API] Workflow ID: {}: ", workflow_id);
 
@@ -898,7 +952,8 @@ pub async fn run_workflow_version(
     println!("[WEBHOOK API] Bundled context: {:?}", rendered_inputs);
 
     //Validate security model
-    if let Some(response) = validate_security_model(&rendered_inputs, &headers, state.clone()).await
+    if let Some(response) =
+        validate_security_model(&rendered_inputs, &headers, &raw_body, state.clone()).await
     {
         return response.into_response();
     }
@@ -908,7 +963,22 @@ pub async fn run_workflow_version(
         return response.into_response();
     }
 
-    let processed_payload = convert_request_to_payload(method.clone(), query, body);
+    let delivery = match prepare_webhook_delivery(
+        state.clone(),
+        &trigger_node,
+        &workflow_id,
+        &rendered_inputs,
+        &headers,
+        method.clone(),
+        query,
+        &raw_body,
+        &flow_session_id.to_string(),
+    )
+    .await
+    {
+        Ok(delivery) => delivery,
+        Err(response) => return response,
+    };
 
     // Create a task to initiate the flow
     println!("[WEBHOOK API] Creating task for workflow execution");
@@ -920,7 +990,7 @@ pub async fn run_workflow_version(
         flow_version_id: workflow_version.flow_version_id.to_string(),
         action_label: trigger_node.label.clone(),
         trigger_id: trigger_node.action_id.clone(),
-        trigger_session_id: Uuid::new_v4().to_string(),
+        trigger_session_id: trigger_session_id.to_string(),
         trigger_session_status: TriggerSessionStatus::Running.as_str().to_string(),
         flow_session_id: flow_session_id.to_string(),
         flow_session_status: FlowSessionStatus::Running.as_str().to_string(),
@@ -934,11 +1004,7 @@ pub async fn run_workflow_version(
             Stage::Testing.as_str().to_string()
         },
         config: task_config,
-        result: Some(json!({
-            "headers": headers.iter().map(|(k,v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()).into_owned())).collect::<HashMap<_,_>>(),
-            "body": processed_payload.clone(),
-            "method": method.to_string(),
-        })),
+        result: Some(delivery.trigger_result.clone()),
         error: None,
         test_config: None,
         started_at: Some(Utc::now()),
@@ -947,13 +1013,12 @@ pub async fn run_workflow_version(
     println!("[WEBHOOK API] Task to be created: {:?}", task);
 
     //Set the flow data in the cache of the processor so we don't do it again
-    let flow_session_data = FlowSessionData {
-        workflow: Some(workflow_version.clone()),
-        tasks: HashMap::new(),
-        flow_session_id: flow_session_id,
-        workflow_id: Uuid::parse_str(&workflow_id).unwrap(),
-        workflow_version_id: Some(workflow_version.flow_version_id),
-    };
+    let flow_session_data = FlowSessionData::new(
+        Some(workflow_version.clone()),
+        flow_session_id,
+        Uuid::parse_str(&workflow_id).unwrap(),
+        Some(workflow_version.flow_version_id),
+    );
 
     println!("[TEST WORKFLOW] Setting flow session data in cache");
     // Set the flow session data in cache
@@ -969,10 +1034,13 @@ pub async fn run_workflow_version(
         flow_session_id: flow_session_id,
         trigger_session_id: trigger_session_id,
         trigger_task: Some(task),
+        response: None,
+        deadline: None,
     };
 
     if let Err(e) = state.processor_sender.send(processor_message).await {
         println!("[TEST WORKFLOW] Failed to send message to processor: {}", e);
+        release_webhook_delivery(&state, &delivery).await;
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to send message to processor: {}", e),
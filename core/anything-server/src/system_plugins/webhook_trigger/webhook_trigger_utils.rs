@@ -1,7 +1,7 @@
 use axum::{
     extract::Query,
-    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
-    response::IntoResponse,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
 
@@ -10,8 +10,13 @@ use serde_json::{json, Value};
 use std::collections::HashMap;
 
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info, warn};
+
+use super::webhook_signature::{verify_signature, SignatureScheme};
 
 use crate::{
+    processor::parsing_utils::{normalize_trigger_payload, validate_trigger_payload},
     secrets::get_secret_by_secret_value,
     types::action_types::{Action, ActionType, PluginName},
     types::workflow_types::WorkflowVersionDefinition,
@@ -133,6 +138,7 @@ pub async fn validate_api_key(state: Arc<AppState>, api_key: String) -> Result<S
 pub async fn validate_security_model(
     rendered_inputs: &Value,
     headers: &HeaderMap,
+    raw_body: &[u8],
     state: Arc<AppState>,
 ) -> Option<impl IntoResponse> {
     // Extract the security model from the rendered inputs
@@ -256,6 +262,59 @@ pub async fn validate_security_model(
             }
             None
         }
+        "signature" => {
+            debug!("[WEBHOOK API] Validating request signature");
+            let scheme = match rendered_inputs
+                .get("signature_scheme")
+                .and_then(|v| v.as_str())
+                .and_then(SignatureScheme::parse)
+            {
+                Some(scheme) => scheme,
+                None => {
+                    warn!("[WEBHOOK API] No valid signature scheme configured");
+                    return Some(
+                        (StatusCode::UNAUTHORIZED, "Invalid signature configuration")
+                            .into_response(),
+                    );
+                }
+            };
+
+            let secret = match rendered_inputs
+                .get("signature_secret")
+                .and_then(|v| v.as_str())
+            {
+                Some(secret) if !secret.is_empty() => secret,
+                _ => {
+                    warn!("[WEBHOOK API] No signature secret configured");
+                    return Some(
+                        (StatusCode::UNAUTHORIZED, "Invalid signature configuration")
+                            .into_response(),
+                    );
+                }
+            };
+
+            let header_name = rendered_inputs
+                .get("signature_header")
+                .and_then(|v| v.as_str())
+                .filter(|name| !name.is_empty())
+                .unwrap_or(scheme.default_header());
+
+            let signature = match headers.get(header_name).and_then(|h| h.to_str().ok()) {
+                Some(signature) => signature,
+                None => {
+                    warn!("[WEBHOOK API] Signature header {} not found", header_name);
+                    return Some(
+                        (StatusCode::UNAUTHORIZED, "Missing signature header").into_response(),
+                    );
+                }
+            };
+
+            if !verify_signature(secret, raw_body, signature, &scheme) {
+                warn!("[WEBHOOK API] Request signature did not verify");
+                return Some((StatusCode::UNAUTHORIZED, "Invalid signature").into_response());
+            }
+            None
+        }
         _ => {
             println!("[WEBHOOK API] Invalid security model specified");
             Some((StatusCode::BAD_REQUEST, "Invalid security model").into_response())
@@ -303,6 +362,186 @@ pub fn validate_request_method(
     None
 }
 
+// Applies the trigger's payload_mapping, if it has one. The payload_schema is checked against
+// the mapped payload since that's the shape the workflow runs with
+pub fn normalize_webhook_payload(
+    trigger_node: &Action,
+    payload: Value,
+) -> Result<Value, axum::response::Response> {
+    let mapping = match trigger_node.payload_mapping.as_ref() {
+        Some(mapping) => mapping,
+        None => return Ok(payload),
+    };
+
+    debug!("[WEBHOOK API] Normalizing payload with trigger payload mapping");
+    normalize_trigger_payload(mapping, &payload).map_err(|e| {
+        warn!("[WEBHOOK API] Failed to normalize payload: {}", e.message);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Payload could not be mapped with the trigger's payload mapping",
+                "message": e.message,
+                "variable": e.variable
+            })),
+        )
+            .into_response()
+    })
+}
+
+// Rejects payloads that don't match the trigger's payload_schema before any task is created
+pub fn validate_webhook_payload(
+    trigger_node: &Action,
+    payload: &Value,
+) -> Option<impl IntoResponse> {
+    let schema = trigger_node.payload_schema.as_ref()?;
+
+    debug!("[WEBHOOK API] Validating payload against trigger payload schema");
+    let problems = validate_trigger_payload(payload, schema);
+    if problems.is_empty() {
+        return None;
+    }
+
+    warn!("[WEBHOOK API] Invalid payload: {:?}", problems);
+    Some(
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "error": "Payload does not match the trigger's payload schema",
+                "problems": problems
+            })),
+        )
+            .into_response(),
+    )
+}
+
+//One Day. Providers give up retrying well before this
+pub const WEBHOOK_DELIVERY_TTL: u64 = 86400;
+
+// Pulls a provider supplied delivery id out of the trigger payload using the configured path
+// ex: "headers.x-github-delivery" for GitHub or "body.id" for Stripe
+pub fn extract_idempotency_key(rendered_inputs: &Value, trigger_payload: &Value) -> Option<String> {
+    let path = rendered_inputs
+        .get("idempotency_key_path")
+        .and_then(|v| v.as_str())
+        .map(|path| path.trim())
+        .filter(|path| !path.is_empty())?;
+
+    let mut current = trigger_payload;
+    for segment in path.split('.') {
+        current = match current {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    match current {
+        Value::String(key) if !key.is_empty() => Some(key.clone()),
+        Value::Number(key) => Some(key.to_string()),
+        _ => None,
+    }
+}
+
+// What a delivery starts its workflow with. A delivery that carries an id keeps it reserved so a
+// retry isn't started twice, release_webhook_delivery frees it if the run never starts
+pub struct WebhookDelivery {
+    pub trigger_result: Value,
+    delivery_key: Option<String>,
+}
+
+// Parses, maps and validates the payload and checks the delivery isn't a retry. Err is the
+// response to send back instead
+pub async fn prepare_webhook_delivery(
+    state: Arc<AppState>,
+    trigger_node: &Action,
+    workflow_id: &str,
+    rendered_inputs: &Value,
+    headers: &HeaderMap,
+    method: Method,
+    query: Option<Query<HashMap<String, String>>>,
+    raw_body: &[u8],
+    flow_session_id: &str,
+) -> Result<WebhookDelivery, Response> {
+    // Signatures are checked against the raw bytes, the payload only needs them parsed
+    let body = serde_json::from_slice::<Value>(raw_body).ok().map(Json);
+    let processed_payload = convert_request_to_payload(method.clone(), query, body);
+    let processed_payload = normalize_webhook_payload(trigger_node, processed_payload)?;
+
+    if let Some(response) = validate_webhook_payload(trigger_node, &processed_payload) {
+        return Err(response.into_response());
+    }
+
+    let trigger_result = json!({
+        "headers": headers.iter().map(|(k,v)| (k.as_str(), String::from_utf8_lossy(v.as_bytes()).into_owned())).collect::<HashMap<_,_>>(),
+        "body": processed_payload,
+        "method": method.to_string(),
+    });
+
+    // Providers retry deliveries with the same id. Don't start a second run for them
+    let delivery_key = dedupe_webhook_delivery(
+        state,
+        workflow_id,
+        rendered_inputs,
+        &trigger_result,
+        flow_session_id,
+    )
+    .await?;
+
+    Ok(WebhookDelivery {
+        trigger_result,
+        delivery_key,
+    })
+}
+
+// Reserves the delivery's id for this flow session, or answers a retry of one that already
+// started. Reserving right away means two retries arriving together can't both start a run
+pub async fn dedupe_webhook_delivery(
+    state: Arc<AppState>,
+    workflow_id: &str,
+    rendered_inputs: &Value,
+    trigger_payload: &Value,
+    flow_session_id: &str,
+) -> Result<Option<String>, Response> {
+    let Some(idempotency_key) = extract_idempotency_key(rendered_inputs, trigger_payload) else {
+        return Ok(None);
+    };
+
+    let now = SystemTime::now();
+    let mut deliveries = state.webhook_deliveries.write().await;
+    deliveries.retain(|_, (_, expires_at)| *expires_at > now);
+
+    let delivery_key = format!("{}:{}", workflow_id, idempotency_key);
+    if let Some((existing_session_id, _)) = deliveries.get(&delivery_key) {
+        info!(
+            "[WEBHOOK API] Duplicate delivery {} already started flow session {}",
+            idempotency_key, existing_session_id
+        );
+        return Err(Json(json!({
+            "success": true,
+            "message": "Duplicate delivery. Workflow already started",
+            "workflow_session_id": existing_session_id,
+            "workflow_id": workflow_id
+        }))
+        .into_response());
+    }
+
+    deliveries.insert(
+        delivery_key.clone(),
+        (
+            flow_session_id.to_string(),
+            now + Duration::from_secs(WEBHOOK_DELIVERY_TTL),
+        ),
+    );
+    Ok(Some(delivery_key))
+}
+
+// For when the run couldn't be sent to the processor, so the provider's retry can start it
+pub async fn release_webhook_delivery(state: &AppState, delivery: &WebhookDelivery) {
+    if let Some(delivery_key) = &delivery.delivery_key {
+        state.webhook_deliveries.write().await.remove(delivery_key);
+    }
+}
+
 pub fn convert_request_to_payload(
     method: axum::http::Method,
     query: Option<Query<HashMap<String, String>>>,
@@ -481,6 +720,29 @@ pub fn parse_response_action_response_into_api_response(stored_result: Value) ->
             }
         }
 
+        // Handle form responses, the Response action already encoded the body
+        ct if ct.contains("application/x-www-form-urlencoded") => {
+            if let Some(body) = stored_result.get("body").and_then(Value::as_str) {
+                headers.insert(
+                    HeaderName::from_static("content-type"),
+                    HeaderValue::from_static("application/x-www-form-urlencoded"),
+                );
+                debug!(
+                    "[WEBHOOK API] [CREATE RESPONSE] Returning form response with status {}: {}",
+                    status_code, body
+                );
+                (
+                    StatusCode::from_u16(status_code).unwrap_or(StatusCode::OK),
+                    headers,
+                    body.to_string(),
+                )
+                    .into_response()
+            } else {
+                error!("[WEBHOOK API] [CREATE RESPONSE] Returning invalid form response error");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Invalid form response").into_response()
+            }
+        }
+
         // Default to JSON responses
         _ => {
             headers.insert(
@@ -501,3 +763,186 @@ pub fn parse_response_action_response_into_api_response(stored_result: Value) ->
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::in_memory_task_store::{action, test_app_state, InMemoryTaskStore};
+
+    async fn response_json(response: Response) -> Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn delivery_payload(delivery_id: &str) -> Value {
+        json!({
+            "headers": { "x-github-delivery": delivery_id },
+            "body": { "id": 42, "events": [{ "id": "evt_1" }] },
+            "method": "POST"
+        })
+    }
+
+    #[test]
+    fn test_extract_idempotency_key() {
+        let payload = delivery_payload("delivery_1");
+        let key_at = |path: &str| {
+            extract_idempotency_key(&json!({ "idempotency_key_path": path }), &payload)
+        };
+
+        assert_eq!(
+            key_at("headers.x-github-delivery"),
+            Some("delivery_1".to_string())
+        );
+        assert_eq!(key_at(" body.id "), Some("42".to_string()));
+        assert_eq!(key_at("body.events.0.id"), Some("evt_1".to_string()));
+
+        assert_eq!(key_at("body.missing"), None);
+        assert_eq!(key_at("body.events.1.id"), None);
+        assert_eq!(key_at("body"), None);
+        assert_eq!(key_at(""), None);
+        assert_eq!(extract_idempotency_key(&json!({}), &payload), None);
+        assert_eq!(
+            extract_idempotency_key(
+                &json!({ "idempotency_key_path": "headers.x-github-delivery" }),
+                &delivery_payload("")
+            ),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_delivery_is_answered_until_released() {
+        let state = test_app_state(Arc::new(InMemoryTaskStore::new()));
+        let rendered_inputs = json!({ "idempotency_key_path": "headers.x-github-delivery" });
+        let payload = delivery_payload("delivery_1");
+
+        let delivery_key = dedupe_webhook_delivery(
+            state.clone(),
+            "workflow_1",
+            &rendered_inputs,
+            &payload,
+            "session_1",
+        )
+        .await
+        .unwrap();
+        assert_eq!(delivery_key, Some("workflow_1:delivery_1".to_string()));
+
+        let duplicate = dedupe_webhook_delivery(
+            state.clone(),
+            "workflow_1",
+            &rendered_inputs,
+            &payload,
+            "session_2",
+        )
+        .await
+        .unwrap_err();
+        let duplicate = response_json(duplicate).await;
+        assert_eq!(
+            duplicate["message"],
+            "Duplicate delivery. Workflow already started"
+        );
+        assert_eq!(duplicate["workflow_session_id"], "session_1");
+
+        // Same delivery id for another workflow is its own delivery
+        assert!(dedupe_webhook_delivery(
+            state.clone(),
+            "workflow_2",
+            &rendered_inputs,
+            &payload,
+            "session_3",
+        )
+        .await
+        .is_ok());
+
+        // The run never reached the processor, so the provider's retry has to start it
+        release_webhook_delivery(
+            &state,
+            &WebhookDelivery {
+                trigger_result: payload.clone(),
+                delivery_key,
+            },
+        )
+        .await;
+        let retried = dedupe_webhook_delivery(
+            state.clone(),
+            "workflow_1",
+            &rendered_inputs,
+            &payload,
+            "session_4",
+        )
+        .await
+        .unwrap();
+        assert_eq!(retried, Some("workflow_1:delivery_1".to_string()));
+
+        // Deliveries without an id are never deduped
+        assert_eq!(
+            dedupe_webhook_delivery(state.clone(), "workflow_1", &json!({}), &payload, "s")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payload_that_does_not_match_the_schema_is_rejected() {
+        let state = test_app_state(Arc::new(InMemoryTaskStore::new()));
+        let mut trigger_node: Action =
+            serde_json::from_value(action("trigger", "trigger", None)).unwrap();
+        trigger_node.payload_schema = Some(
+            serde_json::from_value(json!({
+                "type": "object",
+                "properties": {
+                    "email": { "type": "string", "x-any-validation": { "type": "string" } }
+                },
+                "required": ["email"]
+            }))
+            .unwrap(),
+        );
+        let rendered_inputs = json!({ "idempotency_key_path": "body.id" });
+
+        let rejected = prepare_webhook_delivery(
+            state.clone(),
+            &trigger_node,
+            "workflow_1",
+            &rendered_inputs,
+            &HeaderMap::new(),
+            Method::POST,
+            None,
+            br#"{"id":"evt_1","name":"Ada"}"#,
+            "session_1",
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        let rejected = response_json(rejected).await;
+        assert_eq!(
+            rejected["error"],
+            "Payload does not match the trigger's payload schema"
+        );
+        assert_eq!(rejected["problems"], json!(["'email' is required"]));
+
+        // A rejected payload doesn't reserve its delivery id
+        assert!(state.webhook_deliveries.read().await.is_empty());
+
+        let delivery = prepare_webhook_delivery(
+            state.clone(),
+            &trigger_node,
+            "workflow_1",
+            &rendered_inputs,
+            &HeaderMap::new(),
+            Method::POST,
+            None,
+            br#"{"id":"evt_1","email":"ada@example.com"}"#,
+            "session_1",
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert_eq!(delivery.trigger_result["body"]["email"], "ada@example.com");
+        assert_eq!(delivery.trigger_result["method"], "POST");
+        assert_eq!(delivery.delivery_key, Some("workflow_1:evt_1".to_string()));
+    }
+}
@@ -1,10 +1,27 @@
 use axum::{extract::Path, response::IntoResponse, Extension, Json};
 use chrono::Utc;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{collections::HashMap, env};
 
 use crate::supabase_jwt_middleware::User;
 
+// Comma separated env var names workflows can read as {{system.env.NAME}}. Nothing else is exposed
+const SYSTEM_ENV_ALLOWLIST: &str = "SYSTEM_ENV_ALLOWLIST";
+
+pub fn get_allowed_env_variables(allowlist: &str) -> serde_json::Map<String, Value> {
+    let mut env_vars = serde_json::Map::new();
+    for name in allowlist
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+    {
+        if let Ok(value) = env::var(name) {
+            env_vars.insert(name.to_string(), Value::String(value));
+        }
+    }
+    env_vars
+}
+
 pub fn get_system_variables() -> HashMap<String, Value> {
     let mut system_vars = HashMap::new();
 
@@ -81,6 +98,13 @@ pub fn get_system_variables() -> HashMap<String, Value> {
         Value::String(now.format("%B").to_string()),
     ); // Full month name
 
+    // Names missing from the allowlist are simply not in the context so they resolve as not found
+    let allowlist = env::var(SYSTEM_ENV_ALLOWLIST).unwrap_or_default();
+    system_vars.insert(
+        "env".to_string(),
+        Value::Object(get_allowed_env_variables(&allowlist)),
+    );
+
     system_vars
 }
 
@@ -99,3 +123,25 @@ pub async fn get_system_variables_handler(
     println!("[SYSTEM VARIABLES] Returning response");
     Json(result).into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_allowlist() {
+        env::set_var("ANYTHING_TEST_ALLOWED_ENV", "us-east-1");
+        env::set_var("ANYTHING_TEST_DENIED_ENV", "hidden");
+
+        let env_vars =
+            get_allowed_env_variables(" ANYTHING_TEST_ALLOWED_ENV, ,ANYTHING_TEST_UNSET_ENV");
+
+        assert_eq!(
+            env_vars.get("ANYTHING_TEST_ALLOWED_ENV"),
+            Some(&Value::String("us-east-1".to_string()))
+        );
+        assert!(env_vars.get("ANYTHING_TEST_DENIED_ENV").is_none());
+        assert!(env_vars.get("ANYTHING_TEST_UNSET_ENV").is_none());
+        assert_eq!(env_vars.len(), 1);
+    }
+}
@@ -51,6 +51,8 @@ pub fn create_cron_http_workflow() -> Result<WorkflowVersionDefinition, Box<dyn
         target: "http".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     // Create workflow definition
@@ -109,6 +111,8 @@ pub fn create_webhook_js_workflow() -> Result<WorkflowVersionDefinition, Box<dyn
         target: "javascript".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     let js_to_response = Edge {
@@ -118,6 +122,8 @@ pub fn create_webhook_js_workflow() -> Result<WorkflowVersionDefinition, Box<dyn
         target: "response".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     // Create workflow definition
@@ -186,6 +192,8 @@ pub fn create_input_output_workflow() -> Result<WorkflowVersionDefinition, Box<d
         target: "http".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     let http_to_js = Edge {
@@ -195,6 +203,8 @@ pub fn create_input_output_workflow() -> Result<WorkflowVersionDefinition, Box<d
         target: "javascript".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     let js_to_output = Edge {
@@ -204,6 +214,8 @@ pub fn create_input_output_workflow() -> Result<WorkflowVersionDefinition, Box<d
         target: "output".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     // Create workflow definition
@@ -274,6 +286,8 @@ pub fn create_tool_workflow() -> Result<WorkflowVersionDefinition, Box<dyn std::
         target: "agent_tool_call_response".to_string(),
         source_handle: Some("b".to_string()),
         target_handle: Some("a".to_string()),
+        condition: None,
+        priority: None,
     };
 
     // let http_to_js = Edge {
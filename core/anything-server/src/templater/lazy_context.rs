@@ -0,0 +1,109 @@
+use serde_json::{Map, Value};
+
+type Build<'a> = Box<dyn Fn() -> Value + Send + Sync + 'a>;
+type Entry<'a> = Box<dyn Fn(&str) -> Option<Value> + Send + Sync + 'a>;
+
+enum Namespace<'a> {
+    Built(Value),
+    Lazy(Build<'a>),
+    // Only the names a template reads are built, e.g. one action for {{actions.fetch.result}}
+    Keyed {
+        names: Vec<String>,
+        entry: Entry<'a>,
+    },
+}
+
+// A render context built one namespace at a time, so a template only pays for what it reads.
+// Rendering inputs that use `{{actions.fetch.result.id}}` serializes that one task instead of
+// every task in the session. Secrets aren't here, see Templater::set_secrets
+#[derive(Default)]
+pub struct LazyContext<'a> {
+    namespaces: Vec<(String, Namespace<'a>)>,
+}
+
+impl<'a> LazyContext<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // For namespaces that are already a value or cheap to make, e.g. `loop`
+    pub fn insert(&mut self, namespace: &str, value: Value) {
+        self.set(namespace, Namespace::Built(value));
+    }
+
+    pub fn insert_lazy<F>(&mut self, namespace: &str, build: F)
+    where
+        F: Fn() -> Value + Send + Sync + 'a,
+    {
+        self.set(namespace, Namespace::Lazy(Box::new(build)));
+    }
+
+    // `names` is everything in the namespace. It's all built when a template reads the namespace
+    // itself, e.g. `{{actions | length}}`. Names `entry` returns None for are left out
+    pub fn insert_keyed<F>(&mut self, namespace: &str, names: Vec<String>, entry: F)
+    where
+        F: Fn(&str) -> Option<Value> + Send + Sync + 'a,
+    {
+        let entry = Box::new(entry);
+        self.set(namespace, Namespace::Keyed { names, entry });
+    }
+
+    fn set(&mut self, namespace: &str, value: Namespace<'a>) {
+        match self
+            .namespaces
+            .iter_mut()
+            .find(|(name, _)| name == namespace)
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.namespaces.push((namespace.to_string(), value)),
+        }
+    }
+
+    // What Templater::build_context found a template reads. None builds everything, JMESPath
+    // can read anything in the context
+    pub(super) fn build(&self, read: Option<&[(&str, Option<&str>)]>) -> Value {
+        let mut context = Map::with_capacity(self.namespaces.len());
+        for (namespace, value) in &self.namespaces {
+            let names = match read {
+                Some(read) => {
+                    let mut names = Vec::new();
+                    let mut whole = false;
+                    for (read_namespace, name) in read {
+                        if read_namespace != namespace {
+                            continue;
+                        }
+                        match name {
+                            Some(name) if !names.contains(name) => names.push(*name),
+                            Some(_) => {}
+                            None => whole = true,
+                        }
+                    }
+                    if !whole && names.is_empty() {
+                        continue;
+                    }
+                    (!whole).then_some(names)
+                }
+                None => None,
+            };
+
+            let value = match (value, names) {
+                (Namespace::Built(value), _) => value.clone(),
+                (Namespace::Lazy(build), _) => build(),
+                (Namespace::Keyed { entry, .. }, Some(names)) => Value::Object(
+                    names
+                        .into_iter()
+                        .filter_map(|name| Some((name.to_string(), entry(name)?)))
+                        .collect(),
+                ),
+                (Namespace::Keyed { names, entry }, None) => Value::Object(
+                    names
+                        .iter()
+                        .filter_map(|name| Some((name.clone(), entry(name)?)))
+                        .collect(),
+                ),
+            };
+            context.insert(namespace.clone(), value);
+        }
+        Value::Object(context)
+    }
+}
@@ -1,8 +1,155 @@
 use serde_json::Value;
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
 
 use crate::types::json_schema::ValidationFieldType;
+use crate::types::secret_types::Secret;
+
+mod lazy_context;
+mod template_cache;
+mod truthiness;
+pub use lazy_context::LazyContext;
+pub use template_cache::TemplateCache;
+pub use truthiness::is_truthy;
+
+// Opts a `{{ }}` block into JMESPath instead of dotted paths, e.g. `{{ jmes: actions.*.result.status }}`
+pub const JMESPATH_PREFIX: &str = "jmes:";
+
+const SECRETS_PREFIX: &str = "secrets.";
+
+// `{{ body | parse_json }}` parses a string that holds JSON so a path can traverse into it
+const PARSE_JSON_FILTER: &str = "parse_json";
+
+// Aggregate arrays, e.g. the rows an action returned. Filters chain left to right:
+// `{{ actions.fetch_orders.result.orders | map: "total" | sum }}`
+const SUM_FILTER: &str = "sum";
+const LENGTH_FILTER: &str = "length";
+const JOIN_FILTER: &str = "join"; // `join: ","`
+const MAP_FILTER: &str = "map"; // `map: "field"`, a path into each item
+
+// `{{ variables.ids | query: "id" }}` repeats the key for each item, e.g. `id=1&id=2&id=3`.
+// Keys and values are URL-encoded so it can go straight after the `?` of a url
+const QUERY_FILTER: &str = "query";
+
+const REDACTED: &str = "***";
+
+// How deeply objects and arrays in a template can nest before it's rejected instead of
+// recursing until the stack overflows
+const DEFAULT_MAX_DEPTH: usize = 128;
+
+// See Templater::set_resolver
+type Resolver = Box<dyn Fn(&str) -> Option<Value> + Send + Sync>;
+
+// How values interpolated into a string are escaped for where the string ends up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EscapeMode {
+    #[default]
+    None,
+    Html,  // & < > " and ' become entities
+    Sql,   // Quoted as a string literal with single quotes doubled
+    Shell, // Quoted as one POSIX shell word
+}
+
+impl EscapeMode {
+    pub fn escape(&self, value: &str) -> String {
+        match self {
+            EscapeMode::None => value.to_string(),
+            EscapeMode::Html => {
+                let mut escaped = String::with_capacity(value.len());
+                for c in value.chars() {
+                    match c {
+                        '&' => escaped.push_str("&amp;"),
+                        '<' => escaped.push_str("&lt;"),
+                        '>' => escaped.push_str("&gt;"),
+                        '"' => escaped.push_str("&quot;"),
+                        '\'' => escaped.push_str("&#x27;"),
+                        c => escaped.push(c),
+                    }
+                }
+                escaped
+            }
+            EscapeMode::Sql => format!("'{}'", value.replace('\'', "''")),
+            EscapeMode::Shell => format!("'{}'", value.replace('\'', "'\\''")),
+        }
+    }
+}
+
+// What a null becomes when it's interpolated into a string, e.g. `Hi {{variables.name}}`.
+// A field that is only one `{{ }}` keeps the null either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NullPolicy {
+    #[default]
+    Literal, // "null"
+    Empty,
+    Error,
+}
+
+// See Templater::variable_gaps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VariableGaps {
+    pub unused: Vec<String>,     // Declared but never referenced
+    pub undeclared: Vec<String>, // Referenced but not declared
+}
+
+// A field's value converted to the type its validation asked for, e.g. "42" to a number.
+// See Templater::render_with_coercions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coercion {
+    pub variable: String,
+    pub from_type: String, // The JSON type it rendered as, e.g. "string"
+    pub to_type: String,   // The validation type, e.g. "integer"
+}
+
+// See Templater::assert_renders
+#[derive(Debug)]
+pub enum RenderDiff {
+    Failed(TemplateError),
+    Mismatch(Vec<ValueDifference>),
+}
+
+// One place the rendered output and the expected one disagree. None is a missing key or item
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueDifference {
+    pub pointer: String, // JSON pointer, e.g. `/headers/Authorization` or `/items/0`. "" is the root
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+impl std::fmt::Display for RenderDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let differences = match self {
+            RenderDiff::Failed(error) => return write!(f, "Failed to render: {}", error),
+            RenderDiff::Mismatch(differences) => differences,
+        };
+        let show = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "nothing".to_string(),
+        };
+        writeln!(
+            f,
+            "Rendered output differs in {} places:",
+            differences.len()
+        )?;
+        for difference in differences {
+            // The root's pointer is empty
+            let pointer = match difference.pointer.as_str() {
+                "" => "(root)",
+                pointer => pointer,
+            };
+            writeln!(
+                f,
+                "  {}: expected {}, got {}",
+                pointer,
+                show(&difference.expected),
+                show(&difference.actual)
+            )?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub struct TemplateError {
@@ -22,18 +169,166 @@ impl std::fmt::Display for TemplateError {
 
 impl Error for TemplateError {}
 
+// A parsed `{{#name argument}}body{{else}}else_body{{/name}}` block
+struct Block<'a> {
+    name: &'a str,
+    argument: &'a str,
+    body: &'a str,
+    else_body: &'a str,
+}
+
+// Templates are compiled once in `add_template` so rendering doesn't rescan every string
+enum CompiledTemplate {
+    Literal(Value),
+    // A key with a `{{ }}` keeps its segments so the name is rendered too
+    Object(Vec<(String, Option<Vec<Segment>>, CompiledTemplate)>),
+    Array(Vec<CompiledTemplate>),
+    Variable(String), // The whole string is one `{{ }}` so the value keeps its type
+    Interpolated(Vec<Segment>),
+    Blocks(String), // Block helpers are matched at render time
+    TooDeep,        // Nested past max_depth. Errors when rendered
+}
+
+// One template and its compiled form, shared through a TemplateCache by every templater that adds it
+struct InternedTemplate {
+    source: Value,
+    compiled: CompiledTemplate,
+    max_depth: usize, // What it was compiled with, the same template compiles differently under another limit
+}
+
+enum Segment {
+    Text(String),
+    Variable(String),
+    Unclosed(String), // A `{{` with no `}}`. Errors when rendered like it always has
+}
+
 pub struct Templater {
-    templates: HashMap<String, Value>,
+    templates: HashMap<String, Arc<InternedTemplate>>,
+    cache: Option<Arc<TemplateCache>>, // Where add_template looks for an identical template, see with_cache
+    secrets: HashMap<String, Secret<String>>,
+    exposed_secrets: RefCell<Vec<String>>, // Values substituted from secrets, so logs can mask them
+    resolved_paths: RefCell<Vec<String>>,  // Paths read by the variables that resolved
+    coercions: RefCell<Option<Vec<Coercion>>>, // Only while render_with_coercions is rendering
+    max_output_bytes: Option<usize>,
+    max_depth: usize,
+    parse_json_strings: bool,
+    resolver: Option<Resolver>,
+    escape_mode: EscapeMode,
+    null_policy: NullPolicy,
 }
 
 impl Templater {
     pub fn new() -> Self {
         Templater {
             templates: HashMap::new(),
+            cache: None,
+            secrets: HashMap::new(),
+            exposed_secrets: RefCell::new(Vec::new()),
+            resolved_paths: RefCell::new(Vec::new()),
+            coercions: RefCell::new(None),
+            max_output_bytes: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            parse_json_strings: false,
+            resolver: None,
+            escape_mode: EscapeMode::None,
+            null_policy: NullPolicy::Literal,
+        }
+    }
+
+    // Paths traverse into strings that hold JSON, e.g. `body.id` where body is "{\"id\":1}".
+    // Off by default so string data that happens to look like JSON stays a string.
+    // `{{path | parse_json}}` turns it on for one variable
+    pub fn set_parse_json_strings(&mut self, parse_json_strings: bool) {
+        self.parse_json_strings = parse_json_strings;
+    }
+
+    // Caps every string the templater builds, e.g. a big array interpolated into text or an
+    // each block over a huge list. Rendering stops with an error once a string would grow past it
+    pub fn set_max_output_bytes(&mut self, max_output_bytes: usize) {
+        self.max_output_bytes = Some(max_output_bytes);
+    }
+
+    // Asked for paths that aren't in the context before they're reported missing, so values can
+    // be fetched on demand. Gets the whole path, e.g. "accounts.slack.access_token"
+    pub fn set_resolver<F>(&mut self, resolver: F)
+    where
+        F: Fn(&str) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.resolver = Some(Box::new(resolver));
+    }
+
+    // Templates nested deeper than this fail with "Template nesting too deep". Set it before
+    // add_template, templates are compiled up to the limit in place when they're added
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    // Escapes every value substituted into text, e.g. `<p>{{name}}</p>` with EscapeMode::Html.
+    // The template's own text is left alone and so is a field that is only one `{{ }}`, it
+    // keeps its value instead of becoming text
+    pub fn set_escape_mode(&mut self, escape_mode: EscapeMode) {
+        self.escape_mode = escape_mode;
+    }
+
+    pub fn set_null_policy(&mut self, null_policy: NullPolicy) {
+        self.null_policy = null_policy;
+    }
+
+    // Secrets are kept out of the render context and only exposed where `{{secrets.NAME}}` is substituted
+    pub fn set_secrets(&mut self, secrets: HashMap<String, Secret<String>>) {
+        self.secrets = secrets;
+    }
+
+    pub fn exposed_secrets(&self) -> Vec<String> {
+        self.exposed_secrets.borrow().clone()
+    }
+
+    // Paths of the variables rendered so far in the order first read, e.g. `secrets.API_KEY` or
+    // `accounts.slack.access_token`. JMESPath expressions aren't included
+    pub fn resolved_paths(&self) -> Vec<String> {
+        self.resolved_paths.borrow().clone()
+    }
+
+    // For logging rendered output. Anything that came from a secret while rendering is masked
+    pub fn redact_secrets(&self, value: &Value) -> String {
+        Self::redact(&value.to_string(), &self.exposed_secrets.borrow())
+    }
+
+    pub fn redact(output: &str, secret_values: &[String]) -> String {
+        let mut secret_values: Vec<&String> =
+            secret_values.iter().filter(|s| !s.is_empty()).collect();
+        // Longest first so a secret that contains another one is masked whole
+        secret_values.sort_by_key(|s| std::cmp::Reverse(s.len()));
+
+        let mut redacted = output.to_string();
+        for secret_value in secret_values {
+            redacted = redacted.replace(secret_value.as_str(), REDACTED);
+            // Rendered values are logged as JSON so quotes and backslashes show up escaped
+            let escaped = Value::String(secret_value.clone()).to_string();
+            redacted = redacted.replace(&escaped[1..escaped.len() - 1], REDACTED);
+        }
+        redacted
+    }
+
+    // Templates added to it are shared with every other templater using `cache`, e.g. the
+    // bundler's one per task templaters share AppState::template_cache
+    pub fn with_cache(cache: Arc<TemplateCache>) -> Self {
+        Templater {
+            cache: Some(cache),
+            ..Templater::new()
         }
     }
 
+    // Without a cache the template is only compiled
     pub fn add_template(&mut self, name: &str, template: Value) {
+        let template = match &self.cache {
+            Some(cache) => cache.intern(template, self.max_depth),
+            None => Arc::new(InternedTemplate {
+                compiled: Self::compile(&template, 0, self.max_depth),
+                source: template,
+                max_depth: self.max_depth,
+            }),
+        };
         self.templates.insert(name.to_string(), template);
     }
 
@@ -49,35 +344,236 @@ impl Templater {
                 variable: template_name.to_string(),
             })?;
 
-        self.extract_variables(template)
+        self.extract_variables(&template.source, 0)
     }
 
-    fn extract_variables(&self, value: &Value) -> Result<Vec<String>, TemplateError> {
-        let mut variables = Vec::new();
+    // Pulls the paths out of a `{{ }}` expression, e.g. `status >= 200 ? actions.a.result : 'none'`.
+    // Block helpers, loop variables, literals and JMESPath expressions are skipped
+    pub fn referenced_paths(expression: &str) -> Vec<&str> {
+        let expression = expression.trim();
+        if expression.starts_with(JMESPATH_PREFIX)
+            || expression.starts_with('/')
+            || expression == "else"
+        {
+            return Vec::new();
+        }
+        let expression = expression
+            .strip_prefix("#each ")
+            .or_else(|| expression.strip_prefix("#if "))
+            .unwrap_or(expression);
+
+        let is_path_char = |c: char| c.is_alphanumeric() || "_.[]-@".contains(c);
+        let mut paths = Vec::new();
+        let mut quote = None;
+        let mut start = None;
+        for (i, c) in expression.char_indices().chain([(expression.len(), ' ')]) {
+            if let Some(q) = quote {
+                if c == q {
+                    quote = None;
+                }
+                continue;
+            }
+            if is_path_char(c) {
+                start.get_or_insert(i);
+                continue;
+            }
+            if let Some(s) = start.take() {
+                paths.push(&expression[s..i]);
+            }
+            match c {
+                '"' | '\'' => quote = Some(c),
+                '|' => break, // Filter names aren't paths
+                _ => {}
+            }
+        }
+
+        paths.retain(|path| {
+            let first = path.split(['.', '[']).next().unwrap_or_default();
+            first.starts_with(|c: char| c.is_alphabetic() || c == '_')
+                && !["this", "true", "false", "null"].contains(&first)
+        });
+        paths
+    }
+
+    // Compares the names a template reads under `namespace` with the ones declared for it, e.g.
+    // an action's inputs against the `{{inputs.*}}` its plugin config uses. Both lists keep the
+    // order they're first seen in
+    pub fn variable_gaps(
+        &self,
+        template_name: &str,
+        namespace: &str,
+        declared: &[&str],
+    ) -> Result<VariableGaps, TemplateError> {
+        let mut referenced: Vec<String> = Vec::new();
+        for variable in self.get_template_variables(template_name)? {
+            for path in Self::referenced_paths(&variable) {
+                let mut segments = path.split(['.', '[']);
+                if segments.next() != Some(namespace) {
+                    continue;
+                }
+                if let Some(name) = segments.next().filter(|name| !name.is_empty()) {
+                    if !referenced.iter().any(|seen| seen == name) {
+                        referenced.push(name.to_string());
+                    }
+                }
+            }
+        }
+
+        Ok(VariableGaps {
+            unused: declared
+                .iter()
+                .filter(|name| !referenced.iter().any(|seen| seen == *name))
+                .map(|name| name.to_string())
+                .collect(),
+            undeclared: referenced
+                .into_iter()
+                .filter(|name| !declared.contains(&name.as_str()))
+                .collect(),
+        })
+    }
+
+    // Checks every `{{ }}` in the template is well formed without a context, e.g. for an editor.
+    // Reports every unclosed, empty or unknown filter variable instead of stopping at the first
+    pub fn validate_template(&self, template_name: &str) -> Result<(), Vec<TemplateError>> {
+        let template = self.templates.get(template_name).ok_or_else(|| {
+            vec![TemplateError {
+                message: "Template not found".to_string(),
+                variable: template_name.to_string(),
+            }]
+        })?;
+
+        let mut strings = Vec::new();
+        self.collect_strings(&template.source, 0, &mut strings)
+            .map_err(|e| vec![e])?;
+
+        let mut errors = Vec::new();
+        for s in strings {
+            let (variables, unclosed) = Self::scan_variables(s);
+            for variable in variables {
+                if variable.is_empty() {
+                    errors.push(TemplateError {
+                        message: "Empty template variable".to_string(),
+                        variable: s.to_string(),
+                    });
+                    continue;
+                }
+                // JMESPath uses `|` for its own pipes
+                if variable.starts_with(JMESPATH_PREFIX) {
+                    continue;
+                }
+                for filter in &Self::split_filters(variable)[1..] {
+                    let (name, _) = Self::parse_filter(filter);
+                    if ![
+                        PARSE_JSON_FILTER,
+                        SUM_FILTER,
+                        LENGTH_FILTER,
+                        JOIN_FILTER,
+                        MAP_FILTER,
+                        QUERY_FILTER,
+                    ]
+                    .contains(&name)
+                    {
+                        errors.push(TemplateError {
+                            message: format!("Unknown filter '{}'", filter.trim()),
+                            variable: variable.to_string(),
+                        });
+                    }
+                }
+            }
+            errors.extend(unclosed);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Object keys can be templated too
+    fn collect_strings<'a>(
+        &self,
+        value: &'a Value,
+        depth: usize,
+        strings: &mut Vec<&'a str>,
+    ) -> Result<(), TemplateError> {
+        if depth > self.max_depth {
+            return Err(self.too_deep());
+        }
         match value {
             Value::Object(map) => {
-                for (_, v) in map {
-                    variables.extend(self.extract_variables(v)?);
+                for (k, v) in map {
+                    strings.push(k);
+                    self.collect_strings(v, depth + 1, strings)?;
                 }
             }
             Value::Array(arr) => {
                 for v in arr {
-                    variables.extend(self.extract_variables(v)?);
+                    self.collect_strings(v, depth + 1, strings)?;
                 }
             }
-            Value::String(s) => {
-                let mut start = 0;
-                while let Some(open_idx) = s[start..].find("{{") {
-                    let open_idx = start + open_idx;
-                    let close_idx = s[open_idx..].find("}}").ok_or_else(|| TemplateError {
+            Value::String(s) => strings.push(s),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn too_deep(&self) -> TemplateError {
+        TemplateError {
+            message: "Template nesting too deep".to_string(),
+            variable: format!("more than {} levels", self.max_depth),
+        }
+    }
+
+    // The trimmed inside of every `{{ }}` in order, and an error if the string ends in an unclosed `{{`
+    fn scan_variables(s: &str) -> (Vec<&str>, Option<TemplateError>) {
+        let mut variables = Vec::new();
+        let mut start = 0;
+        while let Some(open_idx) = s[start..].find("{{") {
+            let open_idx = start + open_idx;
+            let close_idx = match s[open_idx..].find("}}") {
+                Some(close_idx) => open_idx + close_idx,
+                None => {
+                    let error = TemplateError {
                         message: "Unclosed template variable".to_string(),
                         variable: s.to_string(),
-                    })?;
-                    let close_idx = open_idx + close_idx;
-                    let variable = s[open_idx + 2..close_idx].trim().to_string();
-                    variables.push(variable);
-                    start = close_idx + 2;
+                    };
+                    return (variables, Some(error));
+                }
+            };
+            variables.push(s[open_idx + 2..close_idx].trim());
+            start = close_idx + 2;
+        }
+        (variables, None)
+    }
+
+    fn extract_variables(&self, value: &Value, depth: usize) -> Result<Vec<String>, TemplateError> {
+        if depth > self.max_depth {
+            return Err(self.too_deep());
+        }
+        let mut variables = Vec::new();
+        match value {
+            Value::Object(map) => {
+                for (k, v) in map {
+                    let (found, unclosed) = Self::scan_variables(k);
+                    if let Some(e) = unclosed {
+                        return Err(e);
+                    }
+                    variables.extend(found.into_iter().map(str::to_string));
+                    variables.extend(self.extract_variables(v, depth + 1)?);
+                }
+            }
+            Value::Array(arr) => {
+                for v in arr {
+                    variables.extend(self.extract_variables(v, depth + 1)?);
+                }
+            }
+            Value::String(s) => {
+                let (found, unclosed) = Self::scan_variables(s);
+                if let Some(e) = unclosed {
+                    return Err(e);
                 }
+                variables.extend(found.into_iter().map(str::to_string));
             }
             _ => {}
         }
@@ -88,29 +584,64 @@ impl Templater {
         context: &Value,
         path: &str,
         expected_type: &ValidationFieldType,
+        parse_json: bool,
     ) -> Option<Value> {
         let mut current = context;
         let parts: Vec<&str> = path.split('.').collect();
 
         for (i, part) in parts.iter().enumerate() {
-            if let Some(index_start) = part.find('[') {
-                let key = &part[..index_start];
-                let index_end = part.find(']').unwrap_or(part.len());
-                let index: usize = part[index_start + 1..index_end].parse().ok()?;
-
-                current = current.get(key)?;
-                if current.is_array() {
-                    current = current.get(index)?;
-                } else {
-                    return None; // Not an array when we expected one
+            // `a?.b` is optional chaining. If `a` is missing or null the path is null instead of not found
+            let (part, optional) = match part.strip_suffix('?') {
+                Some(part) => (part, true),
+                None => (*part, false),
+            };
+            let missing = if optional { Some(Value::Null) } else { None };
+
+            // A part is a key followed by any number of indexes, e.g. `items[0]` or `grid[1][0]`.
+            // The key is empty when we recurse into a parsed array below
+            let (key, mut indexes) = match part.find('[') {
+                Some(index_start) => part.split_at(index_start),
+                None => (part, ""),
+            };
+            if !key.is_empty() || indexes.is_empty() {
+                current = match current.get(key) {
+                    Some(value) => value,
+                    None => return missing,
+                };
+            }
+
+            while let Some(unopened) = indexes.strip_prefix('[') {
+                let index_end = unopened.find(']')?;
+
+                // Arrays stored as JSON strings are parsed before indexing into them
+                if let (Value::String(s), true) = (current, parse_json) {
+                    let parsed: Value = serde_json::from_str(s).ok()?;
+                    let mut rest = format!("{}{}", indexes, if optional { "?" } else { "" });
+                    if i < parts.len() - 1 {
+                        rest = format!("{}.{}", rest, parts[i + 1..].join("."));
+                    }
+                    return Self::get_value_from_path(&parsed, &rest, expected_type, parse_json);
                 }
-            } else {
-                current = current.get(part)?;
+
+                let index: usize = unopened[..index_end].parse().ok()?;
+                // Missing when it's not an array
+                current = match current.as_array().and_then(|items| items.get(index)) {
+                    Some(value) => value,
+                    None => return missing,
+                };
+                indexes = &unopened[index_end + 1..];
+            }
+            if !indexes.is_empty() {
+                return None; // Something other than an index after the key, e.g. `items[0]x`
+            }
+            if optional && current.is_null() {
+                return missing;
             }
 
-            if let Value::String(s) = current {
-                // Only parse JSON if the expected type is not String
-                if *expected_type != ValidationFieldType::String {
+            if let (Value::String(s), true) = (current, parse_json) {
+                // A String field gets the string exactly as it is, e.g. "007" or "1.50". Strings
+                // the path still has to traverse into are parsed either way
+                if i < parts.len() - 1 || *expected_type != ValidationFieldType::String {
                     if let Ok(parsed) = serde_json::from_str(s) {
                         if i < parts.len() - 1 {
                             // If not the last part, continue traversing
@@ -118,6 +649,7 @@ impl Templater {
                                 &parsed,
                                 &parts[i + 1..].join("."),
                                 expected_type,
+                                parse_json,
                             );
                         } else {
                             // If it's the last part, return the parsed value
@@ -130,274 +662,2927 @@ impl Templater {
         Some(current.clone())
     }
 
-    pub fn render(
+    // Resolves the inside of a `{{ }}` block. Supports plain paths, literals and
+    // ternaries like `variables.count > 0 ? variables.count : "none"`.
+    fn resolve_expression(
         &self,
-        template_name: &str,
         context: &Value,
-        validations: HashMap<String, ValidationFieldType>,
+        expression: &str,
+        expected_type: &ValidationFieldType,
+        parse_json: bool,
     ) -> Result<Value, TemplateError> {
-        let template = self
-            .templates
-            .get(template_name)
-            .ok_or_else(|| TemplateError {
-                message: "Template not found".to_string(),
-                variable: template_name.to_string(),
-            })?;
+        // JMESPath has its own `?`, `:`, `|` and comparisons so it has to be checked first
+        if let Some(jmespath_expression) = expression.trim_start().strip_prefix(JMESPATH_PREFIX) {
+            return Self::resolve_jmespath(context, jmespath_expression.trim());
+        }
+
+        let filters = Self::split_filters(expression);
+        if let [subject, filters @ ..] = filters.as_slice() {
+            if !filters.is_empty() {
+                // parse_json changes how the subject resolves, so it only works right after it
+                let (parse_json, filters) = match filters {
+                    [first, rest @ ..] if first.trim() == PARSE_JSON_FILTER => (true, rest),
+                    _ => (parse_json, filters),
+                };
+                // The filters decide what comes out, the subject resolves as if it had no type
+                let mut value = self.resolve_expression(
+                    context,
+                    subject,
+                    &ValidationFieldType::Unknown,
+                    parse_json,
+                )?;
+                for filter in filters {
+                    value = Self::apply_filter(value, filter, expression)?;
+                }
+                return Ok(value);
+            }
+        }
+
+        if let Some((condition, when_true, when_false)) = Self::split_ternary(expression) {
+            let branch = if self.evaluate_condition_with(context, condition, parse_json)? {
+                when_true
+            } else {
+                when_false
+            };
+            return self.resolve_expression(context, branch, expected_type, parse_json);
+        }
+
+        if Self::find_ternary_question(expression).is_some() {
+            return Err(TemplateError {
+                message: "Ternary expression is missing ':'".to_string(),
+                variable: expression.to_string(),
+            });
+        }
 
-        self.render_value(template, context, &validations, &[])
+        self.resolve_operand(context, expression, expected_type, parse_json)
     }
 
-    fn render_value(
+    // An operand is either a literal (quoted string, number, true, false, null) or a path
+    fn resolve_operand(
         &self,
-        value: &Value,
         context: &Value,
-        validations: &HashMap<String, ValidationFieldType>,
-        path: &[String],
+        operand: &str,
+        expected_type: &ValidationFieldType,
+        parse_json: bool,
     ) -> Result<Value, TemplateError> {
-        match value {
-            Value::Object(map) => {
-                let mut result = serde_json::Map::new();
-                for (k, v) in map {
-                    let mut current_path = path.to_vec();
-                    current_path.push(k.clone());
-                    if path.is_empty() {
-                        let validation_type = validations.get(k).ok_or_else(|| TemplateError {
-                            message: format!("Validation not found for key '{}'", k),
-                            variable: k.clone(),
-                        })?;
-                        let rendered = self.render_value(v, context, validations, &current_path)?;
-                        let validated =
-                            self.validate_and_convert_value(rendered, validation_type, k)?;
-                        result.insert(k.clone(), validated);
-                    } else {
-                        result.insert(
-                            k.clone(),
-                            self.render_value(v, context, validations, &current_path)?,
-                        );
+        let operand = operand.trim();
+
+        // `path ?? fallback` falls back when the path is missing or null
+        if let Some(idx) = Self::find_unquoted(operand, "??") {
+            return match self.resolve_operand(context, &operand[..idx], expected_type, parse_json) {
+                Ok(value) if !value.is_null() => Ok(value),
+                _ => self.resolve_operand(context, &operand[idx + 2..], expected_type, parse_json),
+            };
+        }
+
+        if operand.len() >= 2
+            && ((operand.starts_with('"') && operand.ends_with('"'))
+                || (operand.starts_with('\'') && operand.ends_with('\'')))
+        {
+            return Ok(Value::String(operand[1..operand.len() - 1].to_string()));
+        }
+
+        match operand {
+            "true" => return Ok(Value::Bool(true)),
+            "false" => return Ok(Value::Bool(false)),
+            "null" => return Ok(Value::Null),
+            _ => {}
+        }
+
+        if let Ok(number) = operand.parse::<serde_json::Number>() {
+            return Ok(Value::Number(number));
+        }
+
+        Self::get_value_from_path(context, operand, expected_type, parse_json)
+            .or_else(|| {
+                self.resolver
+                    .as_ref()
+                    .and_then(|resolver| resolver(operand))
+            })
+            .ok_or_else(|| TemplateError {
+                message: format!("Variable not found in context: {}", operand),
+                variable: operand.to_string(),
+            })
+    }
+
+    // Missing values resolve to null here like they do in JMESPath instead of erroring like dotted paths
+    fn resolve_jmespath(context: &Value, expression: &str) -> Result<Value, TemplateError> {
+        let compiled = jmespath::compile(expression).map_err(|e| TemplateError {
+            message: format!("Invalid JMESPath expression: {}", e),
+            variable: expression.to_string(),
+        })?;
+
+        let result = compiled.search(context).map_err(|e| TemplateError {
+            message: format!("Failed to evaluate JMESPath expression: {}", e),
+            variable: expression.to_string(),
+        })?;
+
+        serde_json::to_value(&*result).map_err(|e| TemplateError {
+            message: format!("Failed to convert JMESPath result: {}", e),
+            variable: expression.to_string(),
+        })
+    }
+
+    // Splits `condition ? when_true : when_false`, honoring quotes and nested ternaries
+    fn split_ternary(expression: &str) -> Option<(&str, &str, &str)> {
+        let question_idx = Self::find_ternary_question(expression)?;
+        let rest = &expression[question_idx + 1..];
+
+        let mut depth = 0;
+        let mut quote: Option<char> = None;
+        for (idx, c) in rest.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None => match c {
+                    '"' | '\'' => quote = Some(c),
+                    '?' if Self::is_ternary_question(rest, idx) => depth += 1,
+                    ':' if depth == 0 => {
+                        return Some((
+                            expression[..question_idx].trim(),
+                            rest[..idx].trim(),
+                            rest[idx + 1..].trim(),
+                        ));
                     }
-                }
-                Ok(Value::Object(result))
+                    ':' => depth -= 1,
+                    _ => {}
+                },
             }
-            Value::Array(arr) => {
-                let mut result = Vec::new();
-                for v in arr.iter() {
-                    result.push(self.render_value(v, context, validations, path)?);
-                }
-                Ok(Value::Array(result))
+        }
+        None
+    }
+
+    // The first `?` that starts a ternary rather than being part of `?.` or `??`
+    fn find_ternary_question(expression: &str) -> Option<usize> {
+        let mut start = 0;
+        while let Some(idx) = Self::find_unquoted(&expression[start..], "?") {
+            let idx = start + idx;
+            if Self::is_ternary_question(expression, idx) {
+                return Some(idx);
             }
-            Value::String(s) => {
-                let trimmed = s.trim();
-                if trimmed.starts_with("{{") && trimmed.ends_with("}}") {
-                    let variable = trimmed[2..trimmed.len() - 2].trim();
-                    // Only validate if this is a top-level path
-                    if path.is_empty() {
-                        let validation_key = variable.to_string();
-                        let expected_type =
-                            validations
-                                .get(&validation_key)
-                                .ok_or_else(|| TemplateError {
-                                    message: format!(
-                                        "Validation not found for key '{}'",
-                                        validation_key
-                                    ),
-                                    variable: validation_key.clone(),
-                                })?;
-                        let value = Self::get_value_from_path(context, variable, expected_type)
-                            .ok_or_else(|| TemplateError {
-                                message: format!("Variable not found in context: {}", variable),
-                                variable: variable.to_string(),
-                            })?;
-                        let value =
-                            self.validate_and_convert_value(value, expected_type, &validation_key)?;
-                        return Ok(value);
-                    } else {
-                        // For nested variables, just get the value without validation
-                        let value = Self::get_value_from_path(
-                            context,
-                            variable,
-                            &ValidationFieldType::Unknown,
-                        )
-                        .ok_or_else(|| TemplateError {
-                            message: format!("Variable not found in context: {}", variable),
-                            variable: variable.to_string(),
-                        })?;
-                        return Ok(value);
-                    }
-                }
+            start = idx + 1;
+        }
+        None
+    }
 
-                // Regular string interpolation logic
-                let mut result = s.clone();
-                let mut start = 0;
+    fn is_ternary_question(expression: &str, idx: usize) -> bool {
+        !expression[idx..].starts_with("?.")
+            && !expression[idx..].starts_with("??")
+            && !expression[..idx].ends_with('?')
+    }
 
-                while let Some(open_idx) = result[start..].find("{{") {
-                    let open_idx = start + open_idx;
-                    let close_idx = result[open_idx..].find("}}").ok_or_else(|| TemplateError {
-                        message: "Unclosed template variable".to_string(),
-                        variable: result.clone(),
-                    })?;
-                    let close_idx = open_idx + close_idx;
-                    let variable = result[open_idx + 2..close_idx].trim();
-
-                    // Only validate if this is a top-level path
-                    let value = if path.is_empty() {
-                        let validation_key = variable.to_string();
-                        let expected_type =
-                            validations
-                                .get(&validation_key)
-                                .ok_or_else(|| TemplateError {
-                                    message: format!(
-                                        "Validation not found for key '{}'",
-                                        validation_key
-                                    ),
-                                    variable: validation_key.clone(),
-                                })?;
-                        let value = Self::get_value_from_path(context, variable, expected_type)
-                            .ok_or_else(|| TemplateError {
-                                message: "Variable not found in context".to_string(),
-                                variable: variable.to_string(),
-                            })?;
-                        self.validate_and_convert_value(value, expected_type, &validation_key)?
-                    } else {
-                        // For nested variables, just get the value without validation
-                        Self::get_value_from_path(context, variable, &ValidationFieldType::Unknown)
-                            .ok_or_else(|| TemplateError {
-                                message: "Variable not found in context".to_string(),
-                                variable: variable.to_string(),
-                            })?
-                    };
+    // Edge conditions still parse JSON strings along paths like they always have
+    pub fn evaluate_condition(context: &Value, condition: &str) -> Result<bool, TemplateError> {
+        Self::new().evaluate_condition_with(context, condition, true)
+    }
 
-                    let replacement = match value {
-                        Value::String(s) => s.clone(),
-                        _ => value.to_string(),
-                    };
-                    result.replace_range(open_idx..close_idx + 2, &replacement);
-                    start = open_idx + replacement.len();
+    fn evaluate_condition_with(
+        &self,
+        context: &Value,
+        condition: &str,
+        parse_json: bool,
+    ) -> Result<bool, TemplateError> {
+        // Two character operators first so `>=` is not read as `>`
+        for operator in ["==", "!=", ">=", "<=", ">", "<"] {
+            if let Some(idx) = Self::find_unquoted(condition, operator) {
+                let left = self.resolve_operand(
+                    context,
+                    &condition[..idx],
+                    &ValidationFieldType::Unknown,
+                    parse_json,
+                )?;
+                let right = self.resolve_operand(
+                    context,
+                    &condition[idx + operator.len()..],
+                    &ValidationFieldType::Unknown,
+                    parse_json,
+                )?;
+                return Self::compare_values(&left, operator, &right, condition);
+            }
+        }
+
+        let value = self.resolve_operand(
+            context,
+            condition,
+            &ValidationFieldType::Unknown,
+            parse_json,
+        )?;
+        Ok(is_truthy(&value))
+    }
+
+    fn compare_values(
+        left: &Value,
+        operator: &str,
+        right: &Value,
+        condition: &str,
+    ) -> Result<bool, TemplateError> {
+        // Numbers compare numerically, and numeric strings are coerced when compared to a number
+        let numbers = match (left, right) {
+            (Value::Number(_), _) | (_, Value::Number(_)) => {
+                Self::as_f64(left).zip(Self::as_f64(right))
+            }
+            _ => None,
+        };
+
+        match operator {
+            "==" => Ok(numbers.map_or_else(|| left == right, |(l, r)| l == r)),
+            "!=" => Ok(numbers.map_or_else(|| left != right, |(l, r)| l != r)),
+            _ => {
+                let ordering = match (numbers, left, right) {
+                    (Some((l, r)), _, _) => l.partial_cmp(&r),
+                    (None, Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+                    _ => None,
                 }
+                .ok_or_else(|| TemplateError {
+                    message: format!("Cannot compare {} {} {}", left, operator, right),
+                    variable: condition.to_string(),
+                })?;
 
-                Ok(Value::String(result))
+                Ok(match operator {
+                    ">" => ordering == Ordering::Greater,
+                    ">=" => ordering != Ordering::Less,
+                    "<" => ordering == Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                })
             }
-            _ => Ok(value.clone()),
         }
     }
 
-    fn validate_and_convert_value(
-        &self,
-        value: Value,
-        expected_type: &ValidationFieldType,
-        variable: &str,
-    ) -> Result<Value, TemplateError> {
-        match expected_type {
-            ValidationFieldType::String => match value {
-                Value::String(_) => Ok(value),
-                _ => Ok(Value::String(value.to_string())),
-            },
-            ValidationFieldType::Number => match value {
-                Value::Number(_) => Ok(value),
-                Value::String(s) => s.parse::<f64>().map_or_else(
-                    |_| {
-                        Err(TemplateError {
-                            message: format!("Cannot convert value to number: {}", s),
-                            variable: variable.to_string(),
-                        })
-                    },
-                    |n| Ok(Value::Number(serde_json::Number::from_f64(n).unwrap())),
-                ),
-                _ => Err(TemplateError {
-                    message: format!("Expected number, got: {:?}", value),
-                    variable: variable.to_string(),
-                }),
+    fn as_f64(value: &Value) -> Option<f64> {
+        match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    // `subject | filter | filter: "argument"` split on the pipes outside of quotes
+    fn split_filters(expression: &str) -> Vec<&str> {
+        let mut parts = Vec::new();
+        let mut rest = expression;
+        while let Some(pipe) = Self::find_unquoted(rest, "|") {
+            parts.push(&rest[..pipe]);
+            rest = &rest[pipe + 1..];
+        }
+        parts.push(rest);
+        parts
+    }
+
+    // The filter's name and its argument with any quotes taken off
+    fn parse_filter(filter: &str) -> (&str, Option<&str>) {
+        let filter = filter.trim();
+        match filter.split_once(':') {
+            Some((name, argument)) => {
+                let argument = argument.trim();
+                let unquoted = argument
+                    .strip_prefix('"')
+                    .and_then(|argument| argument.strip_suffix('"'))
+                    .or_else(|| {
+                        argument
+                            .strip_prefix('\'')
+                            .and_then(|argument| argument.strip_suffix('\''))
+                    })
+                    .unwrap_or(argument);
+                (name.trim(), Some(unquoted))
+            }
+            None => (filter, None),
+        }
+    }
+
+    fn apply_filter(value: Value, filter: &str, expression: &str) -> Result<Value, TemplateError> {
+        let (name, argument) = Self::parse_filter(filter);
+        let error = |message: String| TemplateError {
+            message,
+            variable: expression.to_string(),
+        };
+        let items = |value: Value| match value {
+            Value::Array(items) => Ok(items),
+            other => Err(error(format!(
+                "Filter '{}' expects an array, got: {}",
+                name, other
+            ))),
+        };
+
+        match (name, argument) {
+            (LENGTH_FILTER, None) => match value {
+                Value::Array(items) => Ok(Value::from(items.len())),
+                Value::Object(map) => Ok(Value::from(map.len())),
+                Value::String(s) => Ok(Value::from(s.chars().count())),
+                other => Err(error(format!(
+                    "Filter 'length' expects an array, object or string, got: {}",
+                    other
+                ))),
             },
-            ValidationFieldType::Boolean => match value {
-                Value::Bool(_) => Ok(value),
-                Value::String(s) => s.parse::<bool>().map_or_else(
-                    |_| {
+            (SUM_FILTER, None) => {
+                let mut integer_sum: Option<i64> = Some(0);
+                let mut float_sum = 0.0;
+                for item in items(value)? {
+                    let number = match &item {
+                        Value::Number(number) => number,
+                        other => {
+                            return Err(error(format!(
+                                "Filter 'sum' expects numbers, got: {}",
+                                other
+                            )))
+                        }
+                    };
+                    integer_sum = integer_sum
+                        .zip(number.as_i64())
+                        .and_then(|(sum, n)| sum.checked_add(n));
+                    float_sum += number.as_f64().unwrap_or_default();
+                }
+                // Stays an integer unless a float was summed or it overflowed
+                Ok(match integer_sum {
+                    Some(sum) => Value::from(sum),
+                    None => Value::from(float_sum),
+                })
+            }
+            (JOIN_FILTER, separator) => {
+                let joined: Vec<String> = items(value)?
+                    .into_iter()
+                    .map(|item| match item {
+                        Value::String(s) => s,
+                        Value::Null => String::new(),
+                        other => other.to_string(),
+                    })
+                    .collect();
+                Ok(Value::String(joined.join(separator.unwrap_or(""))))
+            }
+            (MAP_FILTER, Some(path)) => Ok(Value::Array(
+                items(value)?
+                    .iter()
+                    .map(|item| {
+                        Self::get_value_from_path(item, path, &ValidationFieldType::Unknown, false)
+                            .unwrap_or(Value::Null)
+                    })
+                    .collect(),
+            )),
+            (MAP_FILTER, None) => Err(error(
+                "Filter 'map' needs a field, e.g. map: \"name\"".to_string(),
+            )),
+            (QUERY_FILTER, Some(key)) => {
+                let key = urlencoding::encode(key);
+                let params: Vec<String> = items(value)?
+                    .into_iter()
+                    .map(|item| {
+                        let item = match item {
+                            Value::String(s) => s,
+                            Value::Null => String::new(),
+                            other => other.to_string(),
+                        };
+                        format!("{}={}", key, urlencoding::encode(&item))
+                    })
+                    .collect();
+                Ok(Value::String(params.join("&")))
+            }
+            (QUERY_FILTER, None) => Err(error(
+                "Filter 'query' needs a key, e.g. query: \"id\"".to_string(),
+            )),
+            _ => Err(error(format!("Unknown filter '{}'", filter.trim()))),
+        }
+    }
+
+    // Finds the first `pattern` that is not inside a quoted literal
+    fn find_unquoted(expression: &str, pattern: &str) -> Option<usize> {
+        let mut quote: Option<char> = None;
+        for (idx, c) in expression.char_indices() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None if expression[idx..].starts_with(pattern) => return Some(idx),
+                None => {}
+            }
+        }
+        None
+    }
+
+    pub fn render(
+        &self,
+        template_name: &str,
+        context: &Value,
+        validations: HashMap<String, ValidationFieldType>,
+    ) -> Result<Value, TemplateError> {
+        self.render_ref(template_name, context, &validations)
+    }
+
+    // Like render, along with every top level field validate_and_convert_value changed the type
+    // of in this render, for authors to see where e.g. a number came in as a string
+    pub fn render_with_coercions(
+        &self,
+        template_name: &str,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+    ) -> Result<(Value, Vec<Coercion>), TemplateError> {
+        *self.coercions.borrow_mut() = Some(Vec::new());
+        let rendered = self.render_ref(template_name, context, validations);
+        let coercions = self.coercions.borrow_mut().take().unwrap_or_default();
+        rendered.map(|rendered| (rendered, coercions))
+    }
+
+    // Same as render for callers that keep their validations around between renders
+    pub fn render_ref(
+        &self,
+        template_name: &str,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+    ) -> Result<Value, TemplateError> {
+        let template = self
+            .templates
+            .get(template_name)
+            .map(|template| &template.compiled)
+            .ok_or_else(|| TemplateError {
+                message: "Template not found".to_string(),
+                variable: template_name.to_string(),
+            })?;
+
+        self.render_compiled(template, context, validations, true, 0)
+    }
+
+    // Renders against only the parts of `context` the template reads
+    pub fn render_lazy(
+        &self,
+        template_name: &str,
+        context: &LazyContext,
+        validations: &HashMap<String, ValidationFieldType>,
+    ) -> Result<Value, TemplateError> {
+        let context = self.build_context(template_name, context)?;
+        self.render_ref(template_name, &context, validations)
+    }
+
+    // The context render_lazy renders with, for callers that also need to look at it, e.g. to
+    // key a cache on what was read
+    pub fn build_context(
+        &self,
+        template_name: &str,
+        context: &LazyContext,
+    ) -> Result<Value, TemplateError> {
+        let variables = self.get_template_variables(template_name)?;
+        if variables
+            .iter()
+            .any(|variable| variable.trim_start().starts_with(JMESPATH_PREFIX))
+        {
+            return Ok(context.build(None));
+        }
+
+        let read: Vec<(&str, Option<&str>)> = variables
+            .iter()
+            .flat_map(|variable| Self::referenced_paths(variable))
+            .map(Self::namespace_and_name)
+            .collect();
+        Ok(context.build(Some(&read)))
+    }
+
+    // e.g. ("actions", Some("fetch")) for `actions.fetch.result`. No name when the namespace is
+    // read whole or indexed, `actions` or `actions[0]`
+    fn namespace_and_name(path: &str) -> (&str, Option<&str>) {
+        let (namespace, rest) = path.split_at(path.find(['.', '[']).unwrap_or(path.len()));
+        let name = rest
+            .strip_prefix('.')
+            .map(|rest| &rest[..rest.find(['.', '[']).unwrap_or(rest.len())])
+            .filter(|name| !name.is_empty());
+        (namespace, name)
+    }
+
+    // Like render but only substitutes references under the `allowed` namespaces, e.g. `variables`
+    // in a first pass and `secrets`/`actions` in a later one. Everything else is left as
+    // `{{expression}}` and a top level field still holding one isn't validated, the later pass
+    // does that. JMESPath expressions are always left since they can read anything
+    pub fn render_namespaces(
+        &self,
+        template_name: &str,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        allowed: &[&str],
+    ) -> Result<Value, TemplateError> {
+        let template = self
+            .templates
+            .get(template_name)
+            .map(|template| &template.compiled)
+            .ok_or_else(|| TemplateError {
+                message: "Template not found".to_string(),
+                variable: template_name.to_string(),
+            })?;
+
+        let mut validations = validations.clone();
+        let scoped = match template {
+            CompiledTemplate::Object(entries) => CompiledTemplate::Object(
+                entries
+                    .iter()
+                    .map(|(k, key_segments, v)| {
+                        let (scoped, unresolved) = Self::scope_to_namespaces(v, allowed);
+                        if unresolved {
+                            if let Some(validation_type) = validations.get_mut(k) {
+                                *validation_type = ValidationFieldType::Unknown;
+                            }
+                        }
+                        (k.clone(), Self::scope_key(key_segments, allowed), scoped)
+                    })
+                    .collect(),
+            ),
+            template => Self::scope_to_namespaces(template, allowed).0,
+        };
+
+        self.render_compiled(&scoped, context, &validations, true, 0)
+    }
+
+    // A copy of the template with references outside `allowed` turned into text. The bool is
+    // whether any were
+    fn scope_to_namespaces(
+        template: &CompiledTemplate,
+        allowed: &[&str],
+    ) -> (CompiledTemplate, bool) {
+        let in_scope = |expression: &str| Self::in_namespaces(expression, allowed);
+        let left = |expression: &str| format!("{{{{{}}}}}", expression);
+
+        match template {
+            CompiledTemplate::Literal(value) => (CompiledTemplate::Literal(value.clone()), false),
+            CompiledTemplate::TooDeep => (CompiledTemplate::TooDeep, false),
+            CompiledTemplate::Object(entries) => {
+                let mut unresolved = false;
+                let entries = entries
+                    .iter()
+                    .map(|(k, key_segments, v)| {
+                        let (scoped, left_unresolved) = Self::scope_to_namespaces(v, allowed);
+                        unresolved |= left_unresolved;
+                        (k.clone(), Self::scope_key(key_segments, allowed), scoped)
+                    })
+                    .collect();
+                (CompiledTemplate::Object(entries), unresolved)
+            }
+            CompiledTemplate::Array(items) => {
+                let mut unresolved = false;
+                let items = items
+                    .iter()
+                    .map(|item| {
+                        let (scoped, left_unresolved) = Self::scope_to_namespaces(item, allowed);
+                        unresolved |= left_unresolved;
+                        scoped
+                    })
+                    .collect();
+                (CompiledTemplate::Array(items), unresolved)
+            }
+            CompiledTemplate::Variable(expression) if in_scope(expression) => {
+                (CompiledTemplate::Variable(expression.clone()), false)
+            }
+            CompiledTemplate::Variable(expression) => (
+                CompiledTemplate::Literal(Value::String(left(expression))),
+                true,
+            ),
+            CompiledTemplate::Interpolated(segments) => {
+                let mut unresolved = false;
+                let segments = segments
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Text(text) => Segment::Text(text.clone()),
+                        Segment::Variable(expression) if in_scope(expression) => {
+                            Segment::Variable(expression.clone())
+                        }
+                        Segment::Variable(expression) => {
+                            unresolved = true;
+                            Segment::Text(left(expression))
+                        }
+                        Segment::Unclosed(text) => Segment::Unclosed(text.clone()),
+                    })
+                    .collect();
+                (CompiledTemplate::Interpolated(segments), unresolved)
+            }
+            // A block is rendered as a whole, so only if everything in it is in scope
+            CompiledTemplate::Blocks(s) => {
+                let blocks_in_scope =
+                    Self::compile_segments(s)
+                        .iter()
+                        .all(|segment| match segment {
+                            Segment::Variable(expression) => in_scope(expression),
+                            _ => true,
+                        });
+                if blocks_in_scope {
+                    (CompiledTemplate::Blocks(s.clone()), false)
+                } else {
+                    (CompiledTemplate::Literal(Value::String(s.clone())), true)
+                }
+            }
+        }
+    }
+
+    fn in_namespaces(expression: &str, allowed: &[&str]) -> bool {
+        !expression.trim_start().starts_with(JMESPATH_PREFIX)
+            && Self::referenced_paths(expression)
+                .iter()
+                .all(|path| allowed.contains(&path.split(['.', '[']).next().unwrap_or_default()))
+    }
+
+    // A key is rendered as a whole, one reading anything outside `allowed` is kept as written
+    // for the later pass
+    fn scope_key(key_segments: &Option<Vec<Segment>>, allowed: &[&str]) -> Option<Vec<Segment>> {
+        let segments = key_segments.as_ref()?;
+        segments
+            .iter()
+            .all(|segment| match segment {
+                Segment::Variable(expression) => Self::in_namespaces(expression, allowed),
+                _ => true,
+            })
+            .then(|| {
+                segments
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Text(text) => Segment::Text(text.clone()),
+                        Segment::Variable(expression) => Segment::Variable(expression.clone()),
+                        Segment::Unclosed(text) => Segment::Unclosed(text.clone()),
+                    })
+                    .collect()
+            })
+    }
+
+    // Renders and compares the output with `expected`, e.g. for golden tests of a workflow's
+    // templates. A mismatch lists every place they differ, not just the first
+    pub fn assert_renders(
+        &self,
+        template_name: &str,
+        context: &Value,
+        validations: HashMap<String, ValidationFieldType>,
+        expected: &Value,
+    ) -> Result<(), RenderDiff> {
+        let rendered = self
+            .render(template_name, context, validations)
+            .map_err(RenderDiff::Failed)?;
+
+        let mut differences = Vec::new();
+        Self::diff_values(
+            String::new(),
+            Some(expected),
+            Some(&rendered),
+            &mut differences,
+        );
+        if differences.is_empty() {
+            Ok(())
+        } else {
+            Err(RenderDiff::Mismatch(differences))
+        }
+    }
+
+    fn diff_values(
+        pointer: String,
+        expected: Option<&Value>,
+        actual: Option<&Value>,
+        differences: &mut Vec<ValueDifference>,
+    ) {
+        match (expected, actual) {
+            (Some(Value::Object(expected)), Some(Value::Object(actual))) => {
+                // Expected keys first so the differences read in the order they were written
+                let extra = actual.keys().filter(|key| !expected.contains_key(key));
+                for key in expected.keys().chain(extra) {
+                    // `~` and `/` are escaped in JSON pointers
+                    let escaped = key.replace('~', "~0").replace('/', "~1");
+                    Self::diff_values(
+                        format!("{}/{}", pointer, escaped),
+                        expected.get(key),
+                        actual.get(key),
+                        differences,
+                    );
+                }
+            }
+            (Some(Value::Array(expected)), Some(Value::Array(actual))) => {
+                for index in 0..expected.len().max(actual.len()) {
+                    Self::diff_values(
+                        format!("{}/{}", pointer, index),
+                        expected.get(index),
+                        actual.get(index),
+                        differences,
+                    );
+                }
+            }
+            (expected, actual) if expected != actual => differences.push(ValueDifference {
+                pointer,
+                expected: expected.cloned(),
+                actual: actual.cloned(),
+            }),
+            _ => {}
+        }
+    }
+
+    // Renders a string that isn't a registered template. Everything becomes text, a string that
+    // is only `{{ }}` included, and nothing is validated
+    pub fn render_str(&self, template: &str, context: &Value) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        self.render_str_into(template, context, &mut out)?;
+        Ok(out)
+    }
+
+    // Same as render_str but appends to `out`, so one buffer can be reused across renders:
+    //
+    //     let mut line = String::new();
+    //     for context in &contexts {
+    //         line.clear();
+    //         templater.render_str_into("{{user.name}},{{user.email}}\n", context, &mut line)?;
+    //         writer.write_all(line.as_bytes())?;
+    //     }
+    //
+    // clear() keeps the capacity, so after the first few renders nothing is allocated for the
+    // output. max_output_bytes counts what was already in `out`. On error `out` may hold part of
+    // the render
+    pub fn render_str_into(
+        &self,
+        template: &str,
+        context: &Value,
+        out: &mut String,
+    ) -> Result<(), TemplateError> {
+        let validations = HashMap::new();
+        if template.contains("{{#") || template.contains("{{/") {
+            let rendered = self.render_blocks(template, context, &validations, false)?;
+            return self.push_output(out, &rendered, template);
+        }
+        self.render_segments_into(
+            &Self::compile_segments(template),
+            context,
+            &validations,
+            false,
+            out,
+        )
+    }
+
+    // Renders one template against many contexts, e.g. once per item in a loop.
+    // The template and validations are looked up once and each context gets its own result
+    pub fn render_batch(
+        &self,
+        template_name: &str,
+        contexts: &[Value],
+        validations: HashMap<String, ValidationFieldType>,
+    ) -> Vec<Result<Value, TemplateError>> {
+        let template = match self.templates.get(template_name) {
+            Some(template) => &template.compiled,
+            None => {
+                return contexts
+                    .iter()
+                    .map(|_| {
                         Err(TemplateError {
-                            message: format!("Cannot convert value to boolean: {}", s),
-                            variable: variable.to_string(),
+                            message: "Template not found".to_string(),
+                            variable: template_name.to_string(),
                         })
-                    },
-                    |b| Ok(Value::Bool(b)),
-                ),
-                _ => Err(TemplateError {
-                    message: format!("Expected boolean, got: {:?}", value),
-                    variable: variable.to_string(),
-                }),
-            },
-            ValidationFieldType::Object => match value {
-                Value::Object(_) => Ok(value),
-                Value::String(s) => {
-                    // Try to parse string as JSON object
-                    match serde_json::from_str(&s) {
-                        Ok(parsed) => match parsed {
-                            Value::Object(_) => Ok(parsed),
-                            _ => Err(TemplateError {
-                                message: format!("String parsed but not an object: {}", s),
-                                variable: variable.to_string(),
-                            }),
-                        },
-                        Err(_) => Err(TemplateError {
-                            message: format!("Cannot parse string as object: {}", s),
-                            variable: variable.to_string(),
-                        }),
+                    })
+                    .collect()
+            }
+        };
+
+        contexts
+            .iter()
+            .map(|context| self.render_compiled(template, context, &validations, true, 0))
+            .collect()
+    }
+
+    fn render_compiled(
+        &self,
+        template: &CompiledTemplate,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+        depth: usize,
+    ) -> Result<Value, TemplateError> {
+        if depth > self.max_depth {
+            return Err(self.too_deep());
+        }
+        match template {
+            CompiledTemplate::Literal(value) => Ok(value.clone()),
+            CompiledTemplate::TooDeep => Err(self.too_deep()),
+            CompiledTemplate::Object(entries) => {
+                let mut result = serde_json::Map::new();
+                for (k, key_segments, v) in entries {
+                    // Validations are looked up by the key as written, the rendered name isn't
+                    // known to the schema
+                    let key = match key_segments {
+                        Some(segments) => {
+                            self.render_segments(segments, context, validations, false)?
+                        }
+                        None => k.clone(),
+                    };
+                    if result.contains_key(&key) {
+                        return Err(TemplateError {
+                            message: format!(
+                                "Key '{}' renders to '{}' which the object already has",
+                                k, key
+                            ),
+                            variable: k.clone(),
+                        });
+                    }
+                    if top_level {
+                        let validation_type = validations.get(k).ok_or_else(|| TemplateError {
+                            message: format!("Validation not found for key '{}'", k),
+                            variable: k.clone(),
+                        })?;
+                        // A field that is one `{{ }}` resolves with its type so String fields
+                        // aren't parsed as JSON on the way
+                        let rendered = match (validation_type, v) {
+                            (ValidationFieldType::String, CompiledTemplate::Variable(variable)) => {
+                                self.resolve_variable(context, variable, validation_type)?
+                            }
+                            _ => self.render_compiled(v, context, validations, false, depth + 1)?,
+                        };
+                        let validated = self.convert_value(rendered, validation_type, k)?;
+                        result.insert(key, validated);
+                    } else {
+                        result.insert(
+                            key,
+                            self.render_compiled(v, context, validations, false, depth + 1)?,
+                        );
                     }
                 }
-                _ => Err(TemplateError {
-                    message: format!("Expected object, got: {:?}", value),
-                    variable: variable.to_string(),
-                }),
-            },
-            ValidationFieldType::Array => match value {
-                Value::Array(_) => Ok(value),
-                Value::String(s) => {
-                    // Try to parse string as JSON array
-                    match serde_json::from_str(&s) {
-                        Ok(parsed) => match parsed {
-                            Value::Array(_) => Ok(parsed),
-                            _ => Err(TemplateError {
-                                message: format!("String parsed but not an array: {}", s),
-                                variable: variable.to_string(),
-                            }),
-                        },
-                        Err(_) => Err(TemplateError {
-                            message: format!("Cannot parse string as array: {}", s),
-                            variable: variable.to_string(),
-                        }),
+                if top_level {
+                    // Keys that are never rendered can't be checked above
+                    if let Some((k, _)) = validations.iter().find(|(k, validation_type)| {
+                        **validation_type == ValidationFieldType::Any
+                            && !entries.iter().any(|(entry_key, _, _)| entry_key == *k)
+                    }) {
+                        return Err(TemplateError {
+                            message: format!("Missing value for key '{}'", k),
+                            variable: k.clone(),
+                        });
                     }
                 }
-                _ => Err(TemplateError {
-                    message: format!("Expected array, got: {:?}", value),
-                    variable: variable.to_string(),
-                }),
-            },
-            ValidationFieldType::Null => Ok(Value::Null),
-            ValidationFieldType::Unknown => Ok(value),
+                Ok(Value::Object(result))
+            }
+            CompiledTemplate::Array(items) => {
+                let mut result = Vec::with_capacity(items.len());
+                for item in items {
+                    result.push(self.render_compiled(
+                        item,
+                        context,
+                        validations,
+                        top_level,
+                        depth + 1,
+                    )?);
+                }
+                Ok(Value::Array(result))
+            }
+            CompiledTemplate::Variable(variable) => {
+                self.render_variable(variable, context, validations, top_level)
+            }
+            CompiledTemplate::Interpolated(segments) => Ok(Value::String(self.render_segments(
+                segments,
+                context,
+                validations,
+                top_level,
+            )?)),
+            CompiledTemplate::Blocks(s) => Ok(Value::String(self.render_blocks(
+                s,
+                context,
+                validations,
+                top_level,
+            )?)),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
+    fn compile(value: &Value, depth: usize, max_depth: usize) -> CompiledTemplate {
+        if depth > max_depth {
+            return CompiledTemplate::TooDeep;
+        }
+        match value {
+            Value::Object(map) => CompiledTemplate::Object(
+                map.iter()
+                    .map(|(k, v)| {
+                        let key_segments = k.contains("{{").then(|| Self::compile_segments(k));
+                        (
+                            k.clone(),
+                            key_segments,
+                            Self::compile(v, depth + 1, max_depth),
+                        )
+                    })
+                    .collect(),
+            ),
+            Value::Array(arr) => CompiledTemplate::Array(
+                arr.iter()
+                    .map(|v| Self::compile(v, depth + 1, max_depth))
+                    .collect(),
+            ),
+            Value::String(s) => {
+                // Block helpers always render to a string so they skip the full variable case
+                if s.contains("{{#") || s.contains("{{/") {
+                    return CompiledTemplate::Blocks(s.clone());
+                }
+
+                let trimmed = s.trim();
+                if trimmed.starts_with("{{") && trimmed.ends_with("}}") {
+                    return CompiledTemplate::Variable(
+                        trimmed[2..trimmed.len() - 2].trim().to_string(),
+                    );
+                }
+
+                let segments = Self::compile_segments(s);
+                if segments
+                    .iter()
+                    .all(|segment| matches!(segment, Segment::Text(_)))
+                {
+                    return CompiledTemplate::Literal(value.clone());
+                }
+                CompiledTemplate::Interpolated(segments)
+            }
+            _ => CompiledTemplate::Literal(value.clone()),
+        }
+    }
+
+    // Splits a string into text and `{{ }}` variables. Values substituted in are never rescanned
+    fn compile_segments(s: &str) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut start = 0;
+
+        while let Some(open_idx) = s[start..].find("{{") {
+            let open_idx = start + open_idx;
+            if open_idx > start {
+                segments.push(Segment::Text(s[start..open_idx].to_string()));
+            }
+            match s[open_idx..].find("}}") {
+                Some(close_idx) => {
+                    let close_idx = open_idx + close_idx;
+                    segments.push(Segment::Variable(
+                        s[open_idx + 2..close_idx].trim().to_string(),
+                    ));
+                    start = close_idx + 2;
+                }
+                None => {
+                    segments.push(Segment::Unclosed(s[open_idx..].to_string()));
+                    return segments;
+                }
+            }
+        }
+
+        if start < s.len() {
+            segments.push(Segment::Text(s[start..].to_string()));
+        }
+        segments
+    }
+
+    fn render_interpolated_string(
+        &self,
+        s: &str,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+    ) -> Result<String, TemplateError> {
+        self.render_segments(&Self::compile_segments(s), context, validations, top_level)
+    }
+
+    fn render_segments(
+        &self,
+        segments: &[Segment],
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+    ) -> Result<String, TemplateError> {
+        let mut result = String::new();
+        self.render_segments_into(segments, context, validations, top_level, &mut result)?;
+        Ok(result)
+    }
+
+    fn render_segments_into(
+        &self,
+        segments: &[Segment],
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+        result: &mut String,
+    ) -> Result<(), TemplateError> {
+        let start = result.len();
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => self.push_output(result, text, text)?,
+                Segment::Variable(variable) => {
+                    let value =
+                        match self.render_variable(variable, context, validations, top_level)? {
+                            Value::String(s) => s,
+                            Value::Null => match self.null_policy {
+                                NullPolicy::Literal => "null".to_string(),
+                                NullPolicy::Empty => String::new(),
+                                NullPolicy::Error => {
+                                    return Err(TemplateError {
+                                        message: "Null value interpolated into a string"
+                                            .to_string(),
+                                        variable: variable.to_string(),
+                                    })
+                                }
+                            },
+                            value => value.to_string(),
+                        };
+                    self.push_output(result, &self.escape_mode.escape(&value), variable)?
+                }
+                Segment::Unclosed(rest) => {
+                    return Err(TemplateError {
+                        message: "Unclosed template variable".to_string(),
+                        variable: format!("{}{}", &result[start..], rest),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Checked before every append so an oversized render fails before it allocates the rest
+    fn push_output(
+        &self,
+        output: &mut String,
+        s: &str,
+        variable: &str,
+    ) -> Result<(), TemplateError> {
+        if let Some(max_output_bytes) = self.max_output_bytes {
+            if output.len() + s.len() > max_output_bytes {
+                return Err(TemplateError {
+                    message: format!("rendered output exceeded {} bytes", max_output_bytes),
+                    variable: variable.to_string(),
+                });
+            }
+        }
+        output.push_str(s);
+        Ok(())
+    }
+
+    fn render_variable(
+        &self,
+        variable: &str,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+    ) -> Result<Value, TemplateError> {
+        // Only validate if this is a top-level path
+        if top_level {
+            let expected_type = validations.get(variable).ok_or_else(|| TemplateError {
+                message: format!("Validation not found for key '{}'", variable),
+                variable: variable.to_string(),
+            })?;
+            let value = self.resolve_variable(context, variable, expected_type)?;
+            self.convert_value(value, expected_type, variable)
+        } else {
+            // For nested variables, just get the value without validation
+            self.resolve_variable(context, variable, &ValidationFieldType::Unknown)
+        }
+    }
+
+    // Plain `secrets.NAME` paths read from the wrapped secrets. Anything else, including
+    // secrets used inside ternaries or JMESPath, resolves against the context as usual
+    fn resolve_variable(
+        &self,
+        context: &Value,
+        variable: &str,
+        expected_type: &ValidationFieldType,
+    ) -> Result<Value, TemplateError> {
+        if let Some(path) = variable.strip_prefix(SECRETS_PREFIX) {
+            let is_plain_path = path
+                .chars()
+                .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '[' | ']'));
+            let name = path.split(['.', '[']).next().unwrap_or(path);
+            if let (true, Some(secret)) = (is_plain_path, self.secrets.get(name)) {
+                let mut secret_context = serde_json::Map::new();
+                secret_context.insert(
+                    name.to_string(),
+                    Value::String(secret.expose_secret().clone()),
+                );
+                let value = Self::get_value_from_path(
+                    &Value::Object(secret_context),
+                    path,
+                    expected_type,
+                    self.parse_json_strings,
+                )
+                .ok_or_else(|| TemplateError {
+                    message: format!("Variable not found in context: {}", variable),
+                    variable: variable.to_string(),
+                })?;
+
+                self.expose_secret(&value);
+                self.record_resolved(variable);
+                return Ok(value);
+            }
+        }
+
+        let value =
+            self.resolve_expression(context, variable, expected_type, self.parse_json_strings)?;
+        // Secrets aren't in the context so this one came from the resolver. Mask it the same way
+        if variable.starts_with(SECRETS_PREFIX) {
+            self.expose_secret(&value);
+        }
+        self.record_resolved(variable);
+        Ok(value)
+    }
+
+    fn record_resolved(&self, variable: &str) {
+        let mut resolved_paths = self.resolved_paths.borrow_mut();
+        for path in Self::referenced_paths(variable) {
+            if !resolved_paths.iter().any(|resolved| resolved == path) {
+                resolved_paths.push(path.to_string());
+            }
+        }
+    }
+
+    fn expose_secret(&self, value: &Value) {
+        let exposed = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        let mut exposed_secrets = self.exposed_secrets.borrow_mut();
+        if !exposed_secrets.contains(&exposed) {
+            exposed_secrets.push(exposed);
+        }
+    }
+
+    // Renders a string containing `{{#each}}` / `{{#if}}` blocks. Text outside of blocks
+    // is interpolated as usual. Blocks may be nested; inside an each block `this` and
+    // `@index` refer to the innermost block.
+    fn render_blocks(
+        &self,
+        template: &str,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+    ) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        let mut cursor = 0;
+        let mut search = 0;
+
+        while let Some((tag_start, tag_end, tag)) = Self::next_tag(template, search)? {
+            if let Some(open) = tag.strip_prefix('#') {
+                let (block, close_end) = Self::match_block(template, tag_end, open)?;
+
+                let text = self.render_interpolated_string(
+                    &template[cursor..tag_start],
+                    context,
+                    validations,
+                    top_level,
+                )?;
+                self.push_output(&mut output, &text, template)?;
+                let rendered = self.render_block(&block, context, validations, top_level)?;
+                self.push_output(&mut output, &rendered, template)?;
+
+                cursor = close_end;
+                search = close_end;
+            } else if tag.starts_with('/') || tag == "else" {
+                return Err(TemplateError {
+                    message: format!("Unexpected '{{{{{}}}}}' outside of a block", tag),
+                    variable: template.to_string(),
+                });
+            } else {
+                search = tag_end;
+            }
+        }
+
+        let text =
+            self.render_interpolated_string(&template[cursor..], context, validations, top_level)?;
+        self.push_output(&mut output, &text, template)?;
+        Ok(output)
+    }
+
+    fn render_block(
+        &self,
+        block: &Block,
+        context: &Value,
+        validations: &HashMap<String, ValidationFieldType>,
+        top_level: bool,
+    ) -> Result<String, TemplateError> {
+        match block.name {
+            "each" => {
+                let subject = self.resolve_expression(
+                    context,
+                    block.argument,
+                    &ValidationFieldType::Unknown,
+                    self.parse_json_strings,
+                )?;
+                let items = match subject {
+                    Value::Array(items) => items,
+                    other => {
+                        return Err(TemplateError {
+                            message: format!("Expected array for '{{{{#each}}}}', got: {}", other),
+                            variable: block.argument.to_string(),
+                        })
+                    }
+                };
+
+                if items.is_empty() {
+                    return self.render_blocks(block.else_body, context, validations, top_level);
+                }
+
+                let mut output = String::new();
+                for (index, item) in items.into_iter().enumerate() {
+                    let mut scope = match context {
+                        Value::Object(map) => map.clone(),
+                        _ => serde_json::Map::new(),
+                    };
+                    scope.insert("this".to_string(), item);
+                    scope.insert("@index".to_string(), Value::from(index));
+                    let rendered = self.render_blocks(
+                        block.body,
+                        &Value::Object(scope),
+                        validations,
+                        top_level,
+                    )?;
+                    self.push_output(&mut output, &rendered, block.argument)?;
+                }
+                Ok(output)
+            }
+            "if" => {
+                let branch = if self.evaluate_condition_with(
+                    context,
+                    block.argument,
+                    self.parse_json_strings,
+                )? {
+                    block.body
+                } else {
+                    block.else_body
+                };
+                self.render_blocks(branch, context, validations, top_level)
+            }
+            _ => Err(TemplateError {
+                message: format!("Unknown block helper '#{}'", block.name),
+                variable: block.name.to_string(),
+            }),
+        }
+    }
+
+    // Returns the start, end and trimmed inner text of the next `{{ }}` tag at or after `from`
+    fn next_tag(
+        template: &str,
+        from: usize,
+    ) -> Result<Option<(usize, usize, &str)>, TemplateError> {
+        let Some(open_idx) = template[from..].find("{{") else {
+            return Ok(None);
+        };
+        let open_idx = from + open_idx;
+        let close_idx = template[open_idx..]
+            .find("}}")
+            .ok_or_else(|| TemplateError {
+                message: "Unclosed template variable".to_string(),
+                variable: template.to_string(),
+            })?;
+        let close_idx = open_idx + close_idx;
+        Ok(Some((
+            open_idx,
+            close_idx + 2,
+            template[open_idx + 2..close_idx].trim(),
+        )))
+    }
+
+    // Finds the `{{/name}}` closing the block opened by `{{#open}}` whose body starts at
+    // `from`. Returns the parsed block and where its closing tag ends.
+    fn match_block<'a>(
+        template: &'a str,
+        from: usize,
+        open: &'a str,
+    ) -> Result<(Block<'a>, usize), TemplateError> {
+        let (name, argument) = open
+            .split_once(char::is_whitespace)
+            .map_or((open, ""), |(name, argument)| (name, argument.trim()));
+        let mut open_blocks: Vec<&str> = Vec::new();
+        let mut else_tag = None;
+        let mut search = from;
+
+        while let Some((tag_start, tag_end, tag)) = Self::next_tag(template, search)? {
+            if let Some(open) = tag.strip_prefix('#') {
+                open_blocks.push(open.split_whitespace().next().unwrap_or(""));
+            } else if let Some(close) = tag.strip_prefix('/') {
+                // An empty stack means this tag has to close the block we started with
+                let expected = open_blocks.pop();
+                if close != expected.unwrap_or(name) {
+                    return Err(TemplateError {
+                        message: format!(
+                            "Expected '{{{{/{}}}}}' but found '{{{{/{}}}}}'",
+                            expected.unwrap_or(name),
+                            close
+                        ),
+                        variable: template.to_string(),
+                    });
+                }
+                if expected.is_none() {
+                    let (body, else_body) = match else_tag {
+                        Some((else_start, else_end)) => {
+                            (&template[from..else_start], &template[else_end..tag_start])
+                        }
+                        None => (&template[from..tag_start], ""),
+                    };
+                    let block = Block {
+                        name,
+                        argument,
+                        body,
+                        else_body,
+                    };
+                    return Ok((block, tag_end));
+                }
+            } else if tag == "else" && open_blocks.is_empty() {
+                if else_tag.is_some() {
+                    return Err(TemplateError {
+                        message: format!(
+                            "Block '{{{{#{}}}}}' has more than one '{{{{else}}}}'",
+                            name
+                        ),
+                        variable: template.to_string(),
+                    });
+                }
+                else_tag = Some((tag_start, tag_end));
+            }
+            search = tag_end;
+        }
+
+        Err(TemplateError {
+            message: format!("Unclosed block '{{{{#{}}}}}'", name),
+            variable: template.to_string(),
+        })
+    }
+
+    fn convert_value(
+        &self,
+        value: Value,
+        expected_type: &ValidationFieldType,
+        variable: &str,
+    ) -> Result<Value, TemplateError> {
+        let from_type = Self::json_type(&value);
+        let converted = Self::validate_and_convert_value(value, expected_type, variable)?;
+        if let Some(coercions) = self.coercions.borrow_mut().as_mut() {
+            if Self::json_type(&converted) != from_type {
+                coercions.push(Coercion {
+                    variable: variable.to_string(),
+                    from_type: from_type.to_string(),
+                    to_type: expected_type.to_string(),
+                });
+            }
+        }
+        Ok(converted)
+    }
+
+    fn json_type(value: &Value) -> &'static str {
+        match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+    }
+
+    pub fn validate_and_convert_value(
+        value: Value,
+        expected_type: &ValidationFieldType,
+        variable: &str,
+    ) -> Result<Value, TemplateError> {
+        match expected_type {
+            ValidationFieldType::String => match value {
+                Value::String(_) => Ok(value),
+                _ => Ok(Value::String(value.to_string())),
+            },
+            ValidationFieldType::Number => match value {
+                Value::Number(_) => Ok(value),
+                Value::String(s) => {
+                    parse_number(&s)
+                        .map(Value::Number)
+                        .ok_or_else(|| TemplateError {
+                            message: format!("Cannot convert value to number: {}", s),
+                            variable: variable.to_string(),
+                        })
+                }
+                _ => Err(TemplateError {
+                    message: format!("Expected number, got: {:?}", value),
+                    variable: variable.to_string(),
+                }),
+            },
+            ValidationFieldType::Integer => {
+                let number = match &value {
+                    Value::Number(n) => Some(n.clone()),
+                    Value::String(s) => s.trim().parse::<serde_json::Number>().ok(),
+                    _ => None,
+                }
+                .ok_or_else(|| TemplateError {
+                    message: format!("Cannot convert value to integer: {}", value),
+                    variable: variable.to_string(),
+                })?;
+
+                if number.is_i64() || number.is_u64() {
+                    return Ok(Value::Number(number));
+                }
+
+                // Whole floats like 5.0 are normalized, anything fractional is rejected
+                match number.as_f64() {
+                    Some(f) if f.fract() == 0.0 && f >= i64::MIN as f64 && f <= i64::MAX as f64 => {
+                        Ok(Value::Number(serde_json::Number::from(f as i64)))
+                    }
+                    _ => Err(TemplateError {
+                        message: format!("Expected integer, got fractional value: {}", number),
+                        variable: variable.to_string(),
+                    }),
+                }
+            }
+            ValidationFieldType::Boolean => match value {
+                Value::Bool(_) => Ok(value),
+                Value::String(s) => s.parse::<bool>().map_or_else(
+                    |_| {
+                        Err(TemplateError {
+                            message: format!("Cannot convert value to boolean: {}", s),
+                            variable: variable.to_string(),
+                        })
+                    },
+                    |b| Ok(Value::Bool(b)),
+                ),
+                _ => Err(TemplateError {
+                    message: format!("Expected boolean, got: {:?}", value),
+                    variable: variable.to_string(),
+                }),
+            },
+            ValidationFieldType::Object => match value {
+                Value::Object(_) => Ok(value),
+                Value::String(s) => {
+                    // Try to parse string as JSON object
+                    match serde_json::from_str(&s) {
+                        Ok(parsed) => match parsed {
+                            Value::Object(_) => Ok(parsed),
+                            _ => Err(TemplateError {
+                                message: format!("String parsed but not an object: {}", s),
+                                variable: variable.to_string(),
+                            }),
+                        },
+                        Err(_) => Err(TemplateError {
+                            message: format!("Cannot parse string as object: {}", s),
+                            variable: variable.to_string(),
+                        }),
+                    }
+                }
+                _ => Err(TemplateError {
+                    message: format!("Expected object, got: {:?}", value),
+                    variable: variable.to_string(),
+                }),
+            },
+            ValidationFieldType::Array => match value {
+                Value::Array(_) => Ok(value),
+                Value::String(s) => {
+                    // Try to parse string as JSON array
+                    match serde_json::from_str(&s) {
+                        Ok(parsed) => match parsed {
+                            Value::Array(_) => Ok(parsed),
+                            _ => Err(TemplateError {
+                                message: format!("String parsed but not an array: {}", s),
+                                variable: variable.to_string(),
+                            }),
+                        },
+                        Err(_) => Err(TemplateError {
+                            message: format!("Cannot parse string as array: {}", s),
+                            variable: variable.to_string(),
+                        }),
+                    }
+                }
+                _ => Err(TemplateError {
+                    message: format!("Expected array, got: {:?}", value),
+                    variable: variable.to_string(),
+                }),
+            },
+            ValidationFieldType::OneOf(allowed) | ValidationFieldType::OneOfIgnoreCase(allowed) => {
+                let candidate = match &value {
+                    Value::String(s) => s.clone(),
+                    Value::Number(_) | Value::Bool(_) => value.to_string(),
+                    _ => {
+                        return Err(TemplateError {
+                            message: format!(
+                                "Expected one of [{}], got: {}",
+                                allowed.join(", "),
+                                value
+                            ),
+                            variable: variable.to_string(),
+                        })
+                    }
+                };
+
+                let ignore_case = matches!(expected_type, ValidationFieldType::OneOfIgnoreCase(_));
+                // Case-insensitive matches are normalized to the allowed spelling
+                allowed
+                    .iter()
+                    .find(|option| {
+                        if ignore_case {
+                            option.eq_ignore_ascii_case(&candidate)
+                        } else {
+                            **option == candidate
+                        }
+                    })
+                    .map(|option| Value::String(option.clone()))
+                    .ok_or_else(|| TemplateError {
+                        message: format!(
+                            "Value '{}' is not one of the allowed values: [{}]",
+                            candidate,
+                            allowed.join(", ")
+                        ),
+                        variable: variable.to_string(),
+                    })
+            }
+            ValidationFieldType::Null => Ok(Value::Null),
+            ValidationFieldType::Any => match value {
+                Value::Null => Err(TemplateError {
+                    message: "Expected a value, got null".to_string(),
+                    variable: variable.to_string(),
+                }),
+                _ => Ok(value),
+            },
+            ValidationFieldType::Unknown => Ok(value),
+        }
+    }
+}
+
+// Whole numbers that fit in i64 or u64 keep every digit, e.g. 64 bit ids from Twitter or Discord.
+// Fractions, exponents and whole numbers bigger than u64 go through f64 and can lose precision
+fn parse_number(s: &str) -> Option<serde_json::Number> {
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(n.into());
+    }
+    if let Ok(n) = s.parse::<u64>() {
+        return Some(n.into());
+    }
+    // from_f64 refuses NaN and infinity
+    s.parse::<f64>().ok().and_then(serde_json::Number::from_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn string_variable_replacement() {
+        let mut templater = Templater::new();
+
+        let template = json!({
+            "greeting": "Hello {{variables.name}}"
+        });
+
+        templater.add_template("test_template", template);
+
+        let context = json!({
+            "variables": {
+                "name": "World"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("greeting".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "greeting": "Hello World"
+            })
+        );
+    }
+
+    #[test]
+    fn string_variable_replacement_with_type_coercion_from_number() {
+        let mut templater = Templater::new();
+
+        let template = json!({
+            "greeting": "{{variables.name}}"
+        });
+
+        templater.add_template("test_template", template);
+
+        let context = json!({
+            "variables": {
+                "name": 42  // Providing a number instead of string
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("greeting".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "greeting": "42"
+            })
+        );
+    }
+    #[test]
+    fn number_variable_replacement_with_type_coercion_from_string() {
+        let mut templater = Templater::new();
+
+        let template = json!({
+            "greeting": "{{variables.name}}"
+        });
+
+        templater.add_template("test_template", template);
+
+        let context = json!({
+            "variables": {
+                "name": "42"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("greeting".to_string(), ValidationFieldType::Number);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "greeting": 42,
+            })
+        );
+    }
+
+    #[test]
+    fn object_variable_replacement() {
+        let mut templater = Templater::new();
+
+        let template = json!({
+            "an_object": "{{variables.the_object}}"
+        });
+
+        templater.add_template("test_template", template);
+
+        let context = json!({
+            "variables": {
+                "the_object": {
+                    "a_number": 42
+                }
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("an_object".to_string(), ValidationFieldType::Object);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "an_object": {
+                    "a_number": 42,
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn complicated_replacement() {
+        let mut templater = Templater::new();
+        templater.set_parse_json_strings(true);
+
+        let template = json!({
+            "an_object": "{{variables.the_object}}",
+            "a_number": "{{variables.a_number_var}}",
+            "a_string": "{{variables.a_string_var}}",
+            "a_boolean": "{{variables.a_boolean_var}}",
+            "an_array": "{{variables.an_array_var}}",
+            "a_null": "{{variables.a_null_var}}",
+            "a_float": "{{variables.a_float_var}}",
+            "a_number_string": "{{variables.a_number_string_var}}",
+            "a_boolean_string": "{{variables.a_boolean_string_var}}",
+            "a_array_string": "{{variables.a_array_string_var}}",
+        });
+
+        templater.add_template("test_template", template);
+
+        let context = json!({
+            "variables": {
+                "the_object": {
+                    "a_number": 42
+                },
+                "a_number_var": 43,
+                "a_string_var": "hello",
+                "a_boolean_var": true,
+                "an_array_var": [1, 2, 3],
+                "a_null_var": null,
+                "a_float_var": 1.23,
+                "a_number_string_var": "44",
+                "a_boolean_string_var": "true",
+                "a_array_string_var": "[1, 2, 3]",
+            }
+        });
+
+        let mut template_key_validations = HashMap::new();
+        template_key_validations.insert("an_object".to_string(), ValidationFieldType::Object);
+        template_key_validations.insert("a_number".to_string(), ValidationFieldType::Number);
+        template_key_validations.insert("a_string".to_string(), ValidationFieldType::String);
+        template_key_validations.insert("a_boolean".to_string(), ValidationFieldType::Boolean);
+        template_key_validations.insert("an_array".to_string(), ValidationFieldType::Array);
+        template_key_validations.insert("a_null".to_string(), ValidationFieldType::Null);
+        template_key_validations.insert("a_float".to_string(), ValidationFieldType::Number);
+        template_key_validations.insert("a_number_string".to_string(), ValidationFieldType::String);
+        template_key_validations
+            .insert("a_boolean_string".to_string(), ValidationFieldType::String);
+        template_key_validations.insert("a_array_string".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, template_key_validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "an_object": {
+                    "a_number": 42,
+                },
+                "a_number": 43,
+                "a_string": "hello",
+                "a_boolean": true,
+                "an_array": [1, 2, 3],
+                "a_null": null,
+                "a_float": 1.23,
+                "a_number_string": "44",
+                "a_boolean_string": "true",
+                "a_array_string": "[1, 2, 3]", // String fields aren't reparsed so the spaces stay
+            })
+        );
+    }
+
+    #[test]
+    fn test_deep_array_path() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "result": "{{variables.data.items[0].subitems[1].value}}",
+                "obj_results": "{{variables.data.items[0]}}",
+                "obj_as_string": "{{variables.data.items[0]}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "data": {
+                    "items": [
+                    {
+                        "subitems": [
+                            {"value": "first"},
+                            {"value": "second"},
+                            {"value": 42}
+                        ]
+                    },
+                    {
+                        "subitems": [
+                            {"value": "other"}
+                        ]
+                    }
+                ]
+            }
+        }});
+
+        let mut template_key_validations = HashMap::new();
+        template_key_validations.insert("result".to_string(), ValidationFieldType::String);
+        template_key_validations.insert("obj_results".to_string(), ValidationFieldType::Object);
+        template_key_validations.insert("obj_as_string".to_string(), ValidationFieldType::String);
+        let result = templater
+            .render("test_template", &context, template_key_validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "result": "second",
+                "obj_results": {
+                    "subitems": [
+                        {"value": "first"},
+                        {"value": "second"},
+                        {"value": 42}
+                    ]
+                },
+                "obj_as_string": "{\"subitems\":[{\"value\":\"first\"},{\"value\":\"second\"},{\"value\":42}]}"
+            })
+        );
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "message": "Hello {{variables.user.name}}, your score is {{variables.user.score}}!",
+                "description": "User {{variables.user.id}} ({{variables.user.name}}) joined on {{variables.user.date}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "user": {
+                    "name": "Alice",
+                    "score": 95,
+                    "id": "usr_123",
+                    "date": "2023-10-15"
+                }
+            }
+        });
+
+        let mut template_key_validations = HashMap::new();
+        template_key_validations.insert("message".to_string(), ValidationFieldType::String);
+        template_key_validations.insert("description".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, template_key_validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "message": "Hello Alice, your score is 95!",
+                "description": "User usr_123 (Alice) joined on 2023-10-15"
+            })
+        );
+    }
+
+    #[test]
+    fn test_object_key_interpolation() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "user": {
+                    "name": "{{variables.user.name}}",
+                    "role": "{{variables.user.role}}",
+                    "active": true,
+                    "data": "{{variables.user}}"
+                }
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "user": {
+                    "id": "usr_456",
+                    "name": "Bob",
+                    "role": "admin"
+                }
+            }
+        });
+
+        let mut template_key_validations = HashMap::new();
+        template_key_validations.insert("user".to_string(), ValidationFieldType::Object);
+
+        let result = templater
+            .render("test_template", &context, template_key_validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "user": {
+                    "name": "Bob",
+                    "role": "admin",
+                    "active": true,
+                    "data": {
+                        "id": "usr_456",
+                        "name": "Bob",
+                        "role": "admin"
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_string_json_conversion() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "object_field": "{{variables.string_object}}",
+                "array_field": "{{variables.string_array}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "string_object": "{}",
+                "string_array": "[]"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("object_field".to_string(), ValidationFieldType::Object);
+        validations.insert("array_field".to_string(), ValidationFieldType::Array);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "object_field": {},
+                "array_field": []
+            })
+        );
+    }
+
+    #[test]
+    fn test_ternary_true_and_false_branches() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "enabled": "{{ variables.enabled ? \"yes\" : \"no\" }}",
+                "disabled": "{{ variables.disabled ? \"yes\" : \"no\" }}",
+                "empty": "{{ variables.empty ? 'set' : 'unset' }}",
+                "count": "{{ variables.count > 0 ? variables.count : \"none\" }}",
+                "message": "Status: {{ variables.zero ? 'on' : 'off' }}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "enabled": true,
+                "disabled": false,
+                "empty": "",
+                "count": 3,
+                "zero": 0
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("enabled".to_string(), ValidationFieldType::String);
+        validations.insert("disabled".to_string(), ValidationFieldType::String);
+        validations.insert("empty".to_string(), ValidationFieldType::String);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+        validations.insert("message".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "enabled": "yes",
+                "disabled": "no",
+                "empty": "unset",
+                "count": 3,
+                "message": "Status: off"
+            })
+        );
+    }
+
+    #[test]
+    fn test_ternary_comparison_operators() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "eq": "{{ variables.status == 'active' ? 'match' : 'miss' }}",
+                "neq": "{{ variables.status != 'active' ? 'match' : 'miss' }}",
+                "gt": "{{ variables.count > 5 ? 'match' : 'miss' }}",
+                "lt": "{{ variables.count < 5 ? 'match' : 'miss' }}",
+                "gte": "{{ variables.count >= 10 ? 'match' : 'miss' }}",
+                "lte": "{{ variables.count <= 9 ? 'match' : 'miss' }}",
+                "numeric_string": "{{ variables.count_string == 10 ? 'match' : 'miss' }}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "status": "active",
+                "count": 10,
+                "count_string": "10"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        for key in ["eq", "neq", "gt", "lt", "gte", "lte", "numeric_string"] {
+            validations.insert(key.to_string(), ValidationFieldType::String);
+        }
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "eq": "match",
+                "neq": "miss",
+                "gt": "match",
+                "lt": "miss",
+                "gte": "match",
+                "lte": "miss",
+                "numeric_string": "match"
+            })
+        );
+    }
+
+    #[test]
+    fn test_each_block_comma_joined_list() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "tags": "Tags: {{#each variables.tags}}{{ @index > 0 ? ', ' : '' }}{{this}}{{/each}}",
+                "users": "{{#each variables.users}}{{@index}}={{this.name}};{{/each}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "tags": ["red", "green", "blue"],
+                "users": [{"name": "Alice"}, {"name": "Bob"}]
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("tags".to_string(), ValidationFieldType::String);
+        validations.insert("users".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "tags": "Tags: red, green, blue",
+                "users": "0=Alice;1=Bob;"
+            })
+        );
+    }
+
+    #[test]
+    fn test_nested_each_blocks() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "grid": "{{#each variables.rows}}[{{#each this}}{{this}}{{/each}}]{{/each}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "rows": [[1, 2], [3]]
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("grid".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(result, json!({ "grid": "[12][3]" }));
+    }
+
+    #[test]
+    fn test_each_block_requires_array() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "list": "{{#each variables.name}}{{this}}{{/each}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "name": "not a list"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("list".to_string(), ValidationFieldType::String);
+
+        let result = templater.render("test_template", &context, validations);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_if_block_true_branch() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "flag": "{{#if variables.enabled}}A{{else}}B{{/if}}",
+                "compare": "Count is {{#if variables.count >= 10}}big{{else}}small{{/if}}!",
+                "no_else": "{{#if variables.name}}Hi {{variables.name}}{{/if}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "enabled": true,
+                "count": 12,
+                "name": "Alice"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("flag".to_string(), ValidationFieldType::String);
+        validations.insert("compare".to_string(), ValidationFieldType::String);
+        validations.insert("no_else".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "flag": "A",
+                "compare": "Count is big!",
+                "no_else": "Hi Alice"
+            })
+        );
+    }
+
+    #[test]
+    fn test_if_block_else_branch() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "flag": "{{#if variables.enabled}}A{{else}}B{{/if}}",
+                "nested": "{{#if variables.items}}{{#if variables.enabled}}x{{else}}y{{/if}}{{else}}none{{/if}}",
+                "list": "{{#each variables.items}}{{#if @index}},{{/if}}{{this}}{{else}}empty{{/each}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "enabled": "",
+                "items": []
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("flag".to_string(), ValidationFieldType::String);
+        validations.insert("nested".to_string(), ValidationFieldType::String);
+        validations.insert("list".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "flag": "B",
+                "nested": "none",
+                "list": "empty"
+            })
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_if_block() {
+        let context = json!({
+            "variables": {
+                "enabled": true
+            }
+        });
+
+        for template in [
+            "{{#if variables.enabled}}A",
+            "A{{/if}}",
+            "{{#if variables.enabled}}{{#each variables.items}}A{{/if}}{{/each}}",
+        ] {
+            let mut templater = Templater::new();
+            templater.add_template("test_template", json!({ "text": template }));
+
+            let mut validations = HashMap::new();
+            validations.insert("text".to_string(), ValidationFieldType::String);
+
+            let result = templater.render("test_template", &context, validations);
+            assert!(result.is_err(), "expected error for {}", template);
+        }
+    }
+
+    #[test]
+    fn test_integer_validation() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "from_number": "{{variables.number}}",
+                "from_string": "{{variables.string}}",
+                "from_whole_float": "{{variables.whole_float}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "number": 5,
+                "string": "5",
+                "whole_float": 5.0
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("from_number".to_string(), ValidationFieldType::Integer);
+        validations.insert("from_string".to_string(), ValidationFieldType::Integer);
+        validations.insert("from_whole_float".to_string(), ValidationFieldType::Integer);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "from_number": 5,
+                "from_string": 5,
+                "from_whole_float": 5
+            })
+        );
+        assert!(result["from_whole_float"].is_i64());
+    }
+
+    #[test]
+    fn test_integer_validation_rejects_fractions() {
+        let context = json!({
+            "variables": {
+                "fraction": 5.5,
+                "fraction_string": "5.5"
+            }
+        });
+
+        for variable in ["variables.fraction", "variables.fraction_string"] {
+            let mut templater = Templater::new();
+            templater.add_template(
+                "test_template",
+                json!({ "count": format!("{{{{{}}}}}", variable) }),
+            );
+
+            let mut validations = HashMap::new();
+            validations.insert("count".to_string(), ValidationFieldType::Integer);
+
+            let result = templater.render("test_template", &context, validations);
+            assert!(result.is_err(), "expected error for {}", variable);
+        }
+    }
+
+    #[test]
+    fn test_number_coercion_keeps_large_ids_exact() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "id": "{{variables.id}}",
+                "unsigned_id": "{{variables.unsigned_id}}",
+                "fraction": "{{variables.fraction}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "id": "1234567890123456789",
+                "unsigned_id": "18446744073709551615",
+                "fraction": "1.5"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("id".to_string(), ValidationFieldType::Number);
+        validations.insert("unsigned_id".to_string(), ValidationFieldType::Number);
+        validations.insert("fraction".to_string(), ValidationFieldType::Number);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        // Through f64 the id would come back as 1234567890123456800
+        assert_eq!(result["id"].as_i64(), Some(1234567890123456789));
+        assert_eq!(result["unsigned_id"].as_u64(), Some(u64::MAX));
+        assert_eq!(result["fraction"].as_f64(), Some(1.5));
+    }
+
+    #[test]
+    fn test_json_string_kept_as_string() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "message": "{{variables.message}}",
+                "ok": "{{variables.message == '[1, 2, 3]' ? 'kept' : 'parsed'}}",
+                "first": "{{variables.message[0]}}"
+            }),
+        );
+
+        let mut validations = HashMap::new();
+        validations.insert("message".to_string(), ValidationFieldType::Unknown);
+        validations.insert("ok".to_string(), ValidationFieldType::Unknown);
+
+        // A user's message that happens to be valid JSON stays exactly what they sent
+        let context = json!({ "variables": { "message": "[1, 2, 3]" } });
+        let mut with_first = validations.clone();
+        with_first.insert("first".to_string(), ValidationFieldType::Unknown);
+        let error = templater
+            .render("test_template", &context, with_first)
+            .unwrap_err();
+        assert_eq!(error.variable, "variables.message[0]");
+
+        templater.add_template(
+            "test_template",
+            json!({
+                "message": "{{variables.message}}",
+                "ok": "{{variables.message == '[1, 2, 3]' ? 'kept' : 'parsed'}}"
+            }),
+        );
+        let result = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap();
+        assert_eq!(result, json!({ "message": "[1, 2, 3]", "ok": "kept" }));
+
+        // Existing callers can still opt back into parsing
+        templater.set_parse_json_strings(true);
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+        assert_eq!(result, json!({ "message": [1, 2, 3], "ok": "parsed" }));
+    }
+
+    #[test]
+    fn test_parse_json_filter() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "items": "{{variables.body | parse_json}}",
+                "second": "{{variables.body[1].name | parse_json}}",
+                "names": "{{#each variables.body | parse_json}}{{this.name}};{{/each}}",
+                "raw": "{{variables.body}}"
+            }),
+        );
+
+        let mut validations = HashMap::new();
+        for key in ["items", "second", "names", "raw"] {
+            validations.insert(key.to_string(), ValidationFieldType::Unknown);
+        }
+
+        let body = r#"[{"name": "first"}, {"name": "second"}]"#;
+        let context = json!({ "variables": { "body": body } });
+
+        let result = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "items": [{ "name": "first" }, { "name": "second" }],
+                "second": "second",
+                "names": "first;second;",
+                "raw": body
+            })
+        );
+
+        templater.add_template(
+            "test_template",
+            json!({ "items": "{{variables.body | shout}}" }),
+        );
+        let error = templater
+            .render("test_template", &context, validations)
+            .unwrap_err();
+        assert!(error.message.contains("Unknown filter 'shout'"));
+    }
+
+    #[test]
+    fn test_aggregation_filters() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "count": "{{actions.fetch_orders.result.orders | length}}",
+                "ids": "{{actions.fetch_orders.result.orders | map: \"id\" | join: \",\"}}",
+                "totals": "{{actions.fetch_orders.result.orders | map: \"order.total\"}}",
+                "sum": "{{actions.fetch_orders.result.orders | map: \"order.total\" | sum}}",
+                "summary": "Orders ({{actions.fetch_orders.result.orders | length}}): {{actions.fetch_orders.result.orders | map: \"id\" | join: \", \"}}"
+            }),
+        );
+        assert!(templater.validate_template("test_template").is_ok());
+
+        let mut validations = HashMap::new();
+        for key in ["count", "ids", "totals", "sum", "summary"] {
+            validations.insert(key.to_string(), ValidationFieldType::Unknown);
+        }
+
+        let context = json!({
+            "actions": {
+                "fetch_orders": {
+                    "result": {
+                        "orders": [
+                            { "id": "a", "order": { "total": 5 } },
+                            { "id": "b", "order": { "total": 7 } },
+                            { "id": "c", "order": { "total": 30 } }
+                        ]
+                    }
+                }
+            }
+        });
+
+        let result = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "count": 3,
+                "ids": "a,b,c",
+                "totals": [5, 7, 30],
+                "sum": 42,
+                "summary": "Orders (3): a, b, c"
+            })
+        );
+
+        templater.add_template(
+            "test_template",
+            json!({ "sum": "{{actions.fetch_orders.result.orders | sum}}" }),
+        );
+        let error = templater
+            .render("test_template", &context, validations)
+            .unwrap_err();
+        assert!(error.message.contains("Filter 'sum' expects numbers"));
+    }
+
+    #[test]
+    fn test_query_filter_repeats_the_key() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "url": "https://api.example.com/orders?{{variables.ids | query: \"id\"}}&limit=10",
+                "tags": "{{variables.tags | query: \"tag[]\"}}"
+            }),
+        );
+        assert!(templater.validate_template("test_template").is_ok());
+
+        let mut validations = HashMap::new();
+        validations.insert("url".to_string(), ValidationFieldType::String);
+        validations.insert("tags".to_string(), ValidationFieldType::String);
+        let context = json!({
+            "variables": { "ids": [1, 2, 3], "tags": ["a b", "c&d"] }
+        });
+
+        let result = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "url": "https://api.example.com/orders?id=1&id=2&id=3&limit=10",
+                "tags": "tag%5B%5D=a%20b&tag%5B%5D=c%26d"
+            })
+        );
+
+        templater.add_template(
+            "test_template",
+            json!({ "url": "{{variables.ids | query}}", "tags": "" }),
+        );
+        let error = templater
+            .render("test_template", &context, validations)
+            .unwrap_err();
+        assert!(error.message.contains("Filter 'query' needs a key"));
+    }
+
+    #[test]
+    fn test_variable_gaps_unused_declaration() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "plugin_config",
+            json!({
+                "url": "https://api.example.com/{{inputs.path}}",
+                "body": "{{#each inputs.items}}{{this.id}}{{/each}}",
+                "retry": "{{inputs.path ? 'yes' : 'no'}}"
+            }),
+        );
+
+        let gaps = templater
+            .variable_gaps("plugin_config", "inputs", &["path", "items", "timeout"])
+            .unwrap();
+        assert_eq!(
+            gaps,
+            VariableGaps {
+                unused: vec!["timeout".to_string()],
+                undeclared: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_variable_gaps_undeclared_reference() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "plugin_config",
+            json!({
+                "headers": { "Authorization": "Bearer {{inputs.token}}" },
+                "url": "{{inputs.base_url}}/{{inputs.items[0].id}}",
+                "note": "{{actions.fetch.result.token}} {{ jmes: inputs.ignored }}"
+            }),
+        );
+
+        let gaps = templater
+            .variable_gaps("plugin_config", "inputs", &["base_url"])
+            .unwrap();
+        assert_eq!(
+            gaps,
+            VariableGaps {
+                unused: vec![],
+                undeclared: vec!["token".to_string(), "items".to_string()],
+            }
+        );
+
+        assert_eq!(
+            templater
+                .variable_gaps("missing", "inputs", &[])
+                .unwrap_err()
+                .message,
+            "Template not found"
+        );
+    }
+
+    #[test]
+    fn test_validate_clean_template() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "url": "https://{{variables.host}}/{{ variables.path }}",
+                "items": "{{variables.body | parse_json}}",
+                "status": "{{ jmes: actions.*.result | [0] }}",
+                "{{variables.key}}": ["plain text", 1, "{{#each variables.list}}{{this}}{{/each}}"]
+            }),
+        );
+        assert!(templater.validate_template("test_template").is_ok());
+
+        let errors = templater.validate_template("missing").unwrap_err();
+        assert_eq!(errors[0].message, "Template not found");
+    }
+
+    #[test]
+    fn test_validate_malformed_template_reports_every_problem() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "a": "{{variables.first}} and {{variables.second",
+                "b": "before {{}} after {{  }}",
+                "c": ["{{variables.body | shout}}", "{{variables.body | 'a|b'}}"],
+                "{{oops": "fine"
+            }),
+        );
+
+        let errors = templater.validate_template("test_template").unwrap_err();
+        let mut messages: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|e| (e.message.as_str(), e.variable.as_str()))
+            .collect();
+        messages.sort();
+        assert_eq!(
+            messages,
+            vec![
+                ("Empty template variable", "before {{}} after {{  }}"),
+                ("Empty template variable", "before {{}} after {{  }}"),
+                ("Unclosed template variable", "{{oops"),
+                (
+                    "Unclosed template variable",
+                    "{{variables.first}} and {{variables.second"
+                ),
+                ("Unknown filter ''a|b''", "variables.body | 'a|b'"),
+                ("Unknown filter 'shout'", "variables.body | shout"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_optional_chaining_missing_middle_segment() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "chained": "{{variables.a?.b?.c}}",
+                "defaulted": "{{variables.a?.b?.c ?? 'fallback'}}",
+                "present": "{{variables.a?.name ?? 'fallback'}}",
+                "null_middle": "{{variables.empty?.c ?? variables.a.name}}",
+                "index": "{{variables.items[3]?.id ?? 0}}",
+                "ternary": "{{variables.a.b?.c ? 'yes' : 'no'}}"
+            }),
+        );
+
+        let mut validations = HashMap::new();
+        for key in [
+            "chained",
+            "defaulted",
+            "present",
+            "null_middle",
+            "index",
+            "ternary",
+        ] {
+            validations.insert(key.to_string(), ValidationFieldType::Unknown);
+        }
+
+        let context = json!({
+            "variables": { "a": { "name": "anything" }, "empty": null, "items": [] }
+        });
+        let result = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "chained": null,
+                "defaulted": "fallback",
+                "present": "anything",
+                "null_middle": "anything",
+                "index": 0,
+                "ternary": "no"
+            })
+        );
+
+        // Without the marker a missing middle segment is still an error
+        templater.add_template("test_template", json!({ "chained": "{{variables.a.b.c}}" }));
+        let error = templater
+            .render("test_template", &context, validations)
+            .unwrap_err();
+        assert!(error.message.contains("Variable not found"));
+    }
+
+    #[test]
+    fn test_array_index_standalone_variable() {
+        let mut templater = Templater::new();
+        templater.set_parse_json_strings(true);
+        templater.add_template(
+            "test_template",
+            json!({
+                "first_pair": "{{variables.items[0]}}",
+                "first_user": "{{variables.users[0]}}",
+                "cell": "{{variables.grid[1][0]}}",
+                "from_json_string": "{{variables.json_items[1].name}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "items": [[1, 2], [3, 4]],
+                "users": [{ "name": "ada" }],
+                "grid": [[1, 2], [3, 4]],
+                "json_items": "[{\"name\": \"first\"}, {\"name\": \"second\"}]"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        validations.insert("first_pair".to_string(), ValidationFieldType::Array);
+        validations.insert("first_user".to_string(), ValidationFieldType::Object);
+        validations.insert("cell".to_string(), ValidationFieldType::Number);
+        validations.insert("from_json_string".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "first_pair": [1, 2],
+                "first_user": { "name": "ada" },
+                "cell": 3,
+                "from_json_string": "second"
+            })
+        );
+
+        // Out of range indexes are missing variables, not a panic
+        let mut templater = Templater::new();
+        templater.add_template("test_template", json!({ "item": "{{variables.items[5]}}" }));
+        let mut validations = HashMap::new();
+        validations.insert("item".to_string(), ValidationFieldType::Array);
+        assert!(templater
+            .render("test_template", &context, validations)
+            .is_err());
+    }
+
+    #[test]
+    fn test_string_fields_keep_numeric_looking_strings() {
+        let mut templater = Templater::new();
+        templater.set_parse_json_strings(true);
+        templater.add_template(
+            "test_template",
+            json!({
+                "zip": "{{inputs.zip}}",
+                "price": "{{inputs.price}}",
+                "id": "{{inputs.id}}",
+                "quoted": "{{inputs.quoted}}",
+                "nested_zip": "{{inputs.address.zip}}",
+                "price_value": "{{inputs.price}}"
+            }),
+        );
+
+        let context = json!({
+            "inputs": {
+                "zip": "007",
+                "price": "1.50",
+                "id": "12345678901234567890123",
+                "quoted": "\"hi\"",
+                "address": "{\"zip\": \"02134\"}"
+            }
+        });
+
+        let mut validations = HashMap::new();
+        for key in ["zip", "price", "id", "quoted", "nested_zip"] {
+            validations.insert(key.to_string(), ValidationFieldType::String);
+        }
+        validations.insert("price_value".to_string(), ValidationFieldType::Any);
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "zip": "007",
+                "price": "1.50",
+                "id": "12345678901234567890123",
+                "quoted": "\"hi\"",
+                "nested_zip": "02134",
+                "price_value": 1.5
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_output_bytes() {
+        let context = json!({
+            "variables": {
+                "items": (0..1000).map(|i| format!("item-{}", i)).collect::<Vec<_>>()
+            }
+        });
+
+        for template in [
+            "{{#each variables.items}}{{this}},{{/each}}",
+            "all: {{variables.items}}",
+        ] {
+            let mut templater = Templater::new();
+            templater.set_max_output_bytes(256);
+            templater.add_template("test_template", json!({ "list": template }));
+
+            let mut validations = HashMap::new();
+            validations.insert("list".to_string(), ValidationFieldType::String);
+
+            let error = templater
+                .render("test_template", &context, validations)
+                .unwrap_err();
+            assert_eq!(error.message, "rendered output exceeded 256 bytes");
+        }
+
+        // Output under the limit renders as usual
+        let mut templater = Templater::new();
+        templater.set_max_output_bytes(256);
+        templater.add_template(
+            "test_template",
+            json!({ "first": "first: {{variables.items[0]}}" }),
+        );
+        let mut validations = HashMap::new();
+        validations.insert("first".to_string(), ValidationFieldType::String);
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+        assert_eq!(result, json!({ "first": "first: item-0" }));
+    }
+
+    #[test]
+    fn test_any_requires_a_value() {
+        let context = json!({ "variables": { "present": [1, "two"], "empty": null } });
+        let render = |template: Value, validation_type: ValidationFieldType| {
+            let mut templater = Templater::new();
+            templater.add_template("test_template", template);
+            let mut validations = HashMap::new();
+            validations.insert("value".to_string(), validation_type);
+            validations.insert("other".to_string(), ValidationFieldType::Unknown);
+            templater.render("test_template", &context, validations)
+        };
+
+        // Present values pass through untouched either way
+        for validation_type in [ValidationFieldType::Any, ValidationFieldType::Unknown] {
+            assert_eq!(
+                render(json!({ "value": "{{variables.present}}" }), validation_type).unwrap(),
+                json!({ "value": [1, "two"] })
+            );
+        }
+
+        let null_value = json!({ "value": "{{variables.empty}}" });
+        let error = render(null_value.clone(), ValidationFieldType::Any).unwrap_err();
+        assert_eq!(error.variable, "value");
+        assert_eq!(
+            render(null_value, ValidationFieldType::Unknown).unwrap(),
+            json!({ "value": null })
+        );
+
+        let missing = json!({ "other": "literal" });
+        let error = render(missing.clone(), ValidationFieldType::Any).unwrap_err();
+        assert!(error.message.contains("Missing value for key 'value'"));
+        assert_eq!(
+            render(missing, ValidationFieldType::Unknown).unwrap(),
+            json!({ "other": "literal" })
+        );
+    }
+
+    #[test]
+    fn test_one_of_validation() {
+        let methods = vec!["GET".to_string(), "POST".to_string()];
+        let context = json!({
+            "variables": {
+                "allowed": "POST",
+                "disallowed": "DELETE",
+                "lowercase": "get"
+            }
+        });
+
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "method": "{{variables.allowed}}",
+                "ignore_case_method": "{{variables.lowercase}}"
+            }),
+        );
+
+        let mut validations = HashMap::new();
+        validations.insert(
+            "method".to_string(),
+            ValidationFieldType::OneOf(methods.clone()),
+        );
+        validations.insert(
+            "ignore_case_method".to_string(),
+            ValidationFieldType::OneOfIgnoreCase(methods.clone()),
+        );
+
+        let result = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "method": "POST",
+                "ignore_case_method": "GET"
+            })
+        );
+
+        for variable in ["variables.disallowed", "variables.lowercase"] {
+            let mut templater = Templater::new();
+            templater.add_template(
+                "test_template",
+                json!({ "method": format!("{{{{{}}}}}", variable) }),
+            );
+
+            let mut validations = HashMap::new();
+            validations.insert(
+                "method".to_string(),
+                ValidationFieldType::OneOf(methods.clone()),
+            );
+
+            let error = templater
+                .render("test_template", &context, validations)
+                .unwrap_err();
+            assert!(error.message.contains("GET, POST"));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_condition_for_edges() {
+        let context = json!({
+            "actions": {
+                "check": {
+                    "result": { "ok": true, "status": 200 }
+                }
+            }
+        });
+
+        assert!(
+            Templater::evaluate_condition(&context, "actions.check.result.ok == true").unwrap()
+        );
+        assert!(
+            Templater::evaluate_condition(&context, "actions.check.result.status >= 200").unwrap()
+        );
+        assert!(
+            !Templater::evaluate_condition(&context, "actions.check.result.status != 200").unwrap()
+        );
+        assert!(Templater::evaluate_condition(&context, "actions.check.result.ok").unwrap());
+        assert!(Templater::evaluate_condition(&context, "actions.missing.result == 1").is_err());
+    }
 
     #[test]
-    fn string_variable_replacement() {
+    fn test_jmespath_projection() {
         let mut templater = Templater::new();
-
-        let template = json!({
-            "greeting": "Hello {{variables.name}}"
-        });
-
-        templater.add_template("test_template", template);
+        templater.add_template(
+            "test_template",
+            json!({
+                "statuses": "{{ jmes: actions.*.result.status }}",
+                "summary": "Statuses: {{ jmes: actions.*.result.status }}"
+            }),
+        );
 
         let context = json!({
-            "variables": {
-                "name": "World"
+            "actions": {
+                "fetch_orders": { "result": { "status": "ok" } },
+                "fetch_users": { "result": { "status": "failed" } }
             }
         });
 
         let mut validations = HashMap::new();
-        validations.insert("greeting".to_string(), ValidationFieldType::String);
+        validations.insert("statuses".to_string(), ValidationFieldType::Array);
+        validations.insert("summary".to_string(), ValidationFieldType::String);
 
         let result = templater
             .render("test_template", &context, validations)
@@ -406,355 +3591,1012 @@ mod tests {
         assert_eq!(
             result,
             json!({
-                "greeting": "Hello World"
+                "statuses": ["ok", "failed"],
+                "summary": "Statuses: [\"ok\",\"failed\"]"
             })
         );
     }
 
     #[test]
-    fn string_variable_replacement_with_type_coercion_from_number() {
+    fn test_invalid_jmespath() {
         let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({ "statuses": "{{ jmes: actions.[ }}" }),
+        );
 
-        let template = json!({
-            "greeting": "{{variables.name}}"
+        let mut validations = HashMap::new();
+        validations.insert("statuses".to_string(), ValidationFieldType::Array);
+
+        let error = templater
+            .render("test_template", &json!({ "actions": {} }), validations)
+            .unwrap_err();
+        assert!(error.message.contains("Invalid JMESPath expression"));
+    }
+
+    #[test]
+    fn test_render_ref_matches_render() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "name": "{{variables.name}}",
+                "count": "{{variables.count}}",
+                "greeting": "Hello {{variables.name}}!"
+            }),
+        );
+
+        let mut validations = HashMap::new();
+        validations.insert("name".to_string(), ValidationFieldType::String);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+        validations.insert("greeting".to_string(), ValidationFieldType::String);
+
+        let context = json!({ "variables": { "name": "anything", "count": "3" } });
+        let borrowed = templater
+            .render_ref("test_template", &context, &validations)
+            .unwrap();
+        // The same map can be borrowed again
+        assert_eq!(
+            templater
+                .render_ref("test_template", &context, &validations)
+                .unwrap(),
+            borrowed
+        );
+        assert_eq!(
+            templater
+                .render("test_template", &context, validations)
+                .unwrap(),
+            borrowed
+        );
+        assert_eq!(
+            borrowed,
+            json!({ "name": "anything", "count": 3, "greeting": "Hello anything!" })
+        );
+    }
+
+    #[test]
+    fn test_render_batch() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "greeting": "Hello, {{variables.name}}!",
+                "count": "{{variables.count}}"
+            }),
+        );
+
+        let contexts = vec![
+            json!({ "variables": { "name": "Alice", "count": 1 } }),
+            json!({ "variables": { "name": "Bob", "count": 2 } }),
+            json!({ "variables": { "name": "Carol" } }),
+        ];
+
+        let mut validations = HashMap::new();
+        validations.insert("greeting".to_string(), ValidationFieldType::String);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+
+        let results = templater.render_batch("test_template", &contexts, validations.clone());
+        assert_eq!(results.len(), 3);
+
+        // Each successful result matches a separate render call
+        for (context, result) in contexts.iter().zip(results.iter()).take(2) {
+            let expected = templater
+                .render("test_template", context, validations.clone())
+                .unwrap();
+            assert_eq!(result.as_ref().unwrap(), &expected);
+        }
+        assert_eq!(
+            results[1].as_ref().unwrap(),
+            &json!({ "greeting": "Hello, Bob!", "count": 2 })
+        );
+
+        // One bad context doesn't fail the others
+        assert!(results[2].is_err());
+
+        let missing = templater.render_batch("missing_template", &contexts, HashMap::new());
+        assert!(missing.iter().all(|result| result.is_err()));
+    }
+
+    // cargo test --release bench_render_batch -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_render_batch() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "greeting": "Hello, {{variables.name}}!",
+                "count": "{{variables.count}}"
+            }),
+        );
+        let contexts: Vec<Value> = (0..10_000)
+            .map(|i| json!({ "variables": { "name": format!("user_{}", i), "count": i } }))
+            .collect();
+        let mut validations = HashMap::new();
+        validations.insert("greeting".to_string(), ValidationFieldType::String);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+
+        let started = std::time::Instant::now();
+        let separate: Vec<Result<Value, TemplateError>> = contexts
+            .iter()
+            .map(|context| templater.render("test_template", context, validations.clone()))
+            .collect();
+        let separate_elapsed = started.elapsed();
+
+        let started = std::time::Instant::now();
+        let batched = templater.render_batch("test_template", &contexts, validations);
+        let batched_elapsed = started.elapsed();
+
+        for (separate, batched) in separate.iter().zip(&batched) {
+            assert_eq!(separate.as_ref().unwrap(), batched.as_ref().unwrap());
+        }
+        println!(
+            "[BENCH] {} contexts: separate renders {:?}, render_batch {:?}",
+            contexts.len(),
+            separate_elapsed,
+            batched_elapsed
+        );
+    }
+
+    #[test]
+    fn test_compiled_template_output() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "literal": "no variables here",
+                "number": 42,
+                "full": "{{variables.count}}",
+                "interpolated": "{{variables.name}} has {{variables.count}} items",
+                "nested": {
+                    "list": ["{{variables.name}}", "plain", 1],
+                    "raw": "{{variables.braces}}!"
+                },
+                "tags": "{{#each variables.tags}}{{this}};{{/each}}"
+            }),
+        );
+
+        let context = json!({
+            "variables": {
+                "name": "Alice",
+                "count": 3,
+                "braces": "{{not_a_variable}}",
+                "tags": ["a", "b"]
+            }
         });
 
-        templater.add_template("test_template", template);
+        let mut validations = HashMap::new();
+        validations.insert("literal".to_string(), ValidationFieldType::String);
+        validations.insert("number".to_string(), ValidationFieldType::Number);
+        validations.insert("full".to_string(), ValidationFieldType::Number);
+        validations.insert("interpolated".to_string(), ValidationFieldType::String);
+        validations.insert("nested".to_string(), ValidationFieldType::Object);
+        validations.insert("tags".to_string(), ValidationFieldType::String);
+
+        let result = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "literal": "no variables here",
+                "number": 42,
+                "full": 3,
+                "interpolated": "Alice has 3 items",
+                "nested": {
+                    "list": ["Alice", "plain", 1],
+                    "raw": "{{not_a_variable}}!"
+                },
+                "tags": "a;b;"
+            })
+        );
+
+        // Rendering again from the same compiled template gives the same output
+        let again = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+        assert_eq!(result, again);
+    }
 
+    // cargo test --release bench_compiled_templates -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_compiled_templates() {
+        let template = json!({
+            "url": "https://api.example.com/users/{{variables.id}}/orders?limit={{variables.limit}}",
+            "headers": {
+                "Authorization": "Bearer {{variables.token}}",
+                "Content-Type": "application/json",
+                "X-Request-Id": "{{variables.request_id}}"
+            },
+            "body": {
+                "name": "{{variables.name}}",
+                "note": "Created for {{variables.name}} ({{variables.email}}) at {{variables.created_at}}",
+                "items": ["{{variables.first}}", "{{variables.second}}", "plain", 1, true],
+                "tags": "{{#each variables.tags}}{{this}};{{/each}}"
+            }
+        });
         let context = json!({
             "variables": {
-                "name": 42  // Providing a number instead of string
+                "id": 42, "limit": 10, "token": "abc", "request_id": "req_1", "name": "Ada",
+                "email": "ada@example.com", "created_at": "2024-01-01", "first": "a", "second": "b",
+                "tags": ["x", "y", "z"]
             }
         });
+        let mut validations = HashMap::new();
+        validations.insert("url".to_string(), ValidationFieldType::String);
+        validations.insert("headers".to_string(), ValidationFieldType::Object);
+        validations.insert("body".to_string(), ValidationFieldType::Object);
+        const RENDERS: u32 = 10_000;
+
+        // Compiling on every render walks the raw template and scans every string each time, the
+        // way render did before templates were compiled in add_template
+        let started = std::time::Instant::now();
+        let mut uncompiled = Value::Null;
+        for _ in 0..RENDERS {
+            let mut templater = Templater::new();
+            templater.add_template("test_template", template.clone());
+            uncompiled = templater
+                .render_ref("test_template", &context, &validations)
+                .unwrap();
+        }
+        let uncompiled_elapsed = started.elapsed();
+
+        let mut templater = Templater::new();
+        templater.add_template("test_template", template);
+        let started = std::time::Instant::now();
+        let mut compiled = Value::Null;
+        for _ in 0..RENDERS {
+            compiled = templater
+                .render_ref("test_template", &context, &validations)
+                .unwrap();
+        }
+        let compiled_elapsed = started.elapsed();
+
+        assert_eq!(compiled, uncompiled);
+        println!(
+            "[BENCH] {} renders: compiled per render {:?}, compiled once {:?}",
+            RENDERS, uncompiled_elapsed, compiled_elapsed
+        );
+    }
+
+    #[test]
+    fn test_compiled_unclosed_variable() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({ "greeting": "Hello {{variables.name}} and {{variables.other" }),
+        );
 
+        let context = json!({ "variables": { "name": "Alice" } });
         let mut validations = HashMap::new();
         validations.insert("greeting".to_string(), ValidationFieldType::String);
 
+        let result = templater.render("test_template", &context, validations);
+        let err = result.unwrap_err();
+        assert_eq!(err.message, "Unclosed template variable");
+        assert_eq!(err.variable, "Hello Alice and {{variables.other");
+    }
+
+    #[test]
+    fn test_secrets_substituted_but_redacted() {
+        let mut templater = Templater::new();
+        templater.set_parse_json_strings(true);
+        templater.add_template(
+            "test_template",
+            json!({
+                "headers": {
+                    "Authorization": "Bearer {{secrets.API_KEY}}"
+                },
+                "config": "{{secrets.CONFIG.region}}"
+            }),
+        );
+
+        let mut secrets = HashMap::new();
+        secrets.insert(
+            "API_KEY".to_string(),
+            Secret::new("sk_live_123".to_string()),
+        );
+        secrets.insert(
+            "CONFIG".to_string(),
+            Secret::new(r#"{"region": "eu-west-1"}"#.to_string()),
+        );
+
+        // Debug output of the wrapped secrets never contains the plaintext
+        let debug_output = format!("{:?}", secrets);
+        assert!(!debug_output.contains("sk_live_123"));
+        assert!(debug_output.contains("[REDACTED]"));
+        assert_eq!(
+            Secret::new("sk_live_123".to_string()).to_string(),
+            "[REDACTED]"
+        );
+
+        templater.set_secrets(secrets);
+
+        let mut validations = HashMap::new();
+        validations.insert("headers".to_string(), ValidationFieldType::Object);
+        validations.insert("config".to_string(), ValidationFieldType::String);
+
+        // The context has no secrets in it at all
         let result = templater
+            .render("test_template", &json!({}), validations)
+            .unwrap();
+        assert_eq!(
+            result,
+            json!({
+                "headers": { "Authorization": "Bearer sk_live_123" },
+                "config": "eu-west-1"
+            })
+        );
+    }
+
+    #[test]
+    fn test_redact_secrets_in_log_output() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "url": "{{variables.url}}",
+                "auth": "Bearer {{secrets.API_KEY}}",
+                "quoted": "{{secrets.QUOTED}}"
+            }),
+        );
+
+        let mut secrets = HashMap::new();
+        secrets.insert(
+            "API_KEY".to_string(),
+            Secret::new("sk_live_123".to_string()),
+        );
+        secrets.insert("QUOTED".to_string(), Secret::new(r#"pa"ss"#.to_string()));
+        templater.set_secrets(secrets);
+
+        let context = json!({ "variables": { "url": "https://example.com" } });
+        let mut validations = HashMap::new();
+        validations.insert("url".to_string(), ValidationFieldType::String);
+        validations.insert("auth".to_string(), ValidationFieldType::String);
+        validations.insert("quoted".to_string(), ValidationFieldType::String);
+
+        let rendered = templater
             .render("test_template", &context, validations)
             .unwrap();
 
+        // The returned value still has the real secrets
         assert_eq!(
-            result,
+            rendered,
             json!({
-                "greeting": "42"
+                "url": "https://example.com",
+                "auth": "Bearer sk_live_123",
+                "quoted": r#"pa"ss"#
             })
         );
+
+        let log_line = format!(
+            "[BUNDLER] Rendered inputs output: {}",
+            templater.redact_secrets(&rendered)
+        );
+        assert!(!log_line.contains("sk_live_123"));
+        assert!(!log_line.contains(r#"pa\"ss"#));
+        assert!(log_line.contains("Bearer ***"));
+        assert!(log_line.contains("https://example.com"));
+
+        // Values copied into a later render can be masked with the same list
+        let plugin_config_log = Templater::redact(
+            &json!({ "headers": { "Authorization": "Bearer sk_live_123" } }).to_string(),
+            &templater.exposed_secrets(),
+        );
+        assert_eq!(
+            plugin_config_log,
+            json!({ "headers": { "Authorization": "Bearer ***" } }).to_string()
+        );
     }
+
     #[test]
-    fn number_variable_replacement_with_type_coercion_from_string() {
+    fn test_escape_modes_escape_substituted_values() {
+        let dangerous = "<img src=x onerror=\"alert('hi')\"> & $(rm -rf ~)";
+        let context = json!({ "variables": { "name": dangerous } });
+        let mut validations = HashMap::new();
+        validations.insert("text".to_string(), ValidationFieldType::String);
+        validations.insert("whole".to_string(), ValidationFieldType::String);
+
+        let render = |escape_mode| {
+            let mut templater = Templater::new();
+            templater.set_escape_mode(escape_mode);
+            templater.add_template(
+                "test_template",
+                json!({
+                    "text": "<p title=\"x\">{{variables.name}}</p>",
+                    "whole": "{{variables.name}}"
+                }),
+            );
+            templater
+                .render("test_template", &context, validations.clone())
+                .unwrap()
+        };
+
+        assert_eq!(
+            render(EscapeMode::Html),
+            json!({
+                "text": "<p title=\"x\">&lt;img src=x onerror=&quot;alert(&#x27;hi&#x27;)&quot;&gt; &amp; $(rm -rf ~)</p>",
+                "whole": dangerous
+            })
+        );
+        assert_eq!(
+            render(EscapeMode::None)["text"],
+            json!(format!("<p title=\"x\">{}</p>", dangerous))
+        );
+
+        assert_eq!(
+            EscapeMode::Shell.escape("it's; $(rm -rf ~)"),
+            "'it'\\''s; $(rm -rf ~)'"
+        );
+        assert_eq!(
+            EscapeMode::Sql.escape("x'; DROP TABLE users; --"),
+            "'x''; DROP TABLE users; --'"
+        );
+    }
+
+    #[test]
+    fn test_render_str_into_reuses_the_buffer() {
+        let templater = Templater::new();
+        let template = "{{user.name}} <{{user.email}}>{{#if user.admin}} (admin){{/if}}";
+        let contexts = [
+            json!({ "user": { "name": "Ada", "email": "ada@example.com", "admin": true } }),
+            json!({ "user": { "name": "Bob", "email": "bob@example.com", "admin": false } }),
+        ];
+
+        let mut line = String::from("From: ");
+        templater
+            .render_str_into(template, &contexts[0], &mut line)
+            .unwrap();
+        assert_eq!(line, "From: Ada <ada@example.com> (admin)");
+
+        let mut lines = Vec::new();
+        for context in &contexts {
+            line.clear();
+            templater
+                .render_str_into(template, context, &mut line)
+                .unwrap();
+            assert_eq!(line, templater.render_str(template, context).unwrap());
+            lines.push(line.clone());
+        }
+        assert_eq!(
+            lines,
+            vec!["Ada <ada@example.com> (admin)", "Bob <bob@example.com>"]
+        );
+
+        // A lone `{{ }}` is still text here
+        assert_eq!(
+            templater
+                .render_str("{{user.admin}}", &contexts[0])
+                .unwrap(),
+            "true"
+        );
+        let error = templater
+            .render_str("Hi {{user.name", &contexts[0])
+            .unwrap_err();
+        assert_eq!(error.message, "Unclosed template variable");
+    }
+
+    #[test]
+    fn test_null_policies_for_interpolated_nulls() {
+        let context = json!({ "variables": { "name": null } });
+        let mut validations = HashMap::new();
+        validations.insert("text".to_string(), ValidationFieldType::String);
+        validations.insert("whole".to_string(), ValidationFieldType::Unknown);
+
+        let render = |null_policy| {
+            let mut templater = Templater::new();
+            templater.set_null_policy(null_policy);
+            templater.add_template(
+                "test_template",
+                json!({
+                    "text": "Hi {{variables.name}}!",
+                    "whole": "{{variables.name}}"
+                }),
+            );
+            templater.render("test_template", &context, validations.clone())
+        };
+
+        assert_eq!(
+            render(NullPolicy::Literal).unwrap(),
+            json!({ "text": "Hi null!", "whole": null })
+        );
+        assert_eq!(
+            render(NullPolicy::Empty).unwrap(),
+            json!({ "text": "Hi !", "whole": null })
+        );
+        let error = render(NullPolicy::Error).unwrap_err();
+        assert_eq!(error.message, "Null value interpolated into a string");
+        assert_eq!(error.variable, "variables.name");
+
+        // Only the text is affected, a whole field still passes the null through
+        let mut templater = Templater::new();
+        templater.set_null_policy(NullPolicy::Error);
+        templater.add_template("test_template", json!({ "whole": "{{variables.name}}" }));
+        assert_eq!(
+            templater
+                .render("test_template", &context, validations.clone())
+                .unwrap(),
+            json!({ "whole": null })
+        );
+    }
+
+    #[test]
+    fn test_shell_escaped_value_is_one_word() {
         let mut templater = Templater::new();
+        templater.set_escape_mode(EscapeMode::Shell);
+        templater.add_template(
+            "test_template",
+            json!({ "command": "echo {{variables.name}}" }),
+        );
+        let mut validations = HashMap::new();
+        validations.insert("command".to_string(), ValidationFieldType::String);
 
-        let template = json!({
-            "greeting": "{{variables.name}}"
-        });
+        let context = json!({ "variables": { "name": "a'b; touch /tmp/pwned" } });
+        let rendered = templater
+            .render("test_template", &context, validations)
+            .unwrap();
+        assert_eq!(
+            rendered,
+            json!({ "command": "echo 'a'\\''b; touch /tmp/pwned'" })
+        );
+    }
 
-        templater.add_template("test_template", template);
+    #[test]
+    fn test_lazy_context_builds_only_what_is_read() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-        let context = json!({
-            "variables": {
-                "name": "42"
-            }
+        let built = AtomicUsize::new(0);
+        let system_built = AtomicUsize::new(0);
+        let actions = json!({
+            "fetch": { "result": { "id": 7 } },
+            "notify": { "result": { "sent": true } }
+        });
+        let mut context = LazyContext::new();
+        context.insert("loop", json!({ "index": 2 }));
+        context.insert_keyed(
+            "actions",
+            vec!["fetch".to_string(), "notify".to_string()],
+            |name| {
+                built.fetch_add(1, Ordering::SeqCst);
+                actions.get(name).cloned()
+            },
+        );
+        context.insert_lazy("system", || {
+            system_built.fetch_add(1, Ordering::SeqCst);
+            json!({ "date": "2024-01-01" })
         });
 
         let mut validations = HashMap::new();
-        validations.insert("greeting".to_string(), ValidationFieldType::Number);
+        validations.insert("id".to_string(), ValidationFieldType::Number);
+        validations.insert("missing".to_string(), ValidationFieldType::String);
+        validations.insert("index".to_string(), ValidationFieldType::Number);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+        let mut templater = Templater::new();
+        templater.add_template(
+            "inputs",
+            json!({
+                "id": "{{actions.fetch.result.id}}",
+                "missing": "{{actions.gone.result ?? 'none'}}",
+                "index": "{{loop.index}}"
+            }),
+        );
+        assert_eq!(
+            templater.build_context("inputs", &context).unwrap(),
+            json!({ "loop": { "index": 2 }, "actions": { "fetch": actions["fetch"].clone() } })
+        );
+        assert_eq!(
+            templater
+                .render_lazy("inputs", &context, &validations)
+                .unwrap(),
+            json!({ "id": 7, "missing": "none", "index": 2 })
+        );
+        // fetch and gone, twice. notify and system are never built
+        assert_eq!(built.load(Ordering::SeqCst), 4);
+        assert_eq!(system_built.load(Ordering::SeqCst), 0);
 
-        let result = templater
-            .render("test_template", &context, validations)
-            .unwrap();
+        templater.add_template("count", json!({ "count": "{{actions | length}}" }));
+        assert_eq!(
+            templater
+                .render_lazy("count", &context, &validations)
+                .unwrap(),
+            json!({ "count": 2 })
+        );
 
+        // JMESPath can read anything so everything is built
+        templater.add_template("jmes", json!({ "ids": "{{ jmes: actions.*.result.id }}" }));
         assert_eq!(
-            result,
+            templater.build_context("jmes", &context).unwrap(),
             json!({
-                "greeting": 42,
+                "loop": { "index": 2 },
+                "actions": actions.clone(),
+                "system": { "date": "2024-01-01" }
             })
         );
+        assert_eq!(system_built.load(Ordering::SeqCst), 1);
     }
 
     #[test]
-    fn object_variable_replacement() {
+    fn test_resolver_supplies_missing_variables() {
         let mut templater = Templater::new();
-
-        let template = json!({
-            "an_object": "{{variables.the_object}}"
-        });
-
-        templater.add_template("test_template", template);
-
-        let context = json!({
-            "variables": {
-                "the_object": {
-                    "a_number": 42
-                }
-            }
+        templater.add_template(
+            "test_template",
+            json!({
+                "name": "{{variables.name}}",
+                "token": "Bearer {{accounts.slack.access_token}}",
+                "key": "{{secrets.API_KEY}}",
+                "missing": "{{variables.missing ?? 'none'}}"
+            }),
+        );
+        templater.set_resolver(|path| match path {
+            "accounts.slack.access_token" => Some(json!("xoxb-123")),
+            "secrets.API_KEY" => Some(json!("sk-456")),
+            // Paths that are in the context never get here
+            "variables.name" => Some(json!("resolved")),
+            _ => None,
         });
 
         let mut validations = HashMap::new();
-        validations.insert("an_object".to_string(), ValidationFieldType::Object);
+        for key in ["name", "token", "key", "missing"] {
+            validations.insert(key.to_string(), ValidationFieldType::String);
+        }
 
-        let result = templater
+        let context = json!({ "variables": { "name": "anything" } });
+        let rendered = templater
             .render("test_template", &context, validations)
             .unwrap();
-
         assert_eq!(
-            result,
+            rendered,
             json!({
-                "an_object": {
-                    "a_number": 42,
-                }
+                "name": "anything",
+                "token": "Bearer xoxb-123",
+                "key": "sk-456",
+                "missing": "none"
             })
         );
+        // Secrets fetched on demand are masked like the ones set up front
+        assert_eq!(templater.exposed_secrets(), vec!["sk-456".to_string()]);
     }
 
     #[test]
-    fn complicated_replacement() {
-        let mut templater = Templater::new();
-
-        let template = json!({
-            "an_object": "{{variables.the_object}}",
-            "a_number": "{{variables.a_number_var}}",
-            "a_string": "{{variables.a_string_var}}",
-            "a_boolean": "{{variables.a_boolean_var}}",
-            "an_array": "{{variables.an_array_var}}",
-            "a_null": "{{variables.a_null_var}}",
-            "a_float": "{{variables.a_float_var}}",
-            "a_number_string": "{{variables.a_number_string_var}}",
-            "a_boolean_string": "{{variables.a_boolean_string_var}}",
-            "a_array_string": "{{variables.a_array_string_var}}",
-        });
+    fn test_template_nested_past_the_depth_limit() {
+        let mut nested = json!("{{variables.name}}");
+        for _ in 0..200 {
+            nested = json!({ "nested": nested });
+        }
+        let mut validations = HashMap::new();
+        validations.insert("nested".to_string(), ValidationFieldType::Object);
+        let context = json!({ "variables": { "name": "anything" } });
 
-        templater.add_template("test_template", template);
+        let mut templater = Templater::new();
+        templater.add_template("test_template", nested.clone());
+        let error = templater
+            .get_template_variables("test_template")
+            .unwrap_err();
+        assert_eq!(error.message, "Template nesting too deep");
+        let error = templater
+            .render("test_template", &context, validations.clone())
+            .unwrap_err();
+        assert_eq!(error.message, "Template nesting too deep");
+        assert!(templater.validate_template("test_template").is_err());
 
-        let context = json!({
-            "variables": {
-                "the_object": {
-                    "a_number": 42
-                },
-                "a_number_var": 43,
-                "a_string_var": "hello",
-                "a_boolean_var": true,
-                "an_array_var": [1, 2, 3],
-                "a_null_var": null,
-                "a_float_var": 1.23,
-                "a_number_string_var": "44",
-                "a_boolean_string_var": "true",
-                "a_array_string_var": "[1, 2, 3]",
-            }
-        });
+        // The limit can be raised for templates that really are that deep
+        let mut templater = Templater::new();
+        templater.set_max_depth(256);
+        templater.add_template("test_template", nested);
+        assert_eq!(
+            templater.get_template_variables("test_template").unwrap(),
+            vec!["variables.name".to_string()]
+        );
+        assert!(templater
+            .render("test_template", &context, validations)
+            .is_ok());
+    }
 
-        let mut template_key_validations = HashMap::new();
-        template_key_validations.insert("an_object".to_string(), ValidationFieldType::Object);
-        template_key_validations.insert("a_number".to_string(), ValidationFieldType::Number);
-        template_key_validations.insert("a_string".to_string(), ValidationFieldType::String);
-        template_key_validations.insert("a_boolean".to_string(), ValidationFieldType::Boolean);
-        template_key_validations.insert("an_array".to_string(), ValidationFieldType::Array);
-        template_key_validations.insert("a_null".to_string(), ValidationFieldType::Null);
-        template_key_validations.insert("a_float".to_string(), ValidationFieldType::Number);
-        template_key_validations.insert("a_number_string".to_string(), ValidationFieldType::String);
-        template_key_validations
-            .insert("a_boolean_string".to_string(), ValidationFieldType::String);
-        template_key_validations.insert("a_array_string".to_string(), ValidationFieldType::String);
+    #[test]
+    fn test_render_namespaces_leaves_other_namespaces() {
+        let mut templater = Templater::new();
+        templater.add_template(
+            "test_template",
+            json!({
+                "greeting": "Hi {{variables.name}}, your key is {{ secrets.API_KEY }}",
+                "count": "{{variables.count}}",
+                "token": "{{secrets.API_KEY}}",
+                "retries": "{{secrets.RETRIES}}",
+                "list": "{{#each variables.items}}{{this}} {{/each}}",
+                "mixed": "{{#if variables.name}}{{secrets.API_KEY}}{{/if}}"
+            }),
+        );
+        let mut validations = HashMap::new();
+        validations.insert("greeting".to_string(), ValidationFieldType::String);
+        validations.insert("count".to_string(), ValidationFieldType::Number);
+        validations.insert("token".to_string(), ValidationFieldType::String);
+        validations.insert("retries".to_string(), ValidationFieldType::Number);
+        validations.insert("list".to_string(), ValidationFieldType::String);
+        validations.insert("mixed".to_string(), ValidationFieldType::String);
+        let context = json!({ "variables": { "name": "Ada", "count": 3, "items": ["a", "b"] } });
 
-        let result = templater
-            .render("test_template", &context, template_key_validations)
+        let first_pass = templater
+            .render_namespaces("test_template", &context, &validations, &["variables"])
             .unwrap();
-
         assert_eq!(
-            result,
+            first_pass,
             json!({
-                "an_object": {
-                    "a_number": 42,
-                },
-                "a_number": 43,
-                "a_string": "hello",
-                "a_boolean": true,
-                "an_array": [1, 2, 3],
-                "a_null": null,
-                "a_float": 1.23,
-                "a_number_string": "44",
-                "a_boolean_string": "true",
-                "a_array_string": "[1,2,3]", //DANGER ZONE. Be careful with this. It's a string. and serde makes the spaces go away
+                "greeting": "Hi Ada, your key is {{secrets.API_KEY}}",
+                "count": 3,
+                "token": "{{secrets.API_KEY}}",
+                // Left as is, so not checked as a number yet
+                "retries": "{{secrets.RETRIES}}",
+                "list": "a b ",
+                "mixed": "{{#if variables.name}}{{secrets.API_KEY}}{{/if}}"
             })
         );
+
+        // The later pass resolves the rest
+        let mut templater = Templater::new();
+        templater.set_secrets(HashMap::from([
+            (
+                "API_KEY".to_string(),
+                Secret::new("sk_live_123".to_string()),
+            ),
+            ("RETRIES".to_string(), Secret::new("5".to_string())),
+        ]));
+        templater.add_template("second_pass", first_pass);
+        let result = templater
+            .render("second_pass", &context, validations)
+            .unwrap();
+        assert_eq!(result["greeting"], "Hi Ada, your key is sk_live_123");
+        assert_eq!(result["token"], "sk_live_123");
+        assert_eq!(result["retries"], json!(5));
+        assert_eq!(result["mixed"], "sk_live_123");
     }
 
     #[test]
-    fn test_deep_array_path() {
+    fn test_assert_renders_reports_each_difference() {
         let mut templater = Templater::new();
         templater.add_template(
             "test_template",
             json!({
-                "result": "{{variables.data.items[0].subitems[1].value}}",
-                "obj_results": "{{variables.data.items[0]}}",
-                "obj_as_string": "{{variables.data.items[0]}}"
+                "url": "https://{{variables.host}}/users",
+                "headers": { "X-User": "{{variables.user}}" },
+                "tags": "{{variables.tags}}"
             }),
         );
-
+        let mut validations = HashMap::new();
+        validations.insert("url".to_string(), ValidationFieldType::String);
+        validations.insert("headers".to_string(), ValidationFieldType::Object);
+        validations.insert("tags".to_string(), ValidationFieldType::Array);
         let context = json!({
-            "variables": {
-                "data": {
-                    "items": [
-                    {
-                        "subitems": [
-                            {"value": "first"},
-                            {"value": "second"},
-                            {"value": 42}
-                        ]
-                    },
-                    {
-                        "subitems": [
-                            {"value": "other"}
-                        ]
-                    }
-                ]
-            }
-        }});
+            "variables": { "host": "api.example.com", "user": "ada", "tags": ["a", "b"] }
+        });
 
-        let mut template_key_validations = HashMap::new();
-        template_key_validations.insert("result".to_string(), ValidationFieldType::String);
-        template_key_validations.insert("obj_results".to_string(), ValidationFieldType::Object);
-        template_key_validations.insert("obj_as_string".to_string(), ValidationFieldType::String);
-        let result = templater
-            .render("test_template", &context, template_key_validations)
-            .unwrap();
+        assert!(templater
+            .assert_renders(
+                "test_template",
+                &context,
+                validations.clone(),
+                &json!({
+                    "url": "https://api.example.com/users",
+                    "headers": { "X-User": "ada" },
+                    "tags": ["a", "b"]
+                }),
+            )
+            .is_ok());
 
+        let diff = templater
+            .assert_renders(
+                "test_template",
+                &context,
+                validations.clone(),
+                &json!({
+                    "url": "https://api.example.com/users",
+                    "headers": { "X-User": "grace" },
+                    "tags": ["a"]
+                }),
+            )
+            .unwrap_err();
+        let differences = match &diff {
+            RenderDiff::Mismatch(differences) => differences,
+            RenderDiff::Failed(error) => panic!("Render failed: {}", error),
+        };
         assert_eq!(
-            result,
-            json!({
-                "result": "second",
-                "obj_results": {
-                    "subitems": [
-                        {"value": "first"},
-                        {"value": "second"},
-                        {"value": 42}
-                    ]
+            differences,
+            &vec![
+                ValueDifference {
+                    pointer: "/headers/X-User".to_string(),
+                    expected: Some(json!("grace")),
+                    actual: Some(json!("ada")),
                 },
-                "obj_as_string": "{\"subitems\":[{\"value\":\"first\"},{\"value\":\"second\"},{\"value\":42}]}"
-            })
+                ValueDifference {
+                    pointer: "/tags/1".to_string(),
+                    expected: None,
+                    actual: Some(json!("b")),
+                },
+            ]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "Rendered output differs in 2 places:\n  /headers/X-User: expected \"grace\", got \"ada\"\n  /tags/1: expected nothing, got \"b\"\n"
         );
+
+        // A render that fails isn't a mismatch
+        assert!(matches!(
+            templater.assert_renders("test_template", &json!({}), validations, &json!({})),
+            Err(RenderDiff::Failed(_))
+        ));
     }
 
     #[test]
-    fn test_string_interpolation() {
+    fn test_object_keys_are_rendered() {
         let mut templater = Templater::new();
         templater.add_template(
             "test_template",
             json!({
-                "message": "Hello {{variables.user.name}}, your score is {{variables.user.score}}!",
-                "description": "User {{variables.user.id}} ({{variables.user.name}}) joined on {{variables.user.date}}"
+                "headers": {
+                    "{{variables.header_name}}": "{{variables.token}}",
+                    "X-{{variables.env}}-Id": "42",
+                    "Accept": "application/json"
+                }
             }),
         );
-
+        let mut validations = HashMap::new();
+        validations.insert("headers".to_string(), ValidationFieldType::Object);
         let context = json!({
-            "variables": {
-                "user": {
-                    "name": "Alice",
-                    "score": 95,
-                    "id": "usr_123",
-                    "date": "2023-10-15"
-                }
-            }
+            "variables": { "header_name": "Authorization", "token": "Bearer abc", "env": "Prod" }
         });
 
-        let mut template_key_validations = HashMap::new();
-        template_key_validations.insert("message".to_string(), ValidationFieldType::String);
-        template_key_validations.insert("description".to_string(), ValidationFieldType::String);
-
         let result = templater
-            .render("test_template", &context, template_key_validations)
+            .render("test_template", &context, validations)
             .unwrap();
-
         assert_eq!(
             result,
             json!({
-                "message": "Hello Alice, your score is 95!",
-                "description": "User usr_123 (Alice) joined on 2023-10-15"
+                "headers": {
+                    "Authorization": "Bearer abc",
+                    "X-Prod-Id": "42",
+                    "Accept": "application/json"
+                }
             })
         );
+        assert!(templater
+            .get_template_variables("test_template")
+            .unwrap()
+            .contains(&"variables.header_name".to_string()));
     }
 
     #[test]
-    fn test_object_key_interpolation() {
+    fn test_object_keys_rendering_to_the_same_name_error() {
         let mut templater = Templater::new();
         templater.add_template(
             "test_template",
             json!({
-                "user": {
-                    "name": "{{variables.user.name}}",
-                    "role": "{{variables.user.role}}",
-                    "active": true,
-                    "data": "{{variables.user}}"
+                "headers": {
+                    "Accept": "text/plain",
+                    "{{variables.header_name}}": "application/json"
                 }
             }),
         );
+        let mut validations = HashMap::new();
+        validations.insert("headers".to_string(), ValidationFieldType::Object);
+        let context = json!({ "variables": { "header_name": "Accept" } });
 
-        let context = json!({
-            "variables": {
-                "user": {
-                    "id": "usr_456",
-                    "name": "Bob",
-                    "role": "admin"
-                }
-            }
-        });
+        let error = templater
+            .render("test_template", &context, validations)
+            .unwrap_err();
+        assert_eq!(error.variable, "{{variables.header_name}}");
+        assert_eq!(
+            error.message,
+            "Key '{{variables.header_name}}' renders to 'Accept' which the object already has"
+        );
+    }
 
-        let mut template_key_validations = HashMap::new();
-        template_key_validations.insert("user".to_string(), ValidationFieldType::Object);
+    #[test]
+    fn test_identical_templates_share_one_compiled_entry() {
+        let cache = Arc::new(TemplateCache::new());
+        let template = json!({ "url": "{{variables.base}}/orders", "method": "GET" });
+        // Like two tasks of the same action, each bundled with its own templater
+        let mut first = Templater::with_cache(cache.clone());
+        first.add_template("task_inputs_definition", template.clone());
+        let mut templater = Templater::with_cache(cache.clone());
+        templater.add_template("task_inputs_definition", template.clone());
+        templater.add_template("other_action", json!({ "url": "{{variables.base}}" }));
 
-        let result = templater
-            .render("test_template", &context, template_key_validations)
-            .unwrap();
+        assert!(Arc::ptr_eq(
+            &first.templates["task_inputs_definition"],
+            &templater.templates["task_inputs_definition"]
+        ));
+        assert_eq!(cache.template_count(), 2);
+        let mut validations = HashMap::new();
+        validations.insert("url".to_string(), ValidationFieldType::String);
+        validations.insert("method".to_string(), ValidationFieldType::String);
+        let context = json!({ "variables": { "base": "https://example.com" } });
+        assert_eq!(
+            templater
+                .render("task_inputs_definition", &context, validations.clone())
+                .unwrap()["url"],
+            "https://example.com/orders"
+        );
 
+        // Dropped once no templater holds it
+        drop(first);
+        assert_eq!(cache.template_count(), 2);
+        templater.add_template("task_inputs_definition", json!({}));
+        assert_eq!(cache.template_count(), 2);
         assert_eq!(
-            result,
-            json!({
-                "user": {
-                    "name": "Bob",
-                    "role": "admin",
-                    "active": true,
-                    "data": {
-                        "id": "usr_456",
-                        "name": "Bob",
-                        "role": "admin"
-                    }
-                }
-            })
+            templater
+                .render("other_action", &context, validations)
+                .unwrap()["url"],
+            "https://example.com"
         );
     }
 
     #[test]
-    fn test_string_json_conversion() {
+    fn test_coercions_are_reported() {
         let mut templater = Templater::new();
         templater.add_template(
             "test_template",
             json!({
-                "object_field": "{{variables.string_object}}",
-                "array_field": "{{variables.string_array}}"
+                "count": "{{variables.count}}",
+                "order_id": "{{variables.order_id}}",
+                "enabled": "{{variables.enabled}}",
+                "name": "{{variables.name}}",
+                "options": "{{variables.options}}"
             }),
         );
-
+        let mut validations = HashMap::new();
+        validations.insert("count".to_string(), ValidationFieldType::Integer);
+        validations.insert("order_id".to_string(), ValidationFieldType::String);
+        validations.insert("enabled".to_string(), ValidationFieldType::Boolean);
+        validations.insert("name".to_string(), ValidationFieldType::String);
+        validations.insert("options".to_string(), ValidationFieldType::Object);
         let context = json!({
             "variables": {
-                "string_object": "{}",
-                "string_array": "[]"
+                "count": "42",
+                "order_id": 1001,
+                "enabled": true,
+                "name": "Ada",
+                "options": "{\"retries\": 3}"
             }
         });
 
-        let mut validations = HashMap::new();
-        validations.insert("object_field".to_string(), ValidationFieldType::Object);
-        validations.insert("array_field".to_string(), ValidationFieldType::Array);
-
-        let result = templater
-            .render("test_template", &context, validations)
+        let (rendered, mut coercions) = templater
+            .render_with_coercions("test_template", &context, &validations)
             .unwrap();
+        assert_eq!(rendered["count"], json!(42));
+        assert_eq!(rendered["order_id"], "1001");
 
+        coercions.sort_by(|a, b| a.variable.cmp(&b.variable));
+        let coercion = |variable: &str, from_type: &str, to_type: &str| Coercion {
+            variable: variable.to_string(),
+            from_type: from_type.to_string(),
+            to_type: to_type.to_string(),
+        };
+        // Values that already had their type aren't reported
         assert_eq!(
-            result,
-            json!({
-                "object_field": {},
-                "array_field": []
-            })
+            coercions,
+            vec![
+                coercion("count", "string", "integer"),
+                coercion("options", "string", "object"),
+                coercion("order_id", "number", "string"),
+            ]
         );
+
+        // Each render only reports its own, and plain renders don't record any
+        let context = json!({
+            "variables": {
+                "count": 7,
+                "order_id": "1002",
+                "enabled": "false",
+                "name": "Ada",
+                "options": {}
+            }
+        });
+        templater
+            .render_ref("test_template", &context, &validations)
+            .unwrap();
+        let (_, coercions) = templater
+            .render_with_coercions("test_template", &context, &validations)
+            .unwrap();
+        assert_eq!(coercions, vec![coercion("enabled", "string", "boolean")]);
     }
 }
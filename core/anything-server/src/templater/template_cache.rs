@@ -0,0 +1,114 @@
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, Weak};
+
+use super::{InternedTemplate, Templater};
+
+// Compiled templates shared by every templater made with Templater::with_cache, so the same
+// action in many workflows and runs is compiled once. Entries are weak, a template is dropped
+// with the last templater that holds it
+#[derive(Default)]
+pub struct TemplateCache {
+    interned: Mutex<Interned>,
+}
+
+#[derive(Default)]
+struct Interned {
+    templates: HashMap<u64, Weak<InternedTemplate>>, // By content_hash
+    sweep_at: usize, // Dropped templates are swept out once there are this many entries
+}
+
+impl TemplateCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The hash only finds a candidate, it's shared if its content really is equal
+    pub(super) fn intern(&self, template: Value, max_depth: usize) -> Arc<InternedTemplate> {
+        let hash = content_hash(&template, max_depth);
+        if let Some(interned) = self.get(hash, &template, max_depth) {
+            return interned;
+        }
+
+        // Compiled without the lock, another templater adding the same template at the same time
+        // just compiles it too
+        let compiled = Arc::new(InternedTemplate {
+            compiled: Templater::compile(&template, 0, max_depth),
+            source: template,
+            max_depth,
+        });
+        let mut interned = self.interned.lock().unwrap();
+        if interned.templates.len() >= interned.sweep_at {
+            interned
+                .templates
+                .retain(|_, template| template.strong_count() > 0);
+            interned.sweep_at = (interned.templates.len() * 2).max(64);
+        }
+        interned.templates.insert(hash, Arc::downgrade(&compiled));
+        compiled
+    }
+
+    fn get(&self, hash: u64, template: &Value, max_depth: usize) -> Option<Arc<InternedTemplate>> {
+        self.interned
+            .lock()
+            .unwrap()
+            .templates
+            .get(&hash)
+            .and_then(Weak::upgrade)
+            .filter(|interned| interned.max_depth == max_depth && interned.source == *template)
+    }
+
+    // Templates some templater still holds
+    pub fn template_count(&self) -> usize {
+        self.interned
+            .lock()
+            .unwrap()
+            .templates
+            .values()
+            .filter(|template| template.strong_count() > 0)
+            .count()
+    }
+}
+
+// Walks the template instead of serializing it
+fn content_hash(template: &Value, max_depth: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_value(template, &mut hasher);
+    max_depth.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn hash_value(value: &Value, hasher: &mut DefaultHasher) {
+    match value {
+        Value::Null => 0u8.hash(hasher),
+        Value::Bool(b) => {
+            1u8.hash(hasher);
+            b.hash(hasher);
+        }
+        Value::Number(n) => {
+            2u8.hash(hasher);
+            n.as_f64().map(f64::to_bits).hash(hasher);
+        }
+        Value::String(s) => {
+            3u8.hash(hasher);
+            s.hash(hasher);
+        }
+        Value::Array(items) => {
+            4u8.hash(hasher);
+            items.len().hash(hasher);
+            for item in items {
+                hash_value(item, hasher);
+            }
+        }
+        Value::Object(fields) => {
+            5u8.hash(hasher);
+            fields.len().hash(hasher);
+            for (key, value) in fields {
+                key.hash(hasher);
+                hash_value(value, hasher);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use serde_json::Value;
+
+// The one definition of truthy for everything that branches on a value: `{{#if}}` blocks,
+// ternaries and edge conditions (Decision and Filter are edges with a condition). Falsy values
+// are null, false, 0, "", [] and {}. Strings aren't parsed so "0" and "false" are truthy
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(arr) => !arr.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_truthy() {
+        let cases = [
+            (json!(null), false),
+            (json!(false), false),
+            (json!(true), true),
+            (json!(0), false),
+            (json!(0.0), false),
+            (json!(-0.0), false),
+            (json!(1), true),
+            (json!(-1), true),
+            (json!(0.5), true),
+            (json!(u64::MAX), true),
+            (json!(""), false),
+            (json!(" "), true),
+            (json!("0"), true),
+            (json!("false"), true),
+            (json!("null"), true),
+            (json!([]), false),
+            (json!([null]), true),
+            (json!([false]), true),
+            (json!({}), false),
+            (json!({ "a": null }), true),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(is_truthy(&value), expected, "is_truthy({})", value);
+        }
+    }
+}
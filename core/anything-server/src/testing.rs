@@ -7,7 +7,7 @@ use axum::{
 
 use chrono::Utc;
 use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
 use crate::{
     processor::{flow_session_cache::FlowSessionData, processor::ProcessorMessage},
@@ -158,6 +158,8 @@ pub async fn test_workflow(
         flow_session_id: Uuid::parse_str(&flow_session_id).unwrap(),
         trigger_session_id: Uuid::parse_str(&trigger_session_id).unwrap(),
         trigger_task: Some(input),
+        response: None,
+        deadline: None,
     };
 
     println!("[TEST WORKFLOW] Initializing flow session data");
@@ -165,13 +167,12 @@ pub async fn test_workflow(
     //When we set it in the cache we don't need to fetch it again
     //But since testing is special we want to create our own task
     //To send to the processor
-    let flow_session_data = FlowSessionData {
-        workflow: Some(workflow_version),
-        tasks: HashMap::new(),
-        flow_session_id: Uuid::parse_str(&flow_session_id).unwrap(),
-        workflow_id: Uuid::parse_str(&workflow_id).unwrap(),
-        workflow_version_id: Some(Uuid::parse_str(&workflow_version_id).unwrap()),
-    };
+    let flow_session_data = FlowSessionData::new(
+        Some(workflow_version),
+        Uuid::parse_str(&flow_session_id).unwrap(),
+        Uuid::parse_str(&workflow_id).unwrap(),
+        Some(Uuid::parse_str(&workflow_version_id).unwrap()),
+    );
 
     println!("[TEST WORKFLOW] Setting flow session data in cache");
     // Set the flow session data in cache
@@ -453,6 +454,7 @@ pub async fn get_test_session_results(
                     || trigger_status == Some(&Value::String("failed".to_string())))
                 && (task_status == Some(&Value::String("completed".to_string()))
                     || task_status == Some(&Value::String("canceled".to_string()))
+                    || task_status == Some(&Value::String("skipped".to_string()))
                     || task_status == Some(&Value::String("failed".to_string())))
         })
     });
@@ -372,6 +372,8 @@ async fn create_trigger_task(
         flow_session_id: Uuid::parse_str(&input.flow_session_id).unwrap(),
         trigger_session_id: Uuid::parse_str(&input.trigger_session_id).unwrap(),
         trigger_task: Some(input),
+        response: None,
+        deadline: None,
     };
 
     if let Err(e) = state.processor_sender.send(processor_message).await {
@@ -7,6 +7,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::types::json_schema::JsonSchema;
+use crate::types::task_types::TaskConfig;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PluginName(String);
@@ -70,6 +71,63 @@ pub struct Action {
     pub plugin_config_schema_locked: Option<bool>,
     pub presentation: Option<NodePresentation>,
     pub handles: Option<Vec<HandleProps>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test_config: Option<Value>, //See TaskTestConfig. Lets test runs mock this action's output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip_on_empty: Option<String>, //Path into the bundled inputs e.g. "items". If it is empty or missing the action is skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_schema: Option<JsonSchema>, //Triggers only. The incoming payload is checked against this before the workflow runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_mapping: Option<Value>, //Triggers only. Reshapes the incoming payload before it's checked and run with, see normalize_trigger_payload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_schema: Option<JsonSchema>, //The task fails if its result doesn't match this, so {{actions.<id>.result.*}} can rely on it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency_key: Option<String>, //Tasks of actions with the same key never run at once, in any flow session. See ConcurrencyKeys
+}
+
+impl Action {
+    pub fn executable(&self) -> ExecutableAction<'_> {
+        ExecutableAction {
+            action_id: &self.action_id,
+            label: &self.label,
+            r#type: &self.r#type,
+            plugin_name: &self.plugin_name,
+            plugin_version: &self.plugin_version,
+            inputs: self.inputs.as_ref(),
+            inputs_schema: self.inputs_schema.as_ref(),
+            plugin_config: &self.plugin_config,
+            plugin_config_schema: &self.plugin_config_schema,
+            test_config: self.test_config.as_ref(),
+        }
+    }
+}
+
+// What running an action depends on, borrowed from it. Leaves out what only the editor uses
+// (description, icon, the *_locked flags, presentation and handles) so tasks are built without
+// cloning any of it
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutableAction<'a> {
+    pub action_id: &'a str,
+    pub label: &'a str,
+    pub r#type: &'a ActionType,
+    pub plugin_name: &'a PluginName,
+    pub plugin_version: &'a Version,
+    pub inputs: Option<&'a Value>,
+    pub inputs_schema: Option<&'a JsonSchema>,
+    pub plugin_config: &'a Value,
+    pub plugin_config_schema: &'a JsonSchema,
+    pub test_config: Option<&'a Value>,
+}
+
+impl ExecutableAction<'_> {
+    pub fn task_config(&self) -> TaskConfig {
+        TaskConfig {
+            inputs: self.inputs.cloned(),
+            inputs_schema: self.inputs_schema.cloned(),
+            plugin_config: Some(self.plugin_config.clone()),
+            plugin_config_schema: Some(self.plugin_config_schema.clone()),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -83,6 +141,7 @@ pub enum ActionType {
     Response, // Response action for making api endpoints
     Input,    // Input action for subflows
     Output,   // Output action for subflows
+    Parallel, // Runs the actions it points at concurrently, see run_parallel_task
 }
 
 impl ActionType {
@@ -96,6 +155,7 @@ impl ActionType {
             ActionType::Decision => "decision",
             ActionType::Filter => "filter",
             ActionType::Output => "output",
+            ActionType::Parallel => "parallel",
         }
     }
 }
@@ -8,12 +8,18 @@ use std::collections::HashMap;
 pub enum ValidationFieldType {
     String,
     Number,
+    Integer,
     Object,
     Boolean,
     Array,
     Null,
+    #[serde(rename = "one_of")]
+    OneOf(Vec<String>), // Rendered value must exactly match one of the allowed values
+    #[serde(rename = "one_of_ignore_case")]
+    OneOfIgnoreCase(Vec<String>), // Same as OneOf but matches regardless of case
+    Any, // Must be present and not null, any type is accepted as is
     #[serde(other)]
-    Unknown,
+    Unknown, // No validation at all, missing or null is fine too
 }
 
 impl ValidationFieldType {
@@ -21,13 +27,56 @@ impl ValidationFieldType {
         match self {
             ValidationFieldType::String => "string".to_string(),
             ValidationFieldType::Number => "number".to_string(),
+            ValidationFieldType::Integer => "integer".to_string(),
             ValidationFieldType::Object => "object".to_string(),
             ValidationFieldType::Boolean => "boolean".to_string(),
             ValidationFieldType::Array => "array".to_string(),
             ValidationFieldType::Null => "null".to_string(),
+            ValidationFieldType::OneOf(_) => "one_of".to_string(),
+            ValidationFieldType::OneOfIgnoreCase(_) => "one_of_ignore_case".to_string(),
+            ValidationFieldType::Any => "any".to_string(),
             ValidationFieldType::Unknown => "unknown".to_string(),
         }
     }
+
+    // The validations for each of a schema's properties. Their `x-any-validation` type wins,
+    // otherwise a plain JSON schema `type` or string `enum` is used. Anything else, e.g. a
+    // nullable `["string", "null"]`, is Unknown
+    pub fn from_schema(schema: &Value) -> HashMap<String, ValidationFieldType> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return HashMap::new();
+        };
+        properties
+            .iter()
+            .map(|(name, property)| (name.clone(), Self::from_property(property)))
+            .collect()
+    }
+
+    fn from_property(property: &Value) -> ValidationFieldType {
+        if let Some(validation_type) = property
+            .get("x-any-validation")
+            .and_then(|validation| validation.get("type"))
+        {
+            return serde_json::from_value(validation_type.clone())
+                .unwrap_or(ValidationFieldType::Unknown);
+        }
+        if let Some(allowed) = property.get("enum").and_then(Value::as_array) {
+            let allowed: Option<Vec<String>> = allowed
+                .iter()
+                .map(|value| value.as_str().map(str::to_string))
+                .collect();
+            if let Some(allowed) = allowed {
+                return ValidationFieldType::OneOf(allowed);
+            }
+        }
+        match property.get("type") {
+            Some(Value::String(json_type)) => {
+                serde_json::from_value(Value::String(json_type.clone()))
+                    .unwrap_or(ValidationFieldType::Unknown)
+            }
+            _ => ValidationFieldType::Unknown,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -102,4 +151,3 @@ pub struct JsonSchema {
     #[serde(rename = "additionalProperties")]
     pub additional_properties: Option<bool>,
 }
-
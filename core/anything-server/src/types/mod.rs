@@ -2,5 +2,6 @@ pub mod action_types;
 pub mod json_schema;
 pub mod plugin_types;
 pub mod react_flow_types;
+pub mod secret_types;
 pub mod task_types;
 pub mod workflow_types;
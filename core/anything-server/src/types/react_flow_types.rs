@@ -27,4 +27,8 @@ pub struct Edge {
     pub target: String,
     pub target_handle: Option<String>,
     pub r#type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>, // e.g. "actions.check.result.ok == true". No condition means always taken
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i64>, // Lower is tried first. Ties and edges without a priority go by target action_id
 }
@@ -11,6 +11,7 @@ use node_semver::Version;
 #[serde(rename_all = "lowercase")]
 pub enum Stage {
     Production,
+    Staging,
     Testing,
 }
 
@@ -18,6 +19,7 @@ impl ToString for Stage {
     fn to_string(&self) -> String {
         match self {
             Stage::Production => "production".to_string(),
+            Stage::Staging => "staging".to_string(),
             Stage::Testing => "testing".to_string(),
         }
     }
@@ -27,6 +29,7 @@ impl Stage {
     pub fn as_str(&self) -> &str {
         match self {
             Stage::Production => "production",
+            Stage::Staging => "staging",
             Stage::Testing => "testing",
         }
     }
@@ -41,6 +44,7 @@ pub enum TaskStatus {
     Completed, // Task is completed
     Failed,  // Task failed
     Canceled, // Task was canceled usually because task ahead failed
+    Skipped, // Task did not run because its skip_on_empty input was empty
 }
 
 impl TaskStatus {
@@ -52,6 +56,7 @@ impl TaskStatus {
             TaskStatus::Completed => "completed",
             TaskStatus::Failed => "failed",
             TaskStatus::Canceled => "canceled",
+            TaskStatus::Skipped => "skipped",
         }
     }
 }
@@ -66,6 +71,7 @@ pub enum FlowSessionStatus {
     Completed, // Flow is completed
     Failed,  // Flow failed
     Canceled, // Flow was canceled usually because task ahead failed. Maybe if we delete a workflow and their is uncompleted work
+    Paused,   // Flow stopped at an approval and continues when resume_flow_session is called
 }
 
 //Used to determine if whole workflow is completed or what happened especially with nested flows where we want to trace
@@ -79,6 +85,7 @@ impl FlowSessionStatus {
             FlowSessionStatus::Completed => "completed",
             FlowSessionStatus::Failed => "failed",
             FlowSessionStatus::Canceled => "canceled",
+            FlowSessionStatus::Paused => "paused",
         }
     }
 }
@@ -129,6 +136,7 @@ pub struct Task {
     pub test_config: Option<Value>,
     pub config: TaskConfig,
     pub context: Option<Value>,
+    pub bundled_inputs: Option<Value>, // The rendered inputs the task ran with so it can be replayed
     pub started_at: Option<DateTime<Utc>>,
     pub ended_at: Option<DateTime<Utc>>,
     pub debug_result: Option<Value>,
@@ -142,6 +150,15 @@ pub struct Task {
     pub processing_order: i32,
 }
 
+impl Task {
+    // How long the task ran for. None until it has both started and ended
+    pub fn duration_ms(&self) -> Option<i64> {
+        let duration = self.ended_at? - self.started_at?;
+        // Clocks can disagree when the timestamps were written by different machines
+        Some(duration.num_milliseconds().max(0))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TaskConfig {
     pub inputs: Option<Value>,
@@ -181,3 +198,10 @@ pub struct TestConfig {
     pub variables: Value,
     pub inputs: Value,
 }
+
+//Set as `test_config` on an action. Only used when the workflow runs in the testing stage
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskTestConfig {
+    pub mock_result: Option<Value>, //Returned as the task result instead of running the plugin
+    pub mock_delay_ms: Option<u64>, //Wait before returning the mock to act like a slow system
+}
@@ -9,6 +9,7 @@ use serde_json::Value;
 use std::sync::Arc;
 
 use crate::supabase_jwt_middleware::User;
+use crate::types::task_types::Stage;
 use crate::types::workflow_types::WorkflowVersionDefinition;
 use crate::AppState;
 use uuid::Uuid;
@@ -19,6 +20,12 @@ use std::env;
 use chrono::Utc;
 
 use crate::agents::tools::update_agent_tool_if_needed_on_workflow_publish;
+use crate::processor::flow_diff::diff_flow_versions;
+use crate::processor::plugin_versions::{
+    apply_plugin_versions, installed_plugins, remap_plugin_versions,
+};
+use crate::processor::processor::{cancel_flow_session, CancelOutcome};
+use crate::processor::workflow_lint::{lint_workflow, preflight_workflow};
 use crate::system_workflows::create_workflow_from_template;
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BaseFlowVersionInput {
@@ -50,6 +57,14 @@ pub struct CreateWorkflowInput {
     account_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PreflightWorkflowInput {
+    flow_definition: Value,
+    #[serde(default)]
+    inputs: Value, // What the trigger would return, e.g. a webhook's { "headers", "body", "method" }
+    stage: Option<Stage>, // Testing if not set, like a run of an unpublished version
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct UpdateWorkflowInput {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -269,6 +284,84 @@ pub async fn get_flow_version(
     Json(item).into_response()
 }
 
+pub async fn diff_workflow_versions(
+    Path((account_id, flow_id, from_version_id, to_version_id)): Path<(
+        String,
+        String,
+        String,
+        String,
+    )>,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> impl IntoResponse {
+    println!("Handling a diff_workflow_versions");
+
+    let mut definitions = Vec::new();
+    for version_id in [&from_version_id, &to_version_id] {
+        match fetch_flow_definition(&state, &user, &account_id, &flow_id, version_id).await {
+            Ok(definition) => definitions.push(definition),
+            Err(response) => return response,
+        }
+    }
+
+    Json(diff_flow_versions(&definitions[0], &definitions[1])).into_response()
+}
+
+async fn fetch_flow_definition(
+    state: &AppState,
+    user: &User,
+    account_id: &str,
+    flow_id: &str,
+    version_id: &str,
+) -> Result<WorkflowVersionDefinition, axum::response::Response> {
+    let response = match state
+        .anything_client
+        .from("flow_versions")
+        .auth(user.jwt.clone())
+        .eq("flow_id", flow_id)
+        .eq("flow_version_id", version_id)
+        .eq("account_id", account_id)
+        .select("flow_definition")
+        .single()
+        .execute()
+        .await
+    {
+        Ok(response) => response,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to execute request",
+            )
+                .into_response())
+        }
+    };
+
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(_) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read response body",
+            )
+                .into_response())
+        }
+    };
+
+    #[derive(Deserialize)]
+    struct FlowDefinitionRow {
+        flow_definition: WorkflowVersionDefinition,
+    }
+
+    match serde_json::from_str::<FlowDefinitionRow>(&body) {
+        Ok(row) => Ok(row.flow_definition),
+        Err(_) => Err((
+            StatusCode::NOT_FOUND,
+            format!("Failed to find flow version {}", version_id),
+        )
+            .into_response()),
+    }
+}
+
 pub async fn create_workflow(
     Path(account_id): Path<String>,
     State(state): State<Arc<AppState>>,
@@ -387,15 +480,43 @@ pub async fn create_workflow_from_json(
     let flow_definition: Result<WorkflowVersionDefinition, _> =
         serde_json::from_value(payload.flow_template.clone());
 
-    if let Err(e) = flow_definition {
-        println!("[WORKFLOW FROM JSON] Flow template parsing failed: {}", e);
+    let mut flow_definition = match flow_definition {
+        Ok(flow_definition) => flow_definition,
+        Err(e) => {
+            println!("[WORKFLOW FROM JSON] Flow template parsing failed: {}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid flow template format: {}", e),
+            )
+                .into_response();
+        }
+    };
+    println!("[WORKFLOW FROM JSON] Flow template successfully parsed");
+
+    // A flow using plugin versions we don't have could never run, so don't import it
+    let installed = match installed_plugins() {
+        Ok(installed) => installed,
+        Err(e) => {
+            println!("[WORKFLOW FROM JSON] {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+    };
+    if let Err(unsatisfied) = remap_plugin_versions(&mut flow_definition, &installed) {
+        println!(
+            "[WORKFLOW FROM JSON] {} actions use plugins that aren't installed",
+            unsatisfied.len()
+        );
         return (
-            StatusCode::BAD_REQUEST,
-            format!("Invalid flow template format: {}", e),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "error": "Flow template uses plugin versions that aren't installed",
+                "unsatisfied_dependencies": unsatisfied,
+            })),
         )
             .into_response();
     }
-    println!("[WORKFLOW FROM JSON] Flow template successfully parsed");
+    let mut flow_template = payload.flow_template;
+    apply_plugin_versions(&mut flow_template, &flow_definition);
 
     let flow_id = payload.flow_id;
     println!("[WORKFLOW FROM JSON] Using flow_id: {}", flow_id);
@@ -437,7 +558,7 @@ pub async fn create_workflow_from_json(
     let version_input = BaseFlowVersionInput {
         account_id: account_id.clone(),
         flow_id: flow_id.clone(),
-        flow_definition: payload.flow_template,
+        flow_definition: flow_template,
     };
 
     let version_response = match client
@@ -550,6 +671,139 @@ pub async fn delete_workflow(
     Json(body).into_response()
 }
 
+pub async fn cancel_workflow_session(
+    Path((account_id, flow_id, flow_session_id)): Path<(String, String, String)>,
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<User>,
+) -> impl IntoResponse {
+    println!("Handling a cancel_workflow_session");
+
+    let flow_session_uuid = match Uuid::parse_str(&flow_session_id) {
+        Ok(uuid) => uuid,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid flow session id").into_response(),
+    };
+
+    let client = &state.anything_client;
+
+    //Make sure the session belongs to this account before we touch it
+    let response = match client
+        .from("tasks")
+        .auth(user.jwt)
+        .eq("flow_session_id", &flow_session_id)
+        .eq("flow_id", &flow_id)
+        .eq("account_id", &account_id)
+        .select("task_id")
+        .limit(1)
+        .execute()
+        .await
+    {
+        Ok(response) => response,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to execute request",
+            )
+                .into_response()
+        }
+    };
+
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read response body",
+            )
+                .into_response()
+        }
+    };
+
+    let tasks: Value = match serde_json::from_str(&body) {
+        Ok(tasks) => tasks,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse JSON").into_response()
+        }
+    };
+
+    match tasks.as_array() {
+        Some(tasks) if !tasks.is_empty() => {}
+        _ => return (StatusCode::NOT_FOUND, "Flow session not found").into_response(),
+    }
+
+    //Only running sessions and ones paused at an approval can be canceled
+    if cancel_flow_session(&state, &flow_session_uuid).await == CancelOutcome::NothingToCancel {
+        return (StatusCode::CONFLICT, "Flow session is not running").into_response();
+    }
+
+    Json(serde_json::json!({
+        "flow_session_id": flow_session_id,
+        "flow_session_status": "canceled"
+    }))
+    .into_response()
+}
+
+// Lets the editor check an unsaved flow definition before saving it. Only looks at the definition,
+// nothing is read from the database
+pub async fn lint_workflow_definition(
+    Path(_account_id): Path<String>,
+    Json(flow_definition): Json<Value>,
+) -> impl IntoResponse {
+    println!("Handling a lint_workflow_definition");
+
+    let flow_definition: WorkflowVersionDefinition = match serde_json::from_value(flow_definition) {
+        Ok(flow_definition) => flow_definition,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid flow definition: {}", e),
+            )
+                .into_response()
+        }
+    };
+
+    Json(serde_json::json!({ "issues": lint_workflow(&flow_definition) })).into_response()
+}
+
+// Checks a flow against the account before running it with `inputs`. Same as the lint, the
+// secrets it uses have to exist and every action has to bundle. Nothing is run or written
+pub async fn preflight_workflow_definition(
+    Path(account_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PreflightWorkflowInput>,
+) -> impl IntoResponse {
+    println!("Handling a preflight_workflow_definition");
+
+    let flow_definition: WorkflowVersionDefinition =
+        match serde_json::from_value(payload.flow_definition) {
+            Ok(flow_definition) => flow_definition,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid flow definition: {}", e),
+                )
+                    .into_response()
+            }
+        };
+
+    let stage = payload.stage.unwrap_or(Stage::Testing);
+    match preflight_workflow(
+        state.clone(),
+        &state.anything_client,
+        &account_id,
+        &stage,
+        &flow_definition,
+        &payload.inputs,
+    )
+    .await
+    {
+        Ok(issues) => Json(serde_json::json!({ "issues": issues })).into_response(),
+        Err(e) => {
+            println!("Failed to preflight workflow: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        }
+    }
+}
+
 pub async fn update_workflow(
     Path((account_id, flow_id)): Path<(String, String)>,
     State(state): State<Arc<AppState>>,
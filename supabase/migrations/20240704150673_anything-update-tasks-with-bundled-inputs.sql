@@ -0,0 +1,3 @@
+-- The rendered inputs a task ran with so a single task can be inspected and replayed
+ALTER TABLE anything.tasks
+ADD COLUMN bundled_inputs jsonb;
@@ -0,0 +1,32 @@
+-- Task results over the offload threshold are stored here and the task result holds a reference
+CREATE TABLE IF NOT EXISTS anything.task_large_results
+(
+    result_id uuid unique NOT NULL DEFAULT uuid_generate_v4() primary key,
+    account_id uuid not null references basejump.accounts(id),
+    task_id uuid not null references anything.tasks(task_id) ON DELETE CASCADE,
+    flow_session_id uuid not null,
+    value jsonb not null,
+    size_bytes bigint not null,
+
+    -- timestamps are useful for auditing
+    -- Basejump has some convenience functions defined below for automatically handling these
+    updated_at timestamp with time zone,
+    created_at timestamp with time zone
+);
+
+-- protect the timestamps by setting created_at and updated_at to be read-only and managed by a trigger
+CREATE TRIGGER set_task_large_results_timestamp
+    BEFORE INSERT OR UPDATE ON anything.task_large_results
+    FOR EACH ROW
+EXECUTE PROCEDURE basejump.trigger_set_timestamps();
+
+-- enable RLS on the table
+ALTER TABLE anything.task_large_results ENABLE ROW LEVEL SECURITY;
+
+-- Rows are written by the processor with the service role. Users can only read them
+create policy "Account members can select" on anything.task_large_results
+    for select
+    to authenticated
+    using (
+    (account_id IN ( SELECT basejump.get_accounts_with_role()))
+    );
@@ -0,0 +1,32 @@
+-- Processor messages that failed before a trigger task existed, kept so they can be inspected and replayed
+CREATE TABLE IF NOT EXISTS anything.processor_dead_letters
+(
+    dead_letter_id uuid unique NOT NULL DEFAULT uuid_generate_v4() primary key,
+    account_id uuid references basejump.accounts(id) ON DELETE CASCADE,
+    flow_session_id uuid not null,
+    workflow_id uuid not null,
+    message jsonb not null,
+    error text not null,
+
+    -- timestamps are useful for auditing
+    -- Basejump has some convenience functions defined below for automatically handling these
+    updated_at timestamp with time zone,
+    created_at timestamp with time zone
+);
+
+-- protect the timestamps by setting created_at and updated_at to be read-only and managed by a trigger
+CREATE TRIGGER set_processor_dead_letters_timestamp
+    BEFORE INSERT OR UPDATE ON anything.processor_dead_letters
+    FOR EACH ROW
+EXECUTE PROCEDURE basejump.trigger_set_timestamps();
+
+-- enable RLS on the table
+ALTER TABLE anything.processor_dead_letters ENABLE ROW LEVEL SECURITY;
+
+-- Rows are written and replayed by the processor with the service role. Users can only read them
+create policy "Account members can select" on anything.processor_dead_letters
+    for select
+    to authenticated
+    using (
+    (account_id IN ( SELECT basejump.get_accounts_with_role()))
+    );